@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Collects every template path referenced from the source code, e.g. "login/login.html" or
+// "emails/welcome.txt", fails the build if one of them does not exist in static/templates and
+// writes the list to OUT_DIR so the server can check its runtime template directory against it
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=static/templates");
+
+    let mut sources = Vec::new();
+    collect_files(Path::new("src"), "rs", &mut sources);
+
+    let mut referenced: Vec<String> = Vec::new();
+    for source in &sources {
+        let content = fs::read_to_string(source).expect("Failed to read source file");
+        for template in template_literals(&content) {
+            if !referenced.contains(&template) {
+                referenced.push(template);
+            }
+        }
+    }
+    referenced.sort();
+
+    let missing: Vec<&String> = referenced
+        .iter()
+        .filter(|template| !Path::new("static/templates").join(template).is_file())
+        .collect();
+
+    if !missing.is_empty() {
+        panic!(
+            "Referenced templates missing from static/templates: {:?}",
+            missing
+        );
+    }
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let generated = format!(
+        "pub const REFERENCED_TEMPLATES: &[&str] = &{:?};\n",
+        referenced
+    );
+    fs::write(out_dir.join("template_refs.rs"), generated).expect("Failed to write template list");
+}
+
+fn collect_files(dir: &Path, extension: &str, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir)
+        .expect("Failed to read directory")
+        .flatten()
+    {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, extension, files);
+        } else if path.extension().is_some_and(|ext| ext == extension) {
+            files.push(path);
+        }
+    }
+}
+
+// Every other piece of a line split by quotes is inside a string literal
+fn template_literals(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("//"))
+        .flat_map(|line| line.split('"').skip(1).step_by(2))
+        .filter(|literal| {
+            (literal.ends_with(".html") || literal.ends_with(".txt"))
+                && literal
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '.'))
+        })
+        .map(String::from)
+        .collect()
+}
@@ -0,0 +1,41 @@
+-- Accounts whose emails only differ in case or surrounding spaces would break the unique index
+-- Refuse to continue and list them, they have to be merged or renamed by hand
+DO $$
+DECLARE
+    duplicates text;
+BEGIN
+    SELECT string_agg(accounts, '; ') INTO duplicates
+    FROM (
+        SELECT lower(trim(email)) || ': ' || string_agg(id || ' (' || email || ')', ', ' ORDER BY id) AS accounts
+        FROM users
+        GROUP BY lower(trim(email))
+        HAVING count(*) > 1
+    ) AS shared;
+
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'Accounts share an email address: %', duplicates
+            USING HINT = 'Merge or rename these accounts, then run the migration again';
+    END IF;
+END $$;
+
+-- Internationalized domains are stored as punycode, which SQL cannot convert
+-- Refuse to continue until the normalize_emails binary converted them like the app does
+DO $$
+DECLARE
+    unconverted text;
+BEGIN
+    SELECT string_agg(id || ' (' || email || ')', ', ' ORDER BY id) INTO unconverted
+    FROM users
+    WHERE substring(email FROM '@([^@]*)$') !~ '^[[:ascii:]]*$';
+
+    IF unconverted IS NOT NULL THEN
+        RAISE EXCEPTION 'Accounts have internationalized domains: %', unconverted
+            USING HINT = 'Run cargo run --bin normalize_emails and correct invalid domains by hand, then run the migration again';
+    END IF;
+END $$;
+
+-- Emails are stored normalized (trimmed, lowercase, punycode), bring existing rows in line
+UPDATE users SET email = lower(trim(email));
+
+-- Case-insensitive uniqueness, also covers rows written without normalization
+CREATE UNIQUE INDEX users_email_lower_key ON users (lower(email));
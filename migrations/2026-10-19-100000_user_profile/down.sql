@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER set_updated_at ON users;
+
+ALTER TABLE users
+    DROP COLUMN display_name,
+    DROP COLUMN timezone,
+    DROP COLUMN locale,
+    DROP COLUMN avatar_url,
+    DROP COLUMN created_at,
+    DROP COLUMN updated_at;
@@ -0,0 +1,10 @@
+-- Profile fields shown and edited on the settings page
+ALTER TABLE users
+    ADD COLUMN display_name VARCHAR,
+    ADD COLUMN timezone VARCHAR NOT NULL DEFAULT 'UTC',
+    ADD COLUMN locale VARCHAR NOT NULL DEFAULT 'en',
+    ADD COLUMN avatar_url VARCHAR,
+    ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT NOW();
+
+SELECT diesel_manage_updated_at('users');
@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE user_status_changes;
+DROP INDEX users_deleted_idx;
+
+ALTER TABLE users
+    DROP COLUMN status,
+    DROP COLUMN status_changed_at,
+    DROP COLUMN role;
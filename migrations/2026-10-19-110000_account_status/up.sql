@@ -0,0 +1,23 @@
+ALTER TABLE users
+    ADD COLUMN status VARCHAR NOT NULL DEFAULT 'active'
+        CHECK (status IN ('active', 'unverified', 'locked', 'disabled', 'deleted')),
+    ADD COLUMN status_changed_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    ADD COLUMN role VARCHAR NOT NULL DEFAULT 'user'
+        CHECK (role IN ('user', 'admin'));
+
+-- Who changed the status of an account, when and why
+CREATE TABLE user_status_changes (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    old_status VARCHAR NOT NULL,
+    new_status VARCHAR NOT NULL,
+    -- NULL for changes made by the system
+    changed_by INT REFERENCES users (id) ON DELETE SET NULL,
+    reason VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX user_status_changes_user_id_idx ON user_status_changes (user_id);
+
+-- Used by the job which purges deleted accounts after the grace period
+CREATE INDEX users_deleted_idx ON users (status_changed_at) WHERE status = 'deleted';
@@ -0,0 +1,16 @@
+-- Successful and failed logins of each account
+CREATE TABLE login_events (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    ip VARCHAR NOT NULL,
+    user_agent VARCHAR NOT NULL,
+    outcome VARCHAR NOT NULL
+        CHECK (outcome IN ('success', 'wrong_password', 'blocked')),
+    method VARCHAR NOT NULL,
+    -- Hash of ip and user agent, used to notice logins from new devices
+    fingerprint VARCHAR NOT NULL
+);
+
+CREATE INDEX login_events_user_id_idx ON login_events (user_id, created_at);
+CREATE INDEX login_events_fingerprint_idx ON login_events (user_id, fingerprint) WHERE outcome = 'success';
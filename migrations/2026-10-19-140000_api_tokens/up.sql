@@ -0,0 +1,18 @@
+-- Personal tokens for scripts and CI jobs, sent as "Authorization: Bearer <token>"
+CREATE TABLE api_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    -- Random part of the token which is stored in plain text to find it
+    prefix VARCHAR NOT NULL UNIQUE,
+    -- blake3 of the whole token, the token itself is only shown once
+    token_hash VARCHAR NOT NULL,
+    scopes TEXT[] NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP,
+    last_used_at TIMESTAMP,
+    last_used_ip VARCHAR,
+    revoked_at TIMESTAMP
+);
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
@@ -0,0 +1,18 @@
+-- Rotating refresh tokens of API clients | Every refresh replaces the token with a new one of
+-- the same family, presenting a replaced token again revokes the whole family
+CREATE TABLE refresh_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- Shared by all tokens which descend from the same login
+    family_id UUID NOT NULL,
+    -- blake3 of the token, the token itself is only sent to the client
+    token_hash VARCHAR NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP NOT NULL,
+    -- Set once the token has been exchanged for a new one
+    used_at TIMESTAMP,
+    revoked_at TIMESTAMP
+);
+
+CREATE INDEX refresh_tokens_user_id_idx ON refresh_tokens (user_id);
+CREATE INDEX refresh_tokens_family_id_idx ON refresh_tokens (family_id);
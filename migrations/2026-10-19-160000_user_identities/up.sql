@@ -0,0 +1,16 @@
+-- Accounts at external OpenID Connect providers which can be used to log in
+CREATE TABLE user_identities (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- Name of the provider in the config, e.g. "google"
+    provider VARCHAR NOT NULL,
+    -- Stable id of the account at the provider, the "sub" claim
+    subject VARCHAR NOT NULL,
+    -- Email the provider reported when the identity was linked
+    email VARCHAR,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    last_login_at TIMESTAMP,
+    UNIQUE (provider, subject),
+    -- One account per provider and user
+    UNIQUE (user_id, provider)
+);
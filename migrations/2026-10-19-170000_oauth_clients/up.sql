@@ -0,0 +1,45 @@
+-- Applications which let users log in with their account here, registered by admins
+CREATE TABLE oauth_clients (
+    id SERIAL PRIMARY KEY,
+    -- Public identifier sent by the application
+    client_id VARCHAR NOT NULL UNIQUE,
+    -- blake3 of the client secret | NULL for public clients, e.g. single page apps
+    secret_hash VARCHAR,
+    name VARCHAR NOT NULL,
+    -- Codes are only sent to these exact urls
+    redirect_uris TEXT[] NOT NULL,
+    created_by INT REFERENCES users (id) ON DELETE SET NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMP
+);
+
+-- Short lived codes of the authorization code flow, exchanged once for tokens
+CREATE TABLE oauth_authorization_codes (
+    id SERIAL PRIMARY KEY,
+    -- blake3 of the code, the code itself is only sent to the application
+    code_hash VARCHAR NOT NULL UNIQUE,
+    client_id INT NOT NULL REFERENCES oauth_clients (id) ON DELETE CASCADE,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    redirect_uri VARCHAR NOT NULL,
+    scopes TEXT[] NOT NULL,
+    nonce VARCHAR,
+    -- S256 PKCE challenge
+    code_challenge VARCHAR NOT NULL,
+    -- When the user logged in, sent as auth_time in the ID token
+    auth_time TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP
+);
+
+CREATE INDEX oauth_authorization_codes_user_id_idx ON oauth_authorization_codes (user_id);
+
+-- Scopes users allowed an application to use, so they are only asked again for new ones
+CREATE TABLE oauth_consents (
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    client_id INT NOT NULL REFERENCES oauth_clients (id) ON DELETE CASCADE,
+    scopes TEXT[] NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, client_id)
+);
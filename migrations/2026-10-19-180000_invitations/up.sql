@@ -0,0 +1,15 @@
+-- Invitations to register while registration is not open to everyone
+CREATE TABLE invitations (
+    id SERIAL PRIMARY KEY,
+    -- blake3 hash of the token in the invitation link
+    token_hash VARCHAR NOT NULL UNIQUE,
+    -- The account has to be registered with this address
+    email VARCHAR NOT NULL,
+    invited_by INT REFERENCES users (id) ON DELETE SET NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP NOT NULL,
+    accepted_at TIMESTAMP,
+    revoked_at TIMESTAMP
+);
+
+CREATE INDEX invitations_invited_by_idx ON invitations (invited_by);
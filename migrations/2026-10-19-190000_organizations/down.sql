@@ -0,0 +1,6 @@
+ALTER TABLE invitations
+    DROP COLUMN organization_role,
+    DROP COLUMN organization_id;
+
+DROP TABLE memberships;
+DROP TABLE organizations;
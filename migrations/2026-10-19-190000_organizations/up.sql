@@ -0,0 +1,28 @@
+-- Tenants | Users belong to any number of them through memberships
+CREATE TABLE organizations (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    -- Lower case letters, digits and hyphens, used in urls
+    slug VARCHAR NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE memberships (
+    organization_id INT NOT NULL REFERENCES organizations (id) ON DELETE CASCADE,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role VARCHAR NOT NULL DEFAULT 'member'
+        CHECK (role IN ('owner', 'admin', 'member')),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (organization_id, user_id)
+);
+
+CREATE INDEX memberships_user_id_idx ON memberships (user_id);
+
+-- Invitations to join an organization | The invited address registers first if it has no account
+ALTER TABLE invitations
+    ADD COLUMN organization_id INT REFERENCES organizations (id) ON DELETE CASCADE,
+    ADD COLUMN organization_role VARCHAR
+        CHECK (organization_role IN ('owner', 'admin', 'member')),
+    ADD CHECK ((organization_id IS NULL) = (organization_role IS NULL));
+
+CREATE INDEX invitations_organization_id_idx ON invitations (organization_id);
@@ -0,0 +1,4 @@
+ALTER TABLE organizations
+    DROP COLUMN logo_url,
+    DROP COLUMN primary_color,
+    DROP COLUMN accent_color;
@@ -0,0 +1,5 @@
+-- Shown instead of the defaults on pages of the organization | NULL keeps the default
+ALTER TABLE organizations
+    ADD COLUMN logo_url VARCHAR,
+    ADD COLUMN primary_color VARCHAR CHECK (primary_color ~ '^#[0-9a-f]{6}$'),
+    ADD COLUMN accent_color VARCHAR CHECK (accent_color ~ '^#[0-9a-f]{6}$');
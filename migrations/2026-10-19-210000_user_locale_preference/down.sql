@@ -0,0 +1,5 @@
+UPDATE users SET locale = 'en' WHERE locale IS NULL;
+
+ALTER TABLE users
+    ALTER COLUMN locale SET DEFAULT 'en',
+    ALTER COLUMN locale SET NOT NULL;
@@ -0,0 +1,7 @@
+-- NULL follows the language of the browser | English was the only choice so far, so nobody
+-- has picked it on purpose
+ALTER TABLE users
+    ALTER COLUMN locale DROP NOT NULL,
+    ALTER COLUMN locale DROP DEFAULT;
+
+UPDATE users SET locale = NULL WHERE locale = 'en';
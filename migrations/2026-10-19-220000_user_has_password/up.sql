@@ -0,0 +1,12 @@
+-- Accounts created through a login provider get a random password nobody knows | They set
+-- their first one without the current password and cannot disconnect their last identity
+ALTER TABLE users ADD COLUMN has_password BOOLEAN NOT NULL DEFAULT TRUE;
+
+-- Registered through a provider and never changed the password since
+UPDATE users SET has_password = FALSE
+WHERE 'user:' || id IN (
+    SELECT target FROM audit_log WHERE action = 'user.register' AND metadata ? 'provider'
+)
+AND 'user:' || id NOT IN (
+    SELECT target FROM audit_log WHERE action = 'user.password_change' AND target IS NOT NULL
+);
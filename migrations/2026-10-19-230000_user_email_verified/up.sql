@@ -0,0 +1,11 @@
+-- Whether the owner of the address proved it, by opening a link mailed to it or through a login
+-- provider which verified it | Accounts can be active without, e.g. in open registration
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Confirmed the address, registered with an invitation mailed to it or through a provider
+UPDATE users SET email_verified = TRUE
+WHERE 'user:' || id IN (
+    SELECT target FROM audit_log
+    WHERE action IN ('user.verify_email', 'user.email_change')
+    OR (action = 'user.register' AND (metadata ? 'provider' OR metadata ->> 'invitation_id' IS NOT NULL))
+);
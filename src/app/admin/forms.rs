@@ -0,0 +1,159 @@
+use chrono::{Duration, NaiveDate};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use validator::{Validate, ValidationError};
+
+use crate::models::audit::AuditFilter;
+use crate::models::users::AccountStatus;
+use crate::utils::i18n::translate_with;
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub search: String,
+}
+
+#[derive(Deserialize, Serialize, Validate)]
+pub struct StatusForm {
+    #[validate(custom(function = "validate_status"))]
+    pub status: String,
+    // Kept in the status history of the account
+    #[validate(length(
+        min = 1,
+        max = 500,
+        message = "Please give a reason of at most 500 characters"
+    ))]
+    pub reason: String,
+}
+
+#[derive(Deserialize)]
+pub struct RoleForm {
+    pub role: String,
+}
+
+// New OAuth client
+#[derive(Deserialize, Serialize, Validate)]
+pub struct ClientForm {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1 to 100 characters"))]
+    pub name: String,
+    // One per line
+    #[validate(custom(function = "validate_redirect_uris"))]
+    pub redirect_uris: String,
+    // Checkbox for clients without a secret, only sent when checked
+    #[serde(default)]
+    pub public: Option<String>,
+}
+
+impl ClientForm {
+    pub fn redirect_uris(&self) -> Vec<String> {
+        redirect_uri_lines(&self.redirect_uris)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RevokeClientForm {
+    pub client_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeInvitationForm {
+    pub invitation_id: i32,
+}
+
+// Filters of the audit log | Empty fields match everything
+#[derive(Deserialize, Serialize)]
+pub struct AuditQuery {
+    #[serde(default)]
+    pub action: String,
+    #[serde(default)]
+    pub actor: String,
+    #[serde(default)]
+    pub target: String,
+    // Days as YYYY-MM-DD, both included
+    #[serde(default)]
+    pub from: String,
+    #[serde(default)]
+    pub to: String,
+    pub before: Option<i64>,
+}
+
+impl AuditQuery {
+    pub fn to_filter(&self) -> Result<AuditFilter, String> {
+        let from = parse_day(&self.from)?;
+        let to = parse_day(&self.to)?;
+
+        Ok(AuditFilter {
+            action: non_empty(&self.action),
+            actor: non_empty(&self.actor),
+            target: non_empty(&self.target),
+            from: from.and_then(|day| day.and_hms_opt(0, 0, 0)),
+            to: to.and_then(|day| (day + Duration::days(1)).and_hms_opt(0, 0, 0)),
+            before: self.before,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub format: String,
+}
+
+fn parse_day(day: &str) -> Result<Option<NaiveDate>, String> {
+    match day.trim() {
+        "" => Ok(None),
+        day => NaiveDate::parse_from_str(day, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| {
+                translate_with(
+                    "Invalid date {date}, please use YYYY-MM-DD",
+                    &[("date", day)],
+                )
+            }),
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    match value.trim() {
+        "" => None,
+        value => Some(value.to_string()),
+    }
+}
+
+fn validate_status(status: &str) -> Result<(), ValidationError> {
+    match AccountStatus::parse(status) {
+        Some(_) => Ok(()),
+        None => Err(ValidationError::new("status").with_message("Unknown status".into())),
+    }
+}
+
+fn redirect_uri_lines(uris: &str) -> Vec<String> {
+    uris.lines()
+        .map(str::trim)
+        .filter(|uri| !uri.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Absolute https urls without fragment | Plain http only for applications on the same machine
+fn validate_redirect_uris(uris: &str) -> Result<(), ValidationError> {
+    let uris = redirect_uri_lines(uris);
+    if uris.is_empty() {
+        return Err(ValidationError::new("redirect_uris")
+            .with_message("Please enter at least one redirect url".into()));
+    }
+
+    for uri in uris {
+        let valid = Url::parse(&uri).is_ok_and(|url| {
+            let local = matches!(
+                url.host_str(),
+                Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+            );
+            url.fragment().is_none() && (url.scheme() == "https" || url.scheme() == "http" && local)
+        });
+        if !valid {
+            return Err(ValidationError::new("redirect_uris")
+                .with_message(format!("{} is not an https url without fragment", uri).into()));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,3 @@
+pub mod forms;
+pub mod urls;
+pub mod views;
@@ -0,0 +1,27 @@
+use actix_web::web;
+
+use super::views;
+
+pub fn register_urls(cfg: &mut web::ServiceConfig) {
+    cfg.route("/admin/users", web::get().to(views::users))
+        .route("/admin/users/{id}", web::get().to(views::user))
+        .route(
+            "/admin/users/{id}/status",
+            web::post().to(views::status_submit),
+        )
+        .route("/admin/users/{id}/role", web::post().to(views::role_submit))
+        .route("/admin/audit", web::get().to(views::audit_log))
+        .route("/admin/audit/export", web::get().to(views::audit_export))
+        .route("/admin/audit/verify", web::post().to(views::audit_verify))
+        .route("/admin/clients", web::get().to(views::clients))
+        .route("/admin/clients", web::post().to(views::create_client))
+        .route(
+            "/admin/clients/revoke",
+            web::post().to(views::revoke_client),
+        )
+        .route("/admin/invitations", web::get().to(views::invitations))
+        .route(
+            "/admin/invitations/revoke",
+            web::post().to(views::revoke_invitation),
+        );
+}
@@ -0,0 +1,770 @@
+use actix_session::Session;
+use actix_web::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
+use log::{error, info};
+use std::sync::Arc;
+use tera::Context;
+
+use crate::database::db::Database;
+use crate::database::errors::DatabaseError;
+use crate::models::audit::AuditEntry;
+use crate::models::oauth::NewOAuthClient;
+use crate::models::users::{AccountStatus, Role, User, ACCOUNT_STATUSES, ROLES};
+use crate::services::auth::AuthService;
+use crate::utils::audit::{self, client_target, user_target, Actor, AuditAction, AUDIT_ACTIONS};
+use crate::utils::auth::AdminUser;
+use crate::utils::flash::{FlashMessages, Level};
+use crate::utils::i18n::translate_with;
+use crate::utils::render::{render_error, render_template};
+use crate::utils::templates::Templates;
+use crate::utils::tokens::{generate_client_id, generate_token, hash_token};
+use crate::utils::validation::{FieldErrors, ValidatedForm};
+
+use super::forms::{
+    AuditQuery, ClientForm, ExportQuery, RevokeClientForm, RevokeInvitationForm, RoleForm,
+    SearchQuery, StatusForm,
+};
+
+// Most users listed at once | Narrow the search to find others
+const USERS_PER_PAGE: i64 = 100;
+
+const AUDIT_PAGE_SIZE: i64 = 50;
+
+// Most entries in one export | Narrow the filters to export older entries
+const AUDIT_EXPORT_LIMIT: i64 = 10_000;
+
+const INVITATIONS_PER_PAGE: i64 = 200;
+
+pub async fn users(
+    db: web::Data<Arc<Database>>,
+    session: Session,
+    tera: web::Data<Templates>,
+    _admin: AdminUser,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse, Error> {
+    let search = query.into_inner().search;
+    let term = search.clone();
+    let result = web::block(move || db.search_users(&term, USERS_PER_PAGE)).await;
+
+    let users = match result {
+        Ok(Ok(users)) => users,
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    let users: Vec<serde_json::Value> = users
+        .iter()
+        .map(|user| {
+            serde_json::json!({
+                "id": user.id,
+                "email": user.email,
+                "display_name": user.display_name,
+                "status": user.status,
+                "role": user.role,
+                "created_at": format_datetime(user.created_at),
+            })
+        })
+        .collect();
+
+    let mut context = Context::new();
+    context.insert("search", &search);
+    context.insert("users", &users);
+
+    render_template(
+        &tera,
+        &session,
+        "admin/users.html",
+        &context,
+        StatusCode::OK,
+    )
+}
+
+pub async fn user(
+    db: web::Data<Arc<Database>>,
+    session: Session,
+    tera: web::Data<Templates>,
+    _admin: AdminUser,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let form = StatusForm {
+        status: String::new(),
+        reason: String::new(),
+    };
+    render_user(
+        db,
+        &tera,
+        &session,
+        path.into_inner(),
+        &form,
+        &FieldErrors::new(),
+        StatusCode::OK,
+    )
+    .await
+}
+
+pub async fn status_submit(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    admin: AdminUser,
+    path: web::Path<i32>,
+    post_data: ValidatedForm<StatusForm>,
+) -> Result<HttpResponse, Error> {
+    let user_id = path.into_inner();
+
+    if !post_data.is_valid() {
+        return render_user(
+            db,
+            &tera,
+            &session,
+            user_id,
+            &post_data.data,
+            &post_data.errors,
+            StatusCode::BAD_REQUEST,
+        )
+        .await;
+    }
+
+    // Locking yourself out leaves nobody to undo it
+    if user_id == admin.id {
+        return redirect_to_user(
+            &session,
+            user_id,
+            Level::Error,
+            "You cannot change the status of your own account",
+        );
+    }
+
+    let new_status = match AccountStatus::parse(&post_data.status) {
+        Some(status) => status,
+        None => return Ok(HttpResponse::BadRequest().finish()),
+    };
+    let admin_id = admin.id;
+    let reason = post_data.reason.trim().to_string();
+    let change_reason = reason.clone();
+    let change_db = db.clone();
+    let result = web::block(move || {
+        let user = change_db.get_user_by_id(user_id)?;
+        let updated =
+            change_db.change_user_status(&user, new_status, Some(admin_id), &change_reason)?;
+        Ok::<_, DatabaseError>((user.status, updated))
+    })
+    .await;
+
+    match result {
+        Ok(Ok((old_status, user))) => {
+            info!(
+                "Admin {} changed status of {} to {}",
+                admin.email,
+                user.email,
+                new_status.as_str()
+            );
+            audit::record(
+                &db,
+                Actor::user(&admin, &req),
+                AuditAction::StatusChange,
+                user_target(user.id),
+                serde_json::json!({
+                    "email": user.email,
+                    "old_status": old_status,
+                    "new_status": new_status,
+                    "reason": reason,
+                }),
+            )
+            .await;
+            redirect_to_user(
+                &session,
+                user_id,
+                Level::Success,
+                "The status has been changed",
+            )
+        }
+        Ok(Err(DatabaseError::InvalidStatusChange(message))) => {
+            redirect_to_user(&session, user_id, Level::Error, &message)
+        }
+        Ok(Err(DatabaseError::DieselError(diesel::result::Error::NotFound))) => {
+            not_found(&tera, &session)
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+pub async fn role_submit(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    admin: AdminUser,
+    path: web::Path<i32>,
+    post_data: web::Form<RoleForm>,
+) -> Result<HttpResponse, Error> {
+    let user_id = path.into_inner();
+
+    // Otherwise the last admin could lock everyone out of the admin pages
+    if user_id == admin.id {
+        return redirect_to_user(
+            &session,
+            user_id,
+            Level::Error,
+            "You cannot change the role of your own account",
+        );
+    }
+
+    let new_role = match Role::parse(&post_data.role) {
+        Some(role) => role,
+        None => return Ok(HttpResponse::BadRequest().finish()),
+    };
+    let change_db = db.clone();
+    let result = web::block(move || {
+        let user = change_db.get_user_by_id(user_id)?;
+        let updated = change_db.change_user_role(&user, new_role)?;
+        Ok::<_, DatabaseError>((user.role, updated))
+    })
+    .await;
+
+    match result {
+        Ok(Ok((old_role, user))) => {
+            info!(
+                "Admin {} changed role of {} to {}",
+                admin.email,
+                user.email,
+                new_role.as_str()
+            );
+            audit::record(
+                &db,
+                Actor::user(&admin, &req),
+                AuditAction::RoleChange,
+                user_target(user.id),
+                serde_json::json!({
+                    "email": user.email,
+                    "old_role": old_role,
+                    "new_role": new_role,
+                }),
+            )
+            .await;
+            redirect_to_user(
+                &session,
+                user_id,
+                Level::Success,
+                "The role has been changed",
+            )
+        }
+        Ok(Err(DatabaseError::DieselError(diesel::result::Error::NotFound))) => {
+            not_found(&tera, &session)
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+pub async fn audit_log(
+    db: web::Data<Arc<Database>>,
+    session: Session,
+    tera: web::Data<Templates>,
+    _admin: AdminUser,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, Error> {
+    let actions: Vec<&str> = AUDIT_ACTIONS.iter().map(|action| action.as_str()).collect();
+    let mut context = Context::new();
+    context.insert("query", &*query);
+    context.insert("actions", &actions);
+
+    let filter = match query.to_filter() {
+        Ok(filter) => filter,
+        Err(message) => {
+            context.insert("error", &message);
+            context.insert("entries", &Vec::<serde_json::Value>::new());
+            return render_template(
+                &tera,
+                &session,
+                "admin/audit.html",
+                &context,
+                StatusCode::BAD_REQUEST,
+            );
+        }
+    };
+
+    // One more than shown, to know whether there is another page
+    let result = web::block(move || db.search_audit_log(&filter, AUDIT_PAGE_SIZE + 1)).await;
+    let mut entries = match result {
+        Ok(Ok(entries)) => entries,
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    if entries.len() as i64 > AUDIT_PAGE_SIZE {
+        entries.truncate(AUDIT_PAGE_SIZE as usize);
+        context.insert("next_before", &entries.last().map(|entry| entry.id));
+    }
+
+    let entries: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "id": entry.id,
+                "created_at": format_datetime(entry.created_at),
+                "actor_id": entry.actor_id,
+                "actor_email": entry.actor_email,
+                "ip": entry.ip,
+                "action": entry.action,
+                "target": entry.target,
+                "metadata": entry.metadata.to_string(),
+            })
+        })
+        .collect();
+    context.insert("entries", &entries);
+
+    render_template(
+        &tera,
+        &session,
+        "admin/audit.html",
+        &context,
+        StatusCode::OK,
+    )
+}
+
+// Entries matching the filters as a JSON or CSV download, including their hashes so the
+// chain can be checked outside of the application
+pub async fn audit_export(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    admin: AdminUser,
+    query: web::Query<AuditQuery>,
+    export: web::Query<ExportQuery>,
+) -> Result<HttpResponse, Error> {
+    let filter = match query.to_filter() {
+        Ok(filter) => filter,
+        Err(message) => return Ok(HttpResponse::BadRequest().body(message)),
+    };
+
+    let search_db = db.clone();
+    let result = web::block(move || search_db.search_audit_log(&filter, AUDIT_EXPORT_LIMIT)).await;
+    let entries = match result {
+        Ok(Ok(entries)) => entries,
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    let (content_type, body) = match export.format.as_str() {
+        "json" => match serde_json::to_vec_pretty(&entries) {
+            Ok(body) => ("application/json", body),
+            Err(err) => return unavailable(&tera, &session, err),
+        },
+        "csv" => match audit_csv(&entries) {
+            Ok(body) => ("text/csv; charset=utf-8", body),
+            Err(err) => return unavailable(&tera, &session, err),
+        },
+        _ => return Ok(HttpResponse::BadRequest().body("Unknown export format")),
+    };
+
+    audit::record(
+        &db,
+        Actor::user(&admin, &req),
+        AuditAction::AuditExport,
+        None,
+        serde_json::json!({
+            "format": export.format,
+            "filter": &*query,
+            "entries": entries.len(),
+        }),
+    )
+    .await;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, content_type))
+        .insert_header((
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"audit-log.{}\"", export.format),
+        ))
+        .body(body))
+}
+
+pub async fn audit_verify(
+    db: web::Data<Arc<Database>>,
+    session: Session,
+    tera: web::Data<Templates>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, Error> {
+    let result = web::block(move || db.verify_audit_chain()).await;
+
+    let (level, message) = match result {
+        Ok(Ok(None)) => (
+            Level::Success,
+            String::from("The audit log is intact, no entry has been changed or removed"),
+        ),
+        Ok(Ok(Some(id))) => {
+            error!("Audit log hash chain is broken at entry {}", id);
+            (
+                Level::Error,
+                translate_with(
+                    "The audit log has been tampered with, the hash chain breaks at entry {entry}",
+                    &[("entry", &id.to_string())],
+                ),
+            )
+        }
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    FlashMessages::new(&session).push(level, &message);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/admin/audit"))
+        .finish())
+}
+
+pub async fn clients(
+    db: web::Data<Arc<Database>>,
+    session: Session,
+    tera: web::Data<Templates>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, Error> {
+    render_clients(&db, &tera, &session, Context::new(), StatusCode::OK).await
+}
+
+// The secret is shown right away instead of after a redirect, so it never ends up in the session
+pub async fn create_client(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    admin: AdminUser,
+    post_data: ValidatedForm<ClientForm>,
+) -> Result<HttpResponse, Error> {
+    if !post_data.is_valid() {
+        let mut context = Context::new();
+        context.insert("form", &post_data.data);
+        context.insert("field_errors", &post_data.errors);
+        return render_clients(&db, &tera, &session, context, StatusCode::BAD_REQUEST).await;
+    }
+
+    let secret = match post_data.public {
+        Some(_) => None,
+        None => Some(generate_token()),
+    };
+    let new_client = NewOAuthClient {
+        client_id: generate_client_id(),
+        secret_hash: secret.as_deref().map(hash_token),
+        name: post_data.name.trim().to_string(),
+        redirect_uris: post_data.redirect_uris(),
+        created_by: Some(admin.id),
+    };
+    let create_db = db.clone();
+    let client = match web::block(move || create_db.create_oauth_client(&new_client)).await {
+        Ok(Ok(client)) => client,
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    info!(
+        "Admin {} registered OAuth client {}",
+        admin.email, client.name
+    );
+    audit::record(
+        &db,
+        Actor::user(&admin, &req),
+        AuditAction::OAuthClientCreate,
+        client_target(&client.client_id),
+        serde_json::json!({
+            "name": client.name,
+            "redirect_uris": client.redirect_uris,
+            "public": client.is_public(),
+        }),
+    )
+    .await;
+
+    let mut context = Context::new();
+    context.insert("new_client_id", &client.client_id);
+    context.insert("new_client_secret", &secret);
+    render_clients(&db, &tera, &session, context, StatusCode::OK).await
+}
+
+// Codes and access tokens of the client stop working right away
+pub async fn revoke_client(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    admin: AdminUser,
+    post_data: web::Form<RevokeClientForm>,
+) -> Result<HttpResponse, Error> {
+    let client_id = post_data.client_id;
+    let revoke_db = db.clone();
+    let result = web::block(move || revoke_db.revoke_oauth_client(client_id)).await;
+
+    let message = match result {
+        Ok(Ok(Some(client))) => {
+            info!("Admin {} revoked OAuth client {}", admin.email, client.name);
+            audit::record(
+                &db,
+                Actor::user(&admin, &req),
+                AuditAction::OAuthClientRevoke,
+                client_target(&client.client_id),
+                serde_json::json!({ "name": client.name }),
+            )
+            .await;
+            (Level::Success, "The application has been revoked")
+        }
+        Ok(Ok(None)) => (Level::Error, "The application has been revoked already"),
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    FlashMessages::new(&session).push(message.0, message.1);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/admin/clients"))
+        .finish())
+}
+
+// Invitations of every user, newest first
+pub async fn invitations(
+    auth: AuthService,
+    session: Session,
+    tera: web::Data<Templates>,
+    _admin: AdminUser,
+) -> Result<HttpResponse, Error> {
+    let invitations = match auth.invitations(None, INVITATIONS_PER_PAGE).await {
+        Ok(invitations) => invitations,
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    let now = chrono::Utc::now().naive_utc();
+    let invitations: Vec<serde_json::Value> = invitations
+        .iter()
+        .map(|(invitation, inviter)| {
+            serde_json::json!({
+                "id": invitation.id,
+                "email": invitation.email,
+                "invited_by": invitation.invited_by,
+                "inviter": inviter,
+                "state": invitation.state(now),
+                "created_at": format_datetime(invitation.created_at),
+                "expires_at": format_datetime(invitation.expires_at),
+            })
+        })
+        .collect();
+
+    let mut context = Context::new();
+    context.insert("invitations", &invitations);
+    render_template(
+        &tera,
+        &session,
+        "admin/invitations.html",
+        &context,
+        StatusCode::OK,
+    )
+}
+
+pub async fn revoke_invitation(
+    auth: AuthService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    admin: AdminUser,
+    post_data: web::Form<RevokeInvitationForm>,
+) -> Result<HttpResponse, Error> {
+    let message = match auth
+        .revoke_invitation(&req, &admin, post_data.invitation_id)
+        .await
+    {
+        Ok(Some(invitation)) => {
+            info!(
+                "Admin {} revoked the invitation of {}",
+                admin.email, invitation.email
+            );
+            (Level::Success, "The invitation has been revoked")
+        }
+        Ok(None) => (Level::Error, "The invitation cannot be revoked anymore"),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    FlashMessages::new(&session).push(message.0, message.1);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/admin/invitations"))
+        .finish())
+}
+
+fn audit_csv(entries: &[AuditEntry]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record([
+        "id",
+        "created_at",
+        "actor_id",
+        "actor_email",
+        "ip",
+        "action",
+        "target",
+        "metadata",
+        "prev_hash",
+        "hash",
+    ])?;
+
+    for entry in entries {
+        writer.write_record([
+            entry.id.to_string(),
+            entry.created_at.format("%Y-%m-%dT%H:%M:%S%.6f").to_string(),
+            entry.actor_id.map(|id| id.to_string()).unwrap_or_default(),
+            entry.actor_email.clone().unwrap_or_default(),
+            entry.ip.clone().unwrap_or_default(),
+            entry.action.clone(),
+            entry.target.clone().unwrap_or_default(),
+            entry.metadata.to_string(),
+            entry.prev_hash.clone(),
+            entry.hash.clone(),
+        ])?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|err| csv::Error::from(err.into_error()))
+}
+
+// Details and status history of an account together with the form to change its status
+async fn render_user(
+    db: web::Data<Arc<Database>>,
+    tera: &web::Data<Templates>,
+    session: &Session,
+    user_id: i32,
+    form: &StatusForm,
+    field_errors: &FieldErrors,
+    status_code: StatusCode,
+) -> Result<HttpResponse, Error> {
+    let result = web::block(move || {
+        let user = db.get_user_by_id(user_id)?;
+        let changes = db.get_status_changes(user_id)?;
+        Ok::<_, DatabaseError>((user, changes))
+    })
+    .await;
+
+    let (user, changes) = match result {
+        Ok(Ok(found)) => found,
+        Ok(Err(DatabaseError::DieselError(diesel::result::Error::NotFound))) => {
+            return not_found(tera, session)
+        }
+        Ok(Err(err)) => return unavailable(tera, session, err),
+        Err(err) => return unavailable(tera, session, err),
+    };
+
+    let changes: Vec<serde_json::Value> = changes
+        .iter()
+        .map(|change| {
+            serde_json::json!({
+                "old_status": change.old_status,
+                "new_status": change.new_status,
+                "changed_by": change.changed_by,
+                "reason": change.reason,
+                "created_at": format_datetime(change.created_at),
+            })
+        })
+        .collect();
+
+    // Only the statuses the account can be moved to are offered
+    let statuses: Vec<&str> = ACCOUNT_STATUSES
+        .iter()
+        .filter(|status| user.status.can_change_to(**status))
+        .map(|status| status.as_str())
+        .collect();
+
+    let mut context = Context::new();
+    context.insert("user", &user_context(&user));
+    context.insert("changes", &changes);
+    context.insert("statuses", &statuses);
+    context.insert("roles", &ROLES.map(|role| role.as_str()));
+    context.insert("form", form);
+    context.insert("field_errors", field_errors);
+
+    render_template(tera, session, "admin/user.html", &context, status_code)
+}
+
+// Leaves out the password hash
+// Registered clients next to the form for registering one
+async fn render_clients(
+    db: &web::Data<Arc<Database>>,
+    tera: &web::Data<Templates>,
+    session: &Session,
+    mut context: Context,
+    status: StatusCode,
+) -> Result<HttpResponse, Error> {
+    let list_db = db.clone();
+    let clients = match web::block(move || list_db.get_oauth_clients()).await {
+        Ok(Ok(clients)) => clients,
+        Ok(Err(err)) => return unavailable(tera, session, err),
+        Err(err) => return unavailable(tera, session, err),
+    };
+
+    let clients: Vec<serde_json::Value> = clients
+        .iter()
+        .map(|client| {
+            serde_json::json!({
+                "id": client.id,
+                "client_id": client.client_id,
+                "name": client.name,
+                "redirect_uris": client.redirect_uris,
+                "public": client.is_public(),
+                "created_at": format_datetime(client.created_at),
+                "revoked_at": client.revoked_at.map(format_datetime),
+            })
+        })
+        .collect();
+
+    context.insert("clients", &clients);
+    render_template(tera, session, "admin/clients.html", &context, status)
+}
+
+fn user_context(user: &User) -> serde_json::Value {
+    serde_json::json!({
+        "id": user.id,
+        "email": user.email,
+        "display_name": user.display_name,
+        "status": user.status,
+        "status_changed_at": format_datetime(user.status_changed_at),
+        "role": user.role,
+        "created_at": format_datetime(user.created_at),
+    })
+}
+
+// Admins look at accounts from all timezones, so times are shown in UTC
+fn format_datetime(date: chrono::NaiveDateTime) -> String {
+    date.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn redirect_to_user(
+    session: &Session,
+    user_id: i32,
+    level: Level,
+    text: &str,
+) -> Result<HttpResponse, Error> {
+    FlashMessages::new(session).push(level, text);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, format!("/admin/users/{}", user_id)))
+        .finish())
+}
+
+fn not_found(tera: &web::Data<Templates>, session: &Session) -> Result<HttpResponse, Error> {
+    render_error(
+        tera,
+        session,
+        "This account does not exist.",
+        "errors/error_page.html",
+        StatusCode::NOT_FOUND,
+    )
+}
+
+fn unavailable(
+    tera: &web::Data<Templates>,
+    session: &Session,
+    err: impl std::fmt::Display,
+) -> Result<HttpResponse, Error> {
+    error!("{}", err);
+    render_error(
+        tera,
+        session,
+        "We are experiencing problems, please try again later.",
+        "errors/error_page.html",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
@@ -0,0 +1,119 @@
+use actix_session::SessionExt;
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use std::sync::Arc;
+
+use crate::database::db::Database;
+use crate::models::users::User;
+use crate::services::api_tokens::{access_token_user, authenticate, TokenError};
+use crate::utils::auth::{session_user, SessionUserError};
+use crate::utils::jwt::JwtConfig;
+use crate::utils::sessions::client_ip;
+use crate::utils::tokens::api_token_prefix;
+
+use super::errors::ApiError;
+
+// Logged in user of API requests | Answers 401 instead of redirecting to the login page
+// Requests with an Authorization header need a personal API token or a JWT access token,
+// all others use the session
+pub struct ApiUser {
+    pub user: User,
+    // Scopes of personal API tokens | None for sessions and access tokens, which may do everything
+    pub scopes: Option<Vec<String>>,
+}
+
+impl ApiUser {
+    pub fn require_scope(&self, scope: &str) -> Result<(), ApiError> {
+        match &self.scopes {
+            Some(scopes) if !scopes.iter().any(|granted| granted == scope) => Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "insufficient_scope",
+                &format!("This token lacks the {} scope", scope),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::ops::Deref for ApiUser {
+    type Target = User;
+
+    fn deref(&self) -> &User {
+        &self.user
+    }
+}
+
+impl FromRequest for ApiUser {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let db = req.app_data::<web::Data<Arc<Database>>>().cloned();
+        let bearer = req
+            .headers()
+            .get(AUTHORIZATION)
+            .map(|value| bearer_token(value.to_str().unwrap_or_default()));
+
+        if let Some(token) = bearer {
+            let ip = client_ip(req);
+            let jwt = req.app_data::<web::Data<JwtConfig>>().cloned();
+            return Box::pin(async move {
+                let db = db.ok_or_else(|| ApiError::internal("Database not configured"))?;
+                let token = token.ok_or_else(invalid_token)?;
+                let result = match (api_token_prefix(&token), jwt) {
+                    (Some(_), _) => authenticate(&db, &token, ip)
+                        .await
+                        .map(|(user, api_token)| ApiUser {
+                            user,
+                            scopes: Some(api_token.scopes),
+                        }),
+                    (None, Some(jwt)) => access_token_user(&db, &jwt, &token)
+                        .await
+                        .map(|user| ApiUser { user, scopes: None }),
+                    (None, None) => Err(TokenError::Invalid),
+                };
+                match result {
+                    Ok(api_user) => Ok(api_user),
+                    Err(TokenError::Invalid) => Err(invalid_token()),
+                    Err(TokenError::Blocked(message)) => Err(account_blocked(message)),
+                    Err(TokenError::Internal) => Err(ApiError::internal("Failed to load token")),
+                }
+            });
+        }
+
+        let session = req.get_session();
+        Box::pin(async move {
+            match session_user(&session, db).await {
+                Ok(user) => Ok(ApiUser { user, scopes: None }),
+                Err(SessionUserError::NotLoggedIn) => Err(ApiError::unauthenticated()),
+                Err(SessionUserError::Blocked(message)) => Err(account_blocked(message)),
+                Err(SessionUserError::Internal) => Err(ApiError::internal("Failed to load user")),
+            }
+        })
+    }
+}
+
+// Token of an Authorization header value | None for other schemes
+pub fn bearer_token(value: &str) -> Option<String> {
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+        Some(token.trim().to_string())
+    } else {
+        None
+    }
+}
+
+fn invalid_token() -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "invalid_token",
+        "The token is invalid, expired or revoked",
+    )
+}
+
+fn account_blocked(message: &'static str) -> ApiError {
+    ApiError::new(StatusCode::FORBIDDEN, "account_blocked", message)
+}
@@ -0,0 +1,121 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use log::error;
+use serde::Serialize;
+use std::fmt;
+
+use crate::services::auth::AuthError;
+use crate::utils::validation::FieldErrors;
+
+// Error body of every API response which is not successful, e.g.
+// {"error": "invalid_credentials", "message": "Invalid mail or password"}
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    // Stable identifier clients can match on
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<FieldErrors>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: &'static str, message: &str) -> Self {
+        ApiError {
+            status,
+            error,
+            message: message.to_string(),
+            fields: None,
+        }
+    }
+
+    pub fn validation(fields: FieldErrors) -> Self {
+        ApiError {
+            fields: Some(fields),
+            ..ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "validation_failed",
+                "Some fields are invalid",
+            )
+        }
+    }
+
+    pub fn unauthenticated() -> Self {
+        ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthenticated",
+            "Please log in first",
+        )
+    }
+
+    pub fn not_found() -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", "Not found")
+    }
+
+    // Details are only logged
+    pub fn internal(err: impl fmt::Display) -> Self {
+        error!("{}", err);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "We are experiencing problems, please try again later",
+        )
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(self)
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::Invalid(fields) => ApiError::validation(fields),
+            AuthError::EmailTaken(msg) => ApiError::new(StatusCode::CONFLICT, "email_taken", &msg),
+            AuthError::InvalidCredentials => ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_credentials",
+                "Invalid mail or password",
+            ),
+            AuthError::AccountBlocked(msg) => {
+                ApiError::new(StatusCode::FORBIDDEN, "account_blocked", msg)
+            }
+            AuthError::InvalidRefreshToken => ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_grant",
+                "The refresh token is invalid, expired or revoked",
+            ),
+            AuthError::IdentityRejected(msg) => {
+                ApiError::new(StatusCode::FORBIDDEN, "identity_rejected", &msg)
+            }
+            AuthError::InvalidLink(msg) => {
+                ApiError::new(StatusCode::BAD_REQUEST, "invalid_link", msg)
+            }
+            AuthError::RegistrationClosed(msg) => {
+                ApiError::new(StatusCode::FORBIDDEN, "registration_closed", &msg)
+            }
+            AuthError::Internal(msg) => ApiError::internal(msg),
+        }
+    }
+}
+
+// Malformed JSON bodies, e.g. missing fields or wrong content type
+pub fn json_error_handler(
+    err: actix_web::error::JsonPayloadError,
+    _: &HttpRequest,
+) -> actix_web::Error {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_body", &err.to_string()).into()
+}
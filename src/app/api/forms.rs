@@ -0,0 +1,118 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationErrors};
+
+use crate::models::users::{AccountStatus, Role, User};
+use crate::services::auth::IssuedTokens;
+
+#[derive(Deserialize, Validate)]
+pub struct RegisterRequest {
+    #[validate(email(message = "Please enter a valid email address"))]
+    pub email: String,
+    // Strength is checked against the configured PasswordPolicy
+    pub password: String,
+    // Token of an invitation, needed unless the registration mode allows the address
+    #[serde(default)]
+    pub invitation: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct LoginRequest {
+    #[validate(email(message = "Please enter a valid email address"))]
+    pub email: String,
+    #[validate(length(min = 1, message = "Please enter your password"))]
+    pub password: String,
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct PasswordGrant {
+    #[validate(email(message = "Please enter a valid email address"))]
+    pub email: String,
+    #[validate(length(min = 1, message = "Please enter your password"))]
+    pub password: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct RefreshGrant {
+    #[validate(length(min = 1, message = "Please enter the refresh token"))]
+    pub refresh_token: String,
+}
+
+// Body of the token endpoint, e.g. {"grant_type": "refresh_token", "refresh_token": "..."}
+#[derive(Deserialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+pub enum TokenRequest {
+    Password(PasswordGrant),
+    RefreshToken(RefreshGrant),
+}
+
+impl Validate for TokenRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        match self {
+            TokenRequest::Password(grant) => grant.validate(),
+            TokenRequest::RefreshToken(grant) => grant.validate(),
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct RevokeRequest {
+    #[validate(length(min = 1, message = "Please enter the refresh token"))]
+    pub refresh_token: String,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+impl From<IssuedTokens> for TokenResponse {
+    fn from(tokens: IssuedTokens) -> Self {
+        TokenResponse {
+            access_token: tokens.access_token,
+            token_type: "Bearer",
+            expires_in: tokens.expires_in,
+            refresh_token: tokens.refresh_token,
+        }
+    }
+}
+
+// User as returned by the API | Leaves out the password hash
+#[derive(Serialize)]
+pub struct UserResponse {
+    pub id: i32,
+    pub email: String,
+    pub display_name: Option<String>,
+    pub timezone: String,
+    pub locale: Option<String>,
+    pub avatar_url: Option<String>,
+    pub status: AccountStatus,
+    pub role: Role,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<&User> for UserResponse {
+    fn from(user: &User) -> Self {
+        UserResponse {
+            id: user.id,
+            email: user.email.clone(),
+            display_name: user.display_name.clone(),
+            timezone: user.timezone.clone(),
+            locale: user.locale.clone(),
+            avatar_url: user.avatar_url.clone(),
+            status: user.status,
+            role: user.role,
+            created_at: user.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MessageResponse {
+    pub message: &'static str,
+}
@@ -0,0 +1,5 @@
+pub mod auth;
+pub mod errors;
+pub mod forms;
+pub mod urls;
+pub mod views;
@@ -0,0 +1,20 @@
+use actix_web::web;
+
+use super::errors::json_error_handler;
+use super::views;
+
+pub fn register_urls(cfg: &mut web::ServiceConfig) {
+    cfg.route("/.well-known/jwks.json", web::get().to(views::jwks));
+    cfg.service(
+        web::scope("/api/v1")
+            .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+            .route("/auth/register", web::post().to(views::register))
+            .route("/auth/login", web::post().to(views::login))
+            .route("/auth/logout", web::post().to(views::logout))
+            .route("/auth/token", web::post().to(views::token))
+            .route("/auth/token/revoke", web::post().to(views::revoke_token))
+            .route("/me", web::get().to(views::me))
+            .route("/me", web::put().to(views::update_me))
+            .default_service(web::to(views::not_found)),
+    );
+}
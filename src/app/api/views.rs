@@ -0,0 +1,168 @@
+use actix_session::Session;
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::{web, HttpRequest, HttpResponse};
+use std::sync::Arc;
+
+use crate::app::settings::forms::ProfileForm;
+use crate::database::db::Database;
+use crate::models::api_tokens::{SCOPE_USER_READ, SCOPE_USER_WRITE};
+use crate::services::auth::{AuthService, Registration};
+use crate::utils::audit::{self, user_target, Actor, AuditAction};
+use crate::utils::jwt::JwtConfig;
+use crate::utils::validation::ValidatedJson;
+
+use super::auth::ApiUser;
+use super::errors::ApiError;
+use super::forms::{
+    LoginRequest, MessageResponse, RegisterRequest, RevokeRequest, TokenRequest, TokenResponse,
+    UserResponse,
+};
+
+// 201 with the user, or 202 if the visitor has to check their inbox first
+pub async fn register(
+    auth: AuthService,
+    req: HttpRequest,
+    body: ValidatedJson<RegisterRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if !body.is_valid() {
+        return Err(ApiError::validation(body.errors.clone()));
+    }
+
+    match auth
+        .register(
+            &req,
+            &body.email,
+            &body.password,
+            body.invitation.as_deref(),
+        )
+        .await?
+    {
+        Registration::Created(user) => Ok(HttpResponse::Created().json(UserResponse::from(&user))),
+        Registration::CheckInbox => Ok(HttpResponse::Accepted().json(MessageResponse {
+            message: "Thanks for signing up, please check your inbox to continue",
+        })),
+    }
+}
+
+// Sets the same session cookie as the login page
+pub async fn login(
+    auth: AuthService,
+    req: HttpRequest,
+    session: Session,
+    body: ValidatedJson<LoginRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if !body.is_valid() {
+        return Err(ApiError::validation(body.errors.clone()));
+    }
+
+    let user = auth
+        .login(
+            &req,
+            &session,
+            &body.email,
+            &body.password,
+            body.remember_me,
+        )
+        .await?;
+
+    Ok(HttpResponse::Ok().json(UserResponse::from(&user)))
+}
+
+// Tokens for clients which do not keep a session | Access tokens are used like personal
+// API tokens, refresh tokens can only be exchanged once
+pub async fn token(
+    auth: AuthService,
+    req: HttpRequest,
+    body: ValidatedJson<TokenRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if !body.is_valid() {
+        return Err(ApiError::validation(body.errors.clone()));
+    }
+
+    let (_, tokens) = match &body.data {
+        TokenRequest::Password(grant) => {
+            auth.issue_tokens(&req, &grant.email, &grant.password)
+                .await?
+        }
+        TokenRequest::RefreshToken(grant) => {
+            auth.refresh_tokens(&req, &grant.refresh_token).await?
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .json(TokenResponse::from(tokens)))
+}
+
+// 204 whether or not the token exists
+pub async fn revoke_token(
+    auth: AuthService,
+    req: HttpRequest,
+    body: ValidatedJson<RevokeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if !body.is_valid() {
+        return Err(ApiError::validation(body.errors.clone()));
+    }
+
+    auth.revoke_refresh_token(&req, &body.refresh_token).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Public keys access tokens are signed with
+pub async fn jwks(jwt: web::Data<JwtConfig>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "public, max-age=300"))
+        .json(jwt.jwks())
+}
+
+pub async fn logout(
+    auth: AuthService,
+    req: HttpRequest,
+    session: Session,
+    user: ApiUser,
+) -> Result<HttpResponse, ApiError> {
+    auth.logout(&req, &session, Some(&user)).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn me(user: ApiUser) -> Result<HttpResponse, ApiError> {
+    user.require_scope(SCOPE_USER_READ)?;
+    Ok(HttpResponse::Ok().json(UserResponse::from(&*user)))
+}
+
+// Same fields and checks as the profile section of the settings page
+pub async fn update_me(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    user: ApiUser,
+    body: ValidatedJson<ProfileForm>,
+) -> Result<HttpResponse, ApiError> {
+    user.require_scope(SCOPE_USER_WRITE)?;
+    if !body.is_valid() {
+        return Err(ApiError::validation(body.errors.clone()));
+    }
+
+    let actor = Actor::user(&user, &req);
+    let target = user_target(user.id);
+    let profile = body.to_profile();
+    let update_db = db.clone();
+    let updated = web::block(move || update_db.update_profile(&user, &profile))
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)?;
+
+    audit::record(
+        &db,
+        actor,
+        AuditAction::ProfileUpdate,
+        target,
+        serde_json::to_value(&body.data).unwrap_or_default(),
+    )
+    .await;
+
+    Ok(HttpResponse::Ok().json(UserResponse::from(&updated)))
+}
+
+pub async fn not_found() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::not_found())
+}
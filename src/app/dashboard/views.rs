@@ -1,20 +1,26 @@
-use crate::get_user_id_from_session;
+use crate::models::users::Role;
+use crate::utils::auth::CurrentUser;
 use crate::utils::render::render_template;
+use crate::utils::templates::Templates;
 use actix_session::Session;
-use actix_web::http::header::LOCATION;
 use actix_web::http::StatusCode;
 use actix_web::{web, HttpResponse, Result};
-use tera::{Context, Tera};
+use tera::Context;
 
-pub async fn dashboard(tera: web::Data<Tera>, session: Session) -> Result<HttpResponse> {
-    // Check if user has no session | If that is the case send back to login
-    if get_user_id_from_session!(session).is_none() {
-        return Ok(HttpResponse::SeeOther()
-            .insert_header((LOCATION, "/login"))
-            .finish());
-    }
+// Users without an active session are sent back to login by the CurrentUser extractor
+pub async fn dashboard(
+    tera: web::Data<Templates>,
+    session: Session,
+    user: CurrentUser,
+) -> Result<HttpResponse> {
+    let mut context = Context::new();
+    context.insert("is_admin", &(user.role == Role::Admin));
 
-    let context = Context::new();
-
-    render_template(&tera, "dashboard/dashboard.html", &context, StatusCode::OK)
+    render_template(
+        &tera,
+        &session,
+        "dashboard/dashboard.html",
+        &context,
+        StatusCode::OK,
+    )
 }
@@ -0,0 +1,7 @@
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct LanguageForm {
+    // Empty to go back to the language of the account or browser
+    pub locale: String,
+}
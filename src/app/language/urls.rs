@@ -0,0 +1,7 @@
+use actix_web::web;
+
+use super::views;
+
+pub fn register_urls(cfg: &mut web::ServiceConfig) {
+    cfg.route("/language", web::post().to(views::switch_language));
+}
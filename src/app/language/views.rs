@@ -0,0 +1,56 @@
+use actix_web::cookie::{self, Cookie, SameSite};
+use actix_web::error::ErrorBadRequest;
+use actix_web::http::header::{LOCATION, REFERER};
+use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
+
+use crate::utils::i18n::{LOCALES, LOCALE_COOKIE};
+use crate::utils::sessions::SessionConfig;
+
+use super::forms::LanguageForm;
+
+// The cookie outlives sessions, so the language stays after logging out
+const LOCALE_COOKIE_DAYS: i64 = 365;
+
+// Remembers the language in a cookie and goes back to the page of the switcher
+pub async fn switch_language(
+    req: HttpRequest,
+    session_config: web::Data<SessionConfig>,
+    post_data: web::Form<LanguageForm>,
+) -> Result<HttpResponse, Error> {
+    let mut cookie = Cookie::build(LOCALE_COOKIE, post_data.locale.clone())
+        .path("/")
+        .http_only(true)
+        .secure(session_config.cookie_secure)
+        .same_site(SameSite::Lax)
+        .max_age(cookie::time::Duration::days(LOCALE_COOKIE_DAYS))
+        .finish();
+
+    if post_data.locale.is_empty() {
+        cookie.make_removal();
+    } else if !LOCALES.contains(&post_data.locale.as_str()) {
+        return Err(ErrorBadRequest("Unknown language"));
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, back_location(&req)))
+        .cookie(cookie)
+        .finish())
+}
+
+// The page the form was sent from if it is on this host, the dashboard otherwise
+fn back_location(req: &HttpRequest) -> String {
+    let host = req.connection_info().host().to_string();
+    req.headers()
+        .get(REFERER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|referer| url::Url::parse(referer).ok())
+        .filter(|url| {
+            let origin = match url.port() {
+                Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+                None => url.host_str().unwrap_or_default().to_string(),
+            };
+            matches!(url.scheme(), "http" | "https") && origin == host
+        })
+        .map(String::from)
+        .unwrap_or_else(|| String::from("/dashboard"))
+}
@@ -1,7 +1,34 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Validate, Clone)]
 pub struct LoginForm {
+    #[validate(email(message = "Please enter a valid email address"))]
     pub email: String,
+    #[serde(skip_serializing)]
+    #[validate(length(min = 1, message = "Please enter your password"))]
     pub password: String,
+    // Checkbox, only sent when checked
+    #[serde(default)]
+    pub remember_me: Option<String>,
+}
+
+// Sent with the second button of the login form, which also sends the password field
+#[derive(Deserialize, Serialize, Validate)]
+pub struct MagicLinkForm {
+    #[validate(email(message = "Please enter a valid email address"))]
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkQuery {
+    pub token: String,
+}
+
+// Either code or error is set by the provider
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
 }
@@ -6,5 +6,12 @@ pub fn register_urls(cfg: &mut web::ServiceConfig) {
     cfg.route("/login", web::get().to(views::login))
         .route("/login", web::post().to(views::login_submit))
         .route("/logout", web::get().to(views::logout))
+        .route("/login/magic", web::post().to(views::magic_link_submit))
+        .route("/login/magic", web::get().to(views::magic_link_login))
+        .route("/login/oidc/{provider}", web::get().to(views::oidc_start))
+        .route(
+            "/login/oidc/{provider}/callback",
+            web::get().to(views::oidc_callback),
+        )
         .route("/", web::get().to(views::login));
 }
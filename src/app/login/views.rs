@@ -1,140 +1,493 @@
 use actix_session::Session;
+use actix_web::cookie::{self, Cookie, SameSite};
 use actix_web::http::header::LOCATION;
 use actix_web::http::StatusCode;
-use actix_web::{web, Error, HttpResponse, Result};
-use log::{error, warn};
-use std::sync::Arc;
-use tera::{Context, Tera};
-
-use crate::app::login::forms::LoginForm;
-use crate::database::db::Database;
-use crate::database::errors::DatabaseError;
+use actix_web::{web, Error, FromRequest, HttpRequest, HttpResponse, Result};
+use log::{error, info, warn};
+use serde::Serialize;
+use tera::Context;
+
+use crate::app::login::forms::{LoginForm, MagicLinkForm, MagicLinkQuery, OidcCallbackQuery};
 use crate::get_user_id_from_session;
-use crate::utils::argon2::verify_password;
+use crate::services::auth::{AuthError, AuthService, MAGIC_LINK_TTL_SECONDS};
+use crate::utils::auth::{take_return_to, CurrentUser};
+use crate::utils::flash::{FlashMessages, Level};
+use crate::utils::i18n::translate_with;
+use crate::utils::oidc::{OidcConfig, OidcFlow};
 use crate::utils::render::{render_error, render_template};
+use crate::utils::sessions::SessionConfig;
+use crate::utils::templates::Templates;
+use crate::utils::tokens::generate_token;
+use crate::utils::validation::{FieldErrors, ValidatedForm};
+
+// Session key of the OidcFlow between the redirect to the provider and its callback
+const OIDC_FLOW_KEY: &str = "oidc_flow";
 
-pub async fn login(tera: web::Data<Tera>, session: Session) -> Result<HttpResponse> {
-    let context = Context::new();
+// Cookie with the nonce which binds login links to the browser that requested them
+const MAGIC_LINK_COOKIE: &str = "magic_link_nonce";
+const MAGIC_LINK_PATH: &str = "/login/magic";
+
+pub async fn login(
+    tera: web::Data<Templates>,
+    session: Session,
+    oidc: web::Data<OidcConfig>,
+) -> Result<HttpResponse> {
+    let context = login_context(&oidc);
 
     // Check if user session already exists | If so redirect
-    if let Some(_) = get_user_id_from_session!(session) {
+    if get_user_id_from_session!(session).is_some() {
         return Ok(HttpResponse::SeeOther()
             .insert_header((LOCATION, "/dashboard"))
             .finish());
     }
 
-    render_template(&tera, "login/login.html", &context, StatusCode::OK)
+    render_template(
+        &tera,
+        &session,
+        "login/login.html",
+        &context,
+        StatusCode::OK,
+    )
 }
 
 pub async fn login_submit(
-    db: web::Data<Arc<Database>>,
+    auth: AuthService,
+    req: HttpRequest,
     session: Session,
-    tera: web::Data<Tera>,
-    post_data: web::Form<LoginForm>,
+    tera: web::Data<Templates>,
+    oidc: web::Data<OidcConfig>,
+    post_data: ValidatedForm<LoginForm>,
 ) -> Result<HttpResponse, Error> {
     // Check if user session already exists | If so redirect
-    if let Some(_) = get_user_id_from_session!(session) {
+    if get_user_id_from_session!(session).is_some() {
         return Ok(HttpResponse::SeeOther()
             .insert_header((LOCATION, "/dashboard"))
             .finish());
     }
 
-    // Copy mail here since we do not want to move post_data
-    let mail = post_data.email.clone();
-
-    // Attempt to get user by email from the database
-    let user_result = web::block(move || db.get_user_by_email(&mail)).await;
-
-    match user_result {
-        Ok(Ok(user)) => {
-            // Check if given password is correct
-            match verify_password(&post_data.password, &user.hashed_password) {
-                true => {
-                    // Create user session - Check for error when inserting data
-                    if let Err(e) = session.insert("user_id", user.id) {
-                        error!("Session error: {}", e);
-                        return render_error(
-                            &tera,
-                            "We are experiencing problems, please try again later.",
-                            "error/error_page.html",
-                            actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        );
-                    }
-
-                    return Ok(HttpResponse::SeeOther()
-                        .insert_header((LOCATION, "/dashboard"))
-                        .finish());
-                }
-                false => {
-                    warn!(
-                        "Wrong password: {} for {}",
-                        post_data.password, post_data.email
-                    );
-
-                    render_error(
-                        &tera,
-                        "Invalid mail or password",
-                        "login/login.html",
-                        StatusCode::BAD_REQUEST,
-                    )
-                }
-            }
-        }
-        Ok(Err(DatabaseError::DieselError(err))) => match err {
-            // Here we handle when everything is ok but entry cannot be found
-            diesel::result::Error::NotFound => {
-                error!("{} - {}", post_data.email, err);
-
-                render_error(
-                    &tera,
-                    "Invalid mail or password",
-                    "login/login.html",
-                    StatusCode::BAD_REQUEST,
-                )
-            }
-            // This is for other database errors which should not occur
-            _ => {
-                error!("{}", err);
-
-                render_error(
-                    &tera,
-                    "We are experiencing problems, please try again later.",
-                    "error/error_page.html",
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                )
-            }
-        },
-        Ok(Err(err)) => {
-            // Connection error handling with appropriate HTTP status code
-            error!("{}", err);
+    if !post_data.is_valid() {
+        return render_login(
+            &tera,
+            &session,
+            &oidc,
+            &post_data.data,
+            &post_data.errors,
+            None,
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    let remember_me = post_data.remember_me.is_some();
+    let result = auth
+        .login(
+            &req,
+            &session,
+            &post_data.email,
+            &post_data.password,
+            remember_me,
+        )
+        .await;
 
+    match result {
+        Ok(_) => Ok(HttpResponse::SeeOther()
+            .insert_header((LOCATION, take_return_to(&session)))
+            .finish()),
+        Err(AuthError::InvalidCredentials) => render_login(
+            &tera,
+            &session,
+            &oidc,
+            &post_data.data,
+            &post_data.errors,
+            Some("Invalid mail or password"),
+            StatusCode::BAD_REQUEST,
+        ),
+        Err(AuthError::AccountBlocked(message)) => render_login(
+            &tera,
+            &session,
+            &oidc,
+            &post_data.data,
+            &post_data.errors,
+            Some(message),
+            StatusCode::FORBIDDEN,
+        ),
+        Err(err) => {
+            error!("{}", err);
             render_error(
                 &tera,
+                &session,
                 "We are experiencing problems, please try again later.",
-                "error/error_page.html",
+                "errors/error_page.html",
                 StatusCode::INTERNAL_SERVER_ERROR,
             )
         }
-        // Blocking error
-        Err(blocking_error) => {
-            error!("Blocking error occurred: {:?}", blocking_error);
-            render_error(
-                &tera,
-                "An internal server error occurred. Please try again later.",
-                "error_template.html",
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-            )
-        }
     }
 }
 
-pub async fn logout(session: Session) -> Result<HttpResponse> {
-    // Get the users session
-    if let Some(_) = get_user_id_from_session!(session) {
-        // Purge session
-        session.purge();
+pub async fn logout(
+    auth: AuthService,
+    req: HttpRequest,
+    session: Session,
+    user: Option<CurrentUser>,
+) -> Result<HttpResponse> {
+    auth.logout(&req, &session, user.as_deref()).await;
+
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/login"))
+        .finish())
+}
+
+// Mails a login link | Answers the same whether the account exists or not
+pub async fn magic_link_submit(
+    auth: AuthService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    oidc: web::Data<OidcConfig>,
+    session_config: web::Data<SessionConfig>,
+    post_data: ValidatedForm<MagicLinkForm>,
+) -> Result<HttpResponse, Error> {
+    if get_user_id_from_session!(session).is_some() {
+        return Ok(HttpResponse::SeeOther()
+            .insert_header((LOCATION, "/dashboard"))
+            .finish());
+    }
+
+    if !post_data.is_valid() {
+        return render_login(
+            &tera,
+            &session,
+            &oidc,
+            &post_data.data,
+            &post_data.errors,
+            None,
+            StatusCode::BAD_REQUEST,
+        );
     }
+
+    // Links requested before from this browser keep working
+    let nonce = match req.cookie(MAGIC_LINK_COOKIE) {
+        Some(cookie) if !cookie.value().is_empty() => cookie.value().to_string(),
+        _ => generate_token(),
+    };
+
+    auth.request_magic_link(&req, &post_data.email, &nonce);
+
+    FlashMessages::new(&session).push(
+        Level::Success,
+        &translate_with(
+            "If there is an account for {email}, we sent it a login link. Open it in this browser.",
+            &[("email", &post_data.email)],
+        ),
+    );
     Ok(HttpResponse::SeeOther()
         .insert_header((LOCATION, "/login"))
+        .cookie(magic_link_cookie(&session_config, nonce))
         .finish())
 }
+
+// Target of the mailed link
+pub async fn magic_link_login(
+    auth: AuthService,
+    req: HttpRequest,
+    session: Session,
+    session_config: web::Data<SessionConfig>,
+    query: web::Query<MagicLinkQuery>,
+) -> Result<HttpResponse> {
+    if get_user_id_from_session!(session).is_some() {
+        return Ok(HttpResponse::SeeOther()
+            .insert_header((LOCATION, "/dashboard"))
+            .finish());
+    }
+
+    let nonce = req.cookie(MAGIC_LINK_COOKIE);
+    let result = auth
+        .login_with_magic_link(
+            &req,
+            &session,
+            &query.token,
+            nonce.as_ref().map(|cookie| cookie.value()),
+        )
+        .await;
+
+    match result {
+        Ok(_) => {
+            // Other links of this browser are of no use anymore
+            let mut cookie = magic_link_cookie(&session_config, String::new());
+            cookie.make_removal();
+            Ok(HttpResponse::SeeOther()
+                .insert_header((LOCATION, take_return_to(&session)))
+                .cookie(cookie)
+                .finish())
+        }
+        Err(AuthError::InvalidLink(message)) | Err(AuthError::AccountBlocked(message)) => {
+            Ok(redirect_with_error(&session, "/login", message))
+        }
+        Err(err) => {
+            error!("{}", err);
+            Ok(redirect_with_error(
+                &session,
+                "/login",
+                "We are experiencing problems, please try again later.",
+            ))
+        }
+    }
+}
+
+// Sends the browser to the provider | Logged in users connect the account to theirs instead
+pub async fn oidc_start(
+    session: Session,
+    oidc: web::Data<OidcConfig>,
+    provider: web::Path<String>,
+) -> Result<HttpResponse> {
+    let name = provider.into_inner();
+    let link_user_id = get_user_id_from_session!(session);
+    let failure_location = oidc_return_location(link_user_id);
+    let Some(provider) = oidc.provider(&name) else {
+        return Ok(redirect_with_error(
+            &session,
+            failure_location,
+            "Unknown login provider",
+        ));
+    };
+    let display_name = provider.display_name.clone();
+
+    let flow = OidcFlow::new(&name, link_user_id);
+    let result = web::block(move || {
+        let provider = oidc.provider(&name).expect("provider exists");
+        provider
+            .authorization_url(&oidc.agent, &flow)
+            .map(|url| (url, flow))
+    })
+    .await;
+
+    let (url, flow) = match result {
+        Ok(Ok(result)) => result,
+        Ok(Err(err)) => {
+            error!("{}", err);
+            return Ok(provider_unavailable(
+                &session,
+                failure_location,
+                &display_name,
+            ));
+        }
+        Err(err) => {
+            error!("Blocking error occurred: {:?}", err);
+            return Ok(provider_unavailable(
+                &session,
+                failure_location,
+                &display_name,
+            ));
+        }
+    };
+
+    session.insert(OIDC_FLOW_KEY, &flow)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, url))
+        .finish())
+}
+
+// The provider sends the browser back here with a code, or an error if the user declined
+pub async fn oidc_callback(
+    auth: AuthService,
+    req: HttpRequest,
+    session: Session,
+    oidc: web::Data<OidcConfig>,
+    provider: web::Path<String>,
+    query: web::Query<OidcCallbackQuery>,
+) -> Result<HttpResponse> {
+    let name = provider.into_inner();
+    let query = query.into_inner();
+
+    // The flow can only be completed once and only in the browser which started it
+    let flow = session
+        .remove_as::<OidcFlow>(OIDC_FLOW_KEY)
+        .and_then(Result::ok);
+    let flow = match flow {
+        Some(flow) if flow.matches(&name, query.state.as_deref()) => flow,
+        flow => {
+            let location = oidc_return_location(flow.and_then(|flow| flow.link_user_id));
+            return Ok(redirect_with_error(
+                &session,
+                location,
+                "Your login has expired, please try again",
+            ));
+        }
+    };
+    let location = oidc_return_location(flow.link_user_id);
+    let Some(provider) = oidc.provider(&name) else {
+        return Ok(redirect_with_error(
+            &session,
+            location,
+            "Unknown login provider",
+        ));
+    };
+    let display_name = provider.display_name.clone();
+
+    let Some(code) = query.code else {
+        info!(
+            "Login with {} failed: {}",
+            name,
+            query.error.as_deref().unwrap_or("no code")
+        );
+        return Ok(redirect_with_error(
+            &session,
+            location,
+            &translate_with(
+                "Signing in with {provider} was cancelled",
+                &[("provider", &display_name)],
+            ),
+        ));
+    };
+
+    let link_user_id = flow.link_user_id;
+    let exchange_oidc = oidc.clone();
+    let exchange_name = name.clone();
+    let result = web::block(move || {
+        let provider = exchange_oidc
+            .provider(&exchange_name)
+            .expect("provider exists");
+        provider.exchange_code(&exchange_oidc.agent, &flow, &code)
+    })
+    .await;
+
+    let claims = match result {
+        Ok(Ok(claims)) => claims,
+        Ok(Err(err)) => {
+            warn!("Login with {} failed: {}", name, err);
+            return Ok(provider_unavailable(&session, location, &display_name));
+        }
+        Err(err) => {
+            error!("Blocking error occurred: {:?}", err);
+            return Ok(provider_unavailable(&session, location, &display_name));
+        }
+    };
+
+    let Some(user_id) = link_user_id else {
+        return match auth
+            .login_with_identity(&req, &session, provider, claims)
+            .await
+        {
+            Ok(_) => Ok(HttpResponse::SeeOther()
+                .insert_header((LOCATION, take_return_to(&session)))
+                .finish()),
+            Err(err) => Ok(identity_failure(&session, location, err)),
+        };
+    };
+
+    // The session might have been signed out or switched while at the provider
+    let user = match CurrentUser::extract(&req).await {
+        Ok(user) if user.id == user_id => user,
+        _ => {
+            return Ok(redirect_with_error(
+                &session,
+                "/login",
+                "Your login has expired, please try again",
+            ))
+        }
+    };
+
+    match auth.link_identity(&req, &user, provider, claims).await {
+        Ok(()) => {
+            FlashMessages::new(&session).push(
+                Level::Success,
+                &translate_with(
+                    "Your {provider} account has been connected",
+                    &[("provider", &display_name)],
+                ),
+            );
+            Ok(HttpResponse::SeeOther()
+                .insert_header((LOCATION, location))
+                .finish())
+        }
+        Err(err) => Ok(identity_failure(&session, location, err)),
+    }
+}
+
+// Providers offered next to the login form
+fn login_context(oidc: &OidcConfig) -> Context {
+    let providers: Vec<serde_json::Value> = oidc
+        .providers
+        .iter()
+        .map(|provider| {
+            serde_json::json!({ "name": provider.name, "display_name": provider.display_name })
+        })
+        .collect();
+
+    let mut context = Context::new();
+    context.insert("oidc_providers", &providers);
+    context
+}
+
+// Like render_form, with the providers | Both forms of the page use it
+fn render_login<T: Serialize>(
+    tera: &web::Data<Templates>,
+    session: &Session,
+    oidc: &OidcConfig,
+    form: &T,
+    errors: &FieldErrors,
+    message: Option<&str>,
+    status_code: StatusCode,
+) -> Result<HttpResponse, Error> {
+    let mut context = login_context(oidc);
+    context.insert("form", form);
+    context.insert("field_errors", errors);
+    if let Some(message) = message {
+        context.insert("error_message", message);
+    }
+
+    render_template(tera, session, "login/login.html", &context, status_code)
+}
+
+// Lax, since the link is opened from a mail client | Only sent to the login link
+fn magic_link_cookie(config: &SessionConfig, nonce: String) -> Cookie<'static> {
+    Cookie::build(MAGIC_LINK_COOKIE, nonce)
+        .path(MAGIC_LINK_PATH)
+        .http_only(true)
+        .secure(config.cookie_secure)
+        .same_site(SameSite::Lax)
+        .max_age(cookie::time::Duration::seconds(
+            MAGIC_LINK_TTL_SECONDS as i64,
+        ))
+        .finish()
+}
+
+// Connecting an account starts and ends on the settings page
+fn oidc_return_location(link_user_id: Option<i32>) -> &'static str {
+    match link_user_id {
+        Some(_) => "/settings/identities",
+        None => "/login",
+    }
+}
+
+fn identity_failure(session: &Session, location: &str, err: AuthError) -> HttpResponse {
+    match err {
+        AuthError::AccountBlocked(message) => redirect_with_error(session, location, message),
+        AuthError::IdentityRejected(message) => redirect_with_error(session, location, &message),
+        err => {
+            error!("{}", err);
+            redirect_with_error(
+                session,
+                location,
+                "We are experiencing problems, please try again later.",
+            )
+        }
+    }
+}
+
+fn provider_unavailable(session: &Session, location: &str, display_name: &str) -> HttpResponse {
+    redirect_with_error(
+        session,
+        location,
+        &translate_with(
+            "Signing in with {provider} is not possible right now, please try again later",
+            &[("provider", display_name)],
+        ),
+    )
+}
+
+fn redirect_with_error(session: &Session, location: &str, text: &str) -> HttpResponse {
+    FlashMessages::new(session).push(Level::Error, text);
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
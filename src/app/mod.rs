@@ -1,9 +1,21 @@
+pub mod admin;
+pub mod api;
+pub mod dashboard;
+pub mod language;
 pub mod login;
+pub mod oauth;
+pub mod organizations;
 pub mod register;
-pub mod dashboard;
+pub mod settings;
 
 pub fn register_urls(cfg: &mut actix_web::web::ServiceConfig) {
     login::urls::register_urls(cfg);
     register::urls::register_urls(cfg);
     dashboard::urls::register_urls(cfg);
+    settings::urls::register_urls(cfg);
+    admin::urls::register_urls(cfg);
+    api::urls::register_urls(cfg);
+    oauth::urls::register_urls(cfg);
+    organizations::urls::register_urls(cfg);
+    language::urls::register_urls(cfg);
 }
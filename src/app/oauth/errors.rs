@@ -0,0 +1,37 @@
+use actix_web::http::header::{CACHE_CONTROL, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+
+use crate::services::oauth::OAuthError;
+
+// Body of failed token requests, e.g. {"error": "invalid_grant", "error_description": "..."}
+impl ResponseError for OAuthError {
+    fn status_code(&self) -> StatusCode {
+        match self.error {
+            "invalid_client" => StatusCode::UNAUTHORIZED,
+            "server_error" => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.error == "invalid_client" {
+            response.insert_header((WWW_AUTHENTICATE, "Basic"));
+        }
+        response
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .json(serde_json::json!({
+                "error": self.error,
+                "error_description": self.description,
+            }))
+    }
+}
+
+// Malformed token requests, e.g. a missing grant_type
+pub fn form_error_handler(
+    err: actix_web::error::UrlencodedError,
+    _: &HttpRequest,
+) -> actix_web::Error {
+    OAuthError::invalid_request(&err.to_string()).into()
+}
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+use crate::services::oauth::ClientTokens;
+
+// Parameters of the authorization endpoint | Checked by the view, so errors can be sent back
+// to the application instead of failing the request
+#[derive(Deserialize)]
+pub struct AuthorizeQuery {
+    pub response_type: Option<String>,
+    pub client_id: Option<String>,
+    // May be left out if the client has a single redirect uri
+    pub redirect_uri: Option<String>,
+    #[serde(default)]
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    // Space separated, "none" and "consent" are supported
+    #[serde(default)]
+    pub prompt: String,
+}
+
+// Answer of the consent page
+#[derive(Deserialize)]
+pub struct ConsentForm {
+    pub request_id: String,
+    // "allow" or "deny"
+    pub decision: String,
+}
+
+// Form encoded body of the token endpoint | Client credentials may be sent as basic auth instead
+#[derive(Deserialize)]
+pub struct TokenForm {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    pub scope: String,
+}
+
+impl From<ClientTokens> for TokenResponse {
+    fn from(tokens: ClientTokens) -> Self {
+        TokenResponse {
+            access_token: tokens.access_token,
+            token_type: "Bearer",
+            expires_in: tokens.expires_in,
+            id_token: tokens.id_token,
+            scope: tokens.scope,
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod errors;
+pub mod forms;
+pub mod urls;
+pub mod views;
@@ -0,0 +1,20 @@
+use actix_web::web;
+
+use super::errors::form_error_handler;
+use super::views;
+
+pub fn register_urls(cfg: &mut web::ServiceConfig) {
+    cfg.route(
+        "/.well-known/openid-configuration",
+        web::get().to(views::discovery),
+    )
+    .route("/oauth/authorize", web::get().to(views::authorize))
+    .route("/oauth/authorize", web::post().to(views::authorize_submit))
+    .service(
+        web::resource("/oauth/token")
+            .app_data(web::FormConfig::default().error_handler(form_error_handler))
+            .route(web::post().to(views::token)),
+    )
+    .route("/oauth/userinfo", web::get().to(views::userinfo))
+    .route("/oauth/userinfo", web::post().to(views::userinfo));
+}
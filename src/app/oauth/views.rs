@@ -0,0 +1,463 @@
+use actix_session::Session;
+use actix_web::http::header::{
+    HeaderValue, AUTHORIZATION, CACHE_CONTROL, LOCATION, PRAGMA, WWW_AUTHENTICATE, X_FRAME_OPTIONS,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{error, info};
+use percent_encoding::percent_decode_str;
+use std::fmt;
+use tera::Context;
+use url::Url;
+
+use crate::app::api::auth::bearer_token;
+use crate::models::oauth::{OAuthClient, OAUTH_SCOPES};
+use crate::models::users::User;
+use crate::services::api_tokens::TokenError;
+use crate::services::oauth::{parse_scopes, AuthorizationRequest, OAuthError, OAuthService};
+use crate::utils::auth::{set_return_to, CurrentUser};
+use crate::utils::flash::{FlashMessages, Level};
+use crate::utils::i18n::translate_with;
+use crate::utils::render::{render_error, render_template};
+use crate::utils::templates::Templates;
+use crate::utils::tokens::generate_token;
+
+use super::forms::{AuthorizeQuery, ConsentForm, TokenForm, TokenResponse};
+
+// Session key of the AuthorizationRequest while the user is asked for consent
+const OAUTH_REQUEST_KEY: &str = "oauth_request";
+
+// Start of the authorization code flow | Users who are not logged in are sent to the login
+// page first and come back here afterwards
+pub async fn authorize(
+    oauth: OAuthService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: Option<CurrentUser>,
+    query: web::Query<AuthorizeQuery>,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+
+    // Errors are only sent to redirect uris registered for the client, anything else would
+    // let other sites use this page as open redirect
+    let client = match query.client_id.as_deref() {
+        Some(client_id) => match oauth.client(client_id).await {
+            Ok(client) => client,
+            Err(err) => return unavailable(&tera, &session, err),
+        },
+        None => None,
+    };
+    let Some(client) = client else {
+        return invalid_request_page(&tera, &session, "Unknown application");
+    };
+    let redirect_uri = match query.redirect_uri {
+        Some(redirect_uri) if client.redirect_uris.contains(&redirect_uri) => redirect_uri,
+        None if client.redirect_uris.len() == 1 => client.redirect_uris[0].clone(),
+        _ => {
+            return invalid_request_page(
+                &tera,
+                &session,
+                "The application sent an unknown redirect address",
+            )
+        }
+    };
+    let state = query.state.as_deref();
+
+    if query.response_type.as_deref() != Some("code") {
+        return Ok(error_redirect(
+            &oauth,
+            &redirect_uri,
+            state,
+            OAuthError::new(
+                "unsupported_response_type",
+                "Only the code response type is supported",
+            ),
+        ));
+    }
+    let code_challenge = match (query.code_challenge, query.code_challenge_method.as_deref()) {
+        (Some(challenge), Some("S256")) if !challenge.is_empty() => challenge,
+        _ => {
+            return Ok(error_redirect(
+                &oauth,
+                &redirect_uri,
+                state,
+                OAuthError::invalid_request("PKCE with code_challenge_method S256 is required"),
+            ))
+        }
+    };
+    let scopes = match parse_scopes(&query.scope) {
+        Ok(scopes) => scopes,
+        Err(err) => return Ok(error_redirect(&oauth, &redirect_uri, state, err)),
+    };
+    let prompt: Vec<&str> = query.prompt.split_whitespace().collect();
+
+    let Some(CurrentUser(user)) = user else {
+        if prompt.contains(&"none") {
+            return Ok(error_redirect(
+                &oauth,
+                &redirect_uri,
+                state,
+                OAuthError::new("login_required", "The user is not logged in"),
+            ));
+        }
+        let location = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/oauth/authorize");
+        set_return_to(&session, location)?;
+        FlashMessages::new(&session).push(
+            Level::Info,
+            &translate_with(
+                "Please log in to continue to {application}",
+                &[("application", &client.name)],
+            ),
+        );
+        return Ok(redirect("/login"));
+    };
+
+    let request = AuthorizationRequest {
+        id: generate_token(),
+        user_id: user.id,
+        client_id: client.client_id.clone(),
+        redirect_uri,
+        scopes,
+        state: query.state,
+        nonce: query.nonce,
+        code_challenge,
+    };
+
+    let consented = match oauth.has_consent(user.id, &client, &request.scopes).await {
+        Ok(consented) => consented,
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+    if consented && !prompt.contains(&"consent") {
+        return Ok(redirect_with_code(&oauth, &session, &client, &request).await);
+    }
+    if prompt.contains(&"none") {
+        return Ok(error_redirect(
+            &oauth,
+            &request.redirect_uri,
+            request.state.as_deref(),
+            OAuthError::new("consent_required", "The user has not allowed access yet"),
+        ));
+    }
+
+    session.insert(OAUTH_REQUEST_KEY, &request)?;
+    render_consent(&tera, &session, &user, &client, &request)
+}
+
+// Answer of the consent page
+pub async fn authorize_submit(
+    oauth: OAuthService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    post_data: web::Form<ConsentForm>,
+) -> Result<HttpResponse, Error> {
+    // Every request can only be answered once and only by the user it was shown to
+    let request = session
+        .remove_as::<AuthorizationRequest>(OAUTH_REQUEST_KEY)
+        .and_then(Result::ok);
+    let request = match request {
+        Some(request) if request.id == post_data.request_id && request.user_id == user.id => {
+            request
+        }
+        _ => {
+            return invalid_request_page(
+                &tera,
+                &session,
+                "This request has expired, please go back to the application and try again",
+            )
+        }
+    };
+
+    let client = match oauth.client(&request.client_id).await {
+        Ok(Some(client)) => client,
+        Ok(None) => return invalid_request_page(&tera, &session, "Unknown application"),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    if post_data.decision != "allow" {
+        info!("{} denied access to {}", user.email, client.name);
+        return Ok(error_redirect(
+            &oauth,
+            &request.redirect_uri,
+            request.state.as_deref(),
+            OAuthError::new("access_denied", "The user denied access"),
+        ));
+    }
+
+    if let Err(err) = oauth
+        .grant_consent(&req, &user, &client, &request.scopes)
+        .await
+    {
+        return Ok(error_redirect(
+            &oauth,
+            &request.redirect_uri,
+            request.state.as_deref(),
+            err,
+        ));
+    }
+    Ok(redirect_with_code(&oauth, &session, &client, &request).await)
+}
+
+// Exchanges a code for tokens | Clients authenticate with basic auth, or with client_id and
+// client_secret in the body
+pub async fn token(
+    oauth: OAuthService,
+    req: HttpRequest,
+    form: web::Form<TokenForm>,
+) -> Result<HttpResponse, OAuthError> {
+    let form = form.into_inner();
+    let basic = req
+        .headers()
+        .get(AUTHORIZATION)
+        .map(|value| basic_credentials(value.to_str().unwrap_or_default()));
+    let (client_id, client_secret) = match basic {
+        Some(Some((client_id, client_secret))) => (client_id, Some(client_secret)),
+        Some(None) => return Err(OAuthError::invalid_client()),
+        None => (
+            form.client_id
+                .ok_or_else(|| OAuthError::invalid_request("client_id is missing"))?,
+            form.client_secret,
+        ),
+    };
+
+    if form.grant_type != "authorization_code" {
+        return Err(OAuthError::new(
+            "unsupported_grant_type",
+            "Only the authorization_code grant type is supported",
+        ));
+    }
+    let (Some(code), Some(redirect_uri), Some(code_verifier)) =
+        (form.code, form.redirect_uri, form.code_verifier)
+    else {
+        return Err(OAuthError::invalid_request(
+            "code, redirect_uri and code_verifier are required",
+        ));
+    };
+
+    let client = oauth
+        .authenticate_client(&client_id, client_secret.as_deref())
+        .await?;
+    let tokens = oauth
+        .exchange_code(&req, &client, &code, &redirect_uri, &code_verifier)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .insert_header((PRAGMA, "no-cache"))
+        .json(TokenResponse::from(tokens)))
+}
+
+// Claims of the user an access token was issued for
+pub async fn userinfo(oauth: OAuthService, req: HttpRequest) -> HttpResponse {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| bearer_token(value.to_str().unwrap_or_default()));
+    let Some(token) = token else {
+        return HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, "Bearer"))
+            .finish();
+    };
+
+    match oauth.userinfo(&token).await {
+        Ok(claims) => HttpResponse::Ok()
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .json(claims),
+        Err(TokenError::Invalid) => {
+            invalid_token("The access token is invalid, expired or revoked")
+        }
+        Err(TokenError::Blocked(message)) => invalid_token(message),
+        Err(TokenError::Internal) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// OpenID Connect discovery document, which tells applications where everything is
+pub async fn discovery(oauth: OAuthService) -> HttpResponse {
+    let issuer = oauth.issuer();
+    let scopes: Vec<&str> = OAUTH_SCOPES.iter().map(|(scope, _)| *scope).collect();
+
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "public, max-age=300"))
+        .json(serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+            "token_endpoint": format!("{}/oauth/token", issuer),
+            "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+            "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+            "scopes_supported": scopes,
+            "response_types_supported": ["code"],
+            "response_modes_supported": ["query"],
+            "grant_types_supported": ["authorization_code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["EdDSA"],
+            "token_endpoint_auth_methods_supported": [
+                "client_secret_basic",
+                "client_secret_post",
+                "none",
+            ],
+            "code_challenge_methods_supported": ["S256"],
+            "claims_supported": [
+                "sub",
+                "name",
+                "picture",
+                "zoneinfo",
+                "locale",
+                "updated_at",
+                "email",
+                "email_verified",
+            ],
+            "authorization_response_iss_parameter_supported": true,
+        }))
+}
+
+fn render_consent(
+    tera: &web::Data<Templates>,
+    session: &Session,
+    user: &User,
+    client: &OAuthClient,
+    request: &AuthorizationRequest,
+) -> Result<HttpResponse, Error> {
+    let scopes: Vec<serde_json::Value> = OAUTH_SCOPES
+        .iter()
+        .filter(|(scope, _)| request.scopes.iter().any(|requested| requested == scope))
+        .map(|(scope, description)| {
+            serde_json::json!({ "scope": scope, "description": description })
+        })
+        .collect();
+    let redirect_host = Url::parse(&request.redirect_uri)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_default();
+
+    let mut context = Context::new();
+    context.insert("client_name", &client.name);
+    context.insert("redirect_host", &redirect_host);
+    context.insert("scopes", &scopes);
+    context.insert("request_id", &request.id);
+    context.insert("email", &user.email);
+
+    let mut response = render_template(
+        tera,
+        session,
+        "oauth/consent.html",
+        &context,
+        StatusCode::OK,
+    )?;
+    // Other sites must not lay the page out under something users click on
+    response
+        .headers_mut()
+        .insert(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    Ok(response)
+}
+
+// Sends the code back to the application
+async fn redirect_with_code(
+    oauth: &OAuthService,
+    session: &Session,
+    client: &OAuthClient,
+    request: &AuthorizationRequest,
+) -> HttpResponse {
+    let code = match oauth.issue_code(session, client, request).await {
+        Ok(code) => code,
+        Err(err) => {
+            return error_redirect(oauth, &request.redirect_uri, request.state.as_deref(), err)
+        }
+    };
+
+    let mut params = vec![("code", code.as_str())];
+    if let Some(state) = &request.state {
+        params.push(("state", state));
+    }
+    redirect(&oauth.redirect_location(&request.redirect_uri, &params))
+}
+
+fn error_redirect(
+    oauth: &OAuthService,
+    redirect_uri: &str,
+    state: Option<&str>,
+    err: OAuthError,
+) -> HttpResponse {
+    info!("Authorization request failed: {}", err);
+    let mut params = vec![
+        ("error", err.error),
+        ("error_description", err.description.as_str()),
+    ];
+    if let Some(state) = state {
+        params.push(("state", state));
+    }
+    redirect(&oauth.redirect_location(redirect_uri, &params))
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+// client_id:client_secret of a basic Authorization header | Both are form encoded first
+fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+    Some((form_decode(client_id)?, form_decode(client_secret)?))
+}
+
+fn form_decode(value: &str) -> Option<String> {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8()
+        .ok()
+        .map(|value| value.into_owned())
+}
+
+fn invalid_token(description: &str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((
+            WWW_AUTHENTICATE,
+            format!(
+                "Bearer error=\"invalid_token\", error_description=\"{}\"",
+                description
+            ),
+        ))
+        .json(serde_json::json!({ "error": "invalid_token", "error_description": description }))
+}
+
+// Problems of the application which cannot be sent back to it
+fn invalid_request_page(
+    tera: &web::Data<Templates>,
+    session: &Session,
+    message: &str,
+) -> Result<HttpResponse, Error> {
+    render_error(
+        tera,
+        session,
+        message,
+        "errors/error_page.html",
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+fn unavailable(
+    tera: &web::Data<Templates>,
+    session: &Session,
+    err: impl fmt::Display,
+) -> Result<HttpResponse, Error> {
+    error!("{}", err);
+    render_error(
+        tera,
+        session,
+        "We are experiencing problems, please try again later.",
+        "errors/error_page.html",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
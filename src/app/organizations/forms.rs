@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError};
+
+use crate::models::organizations::OrgRole;
+
+#[derive(Deserialize, Serialize, Validate)]
+pub struct OrganizationForm {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1 to 100 characters"))]
+    pub name: String,
+    #[validate(custom(function = "validate_slug"))]
+    pub slug: String,
+}
+
+#[derive(Deserialize)]
+pub struct SwitchOrganizationForm {
+    pub organization_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct JoinQuery {
+    pub token: String,
+}
+
+#[derive(Deserialize, Serialize, Validate)]
+pub struct MemberInvitationForm {
+    #[validate(email(message = "Please enter a valid email address"))]
+    pub email: String,
+    #[validate(custom(function = "validate_role"))]
+    pub role: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeInvitationForm {
+    pub invitation_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct MemberRoleForm {
+    pub user_id: i32,
+    pub role: String,
+}
+
+#[derive(Deserialize)]
+pub struct RemoveMemberForm {
+    pub user_id: i32,
+}
+
+// Empty fields fall back to the defaults of the app
+#[derive(Deserialize, Serialize, Validate)]
+pub struct BrandingForm {
+    #[validate(custom(function = "validate_logo_url"))]
+    pub logo_url: String,
+    #[validate(custom(function = "validate_color"))]
+    pub primary_color: String,
+    #[validate(custom(function = "validate_color"))]
+    pub accent_color: String,
+}
+
+// 2 to 40 lower case letters, digits and hyphens, not at the start or end
+fn validate_slug(slug: &str) -> Result<(), ValidationError> {
+    let valid = (2..=40).contains(&slug.len())
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-');
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("slug")
+            .with_message("Please use 2 to 40 lower case letters, digits and hyphens".into()))
+    }
+}
+
+fn validate_role(role: &str) -> Result<(), ValidationError> {
+    match OrgRole::parse(role) {
+        Some(_) => Ok(()),
+        None => Err(ValidationError::new("role").with_message("Unknown role".into())),
+    }
+}
+
+// http or https, since it is used as src of an image on every page
+fn validate_logo_url(logo_url: &str) -> Result<(), ValidationError> {
+    let valid = logo_url.is_empty()
+        || logo_url.len() <= 2048
+            && url::Url::parse(logo_url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("logo_url")
+            .with_message("Please enter an http or https address".into()))
+    }
+}
+
+// #rrggbb | Inserted into the stylesheet of the page, so nothing else is accepted
+fn validate_color(color: &str) -> Result<(), ValidationError> {
+    let valid = color.is_empty()
+        || color.len() == 7
+            && color.starts_with('#')
+            && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("color").with_message("Please enter a color like #0056b3".into()))
+    }
+}
@@ -0,0 +1,34 @@
+use actix_web::web;
+
+use super::views;
+
+pub fn register_urls(cfg: &mut web::ServiceConfig) {
+    cfg.route("/organizations", web::get().to(views::organizations))
+        .route("/organizations", web::post().to(views::create_organization))
+        .route(
+            "/organizations/switch",
+            web::post().to(views::switch_organization),
+        )
+        .route("/organizations/join", web::get().to(views::join))
+        .route("/organization", web::get().to(views::organization))
+        .route(
+            "/organization/branding",
+            web::post().to(views::update_branding),
+        )
+        .route(
+            "/organization/invitations",
+            web::post().to(views::invite_member),
+        )
+        .route(
+            "/organization/invitations/revoke",
+            web::post().to(views::revoke_invitation),
+        )
+        .route(
+            "/organization/members/role",
+            web::post().to(views::change_member_role),
+        )
+        .route(
+            "/organization/members/remove",
+            web::post().to(views::remove_member),
+        );
+}
@@ -0,0 +1,532 @@
+use actix_session::Session;
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
+use log::error;
+use tera::Context;
+
+use crate::app::settings::views::format_datetime;
+use crate::models::organizations::{OrgRole, ORG_ROLES};
+use crate::services::organizations::{OrganizationError, OrganizationService};
+use crate::utils::auth::{set_active_organization, set_return_to, ActiveOrganization, CurrentUser};
+use crate::utils::flash::{FlashMessages, Level};
+use crate::utils::i18n::{translate, translate_with};
+use crate::utils::render::{render_error, render_template};
+use crate::utils::templates::Templates;
+use crate::utils::validation::ValidatedForm;
+
+use super::forms::{
+    BrandingForm, JoinQuery, MemberInvitationForm, MemberRoleForm, OrganizationForm,
+    RemoveMemberForm, RevokeInvitationForm, SwitchOrganizationForm,
+};
+
+// Organizations of the user next to the form for creating one
+pub async fn organizations(
+    organizations: OrganizationService,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+) -> Result<HttpResponse, Error> {
+    render_organizations(
+        &organizations,
+        &tera,
+        &session,
+        &user,
+        Context::new(),
+        StatusCode::OK,
+    )
+    .await
+}
+
+// The new organization becomes the active one
+pub async fn create_organization(
+    organizations: OrganizationService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    mut post_data: ValidatedForm<OrganizationForm>,
+) -> Result<HttpResponse, Error> {
+    if post_data.is_valid() {
+        match organizations
+            .create(&req, &user, &post_data.name, &post_data.slug)
+            .await
+        {
+            Ok(organization) => {
+                set_active_organization(&session, organization.id)?;
+                return redirect_to_organization(
+                    &session,
+                    Level::Success,
+                    &translate_with(
+                        "{organization} has been created",
+                        &[("organization", &organization.name)],
+                    ),
+                );
+            }
+            Err(OrganizationError::Invalid(errors)) => {
+                for (field, messages) in errors {
+                    for message in messages {
+                        post_data.add_error(&field, &message);
+                    }
+                }
+            }
+            Err(err) => return unavailable(&tera, &session, err),
+        }
+    }
+
+    let mut context = Context::new();
+    context.insert("form", &post_data.data);
+    context.insert("field_errors", &post_data.errors);
+    render_organizations(
+        &organizations,
+        &tera,
+        &session,
+        &user,
+        context,
+        StatusCode::BAD_REQUEST,
+    )
+    .await
+}
+
+pub async fn switch_organization(
+    organizations: OrganizationService,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    post_data: web::Form<SwitchOrganizationForm>,
+) -> Result<HttpResponse, Error> {
+    match organizations
+        .membership(&user, post_data.organization_id)
+        .await
+    {
+        Ok(Some((organization, _))) => {
+            set_active_organization(&session, organization.id)?;
+            redirect_to_organization(
+                &session,
+                Level::Success,
+                &translate_with(
+                    "You are working in {organization} now",
+                    &[("organization", &organization.name)],
+                ),
+            )
+        }
+        Ok(None) => {
+            FlashMessages::new(&session).push(
+                Level::Error,
+                "You are no member of this organization anymore",
+            );
+            Ok(HttpResponse::SeeOther()
+                .insert_header((LOCATION, "/organizations"))
+                .finish())
+        }
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+// Link of an invitation mail for existing accounts | Visitors log in first and come back here
+pub async fn join(
+    organizations: OrganizationService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: Option<CurrentUser>,
+    query: web::Query<JoinQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(CurrentUser(user)) = user else {
+        let location = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/organizations");
+        set_return_to(&session, location)?;
+        FlashMessages::new(&session).push(Level::Info, "Please log in to join the organization");
+        return Ok(HttpResponse::SeeOther()
+            .insert_header((LOCATION, "/login"))
+            .finish());
+    };
+
+    match organizations.join(&req, &user, &query.token).await {
+        Ok(organization) => {
+            set_active_organization(&session, organization.id)?;
+            redirect_to_organization(
+                &session,
+                Level::Success,
+                &translate_with(
+                    "Welcome to {organization}",
+                    &[("organization", &organization.name)],
+                ),
+            )
+        }
+        Err(OrganizationError::Denied(message)) => render_error(
+            &tera,
+            &session,
+            &message,
+            "errors/error_page.html",
+            StatusCode::BAD_REQUEST,
+        ),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+// Members and invitations of the active organization
+pub async fn organization(
+    organizations: OrganizationService,
+    session: Session,
+    tera: web::Data<Templates>,
+    organization: ActiveOrganization,
+) -> Result<HttpResponse, Error> {
+    render_organization(
+        &organizations,
+        &tera,
+        &session,
+        &organization,
+        Context::new(),
+        StatusCode::OK,
+    )
+    .await
+}
+
+pub async fn update_branding(
+    organizations: OrganizationService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    organization: ActiveOrganization,
+    post_data: ValidatedForm<BrandingForm>,
+) -> Result<HttpResponse, Error> {
+    if post_data.is_valid() {
+        match organizations
+            .update_branding(
+                &req,
+                &organization,
+                &post_data.logo_url,
+                &post_data.primary_color,
+                &post_data.accent_color,
+            )
+            .await
+        {
+            Ok(_) => {
+                return redirect_to_organization(
+                    &session,
+                    Level::Success,
+                    "The branding has been saved",
+                )
+            }
+            Err(OrganizationError::Denied(message)) => {
+                return redirect_to_organization(&session, Level::Error, &message)
+            }
+            Err(err) => return unavailable(&tera, &session, err),
+        }
+    }
+
+    // Kept apart from the values of the invitation form on the same page
+    let mut context = Context::new();
+    context.insert("branding_form", &post_data.data);
+    context.insert("branding_errors", &post_data.errors);
+    render_organization(
+        &organizations,
+        &tera,
+        &session,
+        &organization,
+        context,
+        StatusCode::BAD_REQUEST,
+    )
+    .await
+}
+
+pub async fn invite_member(
+    organizations: OrganizationService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    organization: ActiveOrganization,
+    mut post_data: ValidatedForm<MemberInvitationForm>,
+) -> Result<HttpResponse, Error> {
+    if post_data.is_valid() {
+        let role = OrgRole::parse(&post_data.role).unwrap_or(OrgRole::Member);
+        match organizations
+            .invite(&req, &organization, &post_data.email, role)
+            .await
+        {
+            Ok(invitation) => {
+                return redirect_to_organization(
+                    &session,
+                    Level::Success,
+                    &translate_with(
+                        "We sent an invitation to {email}",
+                        &[("email", &invitation.email)],
+                    ),
+                )
+            }
+            Err(OrganizationError::Invalid(errors)) => {
+                for (field, messages) in errors {
+                    for message in messages {
+                        post_data.add_error(&field, &message);
+                    }
+                }
+            }
+            Err(OrganizationError::Denied(message)) => {
+                return redirect_to_organization(&session, Level::Error, &message)
+            }
+            Err(err) => return unavailable(&tera, &session, err),
+        }
+    }
+
+    let mut context = Context::new();
+    context.insert("form", &post_data.data);
+    context.insert("field_errors", &post_data.errors);
+    render_organization(
+        &organizations,
+        &tera,
+        &session,
+        &organization,
+        context,
+        StatusCode::BAD_REQUEST,
+    )
+    .await
+}
+
+pub async fn revoke_invitation(
+    organizations: OrganizationService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    organization: ActiveOrganization,
+    post_data: web::Form<RevokeInvitationForm>,
+) -> Result<HttpResponse, Error> {
+    match organizations
+        .revoke_invitation(&req, &organization, post_data.invitation_id)
+        .await
+    {
+        Ok(Some(invitation)) => redirect_to_organization(
+            &session,
+            Level::Success,
+            &translate_with(
+                "The invitation of {email} has been revoked",
+                &[("email", &invitation.email)],
+            ),
+        ),
+        Ok(None) => redirect_to_organization(
+            &session,
+            Level::Error,
+            "The invitation cannot be revoked anymore",
+        ),
+        Err(OrganizationError::Denied(message)) => {
+            redirect_to_organization(&session, Level::Error, &message)
+        }
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+pub async fn change_member_role(
+    organizations: OrganizationService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    organization: ActiveOrganization,
+    post_data: web::Form<MemberRoleForm>,
+) -> Result<HttpResponse, Error> {
+    let Some(role) = OrgRole::parse(&post_data.role) else {
+        return redirect_to_organization(&session, Level::Error, "Unknown role");
+    };
+
+    match organizations
+        .change_role(&req, &organization, post_data.user_id, role)
+        .await
+    {
+        Ok(Some(_)) => redirect_to_organization(
+            &session,
+            Level::Success,
+            &translate_with(
+                "The role has been changed to {role}",
+                &[("role", &translate(role.as_str()))],
+            ),
+        ),
+        Ok(None) => redirect_to_organization(
+            &session,
+            Level::Error,
+            "This account is no member of the organization",
+        ),
+        Err(OrganizationError::Denied(message)) => {
+            redirect_to_organization(&session, Level::Error, &message)
+        }
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+// Also used by members who leave the organization
+pub async fn remove_member(
+    organizations: OrganizationService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    organization: ActiveOrganization,
+    post_data: web::Form<RemoveMemberForm>,
+) -> Result<HttpResponse, Error> {
+    match organizations
+        .remove_member(&req, &organization, post_data.user_id)
+        .await
+    {
+        Ok(Some(_)) if post_data.user_id == organization.user.id => {
+            FlashMessages::new(&session).push(
+                Level::Success,
+                &translate_with(
+                    "You left {organization}",
+                    &[("organization", &organization.organization.name)],
+                ),
+            );
+            Ok(HttpResponse::SeeOther()
+                .insert_header((LOCATION, "/organizations"))
+                .finish())
+        }
+        Ok(Some(_)) => {
+            redirect_to_organization(&session, Level::Success, "The member has been removed")
+        }
+        Ok(None) => redirect_to_organization(
+            &session,
+            Level::Error,
+            "This account is no member of the organization",
+        ),
+        Err(OrganizationError::Denied(message)) => {
+            redirect_to_organization(&session, Level::Error, &message)
+        }
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+async fn render_organizations(
+    organizations: &OrganizationService,
+    tera: &web::Data<Templates>,
+    session: &Session,
+    user: &CurrentUser,
+    mut context: Context,
+    status: StatusCode,
+) -> Result<HttpResponse, Error> {
+    let memberships = match organizations.memberships(user).await {
+        Ok(memberships) => memberships,
+        Err(err) => return unavailable(tera, session, err),
+    };
+
+    let memberships: Vec<serde_json::Value> = memberships
+        .iter()
+        .map(|(organization, role)| {
+            serde_json::json!({
+                "id": organization.id,
+                "name": organization.name,
+                "slug": organization.slug,
+                "role": role,
+            })
+        })
+        .collect();
+
+    context.insert("memberships", &memberships);
+    render_template(
+        tera,
+        session,
+        "organizations/organizations.html",
+        &context,
+        status,
+    )
+}
+
+async fn render_organization(
+    organizations: &OrganizationService,
+    tera: &web::Data<Templates>,
+    session: &Session,
+    organization: &ActiveOrganization,
+    mut context: Context,
+    status: StatusCode,
+) -> Result<HttpResponse, Error> {
+    let members = match organizations.members(organization).await {
+        Ok(members) => members,
+        Err(err) => return unavailable(tera, session, err),
+    };
+    let user = &organization.user;
+    let members: Vec<serde_json::Value> = members
+        .iter()
+        .map(|(membership, email)| {
+            serde_json::json!({
+                "user_id": membership.user_id,
+                "email": email,
+                "role": membership.role,
+                "is_self": membership.user_id == user.id,
+                "editable": organization.role.can_manage_members()
+                    && organization.role.can_assign(membership.role),
+                "joined_at": format_datetime(user, membership.created_at),
+            })
+        })
+        .collect();
+
+    // Members only see who belongs to the organization
+    let can_manage = organization.role.can_manage_members();
+    if can_manage {
+        let invitations = match organizations.invitations(organization).await {
+            Ok(invitations) => invitations,
+            Err(err) => return unavailable(tera, session, err),
+        };
+        let now = chrono::Utc::now().naive_utc();
+        let invitations: Vec<serde_json::Value> = invitations
+            .iter()
+            .map(|invitation| {
+                serde_json::json!({
+                    "id": invitation.id,
+                    "email": invitation.email,
+                    "role": invitation.organization_role,
+                    "state": invitation.state(now),
+                    "created_at": format_datetime(user, invitation.created_at),
+                    "expires_at": format_datetime(user, invitation.expires_at),
+                })
+            })
+            .collect();
+        context.insert("invitations", &invitations);
+    }
+
+    let assignable: Vec<&str> = ORG_ROLES
+        .iter()
+        .filter(|role| organization.role.can_assign(**role))
+        .map(OrgRole::as_str)
+        .collect();
+
+    context.insert("organization", &organization.organization);
+    context.insert("role", &organization.role);
+    context.insert("can_manage", &can_manage);
+    context.insert(
+        "can_change_branding",
+        &organization.role.can_change_branding(),
+    );
+    context.insert("roles", &assignable);
+    context.insert("members", &members);
+    render_template(
+        tera,
+        session,
+        "organizations/organization.html",
+        &context,
+        status,
+    )
+}
+
+fn redirect_to_organization(
+    session: &Session,
+    level: Level,
+    text: &str,
+) -> Result<HttpResponse, Error> {
+    FlashMessages::new(session).push(level, text);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/organization"))
+        .finish())
+}
+
+fn unavailable(
+    tera: &web::Data<Templates>,
+    session: &Session,
+    err: impl std::fmt::Display,
+) -> Result<HttpResponse, Error> {
+    error!("{}", err);
+    render_error(
+        tera,
+        session,
+        "We are experiencing problems, please try again later.",
+        "errors/error_page.html",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
@@ -1,9 +1,27 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Validate, Clone)]
 pub struct RegisterForm {
+    #[validate(email(message = "Please enter a valid email address"))]
     pub email: String,
+    // Strength is checked against the configured PasswordPolicy in the view
+    #[serde(skip_serializing)]
     pub password: String,
-    #[serde(rename = "password-confirm")]
+    #[serde(rename = "password-confirm", skip_serializing)]
+    #[validate(must_match(other = "password", message = "Passwords do not match"))]
     pub password_confirm: String,
+    // Token of the invitation link, kept in a hidden field
+    #[serde(default)]
+    pub invitation: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterQuery {
+    pub invitation: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery {
+    pub token: String,
 }
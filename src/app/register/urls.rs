@@ -4,5 +4,6 @@ use crate::app::register::views;
 
 pub fn register_urls(cfg: &mut web::ServiceConfig) {
     cfg.route("/register", web::get().to(views::register))
-        .route("/register", web::post().to(views::register_submit));
+        .route("/register", web::post().to(views::register_submit))
+        .route("/register/verify", web::get().to(views::verify_email));
 }
@@ -1,117 +1,264 @@
 use actix_session::Session;
 use actix_web::http::header::LOCATION;
 use actix_web::http::StatusCode;
-use actix_web::{web, Error, HttpResponse, Result};
+use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
 use log::{error, info};
 use std::sync::Arc;
-use tera::{Context, Tera};
+use tera::Context;
 
 use crate::database::db::Database;
 use crate::database::errors::DatabaseError;
 use crate::get_user_id_from_session;
-use crate::models::users::NewUser;
-use crate::utils::render::{render_error, render_template};
+use crate::models::users::AccountStatus;
+use crate::services::auth::{AuthError, AuthService, Registration, INVITATION_INVALID};
+use crate::utils::audit::{self, user_target, Actor, AuditAction};
+use crate::utils::flash::{FlashMessages, Level};
+use crate::utils::registration::{RegistrationConfig, RegistrationMode};
+use crate::utils::render::{render_error, render_form, render_template};
+use crate::utils::templates::Templates;
+use crate::utils::tokens::{hash_token, EMAIL_VERIFICATION};
+use crate::utils::validation::ValidatedForm;
 
-use super::forms::RegisterForm;
+use super::forms::{RegisterForm, RegisterQuery, VerifyEmailQuery};
 
-pub async fn register(tera: web::Data<Tera>, session: Session) -> Result<HttpResponse> {
+// Links in invitations add the token, which fills in the invited address
+pub async fn register(
+    auth: AuthService,
+    tera: web::Data<Templates>,
+    session: Session,
+    registration: web::Data<RegistrationConfig>,
+    query: web::Query<RegisterQuery>,
+) -> Result<HttpResponse, Error> {
     // Check if user session already exists | If so redirect
-    if let Some(_) = get_user_id_from_session!(session) {
+    if get_user_id_from_session!(session).is_some() {
         return Ok(HttpResponse::SeeOther()
             .insert_header((LOCATION, "/dashboard"))
             .finish());
     }
 
-    let context = Context::new();
+    let invitation = match query.invitation.as_deref() {
+        Some(token) => match auth.invitation(token).await {
+            Ok(invitation) => Some((token, invitation)),
+            // The context says so
+            Err(AuthError::RegistrationClosed(_)) => None,
+            Err(err) => {
+                error!("{}", err);
+                return render_error(
+                    &tera,
+                    &session,
+                    "We are experiencing technical difficulties. Please try again later.",
+                    "errors/error_page.html",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                );
+            }
+        },
+        None => None,
+    };
+
+    let invited = matches!(invitation, Some((_, Some(_))));
+    let mut context = register_context(&registration, invited);
+    match invitation {
+        Some((token, Some(invitation))) => context.insert(
+            "form",
+            &serde_json::json!({ "email": invitation.email, "invitation": token }),
+        ),
+        Some((_, None)) => context.insert("error_message", INVITATION_INVALID),
+        None => {}
+    }
 
-    render_template(&tera, "register/register.html", &context, StatusCode::OK)
+    render_template(
+        &tera,
+        &session,
+        "register/register.html",
+        &context,
+        StatusCode::OK,
+    )
 }
 
 pub async fn register_submit(
-    db: web::Data<Arc<Database>>,
+    auth: AuthService,
+    req: HttpRequest,
     session: Session,
-    tera: web::Data<Tera>,
-    post_data: web::Form<RegisterForm>,
+    tera: web::Data<Templates>,
+    registration: web::Data<RegistrationConfig>,
+    mut post_data: ValidatedForm<RegisterForm>,
 ) -> Result<HttpResponse, Error> {
     // Check if user session already exists | If so redirect
-    if let Some(_) = get_user_id_from_session!(session) {
+    if get_user_id_from_session!(session).is_some() {
         return Ok(HttpResponse::SeeOther()
             .insert_header((LOCATION, "/dashboard"))
             .finish());
     }
 
-    // Check if passwords are equal
-    if post_data.password != post_data.password_confirm {
-        return render_error(
-            &tera,
-            "Passwords do not match",
-            "register/register.html",
-            StatusCode::BAD_REQUEST,
-        );
-    }
+    let invitation = post_data
+        .invitation
+        .clone()
+        .filter(|invitation| !invitation.is_empty());
+    let mut message = None;
 
-    // Clone mail here since we move it into web::block and need it later
-    let mail = post_data.email.clone();
-    let password = post_data.password.clone();
+    // Mail format and equal passwords are checked here, password strength and the registration
+    // mode by the service
+    if post_data.is_valid() {
+        let result = auth
+            .register(
+                &req,
+                &post_data.email,
+                &post_data.password,
+                invitation.as_deref(),
+            )
+            .await;
 
-    // Handle all database logic in one web::block
-    let result = web::block(move || {
-        // Check if a user already exists with the provided email
-        match db.get_user_by_email(&mail) {
-            // When an user already exists with that mail just return normal error
-            Ok(_) => Err(DatabaseError::UserAlreadyExists(
-                "An account already exists with that mail".to_string(),
-            )),
-            Err(DatabaseError::DieselError(diesel::result::Error::NotFound)) => {
-                // User does not exist, proceed to create new user
-                let new_user = NewUser::new(&mail, &password);
-                match new_user {
-                    Ok(user) => {
-                        // Insert new user into the database
-                        db.create_user(&user)
+        match result {
+            Ok(Registration::Created(_)) => {
+                FlashMessages::new(&session).push(
+                    Level::Success,
+                    "Your account has been created, you can log in now",
+                );
+                return Ok(HttpResponse::SeeOther()
+                    .insert_header((LOCATION, "/login"))
+                    .finish());
+            }
+            Ok(Registration::CheckInbox) => return check_inbox(&session),
+            Err(AuthError::Invalid(errors)) => {
+                for (field, messages) in errors {
+                    for message in messages {
+                        post_data.add_error(&field, &message);
                     }
-                    Err(err) => Err(err.into()),
                 }
             }
-            Err(err) => Err(err.into()),
+            Err(AuthError::EmailTaken(err)) => post_data.add_error("email", &err),
+            Err(AuthError::RegistrationClosed(err)) => message = Some(err),
+            Err(err) => {
+                error!("{}", err);
+                return render_error(
+                    &tera,
+                    &session,
+                    "We are experiencing technical difficulties. Please try again later.",
+                    "errors/error_page.html",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                );
+            }
+        }
+    }
+
+    render_form(
+        &tera,
+        &session,
+        "register/register.html",
+        register_context(&registration, invitation.is_some()),
+        &post_data,
+        message.as_deref(),
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+// Activates the account | The token alone proves access to the address
+pub async fn verify_email(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    query: web::Query<VerifyEmailQuery>,
+) -> Result<HttpResponse, Error> {
+    let token_hash = hash_token(&query.token);
+    let verify_db = db.clone();
+    let result = web::block(move || {
+        let db = verify_db;
+        let user_id = match db.take_token::<i32>(EMAIL_VERIFICATION, &token_hash)? {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+
+        let user = db.get_user_by_id(user_id)?;
+        match user.status {
+            AccountStatus::Unverified => db
+                .change_user_status(
+                    &user,
+                    AccountStatus::Active,
+                    Some(user.id),
+                    "Email address confirmed",
+                )
+                .and_then(|user| db.mark_email_verified(user.id))
+                .map(Some),
+            // Already verified or blocked in the meantime
+            _ => Ok(None),
         }
     })
     .await;
 
     match result {
-        Ok(Ok(_)) => {
-            info!("Created new user with email {}", &post_data.email);
-            Ok(HttpResponse::SeeOther()
-                .insert_header((LOCATION, "/login"))
-                .finish())
+        Ok(Ok(Some(user))) => {
+            info!("Verified email of {}", user.email);
+            audit::record(
+                &db,
+                Actor::user(&user, &req),
+                AuditAction::VerifyEmail,
+                user_target(user.id),
+                serde_json::json!({}),
+            )
+            .await;
+            FlashMessages::new(&session).push(
+                Level::Success,
+                "Your email address has been confirmed, you can log in now",
+            );
+        }
+        Ok(Ok(None)) | Ok(Err(DatabaseError::DieselError(diesel::result::Error::NotFound))) => {
+            FlashMessages::new(&session).push(
+                Level::Error,
+                "This confirmation link is invalid or has expired",
+            );
         }
-
-        // If user does not exist
-        Ok(Err(DatabaseError::UserAlreadyExists(err))) => render_error(
-            &tera,
-            &err,
-            "register/register.html",
-            StatusCode::BAD_REQUEST,
-        ),
-
-        // If some error occurred within database operations
         Ok(Err(err)) => {
             error!("Database error: {}", err);
-            render_error(
+            return render_error(
                 &tera,
+                &session,
                 "We are experiencing technical difficulties. Please try again later.",
-                "register/register.html",
-                StatusCode::BAD_REQUEST,
-            )
+                "errors/error_page.html",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            );
         }
         Err(err) => {
-            error!("Database error: {}", err);
-            render_error(
+            error!("Blocking error occurred: {:?}", err);
+            return render_error(
                 &tera,
+                &session,
                 "We are experiencing technical difficulties. Please try again later.",
                 "errors/error_page.html",
                 StatusCode::INTERNAL_SERVER_ERROR,
-            )
+            );
+        }
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/login"))
+        .finish())
+}
+
+// Why there is no form, or which addresses it accepts | Invitations open the form unless
+// registration is closed
+fn register_context(registration: &RegistrationConfig, invited: bool) -> Context {
+    let mut context = Context::new();
+    match &registration.mode {
+        RegistrationMode::Open => {}
+        RegistrationMode::AllowedDomains(_) if invited => {}
+        RegistrationMode::AllowedDomains(_) => {
+            context.insert("email_hint", &registration.mode.restriction())
+        }
+        RegistrationMode::InviteOnly if invited => {}
+        RegistrationMode::InviteOnly | RegistrationMode::Closed => {
+            context.insert("registration_closed", &registration.mode.restriction())
         }
     }
+    context
+}
+
+fn check_inbox(session: &Session) -> Result<HttpResponse, Error> {
+    FlashMessages::new(session).push(
+        Level::Info,
+        "Thanks for signing up, please check your inbox to continue",
+    );
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/login"))
+        .finish())
 }
@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use validator::{Validate, ValidationError};
+
+use crate::models::api_tokens::API_SCOPES;
+use crate::models::users::{User, UserProfile};
+use crate::utils::i18n::LOCALES;
+
+#[derive(Deserialize, Serialize, Validate)]
+pub struct ProfileForm {
+    #[validate(length(max = 100, message = "Display name must be at most 100 characters"))]
+    pub display_name: String,
+    #[validate(custom(function = "validate_timezone"))]
+    pub timezone: String,
+    // Empty to follow the language of the browser
+    #[validate(custom(function = "validate_locale"))]
+    pub locale: String,
+    // Empty for no avatar
+    #[validate(custom(function = "validate_avatar_url"))]
+    pub avatar_url: String,
+}
+
+impl ProfileForm {
+    // Current values to fill the form with
+    pub fn from_user(user: &User) -> Self {
+        ProfileForm {
+            display_name: user.display_name.clone().unwrap_or_default(),
+            timezone: user.timezone.clone(),
+            locale: user.locale.clone().unwrap_or_default(),
+            avatar_url: user.avatar_url.clone().unwrap_or_default(),
+        }
+    }
+
+    pub fn to_profile(&self) -> UserProfile {
+        UserProfile {
+            display_name: non_empty(&self.display_name),
+            timezone: self.timezone.clone(),
+            locale: non_empty(&self.locale),
+            avatar_url: non_empty(&self.avatar_url),
+        }
+    }
+}
+
+// Strength of the new password is checked against the configured PasswordPolicy in the view |
+// The current password is checked there too, accounts without one set it without
+#[derive(Deserialize, Serialize, Validate)]
+pub struct PasswordForm {
+    #[serde(default, skip_serializing)]
+    pub current_password: String,
+    #[serde(skip_serializing)]
+    pub new_password: String,
+    #[serde(rename = "new-password-confirm", skip_serializing)]
+    #[validate(must_match(other = "new_password", message = "Passwords do not match"))]
+    pub new_password_confirm: String,
+}
+
+#[derive(Deserialize, Serialize, Validate)]
+pub struct EmailForm {
+    #[validate(email(message = "Please enter a valid email address"))]
+    pub email: String,
+    #[serde(skip_serializing)]
+    #[validate(length(min = 1, message = "Please enter your current password"))]
+    pub current_password: String,
+}
+
+#[derive(Deserialize, Serialize, Validate)]
+pub struct DeleteAccountForm {
+    #[serde(skip_serializing)]
+    #[validate(length(min = 1, message = "Please enter your current password"))]
+    pub current_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionForm {
+    pub session_id: String,
+}
+
+// Lifetimes a new API token can be given, in days | Empty for tokens which never expire
+pub const TOKEN_EXPIRY_DAYS: &[&str] = &["7", "30", "90", "365", ""];
+
+// Scopes are checkboxes named scope:<scope>, at least one is checked in the view
+#[derive(Deserialize, Serialize, Validate)]
+pub struct TokenForm {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1 to 100 characters"))]
+    pub name: String,
+    #[validate(custom(function = "validate_token_expiry"))]
+    pub expires_in_days: String,
+    #[serde(flatten)]
+    pub checkboxes: HashMap<String, String>,
+}
+
+impl TokenForm {
+    pub fn scopes(&self) -> Vec<String> {
+        API_SCOPES
+            .iter()
+            .filter(|(scope, _)| self.checkboxes.contains_key(&format!("scope:{}", scope)))
+            .map(|(scope, _)| scope.to_string())
+            .collect()
+    }
+
+    pub fn expires_in_days(&self) -> Option<i64> {
+        self.expires_in_days.parse().ok()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UnlinkIdentityForm {
+    pub identity_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeTokenForm {
+    pub token_id: i32,
+}
+
+#[derive(Deserialize, Serialize, Validate)]
+pub struct InvitationForm {
+    #[validate(email(message = "Please enter a valid email address"))]
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeInvitationForm {
+    pub invitation_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeApplicationForm {
+    // Row id of the OAuth client
+    pub client_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmEmailQuery {
+    pub token: String,
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    match value.trim() {
+        "" => None,
+        value => Some(value.to_string()),
+    }
+}
+
+fn invalid(code: &'static str, message: &'static str) -> ValidationError {
+    ValidationError::new(code).with_message(Cow::Borrowed(message))
+}
+
+fn validate_timezone(timezone: &str) -> Result<(), ValidationError> {
+    match timezone.parse::<chrono_tz::Tz>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(invalid(
+            "timezone",
+            "Please choose a timezone from the list",
+        )),
+    }
+}
+
+fn validate_locale(locale: &str) -> Result<(), ValidationError> {
+    match locale.is_empty() || LOCALES.contains(&locale) {
+        true => Ok(()),
+        false => Err(invalid("locale", "Please choose a language from the list")),
+    }
+}
+
+fn validate_token_expiry(expires_in_days: &str) -> Result<(), ValidationError> {
+    match TOKEN_EXPIRY_DAYS.contains(&expires_in_days) {
+        true => Ok(()),
+        false => Err(invalid(
+            "expires_in_days",
+            "Please choose an expiration from the list",
+        )),
+    }
+}
+
+fn validate_avatar_url(avatar_url: &str) -> Result<(), ValidationError> {
+    let avatar_url = avatar_url.trim();
+    if avatar_url.is_empty() || avatar_url.starts_with("https://") && avatar_url.len() <= 2048 {
+        Ok(())
+    } else {
+        Err(invalid(
+            "avatar_url",
+            "Avatar must be an https:// link to an image",
+        ))
+    }
+}
@@ -0,0 +1,49 @@
+use actix_web::web;
+
+use super::views;
+
+pub fn register_urls(cfg: &mut web::ServiceConfig) {
+    cfg.route("/settings", web::get().to(views::settings))
+        .route("/settings/profile", web::post().to(views::profile_submit))
+        .route("/settings/password", web::post().to(views::password_submit))
+        .route("/settings/email", web::post().to(views::email_submit))
+        .route(
+            "/settings/email/confirm",
+            web::get().to(views::confirm_email),
+        )
+        .route("/settings/delete", web::post().to(views::delete_submit))
+        .route("/settings/sessions", web::get().to(views::sessions))
+        .route(
+            "/settings/sessions/revoke",
+            web::post().to(views::revoke_session),
+        )
+        .route(
+            "/settings/sessions/revoke-others",
+            web::post().to(views::revoke_other_sessions),
+        )
+        .route("/settings/identities", web::get().to(views::identities))
+        .route(
+            "/settings/identities/unlink",
+            web::post().to(views::unlink_identity),
+        )
+        .route("/settings/tokens", web::get().to(views::tokens))
+        .route("/settings/tokens", web::post().to(views::create_token))
+        .route(
+            "/settings/tokens/revoke",
+            web::post().to(views::revoke_token),
+        )
+        .route("/settings/invitations", web::get().to(views::invitations))
+        .route(
+            "/settings/invitations",
+            web::post().to(views::create_invitation),
+        )
+        .route(
+            "/settings/invitations/revoke",
+            web::post().to(views::revoke_invitation),
+        )
+        .route("/settings/applications", web::get().to(views::applications))
+        .route(
+            "/settings/applications/revoke",
+            web::post().to(views::revoke_application),
+        );
+}
@@ -0,0 +1,1091 @@
+use actix_session::Session;
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
+use chrono::TimeZone;
+use log::{error, info};
+use serde::Serialize;
+use std::sync::Arc;
+use tera::Context;
+
+use crate::database::db::Database;
+use crate::database::errors::DatabaseError;
+use crate::models::api_tokens::API_SCOPES;
+use crate::models::oauth::OAUTH_SCOPES;
+use crate::models::users::{normalize_email, AccountStatus, EmailChange, User};
+use crate::services::api_tokens::new_api_token;
+use crate::services::auth::{AuthError, AuthService};
+use crate::utils::argon2::{hash_password, verify_password};
+use crate::utils::audit::{self, user_target, Actor, AuditAction};
+use crate::utils::auth::CurrentUser;
+use crate::utils::flash::{FlashMessages, Level};
+use crate::utils::i18n::translate_with;
+use crate::utils::login_history::LOGIN_HISTORY_LENGTH;
+use crate::utils::mailer::Mailer;
+use crate::utils::oidc::OidcConfig;
+use crate::utils::password_policy::PasswordPolicy;
+use crate::utils::render::{render_error, render_template};
+use crate::utils::sessions::{active_sessions, current_session_id, SessionConfig};
+use crate::utils::templates::Templates;
+use crate::utils::tokens::{generate_token, hash_token, EMAIL_CHANGE};
+use crate::utils::validation::ValidatedForm;
+
+use super::forms::{
+    ConfirmEmailQuery, DeleteAccountForm, EmailForm, InvitationForm, PasswordForm, ProfileForm,
+    RevokeApplicationForm, RevokeInvitationForm, RevokeSessionForm, RevokeTokenForm, TokenForm,
+    UnlinkIdentityForm, TOKEN_EXPIRY_DAYS,
+};
+
+// Invitations shown on the invitations page
+const INVITATION_LIST_LENGTH: i64 = 50;
+
+pub async fn settings(
+    db: web::Data<Arc<Database>>,
+    tera: web::Data<Templates>,
+    session: Session,
+    user: CurrentUser,
+) -> Result<HttpResponse> {
+    render_template(
+        &tera,
+        &session,
+        "settings/settings.html",
+        &settings_context(&db, &user).await,
+        StatusCode::OK,
+    )
+}
+
+pub async fn profile_submit(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    post_data: ValidatedForm<ProfileForm>,
+) -> Result<HttpResponse, Error> {
+    if !post_data.is_valid() {
+        return render_section(&db, &tera, &session, &user, "profile", &post_data).await;
+    }
+
+    let actor = Actor::user(&user, &req);
+    let target = user_target(user.id);
+    let profile = post_data.to_profile();
+    let update_db = db.clone();
+    let result = web::block(move || update_db.update_profile(&user, &profile)).await;
+
+    match result {
+        Ok(Ok(_)) => {
+            audit::record(
+                &db,
+                actor,
+                AuditAction::ProfileUpdate,
+                target,
+                serde_json::to_value(&post_data.data).unwrap_or_default(),
+            )
+            .await;
+            redirect_with_message(&session, Level::Success, "Your profile has been updated")
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+pub async fn password_submit(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    mailer: web::Data<Mailer>,
+    password_policy: web::Data<PasswordPolicy>,
+    user: CurrentUser,
+    mut post_data: ValidatedForm<PasswordForm>,
+) -> Result<HttpResponse, Error> {
+    // Accounts created through a login provider set their first password without, nobody knows
+    // the generated one
+    if user.has_password {
+        if post_data.current_password.is_empty() {
+            post_data.add_error("current_password", "Please enter your current password");
+        } else if !verify_password(&post_data.current_password, &user.hashed_password) {
+            post_data.add_error("current_password", "Current password is incorrect");
+        }
+    }
+
+    for violation in password_policy.check(&post_data.new_password, &user.email) {
+        post_data.add_error("new_password", &violation);
+    }
+
+    if !post_data.is_valid() {
+        return render_section(&db, &tera, &session, &user, "password", &post_data).await;
+    }
+
+    let email = user.email.clone();
+    let locale = user.locale.clone();
+    let actor = Actor::user(&user, &req);
+    let target = user_target(user.id);
+    let new_password = post_data.new_password.clone();
+    let session_id = current_session_id(&session);
+    let update_db = db.clone();
+    let result = web::block(move || {
+        let hashed_password = hash_password(&new_password)?;
+        update_db.update_password(&user, &hashed_password)?;
+        // Whoever knew the old password should not stay signed in, in the browser or the API
+        update_db.remove_sessions(user.id, session_id.as_deref())?;
+        update_db.revoke_refresh_tokens(user.id).map(|_| ())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(_)) => {
+            info!("Changed password of {}", email);
+            audit::record(
+                &db,
+                actor,
+                AuditAction::PasswordChange,
+                target,
+                serde_json::json!({}),
+            )
+            .await;
+
+            // New session id after every change to the credentials
+            session.renew();
+
+            let mut mail_context = Context::new();
+            mail_context.insert("email", &email);
+            Mailer::send_template(
+                &mailer,
+                &tera,
+                &email,
+                locale.as_deref(),
+                "Your password has been changed",
+                "emails/password_changed.txt",
+                &mail_context,
+            );
+
+            redirect_with_message(&session, Level::Success, "Your password has been changed")
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+// The new address only replaces the old one once the link sent to it has been opened
+pub async fn email_submit(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    mailer: web::Data<Mailer>,
+    user: CurrentUser,
+    mut post_data: ValidatedForm<EmailForm>,
+) -> Result<HttpResponse, Error> {
+    if !verify_password(&post_data.current_password, &user.hashed_password) {
+        post_data.add_error("current_password", "Current password is incorrect");
+    }
+
+    let new_email = normalize_email(&post_data.email);
+    if new_email == user.email {
+        post_data.add_error("email", "This is already your email address");
+    }
+
+    if !post_data.is_valid() {
+        return render_section(&db, &tera, &session, &user, "email", &post_data).await;
+    }
+
+    let token = generate_token();
+    let token_hash = hash_token(&token);
+    let change = EmailChange {
+        user_id: user.id,
+        email: new_email.clone(),
+    };
+    let token_db = db.clone();
+    let result =
+        web::block(move || token_db.store_token(EMAIL_CHANGE, &token_hash, &change, 86400)).await;
+
+    match result {
+        Ok(Ok(_)) => {
+            audit::record(
+                &db,
+                Actor::user(&user, &req),
+                AuditAction::EmailChangeRequest,
+                user_target(user.id),
+                serde_json::json!({ "new_email": new_email }),
+            )
+            .await;
+
+            let mut mail_context = Context::new();
+            mail_context.insert("email", &user.email);
+            mail_context.insert("new_email", &new_email);
+            mail_context.insert("token", &token);
+
+            Mailer::send_template(
+                &mailer,
+                &tera,
+                &new_email,
+                user.locale.as_deref(),
+                "Confirm your new email address",
+                "emails/confirm_email.txt",
+                &mail_context,
+            );
+            Mailer::send_template(
+                &mailer,
+                &tera,
+                &user.email,
+                user.locale.as_deref(),
+                "Your email address is about to change",
+                "emails/email_change_requested.txt",
+                &mail_context,
+            );
+
+            redirect_with_message(
+                &session,
+                Level::Info,
+                &translate_with(
+                    "We sent a confirmation link to {email}, your email changes once you open it",
+                    &[("email", &new_email)],
+                ),
+            )
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+// Works without a session, the token alone proves access to the new address
+pub async fn confirm_email(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    query: web::Query<ConfirmEmailQuery>,
+) -> Result<HttpResponse, Error> {
+    let token_hash = hash_token(&query.token);
+    let change_db = db.clone();
+    let result = web::block(move || {
+        let db = change_db;
+        match db.take_token::<EmailChange>(EMAIL_CHANGE, &token_hash)? {
+            Some(change) => {
+                let user = db.get_user_by_id(change.user_id)?;
+                let updated = db.update_email(&user, &change.email)?;
+                // Refresh tokens were issued for the old address
+                db.revoke_refresh_tokens(user.id)?;
+                Ok(Some((user.email, updated)))
+            }
+            None => Ok(None),
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Some((old_email, user)))) => {
+            info!("Changed email of user {} to {}", user.id, user.email);
+            audit::record(
+                &db,
+                Actor::user(&user, &req),
+                AuditAction::EmailChange,
+                user_target(user.id),
+                serde_json::json!({ "old_email": old_email, "new_email": user.email }),
+            )
+            .await;
+            redirect_with_message(
+                &session,
+                Level::Success,
+                "Your email address has been changed",
+            )
+        }
+        Ok(Ok(None)) | Ok(Err(DatabaseError::DieselError(diesel::result::Error::NotFound))) => {
+            redirect_with_message(
+                &session,
+                Level::Error,
+                "This confirmation link is invalid or has expired",
+            )
+        }
+        Ok(Err(DatabaseError::UserAlreadyExists(err))) => {
+            redirect_with_message(&session, Level::Error, &err)
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+pub async fn delete_submit(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    mut post_data: ValidatedForm<DeleteAccountForm>,
+) -> Result<HttpResponse, Error> {
+    if !verify_password(&post_data.current_password, &user.hashed_password) {
+        post_data.add_error("current_password", "Current password is incorrect");
+    }
+
+    if !post_data.is_valid() {
+        return render_section(&db, &tera, &session, &user, "delete", &post_data).await;
+    }
+
+    let email = user.email.clone();
+    let actor = Actor::user(&user, &req);
+    let target = user_target(user.id);
+    // Purged for good by a background job after the grace period
+    let delete_db = db.clone();
+    let result = web::block(move || {
+        delete_db.change_user_status(
+            &user,
+            AccountStatus::Deleted,
+            Some(user.id),
+            "Deleted by the user",
+        )
+    })
+    .await;
+
+    match result {
+        Ok(Ok(_)) => {
+            info!("Deleted account of {}", email);
+            audit::record(
+                &db,
+                actor,
+                AuditAction::AccountDelete,
+                target,
+                serde_json::json!({}),
+            )
+            .await;
+
+            // Fresh session without the user, which still carries the message to the login page
+            session.clear();
+            session.renew();
+            FlashMessages::new(&session).push(Level::Info, "Your account has been deleted");
+
+            Ok(HttpResponse::SeeOther()
+                .insert_header((LOCATION, "/login"))
+                .finish())
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+pub async fn sessions(
+    db: web::Data<Arc<Database>>,
+    session: Session,
+    session_config: web::Data<SessionConfig>,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+) -> Result<HttpResponse, Error> {
+    let user_id = user.id;
+    let result = web::block(move || active_sessions(&db, &session_config, user_id)).await;
+
+    let sessions = match result {
+        Ok(Ok(sessions)) => sessions,
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    let current_session_id = current_session_id(&session);
+    let sessions: Vec<serde_json::Value> = sessions
+        .iter()
+        .map(|info| {
+            serde_json::json!({
+                "id": info.id,
+                "ip": info.ip,
+                "user_agent": info.user_agent,
+                "created_at": format_datetime(&user, info.created_at),
+                "last_seen": format_datetime(&user, info.last_seen),
+                "current": current_session_id.as_ref() == Some(&info.id),
+            })
+        })
+        .collect();
+
+    let mut context = Context::new();
+    context.insert("sessions", &sessions);
+
+    render_template(
+        &tera,
+        &session,
+        "settings/sessions.html",
+        &context,
+        StatusCode::OK,
+    )
+}
+
+pub async fn revoke_session(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    post_data: web::Form<RevokeSessionForm>,
+) -> Result<HttpResponse, Error> {
+    let user_id = user.id;
+    let session_id = post_data.into_inner().session_id;
+    let revoked_id = session_id.clone();
+    let revoke_db = db.clone();
+    let result = web::block(move || revoke_db.remove_session(user_id, &session_id)).await;
+
+    match result {
+        Ok(Ok(_)) => {
+            audit::record(
+                &db,
+                Actor::user(&user, &req),
+                AuditAction::SessionRevoke,
+                user_target(user_id),
+                serde_json::json!({ "session_id": revoked_id }),
+            )
+            .await;
+            redirect_to_sessions(&session, "The session has been signed out")
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+pub async fn revoke_other_sessions(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+) -> Result<HttpResponse, Error> {
+    let user_id = user.id;
+    let session_id = current_session_id(&session);
+    let revoke_db = db.clone();
+    let result =
+        web::block(move || revoke_db.remove_sessions(user_id, session_id.as_deref())).await;
+
+    match result {
+        Ok(Ok(_)) => {
+            audit::record(
+                &db,
+                Actor::user(&user, &req),
+                AuditAction::SessionRevokeOthers,
+                user_target(user_id),
+                serde_json::json!({}),
+            )
+            .await;
+            redirect_to_sessions(&session, "All other sessions have been signed out")
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+// Accounts at OpenID Connect providers which can be used to log in
+pub async fn identities(
+    db: web::Data<Arc<Database>>,
+    session: Session,
+    oidc: web::Data<OidcConfig>,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+) -> Result<HttpResponse, Error> {
+    let user_id = user.id;
+    let result = web::block(move || db.get_identities(user_id)).await;
+
+    let identities = match result {
+        Ok(Ok(identities)) => identities,
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    // Identities of providers which have been removed from the config are still listed
+    let display_name = |name: &str| match oidc.provider(name) {
+        Some(provider) => provider.display_name.clone(),
+        None => name.to_string(),
+    };
+    let connected: Vec<serde_json::Value> = identities
+        .iter()
+        .map(|identity| {
+            serde_json::json!({
+                "id": identity.id,
+                "provider": display_name(&identity.provider),
+                "email": identity.email,
+                "created_at": format_datetime(&user, identity.created_at),
+                "last_login_at": identity.last_login_at.map(|date| format_datetime(&user, date)),
+            })
+        })
+        .collect();
+    let available: Vec<serde_json::Value> = oidc
+        .providers
+        .iter()
+        .filter(|provider| {
+            !identities
+                .iter()
+                .any(|identity| identity.provider == provider.name)
+        })
+        .map(|provider| {
+            serde_json::json!({ "name": provider.name, "display_name": provider.display_name })
+        })
+        .collect();
+
+    let mut context = Context::new();
+    context.insert("identities", &connected);
+    context.insert("providers", &available);
+
+    render_template(
+        &tera,
+        &session,
+        "settings/identities.html",
+        &context,
+        StatusCode::OK,
+    )
+}
+
+pub async fn unlink_identity(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    post_data: web::Form<UnlinkIdentityForm>,
+) -> Result<HttpResponse, Error> {
+    let user_id = user.id;
+    let identity_id = post_data.identity_id;
+    let unlink_db = db.clone();
+    let result = web::block(move || unlink_db.unlink_identity(user_id, identity_id)).await;
+
+    match result {
+        Ok(Ok(Some(identity))) => {
+            audit::record(
+                &db,
+                Actor::user(&user, &req),
+                AuditAction::IdentityUnlink,
+                user_target(user_id),
+                serde_json::json!({ "provider": identity.provider, "subject": identity.subject }),
+            )
+            .await;
+            redirect_to_identities(
+                &session,
+                Level::Success,
+                "The account has been disconnected",
+            )
+        }
+        Ok(Ok(None)) => redirect_to_identities(
+            &session,
+            Level::Error,
+            "The account is not connected anymore",
+        ),
+        Ok(Err(DatabaseError::LastIdentity(msg))) => {
+            redirect_to_identities(&session, Level::Error, &msg)
+        }
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+pub async fn tokens(
+    db: web::Data<Arc<Database>>,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+) -> Result<HttpResponse, Error> {
+    render_tokens(&db, &tera, &session, &user, Context::new(), StatusCode::OK).await
+}
+
+// The new token is shown right away instead of after a redirect, so it never ends up in the session
+pub async fn create_token(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    mut post_data: ValidatedForm<TokenForm>,
+) -> Result<HttpResponse, Error> {
+    let name = post_data.name.trim().to_string();
+    if name.is_empty() {
+        post_data.add_error("name", "Name must be 1 to 100 characters");
+    }
+    let scopes = post_data.scopes();
+    if scopes.is_empty() {
+        post_data.add_error("scopes", "Please choose at least one scope");
+    }
+    if !post_data.is_valid() {
+        let mut context = Context::new();
+        context.insert("form", &post_data.data);
+        context.insert("field_errors", &post_data.errors);
+        return render_tokens(
+            &db,
+            &tera,
+            &session,
+            &user,
+            context,
+            StatusCode::BAD_REQUEST,
+        )
+        .await;
+    }
+
+    let expires_at = post_data
+        .expires_in_days()
+        .map(|days| chrono::Utc::now().naive_utc() + chrono::Duration::days(days));
+    let (token, new_token) = new_api_token(&user, name, scopes, expires_at);
+    let create_db = db.clone();
+    let api_token = match web::block(move || create_db.create_api_token(&new_token)).await {
+        Ok(Ok(api_token)) => api_token,
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    audit::record(
+        &db,
+        Actor::user(&user, &req),
+        AuditAction::TokenCreate,
+        user_target(user.id),
+        serde_json::json!({
+            "token_id": api_token.id,
+            "name": api_token.name,
+            "prefix": api_token.prefix,
+            "scopes": api_token.scopes,
+            "expires_at": api_token.expires_at,
+        }),
+    )
+    .await;
+
+    let mut context = Context::new();
+    context.insert("new_token", &token);
+    render_tokens(&db, &tera, &session, &user, context, StatusCode::OK).await
+}
+
+pub async fn revoke_token(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    post_data: web::Form<RevokeTokenForm>,
+) -> Result<HttpResponse, Error> {
+    let user_id = user.id;
+    let token_id = post_data.token_id;
+    let revoke_db = db.clone();
+    let result = web::block(move || revoke_db.revoke_api_token(user_id, token_id)).await;
+
+    match result {
+        Ok(Ok(Some(api_token))) => {
+            audit::record(
+                &db,
+                Actor::user(&user, &req),
+                AuditAction::TokenRevoke,
+                user_target(user_id),
+                serde_json::json!({ "token_id": api_token.id, "prefix": api_token.prefix }),
+            )
+            .await;
+            redirect_to_tokens(&session, Level::Success, "The token has been revoked")
+        }
+        Ok(Ok(None)) => redirect_to_tokens(&session, Level::Error, "The token no longer exists"),
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+// Applications the user has allowed to use their account
+pub async fn applications(
+    db: web::Data<Arc<Database>>,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+) -> Result<HttpResponse, Error> {
+    let user_id = user.id;
+    let consents = match web::block(move || db.get_consents(user_id)).await {
+        Ok(Ok(consents)) => consents,
+        Ok(Err(err)) => return unavailable(&tera, &session, err),
+        Err(err) => return unavailable(&tera, &session, err),
+    };
+
+    let applications: Vec<serde_json::Value> = consents
+        .iter()
+        .map(|(consent, client)| {
+            let scopes: Vec<&str> = OAUTH_SCOPES
+                .iter()
+                .filter(|(scope, _)| consent.scopes.iter().any(|granted| granted == scope))
+                .map(|(_, description)| *description)
+                .collect();
+            serde_json::json!({
+                "client_id": client.id,
+                "name": client.name,
+                "scopes": scopes,
+                "created_at": format_datetime(&user, consent.created_at),
+            })
+        })
+        .collect();
+
+    let mut context = Context::new();
+    context.insert("applications", &applications);
+
+    render_template(
+        &tera,
+        &session,
+        "settings/applications.html",
+        &context,
+        StatusCode::OK,
+    )
+}
+
+// Access tokens issued before stop working as well, the application has to ask again
+pub async fn revoke_application(
+    db: web::Data<Arc<Database>>,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    post_data: web::Form<RevokeApplicationForm>,
+) -> Result<HttpResponse, Error> {
+    let user_id = user.id;
+    let client_id = post_data.client_id;
+    let revoke_db = db.clone();
+    let result = web::block(move || revoke_db.revoke_consent(user_id, client_id)).await;
+
+    match result {
+        Ok(Ok(Some(client))) => {
+            audit::record(
+                &db,
+                Actor::user(&user, &req),
+                AuditAction::OAuthConsentRevoke,
+                user_target(user_id),
+                serde_json::json!({ "client_id": client.client_id, "name": client.name }),
+            )
+            .await;
+            redirect_to_applications(
+                &session,
+                Level::Success,
+                &translate_with(
+                    "{application} can no longer use your account",
+                    &[("application", &client.name)],
+                ),
+            )
+        }
+        Ok(Ok(None)) => redirect_to_applications(
+            &session,
+            Level::Error,
+            "The application has no access anymore",
+        ),
+        Ok(Err(err)) => unavailable(&tera, &session, err),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+// Invitations the user sent, next to the form for sending one
+pub async fn invitations(
+    auth: AuthService,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+) -> Result<HttpResponse, Error> {
+    render_invitations(
+        &auth,
+        &tera,
+        &session,
+        &user,
+        Context::new(),
+        StatusCode::OK,
+    )
+    .await
+}
+
+pub async fn create_invitation(
+    auth: AuthService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    mut post_data: ValidatedForm<InvitationForm>,
+) -> Result<HttpResponse, Error> {
+    if post_data.is_valid() {
+        match auth.invite(&req, &user, &post_data.email).await {
+            Ok(invitation) => {
+                return redirect_to_invitations(
+                    &session,
+                    Level::Success,
+                    &translate_with(
+                        "We sent an invitation to {email}",
+                        &[("email", &invitation.email)],
+                    ),
+                )
+            }
+            Err(AuthError::Invalid(errors)) => {
+                for (field, messages) in errors {
+                    for message in messages {
+                        post_data.add_error(&field, &message);
+                    }
+                }
+            }
+            Err(AuthError::RegistrationClosed(message)) => {
+                return redirect_to_invitations(&session, Level::Error, &message)
+            }
+            Err(err) => return unavailable(&tera, &session, err),
+        }
+    }
+
+    let mut context = Context::new();
+    context.insert("form", &post_data.data);
+    context.insert("field_errors", &post_data.errors);
+    render_invitations(
+        &auth,
+        &tera,
+        &session,
+        &user,
+        context,
+        StatusCode::BAD_REQUEST,
+    )
+    .await
+}
+
+pub async fn revoke_invitation(
+    auth: AuthService,
+    req: HttpRequest,
+    session: Session,
+    tera: web::Data<Templates>,
+    user: CurrentUser,
+    post_data: web::Form<RevokeInvitationForm>,
+) -> Result<HttpResponse, Error> {
+    match auth
+        .revoke_invitation(&req, &user, post_data.invitation_id)
+        .await
+    {
+        Ok(Some(invitation)) => redirect_to_invitations(
+            &session,
+            Level::Success,
+            &translate_with(
+                "The invitation of {email} has been revoked",
+                &[("email", &invitation.email)],
+            ),
+        ),
+        Ok(None) => redirect_to_invitations(
+            &session,
+            Level::Error,
+            "The invitation cannot be revoked anymore",
+        ),
+        Err(err) => unavailable(&tera, &session, err),
+    }
+}
+
+// Lists the invitations of the user next to the form for sending one
+async fn render_invitations(
+    auth: &AuthService,
+    tera: &web::Data<Templates>,
+    session: &Session,
+    user: &User,
+    mut context: Context,
+    status: StatusCode,
+) -> Result<HttpResponse, Error> {
+    let invitations = match auth
+        .invitations(Some(user.id), INVITATION_LIST_LENGTH)
+        .await
+    {
+        Ok(invitations) => invitations,
+        Err(err) => return unavailable(tera, session, err),
+    };
+
+    let now = chrono::Utc::now().naive_utc();
+    let invitations: Vec<serde_json::Value> = invitations
+        .iter()
+        .map(|(invitation, _)| {
+            serde_json::json!({
+                "id": invitation.id,
+                "email": invitation.email,
+                "state": invitation.state(now),
+                "created_at": format_datetime(user, invitation.created_at),
+                "expires_at": format_datetime(user, invitation.expires_at),
+            })
+        })
+        .collect();
+
+    let registration = auth.registration();
+    context.insert("invitations", &invitations);
+    context.insert(
+        "invitations_enabled",
+        &registration.mode.allows_invitations(),
+    );
+    context.insert("days", &registration.invitation_ttl.num_days());
+    render_template(tera, session, "settings/invitations.html", &context, status)
+}
+
+// Lists the tokens of the user next to the form for creating one
+async fn render_tokens(
+    db: &web::Data<Arc<Database>>,
+    tera: &web::Data<Templates>,
+    session: &Session,
+    user: &User,
+    mut context: Context,
+    status: StatusCode,
+) -> Result<HttpResponse, Error> {
+    let user_id = user.id;
+    let list_db = db.clone();
+    let api_tokens = match web::block(move || list_db.get_api_tokens(user_id)).await {
+        Ok(Ok(api_tokens)) => api_tokens,
+        Ok(Err(err)) => return unavailable(tera, session, err),
+        Err(err) => return unavailable(tera, session, err),
+    };
+
+    let now = chrono::Utc::now().naive_utc();
+    let api_tokens: Vec<serde_json::Value> = api_tokens
+        .iter()
+        .map(|api_token| {
+            serde_json::json!({
+                "id": api_token.id,
+                "name": api_token.name,
+                "prefix": api_token.prefix,
+                "scopes": api_token.scopes,
+                "created_at": format_datetime(user, api_token.created_at),
+                "expires_at": api_token.expires_at.map(|date| format_datetime(user, date)),
+                "expired": !api_token.is_usable(now),
+                "last_used_at": api_token.last_used_at.map(|date| format_datetime(user, date)),
+                "last_used_ip": api_token.last_used_ip,
+            })
+        })
+        .collect();
+
+    context.insert("tokens", &api_tokens);
+    context.insert("scopes", API_SCOPES);
+    context.insert("expiry_days", TOKEN_EXPIRY_DAYS);
+    render_template(tera, session, "settings/tokens.html", &context, status)
+}
+
+// Values shown in every section of the settings page
+async fn settings_context(db: &web::Data<Arc<Database>>, user: &User) -> Context {
+    let timezones: Vec<&str> = chrono_tz::TZ_VARIANTS.iter().map(|tz| tz.name()).collect();
+
+    // The rest of the page still works without the login history
+    let user_id = user.id;
+    let db = db.clone();
+    let login_events =
+        match web::block(move || db.get_login_events(user_id, LOGIN_HISTORY_LENGTH)).await {
+            Ok(Ok(events)) => events,
+            Ok(Err(err)) => {
+                error!("Failed to load login history: {}", err);
+                Vec::new()
+            }
+            Err(err) => {
+                error!("Blocking error occurred: {:?}", err);
+                Vec::new()
+            }
+        };
+    let login_events: Vec<serde_json::Value> = login_events
+        .iter()
+        .map(|event| {
+            serde_json::json!({
+                "created_at": format_datetime(user, event.created_at),
+                "ip": event.ip,
+                "user_agent": event.user_agent,
+                "outcome": event.outcome,
+                "description": event.outcome.description(),
+            })
+        })
+        .collect();
+
+    let mut context = Context::new();
+    context.insert("email", &user.email);
+    context.insert("has_password", &user.has_password);
+    context.insert("profile", &ProfileForm::from_user(user));
+    context.insert("member_since", &format_date(user, user.created_at));
+    context.insert("timezones", &timezones);
+    context.insert("login_events", &login_events);
+    context
+}
+
+// Re-renders the settings page with the errors and values of the form which failed
+async fn render_section<T: Serialize>(
+    db: &web::Data<Arc<Database>>,
+    tera: &web::Data<Templates>,
+    session: &Session,
+    user: &User,
+    section: &str,
+    form: &ValidatedForm<T>,
+) -> Result<HttpResponse, Error> {
+    let mut context = settings_context(db, user).await;
+    context.insert("section", section);
+    context.insert("form", &form.data);
+    context.insert("field_errors", &form.errors);
+
+    render_template(
+        tera,
+        session,
+        "settings/settings.html",
+        &context,
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+// Dates are stored in UTC and shown in the timezone of the user
+fn format_date(user: &User, date: chrono::NaiveDateTime) -> String {
+    let timezone: chrono_tz::Tz = user.timezone.parse().unwrap_or(chrono_tz::UTC);
+    timezone
+        .from_utc_datetime(&date)
+        .format("%B %-d, %Y")
+        .to_string()
+}
+
+pub fn format_datetime(user: &User, date: chrono::NaiveDateTime) -> String {
+    let timezone: chrono_tz::Tz = user.timezone.parse().unwrap_or(chrono_tz::UTC);
+    timezone
+        .from_utc_datetime(&date)
+        .format("%B %-d, %Y %H:%M")
+        .to_string()
+}
+
+fn redirect_to_sessions(session: &Session, text: &str) -> Result<HttpResponse, Error> {
+    FlashMessages::new(session).push(Level::Success, text);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/settings/sessions"))
+        .finish())
+}
+
+fn redirect_to_identities(
+    session: &Session,
+    level: Level,
+    text: &str,
+) -> Result<HttpResponse, Error> {
+    FlashMessages::new(session).push(level, text);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/settings/identities"))
+        .finish())
+}
+
+fn redirect_to_applications(
+    session: &Session,
+    level: Level,
+    text: &str,
+) -> Result<HttpResponse, Error> {
+    FlashMessages::new(session).push(level, text);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/settings/applications"))
+        .finish())
+}
+
+fn redirect_to_invitations(
+    session: &Session,
+    level: Level,
+    text: &str,
+) -> Result<HttpResponse, Error> {
+    FlashMessages::new(session).push(level, text);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/settings/invitations"))
+        .finish())
+}
+
+fn redirect_to_tokens(session: &Session, level: Level, text: &str) -> Result<HttpResponse, Error> {
+    FlashMessages::new(session).push(level, text);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/settings/tokens"))
+        .finish())
+}
+
+fn redirect_with_message(
+    session: &Session,
+    level: Level,
+    text: &str,
+) -> Result<HttpResponse, Error> {
+    FlashMessages::new(session).push(level, text);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/settings"))
+        .finish())
+}
+
+fn unavailable(
+    tera: &web::Data<Templates>,
+    session: &Session,
+    err: impl std::fmt::Display,
+) -> Result<HttpResponse, Error> {
+    error!("{}", err);
+    render_error(
+        tera,
+        session,
+        "We are experiencing problems, please try again later.",
+        "errors/error_page.html",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
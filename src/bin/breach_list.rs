@@ -0,0 +1,44 @@
+// Converts a newline separated password list, e.g. a breach corpus, into the compact hashed
+// format loaded through PASSWORD_BREACH_LIST
+//
+// cargo run --bin breach_list -- data/common_passwords.txt data/breached_passwords.bin
+use std::fs;
+use std::path::Path;
+
+#[allow(dead_code)]
+#[path = "../utils/breached_passwords.rs"]
+mod breached_passwords;
+
+use breached_passwords::{hash, MAGIC};
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <passwords.txt> <output.bin>", args[0]);
+        std::process::exit(1);
+    }
+
+    // Breach corpora are not always valid utf-8
+    let content = fs::read(&args[1])?;
+    let content = String::from_utf8_lossy(&content);
+
+    let mut hashes: Vec<u64> = content
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(hash)
+        .collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    let mut bytes = Vec::with_capacity(MAGIC.len() + hashes.len() * 8);
+    bytes.extend_from_slice(MAGIC);
+    for hash in &hashes {
+        bytes.extend_from_slice(&hash.to_be_bytes());
+    }
+    fs::write(Path::new(&args[2]), bytes)?;
+
+    println!("Wrote {} password hashes to {}", hashes.len(), args[2]);
+
+    Ok(())
+}
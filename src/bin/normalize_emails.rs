@@ -0,0 +1,72 @@
+// Brings the emails of existing accounts into the form the app stores and looks them up in,
+// including punycode for internationalized domains, which the migration cannot do in SQL
+// Lists accounts whose emails are the same once normalized and changes nothing while there are any
+//
+// DATABASE_URL=postgresql://... cargo run --bin normalize_emails
+use diesel::prelude::*;
+use diesel::sql_types::{Integer, Text};
+use std::collections::BTreeMap;
+
+#[path = "../utils/email.rs"]
+mod email;
+
+use email::normalize_email;
+
+#[derive(QueryableByName)]
+struct Account {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+    #[diesel(sql_type = Text)]
+    email: String,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let database_url = std::env::var("DATABASE_URL").map_err(|_| "DATABASE_URL must be set")?;
+    let mut conn = PgConnection::establish(&database_url)?;
+
+    let accounts: Vec<Account> =
+        diesel::sql_query("SELECT id, email FROM users ORDER BY id").load(&mut conn)?;
+
+    let mut by_email: BTreeMap<String, Vec<&Account>> = BTreeMap::new();
+    for account in &accounts {
+        by_email
+            .entry(normalize_email(&account.email))
+            .or_default()
+            .push(account);
+    }
+
+    let duplicates: Vec<_> = by_email
+        .iter()
+        .filter(|(_, accounts)| accounts.len() > 1)
+        .collect();
+    if !duplicates.is_empty() {
+        eprintln!("These accounts share an email address, merge or rename them first:");
+        for (email, accounts) in duplicates {
+            let ids: Vec<String> = accounts
+                .iter()
+                .map(|account| format!("{} ({})", account.id, account.email))
+                .collect();
+            eprintln!("{}: {}", email, ids.join(", "));
+        }
+        std::process::exit(1);
+    }
+
+    let changed = conn.transaction::<usize, diesel::result::Error, _>(|conn| {
+        let mut changed = 0;
+        for (email, accounts) in &by_email {
+            let account = accounts[0];
+            if *email != account.email {
+                diesel::sql_query("UPDATE users SET email = $1 WHERE id = $2")
+                    .bind::<Text, _>(email)
+                    .bind::<Integer, _>(account.id)
+                    .execute(conn)?;
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    })?;
+    println!("Normalized {} of {} emails", changed, accounts.len());
+
+    Ok(())
+}
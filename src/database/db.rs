@@ -1,16 +1,53 @@
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
+use diesel::result::DatabaseErrorKind;
+use diesel::sql_types::Text;
+use log::warn;
 use r2d2_redis::redis::Commands;
 use r2d2_redis::{r2d2 as garnet_r2d2, RedisConnectionManager};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json;
+use std::collections::HashMap;
 
 use super::errors::DatabaseError;
-use crate::models::users::{NewUser, User};
+use super::tenant::OrgScope;
+use crate::models::api_tokens::{ApiToken, NewApiToken};
+use crate::models::audit::{AuditEntry, AuditFilter, AuditRecord, NewAuditEntry, GENESIS_HASH};
+use crate::models::identities::{NewUserIdentity, UserIdentity};
+use crate::models::login_events::{LoginEvent, LoginOutcome, NewLoginEvent};
+use crate::models::oauth::{
+    AuthorizationCode, NewAuthorizationCode, NewOAuthClient, OAuthClient, OAuthConsent,
+};
+use crate::models::organizations::{Organization, OrganizationBranding};
+use crate::models::refresh_tokens::{NewRefreshToken, RefreshToken, Rotation};
+use crate::models::sessions::SessionInfo;
+use crate::models::users::{
+    normalize_email, AccountStatus, NewStatusChange, NewUser, Role, StatusChange, User, UserProfile,
+};
+use crate::schema::api_tokens::dsl as token_dsl;
+use crate::schema::audit_log::dsl as audit_dsl;
+use crate::schema::login_events::dsl as login_dsl;
+use crate::schema::oauth_authorization_codes::dsl as code_dsl;
+use crate::schema::oauth_clients::dsl as client_dsl;
+use crate::schema::oauth_consents::dsl as consent_dsl;
+use crate::schema::organizations::dsl as organization_dsl;
+use crate::schema::refresh_tokens::dsl as refresh_dsl;
+use crate::schema::user_identities::dsl as identity_dsl;
+use crate::schema::user_status_changes::dsl as status_dsl;
 use crate::schema::users::dsl as user_dsl;
 
+// Uses the users_email_lower_key index for email lookups
+define_sql_function!(fn lower(x: Text) -> Text);
+
 // Type alias for using the specific Postgres connection pool
 pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 pub type Cache = garnet_r2d2::Pool<RedisConnectionManager>;
+type RedisConnection = garnet_r2d2::PooledConnection<RedisConnectionManager>;
+
+// Key of the advisory lock appends to the audit log take, "audit" in ascii
+const AUDIT_LOG_LOCK: i64 = 0x61_7564_6974;
 
 #[derive(Clone)]
 pub struct Database {
@@ -43,46 +80,1082 @@ impl Database {
 
     // Users
     // Inserts a new user into the database
-    pub fn create_user(&self, new_user: &NewUser) -> Result<(), DatabaseError> {
+    pub fn create_user(&self, new_user: &NewUser) -> Result<User, DatabaseError> {
         let mut db_conn = self.db_pool.get()?;
         let mut cache_conn = self.cache_pool.get()?;
 
-        // Insert user into database
+        // Insert user into database | The unique email index rejects duplicates, even concurrent ones
         let user: User = diesel::insert_into(user_dsl::users)
             .values(new_user)
-            .get_result(&mut db_conn)?;
+            .get_result(&mut db_conn)
+            .map_err(|err| match err {
+                diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                    DatabaseError::UserAlreadyExists(
+                        "An account already exists with that mail".to_string(),
+                    )
+                }
+                err => err.into(),
+            })?;
 
         // Serialize object and insert it into cache
         let user_serialized = serde_json::to_string(&user)?;
 
-        // Insert the challenge with TTL into Redis | Format entry to avoid collisions with other db tables
-        cache_conn.set(format!("user:{}", user.id), user_serialized)?;
+        // Insert the user with TTL into Redis | Format entry to avoid collisions with other db tables
+        cache_conn.set_ex::<_, _, ()>(format!("user:{}", user.id), user_serialized, 3600)?;
+
+        Ok(user)
+    }
+
+    // Retrieves a user by id
+    pub fn get_user_by_id(&self, user_id: i32) -> Result<User, DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        let cache_key = format!("user:{}", user_id);
+
+        if let Some(user) = cached_user(&mut cache_conn, &cache_key)? {
+            return Ok(user);
+        }
+
+        let mut db_conn = self.db_pool.get()?;
+        let user: User = user_dsl::users.find(user_id).first(&mut db_conn)?;
+
+        let user_serialized = serde_json::to_string(&user)?;
+        cache_conn.set_ex::<_, _, ()>(cache_key, user_serialized, 3600)?;
+
+        Ok(user)
+    }
+
+    pub fn update_profile(
+        &self,
+        user: &User,
+        profile: &UserProfile,
+    ) -> Result<User, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let updated = diesel::update(user_dsl::users.find(user.id))
+            .set(profile)
+            .get_result(&mut db_conn)?;
+
+        self.invalidate_user(user)?;
+        Ok(updated)
+    }
+
+    pub fn update_password(&self, user: &User, hashed_password: &str) -> Result<(), DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        diesel::update(user_dsl::users.find(user.id))
+            .set((
+                user_dsl::hashed_password.eq(hashed_password),
+                user_dsl::has_password.eq(true),
+            ))
+            .execute(&mut db_conn)?;
+
+        self.invalidate_user(user)
+    }
+
+    // Fails with UserAlreadyExists if another account uses the new email
+    pub fn update_email(&self, user: &User, new_email: &str) -> Result<User, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let updated = diesel::update(user_dsl::users.find(user.id))
+            // Only changed once the link sent to the new address was opened
+            .set((
+                user_dsl::email.eq(normalize_email(new_email)),
+                user_dsl::email_verified.eq(true),
+            ))
+            .get_result(&mut db_conn)
+            .map_err(|err| match err {
+                diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                    DatabaseError::UserAlreadyExists(
+                        "An account already exists with that mail".to_string(),
+                    )
+                }
+                err => err.into(),
+            })?;
+
+        self.invalidate_user(user)?;
+        Ok(updated)
+    }
+
+    // The owner of the address proved it, e.g. with a link mailed to it
+    pub fn mark_email_verified(&self, user_id: i32) -> Result<User, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let updated = diesel::update(user_dsl::users.find(user_id))
+            .set(user_dsl::email_verified.eq(true))
+            .get_result(&mut db_conn)?;
+
+        self.invalidate_user(&updated)?;
+        Ok(updated)
+    }
+
+    // Checks the transition, records it in the status history and signs out every session and
+    // refresh token of the user unless the account is active afterwards
+    // changed_by is None for changes made by the system
+    pub fn change_user_status(
+        &self,
+        user: &User,
+        new_status: AccountStatus,
+        changed_by: Option<i32>,
+        reason: &str,
+    ) -> Result<User, DatabaseError> {
+        if !user.status.can_change_to(new_status) {
+            return Err(DatabaseError::InvalidStatusChange(format!(
+                "An account cannot change from {} to {}",
+                user.status.as_str(),
+                new_status.as_str()
+            )));
+        }
+
+        let mut db_conn = self.db_pool.get()?;
+        let updated = db_conn.transaction::<User, diesel::result::Error, _>(|conn| {
+            let updated = diesel::update(user_dsl::users.find(user.id))
+                .set((
+                    user_dsl::status.eq(new_status),
+                    user_dsl::status_changed_at.eq(diesel::dsl::now),
+                ))
+                .get_result(conn)?;
+
+            diesel::insert_into(status_dsl::user_status_changes)
+                .values(&NewStatusChange {
+                    user_id: user.id,
+                    old_status: user.status,
+                    new_status,
+                    changed_by,
+                    reason,
+                })
+                .execute(conn)?;
+
+            Ok(updated)
+        })?;
+
+        self.invalidate_user(user)?;
+        if new_status != AccountStatus::Active {
+            self.remove_sessions(user.id, None)?;
+            self.revoke_refresh_tokens(user.id)?;
+        }
+
+        Ok(updated)
+    }
+
+    // Newest first
+    pub fn get_status_changes(&self, user_id: i32) -> Result<Vec<StatusChange>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let changes = status_dsl::user_status_changes
+            .filter(status_dsl::user_id.eq(user_id))
+            .order(status_dsl::created_at.desc())
+            .load(&mut db_conn)?;
+
+        Ok(changes)
+    }
+
+    // Removes accounts which have been deleted before the given time for good
+    pub fn purge_deleted_users(
+        &self,
+        deleted_before: NaiveDateTime,
+    ) -> Result<Vec<User>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let purged: Vec<User> = diesel::delete(
+            user_dsl::users
+                .filter(user_dsl::status.eq(AccountStatus::Deleted))
+                .filter(user_dsl::status_changed_at.lt(deleted_before)),
+        )
+        .get_results(&mut db_conn)?;
+
+        for user in &purged {
+            self.invalidate_user(user)?;
+        }
+
+        Ok(purged)
+    }
+
+    // Users whose email contains the search term, ordered by id
+    pub fn search_users(&self, search: &str, limit: i64) -> Result<Vec<User>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let users = user_dsl::users
+            .filter(user_dsl::email.like(contains_pattern(search)))
+            .order(user_dsl::id)
+            .limit(limit)
+            .load(&mut db_conn)?;
+
+        Ok(users)
+    }
+
+    pub fn change_user_role(&self, user: &User, role: Role) -> Result<User, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let updated = diesel::update(user_dsl::users.find(user.id))
+            .set(user_dsl::role.eq(role))
+            .get_result(&mut db_conn)?;
+
+        self.invalidate_user(user)?;
+        Ok(updated)
+    }
+
+    // Audit log
+
+    // Appends the record to the end of the hash chain | Writers wait for each other,
+    // so two entries can never link to the same predecessor
+    pub fn append_audit_entry(&self, record: &AuditRecord) -> Result<AuditEntry, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let entry = db_conn.transaction::<AuditEntry, diesel::result::Error, _>(|conn| {
+            // Entries are chained, so appends take turns | An advisory lock instead of a table lock
+            // keeps searches and exports of the log from waiting for it
+            diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+                .bind::<diesel::sql_types::BigInt, _>(AUDIT_LOG_LOCK)
+                .execute(conn)?;
+
+            let prev_hash = audit_dsl::audit_log
+                .select(audit_dsl::hash)
+                .order(audit_dsl::id.desc())
+                .first::<String>(conn)
+                .optional()?
+                .unwrap_or_else(|| GENESIS_HASH.to_string());
+
+            diesel::insert_into(audit_dsl::audit_log)
+                .values(&NewAuditEntry::chain(
+                    record,
+                    prev_hash,
+                    chrono::Utc::now().naive_utc(),
+                ))
+                .get_result(conn)
+        })?;
+
+        Ok(entry)
+    }
+
+    // Newest first
+    pub fn search_audit_log(
+        &self,
+        filter: &AuditFilter,
+        limit: i64,
+    ) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let mut query = audit_dsl::audit_log.into_boxed();
+
+        if let Some(action) = &filter.action {
+            query = query.filter(audit_dsl::action.eq(action));
+        }
+        if let Some(actor) = &filter.actor {
+            query = query.filter(audit_dsl::actor_email.like(contains_pattern(actor)));
+        }
+        if let Some(target) = &filter.target {
+            query = query.filter(audit_dsl::target.eq(target));
+        }
+        if let Some(from) = filter.from {
+            query = query.filter(audit_dsl::created_at.ge(from));
+        }
+        if let Some(to) = filter.to {
+            query = query.filter(audit_dsl::created_at.lt(to));
+        }
+        if let Some(before) = filter.before {
+            query = query.filter(audit_dsl::id.lt(before));
+        }
+
+        let entries = query
+            .order(audit_dsl::id.desc())
+            .limit(limit)
+            .load(&mut db_conn)?;
+
+        Ok(entries)
+    }
+
+    // Walks the whole chain | Returns the id of the first entry which has been changed or
+    // does not link to the one before, None if the chain is intact
+    pub fn verify_audit_chain(&self) -> Result<Option<i64>, DatabaseError> {
+        const BATCH_SIZE: i64 = 1000;
+
+        let mut db_conn = self.db_pool.get()?;
+        let mut prev_hash = GENESIS_HASH.to_string();
+        let mut last_id = 0;
+
+        loop {
+            let batch: Vec<AuditEntry> = audit_dsl::audit_log
+                .filter(audit_dsl::id.gt(last_id))
+                .order(audit_dsl::id)
+                .limit(BATCH_SIZE)
+                .load(&mut db_conn)?;
+
+            for entry in &batch {
+                if entry.prev_hash != prev_hash || !entry.hash_matches() {
+                    return Ok(Some(entry.id));
+                }
+                prev_hash = entry.hash.clone();
+                last_id = entry.id;
+            }
+
+            if (batch.len() as i64) < BATCH_SIZE {
+                return Ok(None);
+            }
+        }
+    }
+
+    // API tokens
+
+    pub fn create_api_token(&self, new_token: &NewApiToken) -> Result<ApiToken, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let token = diesel::insert_into(token_dsl::api_tokens)
+            .values(new_token)
+            .get_result(&mut db_conn)?;
+
+        Ok(token)
+    }
+
+    // Tokens which have not been revoked, newest first | Expired ones are included
+    pub fn get_api_tokens(&self, user_id: i32) -> Result<Vec<ApiToken>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let tokens = token_dsl::api_tokens
+            .filter(token_dsl::user_id.eq(user_id))
+            .filter(token_dsl::revoked_at.is_null())
+            .order(token_dsl::created_at.desc())
+            .load(&mut db_conn)?;
+
+        Ok(tokens)
+    }
+
+    pub fn get_api_token_by_prefix(&self, prefix: &str) -> Result<Option<ApiToken>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let token = token_dsl::api_tokens
+            .filter(token_dsl::prefix.eq(prefix))
+            .first(&mut db_conn)
+            .optional()?;
+
+        Ok(token)
+    }
+
+    // Returns None if the user has no such token or it has been revoked already
+    pub fn revoke_api_token(
+        &self,
+        user_id: i32,
+        token_id: i32,
+    ) -> Result<Option<ApiToken>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let token = diesel::update(
+            token_dsl::api_tokens
+                .filter(token_dsl::id.eq(token_id))
+                .filter(token_dsl::user_id.eq(user_id))
+                .filter(token_dsl::revoked_at.is_null()),
+        )
+        .set(token_dsl::revoked_at.eq(diesel::dsl::now))
+        .get_result(&mut db_conn)
+        .optional()?;
+
+        Ok(token)
+    }
+
+    pub fn touch_api_token(&self, token_id: i32, ip: &str) -> Result<(), DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        diesel::update(token_dsl::api_tokens.find(token_id))
+            .set((
+                token_dsl::last_used_at.eq(diesel::dsl::now),
+                token_dsl::last_used_ip.eq(ip),
+            ))
+            .execute(&mut db_conn)?;
 
         Ok(())
     }
 
-    // Retrieves a user by their email
+    // Refresh tokens
+
+    pub fn create_refresh_token(
+        &self,
+        new_token: &NewRefreshToken,
+    ) -> Result<RefreshToken, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let token = diesel::insert_into(refresh_dsl::refresh_tokens)
+            .values(new_token)
+            .returning(RefreshToken::as_returning())
+            .get_result(&mut db_conn)?;
+
+        Ok(token)
+    }
+
+    // Exchanges the token for a new one of the same family | The row is locked, so a token
+    // presented twice at the same time is only rotated once and the other request sees the reuse
+    pub fn rotate_refresh_token(
+        &self,
+        token_hash: &str,
+        new_token_hash: String,
+        expires_at: NaiveDateTime,
+    ) -> Result<Rotation, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let rotation = db_conn.transaction::<Rotation, diesel::result::Error, _>(|conn| {
+            let now = chrono::Utc::now().naive_utc();
+            let token = match refresh_dsl::refresh_tokens
+                .filter(refresh_dsl::token_hash.eq(token_hash))
+                .select(RefreshToken::as_select())
+                .for_update()
+                .first(conn)
+                .optional()?
+            {
+                Some(token) => token,
+                None => return Ok(Rotation::Invalid),
+            };
+
+            if token.revoked_at.is_some() || token.expires_at <= now {
+                return Ok(Rotation::Invalid);
+            }
+
+            if token.used_at.is_some() {
+                diesel::update(
+                    refresh_dsl::refresh_tokens
+                        .filter(refresh_dsl::family_id.eq(token.family_id))
+                        .filter(refresh_dsl::revoked_at.is_null()),
+                )
+                .set(refresh_dsl::revoked_at.eq(now))
+                .execute(conn)?;
+                return Ok(Rotation::Reused(token));
+            }
+
+            diesel::update(refresh_dsl::refresh_tokens.find(token.id))
+                .set(refresh_dsl::used_at.eq(now))
+                .execute(conn)?;
+
+            let replacement = diesel::insert_into(refresh_dsl::refresh_tokens)
+                .values(&NewRefreshToken {
+                    user_id: token.user_id,
+                    family_id: token.family_id,
+                    token_hash: new_token_hash,
+                    expires_at,
+                })
+                .returning(RefreshToken::as_returning())
+                .get_result(conn)?;
+            Ok(Rotation::Rotated(replacement))
+        })?;
+
+        Ok(rotation)
+    }
+
+    // Revokes every refresh token of the user, e.g. after the password changed
+    pub fn revoke_refresh_tokens(&self, user_id: i32) -> Result<usize, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let revoked = diesel::update(
+            refresh_dsl::refresh_tokens
+                .filter(refresh_dsl::user_id.eq(user_id))
+                .filter(refresh_dsl::revoked_at.is_null()),
+        )
+        .set(refresh_dsl::revoked_at.eq(diesel::dsl::now))
+        .execute(&mut db_conn)?;
+
+        Ok(revoked)
+    }
+
+    // Revokes the family of the token | Returns None for unknown tokens
+    pub fn revoke_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<RefreshToken>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let token = refresh_dsl::refresh_tokens
+            .filter(refresh_dsl::token_hash.eq(token_hash))
+            .select(RefreshToken::as_select())
+            .first(&mut db_conn)
+            .optional()?;
+
+        if let Some(token) = &token {
+            diesel::update(
+                refresh_dsl::refresh_tokens
+                    .filter(refresh_dsl::family_id.eq(token.family_id))
+                    .filter(refresh_dsl::revoked_at.is_null()),
+            )
+            .set(refresh_dsl::revoked_at.eq(diesel::dsl::now))
+            .execute(&mut db_conn)?;
+        }
+
+        Ok(token)
+    }
+
+    // External identities
+
+    pub fn get_identity(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<UserIdentity>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let identity = identity_dsl::user_identities
+            .filter(identity_dsl::provider.eq(provider))
+            .filter(identity_dsl::subject.eq(subject))
+            .first(&mut db_conn)
+            .optional()?;
+
+        Ok(identity)
+    }
+
+    pub fn get_identities(&self, user_id: i32) -> Result<Vec<UserIdentity>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let identities = identity_dsl::user_identities
+            .filter(identity_dsl::user_id.eq(user_id))
+            .order(identity_dsl::provider.asc())
+            .load(&mut db_conn)?;
+
+        Ok(identities)
+    }
+
+    // Fails with IdentityAlreadyLinked if the identity belongs to someone else or the user
+    // already has an identity at the provider
+    pub fn link_identity(
+        &self,
+        new_identity: &NewUserIdentity,
+    ) -> Result<UserIdentity, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let identity = diesel::insert_into(identity_dsl::user_identities)
+            .values(new_identity)
+            .get_result(&mut db_conn)
+            .map_err(identity_error)?;
+
+        Ok(identity)
+    }
+
+    // New account which can only log in through the identity at first
+    pub fn create_user_with_identity(
+        &self,
+        new_user: &NewUser,
+        provider: &str,
+        subject: &str,
+    ) -> Result<(User, UserIdentity), DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let created = db_conn.transaction::<_, DatabaseError, _>(|conn| {
+            let user: User = diesel::insert_into(user_dsl::users)
+                .values(new_user)
+                .get_result(conn)
+                .map_err(|err| match err {
+                    diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                        DatabaseError::UserAlreadyExists(
+                            "An account already exists with that mail".to_string(),
+                        )
+                    }
+                    err => err.into(),
+                })?;
+
+            let identity = diesel::insert_into(identity_dsl::user_identities)
+                .values(&NewUserIdentity {
+                    user_id: user.id,
+                    provider,
+                    subject,
+                    email: Some(&user.email),
+                })
+                .get_result(conn)
+                .map_err(identity_error)?;
+
+            Ok((user, identity))
+        })?;
+
+        Ok(created)
+    }
+
+    pub fn touch_identity(&self, identity_id: i32) -> Result<(), DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        diesel::update(identity_dsl::user_identities.find(identity_id))
+            .set(identity_dsl::last_login_at.eq(diesel::dsl::now))
+            .execute(&mut db_conn)?;
+
+        Ok(())
+    }
+
+    // Returns None if the user has no such identity | Fails with LastIdentity instead of removing
+    // the only way to log in of an account without password
+    pub fn unlink_identity(
+        &self,
+        user_id: i32,
+        identity_id: i32,
+    ) -> Result<Option<UserIdentity>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let identity = db_conn.transaction::<_, DatabaseError, _>(|conn| {
+            // Locks the user, so two last identities cannot be disconnected at the same time
+            let has_password: bool = user_dsl::users
+                .find(user_id)
+                .select(user_dsl::has_password)
+                .for_update()
+                .first(conn)?;
+
+            let identity: Option<UserIdentity> = diesel::delete(
+                identity_dsl::user_identities
+                    .filter(identity_dsl::id.eq(identity_id))
+                    .filter(identity_dsl::user_id.eq(user_id)),
+            )
+            .get_result(conn)
+            .optional()?;
+
+            let remaining: i64 = identity_dsl::user_identities
+                .filter(identity_dsl::user_id.eq(user_id))
+                .count()
+                .get_result(conn)?;
+            if identity.is_some() && !has_password && remaining == 0 {
+                return Err(DatabaseError::LastIdentity(
+                    "Set a password before you disconnect your last account".to_string(),
+                ));
+            }
+
+            Ok(identity)
+        })?;
+
+        Ok(identity)
+    }
+
+    // Organizations
+    // Organization a request is routed to by its subdomain or path
+    pub fn get_organization_by_slug(
+        &self,
+        slug: &str,
+    ) -> Result<Option<Organization>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let organization = organization_dsl::organizations
+            .filter(organization_dsl::slug.eq(slug))
+            .select(Organization::as_select())
+            .first(&mut db_conn)
+            .optional()?;
+
+        Ok(organization)
+    }
+
+    pub fn update_organization_branding(
+        &self,
+        scope: OrgScope,
+        branding: &OrganizationBranding,
+    ) -> Result<Organization, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let organization = diesel::update(
+            organization_dsl::organizations
+                .filter(organization_dsl::id.eq(scope.organization_id())),
+        )
+        .set(branding)
+        .returning(Organization::as_returning())
+        .get_result(&mut db_conn)?;
+
+        Ok(organization)
+    }
+
+    // OAuth clients
+
+    pub fn create_oauth_client(
+        &self,
+        new_client: &NewOAuthClient,
+    ) -> Result<OAuthClient, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let client = diesel::insert_into(client_dsl::oauth_clients)
+            .values(new_client)
+            .returning(OAuthClient::as_returning())
+            .get_result(&mut db_conn)?;
+
+        Ok(client)
+    }
+
+    // Revoked clients are included, newest first
+    pub fn get_oauth_clients(&self) -> Result<Vec<OAuthClient>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let clients = client_dsl::oauth_clients
+            .order(client_dsl::created_at.desc())
+            .select(OAuthClient::as_select())
+            .load(&mut db_conn)?;
+
+        Ok(clients)
+    }
+
+    // Looks up a client by the id it sends | Returns None for unknown and revoked clients
+    pub fn get_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClient>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let client = client_dsl::oauth_clients
+            .filter(client_dsl::client_id.eq(client_id))
+            .filter(client_dsl::revoked_at.is_null())
+            .select(OAuthClient::as_select())
+            .first(&mut db_conn)
+            .optional()?;
+
+        Ok(client)
+    }
+
+    // Returns None if the client does not exist or has been revoked already
+    pub fn revoke_oauth_client(&self, id: i32) -> Result<Option<OAuthClient>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let client = diesel::update(
+            client_dsl::oauth_clients
+                .find(id)
+                .filter(client_dsl::revoked_at.is_null()),
+        )
+        .set(client_dsl::revoked_at.eq(diesel::dsl::now))
+        .returning(OAuthClient::as_returning())
+        .get_result(&mut db_conn)
+        .optional()?;
+
+        Ok(client)
+    }
+
+    pub fn create_authorization_code(
+        &self,
+        new_code: &NewAuthorizationCode,
+    ) -> Result<(), DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        diesel::insert_into(code_dsl::oauth_authorization_codes)
+            .values(new_code)
+            .execute(&mut db_conn)?;
+
+        Ok(())
+    }
+
+    // Marks the code as used and returns it as it was before | The row is locked, so a code
+    // presented twice at the same time is only redeemed once and the other request sees used_at
+    pub fn redeem_authorization_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<AuthorizationCode>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let code = db_conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let code = code_dsl::oauth_authorization_codes
+                .filter(code_dsl::code_hash.eq(code_hash))
+                .select(AuthorizationCode::as_select())
+                .for_update()
+                .first(conn)
+                .optional()?;
+
+            if let Some(code) = code.as_ref().filter(|code| code.used_at.is_none()) {
+                diesel::update(code_dsl::oauth_authorization_codes.find(code.id))
+                    .set(code_dsl::used_at.eq(diesel::dsl::now))
+                    .execute(conn)?;
+            }
+            Ok(code)
+        })?;
+
+        Ok(code)
+    }
+
+    pub fn get_consent(
+        &self,
+        user_id: i32,
+        client_id: i32,
+    ) -> Result<Option<OAuthConsent>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let consent = consent_dsl::oauth_consents
+            .find((user_id, client_id))
+            .select(OAuthConsent::as_select())
+            .first(&mut db_conn)
+            .optional()?;
+
+        Ok(consent)
+    }
+
+    // Replaces the scopes of an earlier consent
+    pub fn save_consent(
+        &self,
+        user_id: i32,
+        client_id: i32,
+        scopes: &[String],
+    ) -> Result<(), DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        diesel::insert_into(consent_dsl::oauth_consents)
+            .values((
+                consent_dsl::user_id.eq(user_id),
+                consent_dsl::client_id.eq(client_id),
+                consent_dsl::scopes.eq(scopes),
+            ))
+            .on_conflict((consent_dsl::user_id, consent_dsl::client_id))
+            .do_update()
+            .set((
+                consent_dsl::scopes.eq(scopes),
+                consent_dsl::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(&mut db_conn)?;
+
+        Ok(())
+    }
+
+    // Applications the user has allowed to use their account | Revoked clients are left out
+    pub fn get_consents(
+        &self,
+        user_id: i32,
+    ) -> Result<Vec<(OAuthConsent, OAuthClient)>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let consents = consent_dsl::oauth_consents
+            .inner_join(client_dsl::oauth_clients)
+            .filter(consent_dsl::user_id.eq(user_id))
+            .filter(client_dsl::revoked_at.is_null())
+            .order(consent_dsl::updated_at.desc())
+            .select((OAuthConsent::as_select(), OAuthClient::as_select()))
+            .load(&mut db_conn)?;
+
+        Ok(consents)
+    }
+
+    // Codes which have not been exchanged yet are dropped with the consent
+    // Returns the client, None if the user has not allowed it anything
+    pub fn revoke_consent(
+        &self,
+        user_id: i32,
+        client_id: i32,
+    ) -> Result<Option<OAuthClient>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let client = db_conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(
+                code_dsl::oauth_authorization_codes
+                    .filter(code_dsl::user_id.eq(user_id))
+                    .filter(code_dsl::client_id.eq(client_id)),
+            )
+            .execute(conn)?;
+
+            let deleted = diesel::delete(consent_dsl::oauth_consents.find((user_id, client_id)))
+                .execute(conn)?;
+            if deleted == 0 {
+                return Ok(None);
+            }
+
+            client_dsl::oauth_clients
+                .find(client_id)
+                .select(OAuthClient::as_select())
+                .first(conn)
+                .optional()
+        })?;
+
+        Ok(client)
+    }
+
+    // Login history
+
+    // Returns true if a successful login comes from a device which has not logged in before
+    // The first login of an account is not reported, every device is new then
+    pub fn record_login_event(&self, event: &NewLoginEvent) -> Result<bool, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let new_device = db_conn.transaction::<bool, diesel::result::Error, _>(|conn| {
+            let successful = login_dsl::login_events
+                .filter(login_dsl::user_id.eq(event.user_id))
+                .filter(login_dsl::outcome.eq(LoginOutcome::Success));
+            let seen_before: bool = diesel::select(diesel::dsl::exists(
+                successful.filter(login_dsl::fingerprint.eq(&event.fingerprint)),
+            ))
+            .get_result(conn)?;
+            let logged_in_before: bool =
+                diesel::select(diesel::dsl::exists(successful)).get_result(conn)?;
+
+            diesel::insert_into(login_dsl::login_events)
+                .values(event)
+                .execute(conn)?;
+
+            Ok(event.outcome == LoginOutcome::Success && logged_in_before && !seen_before)
+        })?;
+
+        Ok(new_device)
+    }
+
+    // Newest first
+    pub fn get_login_events(
+        &self,
+        user_id: i32,
+        limit: i64,
+    ) -> Result<Vec<LoginEvent>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let events = login_dsl::login_events
+            .filter(login_dsl::user_id.eq(user_id))
+            .order(login_dsl::created_at.desc())
+            .limit(limit)
+            .load(&mut db_conn)?;
+
+        Ok(events)
+    }
+
+    // One time tokens
+    // Stores a value under the hash of a token sent by mail, e.g. an email change
+    pub fn store_token<T: Serialize>(
+        &self,
+        purpose: &str,
+        token_hash: &str,
+        value: &T,
+        ttl_seconds: usize,
+    ) -> Result<(), DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        let value_serialized = serde_json::to_string(value)?;
+        cache_conn.set_ex::<_, _, ()>(
+            format!("{}:{}", purpose, token_hash),
+            value_serialized,
+            ttl_seconds,
+        )?;
+
+        Ok(())
+    }
+
+    // Returns and removes the value so every link works only once | GETDEL is atomic, so of
+    // concurrent requests with the same link only one gets the value
+    pub fn take_token<T: DeserializeOwned>(
+        &self,
+        purpose: &str,
+        token_hash: &str,
+    ) -> Result<Option<T>, DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        let value = r2d2_redis::redis::cmd("GETDEL")
+            .arg(format!("{}:{}", purpose, token_hash))
+            .query::<Option<String>>(&mut *cache_conn)?;
+
+        match value {
+            Some(value_serialized) => Ok(Some(serde_json::from_str(&value_serialized)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Returns the value and keeps it, e.g. to check it before the token is used up
+    pub fn peek_token<T: DeserializeOwned>(
+        &self,
+        purpose: &str,
+        token_hash: &str,
+    ) -> Result<Option<T>, DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+
+        match cache_conn.get::<_, Option<String>>(format!("{}:{}", purpose, token_hash))? {
+            Some(value_serialized) => Ok(Some(serde_json::from_str(&value_serialized)?)),
+            None => Ok(None),
+        }
+    }
+
+    // True for the first call with the key in ttl_seconds | Limits how often mails are sent
+    pub fn claim_cooldown(&self, key: &str, ttl_seconds: usize) -> Result<bool, DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        let claimed = r2d2_redis::redis::cmd("SET")
+            .arg(format!("cooldown:{}", key))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl_seconds)
+            .query::<Option<String>>(&mut *cache_conn)?;
+
+        Ok(claimed.is_some())
+    }
+
+    // Sessions
+    // Every user has one hash of session id -> SessionInfo, which expires with the last session
+    pub fn save_session(
+        &self,
+        user_id: i32,
+        info: &SessionInfo,
+        ttl_seconds: i64,
+    ) -> Result<(), DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        let cache_key = format!("user_sessions:{}", user_id);
+        let info_serialized = serde_json::to_string(info)?;
+
+        cache_conn.hset::<_, _, _, ()>(&cache_key, &info.id, info_serialized)?;
+        cache_conn.expire::<_, ()>(&cache_key, ttl_seconds as usize)?;
+
+        Ok(())
+    }
+
+    pub fn get_session(
+        &self,
+        user_id: i32,
+        session_id: &str,
+    ) -> Result<Option<SessionInfo>, DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        let info = cache_conn
+            .hget::<_, _, Option<String>>(format!("user_sessions:{}", user_id), session_id)?;
+
+        match info {
+            Some(info_serialized) => Ok(Some(serde_json::from_str(&info_serialized)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_sessions(&self, user_id: i32) -> Result<Vec<SessionInfo>, DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        let sessions = cache_conn
+            .hgetall::<_, HashMap<String, String>>(format!("user_sessions:{}", user_id))?;
+
+        sessions
+            .values()
+            .map(|info_serialized| Ok(serde_json::from_str(info_serialized)?))
+            .collect()
+    }
+
+    pub fn remove_session(&self, user_id: i32, session_id: &str) -> Result<(), DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        cache_conn.hdel::<_, _, ()>(format!("user_sessions:{}", user_id), session_id)?;
+
+        Ok(())
+    }
+
+    // Signs out every session of the user, except the given one if any
+    pub fn remove_sessions(&self, user_id: i32, except: Option<&str>) -> Result<(), DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        let cache_key = format!("user_sessions:{}", user_id);
+
+        match except {
+            Some(session_id) => {
+                let session_ids = cache_conn.hkeys::<_, Vec<String>>(&cache_key)?;
+                let others: Vec<&String> = session_ids
+                    .iter()
+                    .filter(|other| *other != session_id)
+                    .collect();
+                if !others.is_empty() {
+                    cache_conn.hdel::<_, _, ()>(&cache_key, others)?;
+                }
+            }
+            None => cache_conn.del::<_, ()>(&cache_key)?,
+        }
+
+        Ok(())
+    }
+
+    // Removes every cache entry of the user | Call after each change to the users row
+    fn invalidate_user(&self, user: &User) -> Result<(), DatabaseError> {
+        let mut cache_conn = self.cache_pool.get()?;
+        cache_conn.del::<_, ()>(&[
+            format!("user:{}", user.id),
+            format!("user_email:{}", user.email),
+        ])?;
+
+        Ok(())
+    }
+
+    // Retrieves a user by their email | Case-insensitive, the email is normalized first
     pub fn get_user_by_email(&self, user_email: &str) -> Result<User, DatabaseError> {
+        let user_email = normalize_email(user_email);
         let mut cache_conn = self.cache_pool.get()?;
         let cache_key = format!("user_email:{}", user_email);
 
         // Try to retrieve the user from the cache
-        if let Some(user_serialized) = cache_conn.get::<_, Option<String>>(&cache_key)? {
-            // Deserialize and return if found in cache
-            let user: User = serde_json::from_str(&user_serialized)?;
+        if let Some(user) = cached_user(&mut cache_conn, &cache_key)? {
             return Ok(user);
         }
 
         // If not found in the cache, fetch from the database
         let mut db_conn = self.db_pool.get()?;
         let user = user_dsl::users
-            .filter(user_dsl::email.eq(user_email))
+            .filter(lower(user_dsl::email).eq(&user_email))
             .first(&mut db_conn)?;
 
         // Serialize and cache the user
         let user_serialized = serde_json::to_string(&user)?;
-        cache_conn.set_ex(cache_key, user_serialized, 3600)?;
+        cache_conn.set_ex::<_, _, ()>(cache_key, user_serialized, 3600)?;
 
         Ok(user)
     }
 }
+
+// Entries written before a change to User cannot be deserialized | Those count as a miss
+fn cached_user(
+    cache_conn: &mut RedisConnection,
+    cache_key: &str,
+) -> Result<Option<User>, DatabaseError> {
+    match cache_conn.get::<_, Option<String>>(cache_key)? {
+        Some(user_serialized) => match serde_json::from_str(&user_serialized) {
+            Ok(user) => Ok(Some(user)),
+            Err(err) => {
+                warn!("Ignoring outdated cache entry {}: {}", cache_key, err);
+                Ok(None)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+// LIKE pattern matching values which contain the search term
+fn contains_pattern(search: &str) -> String {
+    let escaped = search
+        .trim()
+        .to_lowercase()
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+// Unique violations of user_identities
+fn identity_error(err: diesel::result::Error) -> DatabaseError {
+    match err {
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            DatabaseError::IdentityAlreadyLinked(
+                "The account at this provider is already linked".to_string(),
+            )
+        }
+        err => err.into(),
+    }
+}
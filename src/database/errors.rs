@@ -12,6 +12,13 @@ pub enum DatabaseError {
     DeserializationError(SerdeError),
     Argon2Error(argon2::password_hash::Error),
     UserAlreadyExists(String),
+    InvalidStatusChange(String),
+    IdentityAlreadyLinked(String),
+    SlugTaken(String),
+    // The change would leave an organization without owner
+    LastOwner(String),
+    // The account would have neither a password nor an identity to log in with
+    LastIdentity(String),
 }
 
 impl From<diesel::result::Error> for DatabaseError {
@@ -72,6 +79,21 @@ impl fmt::Display for DatabaseError {
             DatabaseError::UserAlreadyExists(msg) => {
                 write!(f, "User already exists: {}", msg)
             }
+            DatabaseError::InvalidStatusChange(msg) => {
+                write!(f, "Invalid status change: {}", msg)
+            }
+            DatabaseError::IdentityAlreadyLinked(msg) => {
+                write!(f, "Identity already linked: {}", msg)
+            }
+            DatabaseError::SlugTaken(msg) => {
+                write!(f, "Slug taken: {}", msg)
+            }
+            DatabaseError::LastOwner(msg) => {
+                write!(f, "Last owner: {}", msg)
+            }
+            DatabaseError::LastIdentity(msg) => {
+                write!(f, "Last identity: {}", msg)
+            }
         }
     }
 }
@@ -0,0 +1,558 @@
+use chrono::NaiveDateTime;
+use diesel::dsl::{Eq, Filter, SqlTypeOf};
+use diesel::expression::AsExpression;
+use diesel::prelude::*;
+use diesel::query_dsl::methods;
+use diesel::result::DatabaseErrorKind;
+use diesel::sql_types::SqlType;
+use serde::Serialize;
+
+use super::db::Database;
+use super::errors::DatabaseError;
+use crate::models::organizations::{NewOrganization, OrgRole, Organization};
+use crate::models::users::{NewUser, User};
+use crate::schema::organizations::dsl as organization_dsl;
+use crate::schema::users::dsl as user_dsl;
+use tables::invitations::dsl as invitation_dsl;
+use tables::memberships::dsl as membership_dsl;
+
+// Tables whose rows belong to organizations | Left out of schema.rs and private to this module,
+// so every query on them is one of the ones below | Those take an OrgScope, or are keyed on
+// what the user proves, e.g. the token of an invitation sent to their email
+mod tables {
+    use crate::schema::{organizations, users};
+
+    diesel::table! {
+        invitations (id) {
+            id -> Int4,
+            token_hash -> Varchar,
+            email -> Varchar,
+            invited_by -> Nullable<Int4>,
+            created_at -> Timestamp,
+            expires_at -> Timestamp,
+            accepted_at -> Nullable<Timestamp>,
+            revoked_at -> Nullable<Timestamp>,
+            organization_id -> Nullable<Int4>,
+            organization_role -> Nullable<Varchar>,
+        }
+    }
+
+    diesel::table! {
+        memberships (organization_id, user_id) {
+            organization_id -> Int4,
+            user_id -> Int4,
+            role -> Varchar,
+            created_at -> Timestamp,
+        }
+    }
+
+    diesel::joinable!(invitations -> organizations (organization_id));
+    diesel::joinable!(invitations -> users (invited_by));
+    diesel::joinable!(memberships -> organizations (organization_id));
+    diesel::joinable!(memberships -> users (user_id));
+
+    // Pairs with the tables of schema.rs only, those are already allowed among each other
+    diesel::allow_tables_to_appear_in_same_query!(invitations, memberships, organizations);
+    diesel::allow_tables_to_appear_in_same_query!(invitations, users);
+    diesel::allow_tables_to_appear_in_same_query!(memberships, users);
+}
+
+// Organization whose data a query may touch | Only created once the membership of the user has
+// been checked, e.g. by the ActiveOrganization extractor, so a scope always belongs to the user
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OrgScope(i32);
+
+impl OrgScope {
+    // Callers check the membership first
+    pub(crate) fn new(organization_id: i32) -> Self {
+        OrgScope(organization_id)
+    }
+
+    pub fn organization_id(&self) -> i32 {
+        self.0
+    }
+}
+
+// Table whose rows belong to one organization | Queries on these tables start from
+// TenantOwned::scoped instead of the table, so they cannot leave out the filter
+pub trait TenantOwned: Table + Sized {
+    type OrganizationId: Column<Table = Self> + ExpressionMethods + Default;
+
+    fn scoped(self, scope: OrgScope) -> Filter<Self, Eq<Self::OrganizationId, i32>>
+    where
+        SqlTypeOf<Self::OrganizationId>: SqlType,
+        i32: AsExpression<SqlTypeOf<Self::OrganizationId>>,
+        Self: methods::FilterDsl<Eq<Self::OrganizationId, i32>>,
+    {
+        methods::FilterDsl::filter(self, Self::OrganizationId::default().eq(scope.0))
+    }
+}
+
+impl TenantOwned for tables::memberships::table {
+    type OrganizationId = tables::memberships::organization_id;
+}
+
+// Only invitations to join an organization, registration invitations have no organization
+impl TenantOwned for tables::invitations::table {
+    type OrganizationId = tables::invitations::organization_id;
+}
+
+// The hash is only used to find the invitation, so it is not selected
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name = tables::invitations)]
+pub struct Invitation {
+    pub id: i32,
+    pub email: String,
+    // None once the inviting account has been removed
+    pub invited_by: Option<i32>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub accepted_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    // Set for invitations to join an organization, together with the role the member gets
+    pub organization_id: Option<i32>,
+    pub organization_role: Option<OrgRole>,
+}
+
+impl Invitation {
+    // Shown next to each invitation
+    pub fn state(&self, now: NaiveDateTime) -> &'static str {
+        match (self.accepted_at, self.revoked_at) {
+            (Some(_), _) => "accepted",
+            (_, Some(_)) => "revoked",
+            _ if self.expires_at <= now => "expired",
+            _ => "pending",
+        }
+    }
+}
+
+// The organization comes from the scope passed to Database::create_invitation
+#[derive(Insertable)]
+#[diesel(table_name = tables::invitations)]
+pub struct NewInvitation {
+    pub token_hash: String,
+    pub email: String,
+    pub invited_by: Option<i32>,
+    pub expires_at: NaiveDateTime,
+    pub organization_role: Option<OrgRole>,
+}
+
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name = tables::memberships)]
+pub struct Membership {
+    pub organization_id: i32,
+    pub user_id: i32,
+    pub role: OrgRole,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = tables::memberships)]
+struct NewMembership {
+    organization_id: i32,
+    user_id: i32,
+    role: OrgRole,
+}
+
+impl Database {
+    // Invitations
+    // Invitation to register, or to join the organization of the scope
+    pub fn create_invitation(
+        &self,
+        scope: Option<OrgScope>,
+        new_invitation: &NewInvitation,
+    ) -> Result<Invitation, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let organization_id = scope.map(|scope| scope.organization_id());
+        let invitation = diesel::insert_into(invitation_dsl::invitations)
+            .values((
+                new_invitation,
+                invitation_dsl::organization_id.eq(organization_id),
+            ))
+            .returning(Invitation::as_returning())
+            .get_result(&mut db_conn)?;
+
+        Ok(invitation)
+    }
+
+    // Looks up an invitation by the hash of its token | Returns None unless it can be accepted
+    pub fn get_invitation(&self, token_hash: &str) -> Result<Option<Invitation>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let invitation = pending_invitations()
+            .filter(invitation_dsl::token_hash.eq(token_hash))
+            .select(Invitation::as_select())
+            .first(&mut db_conn)
+            .optional()?;
+
+        Ok(invitation)
+    }
+
+    // Invitations with the email of the inviting user, newest first | Only those of one user
+    // if invited_by is set
+    pub fn get_invitations(
+        &self,
+        invited_by: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<(Invitation, Option<String>)>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let mut query = invitation_dsl::invitations
+            .left_join(user_dsl::users)
+            .order(invitation_dsl::created_at.desc())
+            .limit(limit)
+            .select((Invitation::as_select(), user_dsl::email.nullable()))
+            .into_boxed();
+        if let Some(user_id) = invited_by {
+            query = query.filter(invitation_dsl::invited_by.eq(user_id));
+        }
+
+        Ok(query.load(&mut db_conn)?)
+    }
+
+    pub fn count_pending_invitations(&self, invited_by: i32) -> Result<i64, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let count = pending_invitations()
+            .filter(invitation_dsl::invited_by.eq(invited_by))
+            .count()
+            .get_result(&mut db_conn)?;
+
+        Ok(count)
+    }
+
+    // Returns None if the invitation cannot be accepted anymore | Only the inviting user's
+    // invitations if invited_by is set
+    pub fn revoke_invitation(
+        &self,
+        invitation_id: i32,
+        invited_by: Option<i32>,
+    ) -> Result<Option<Invitation>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let mut query = diesel::update(invitation_dsl::invitations)
+            .filter(invitation_dsl::id.eq(invitation_id))
+            .filter(invitation_dsl::accepted_at.is_null())
+            .filter(invitation_dsl::revoked_at.is_null())
+            .into_boxed();
+        if let Some(user_id) = invited_by {
+            query = query.filter(invitation_dsl::invited_by.eq(user_id));
+        }
+        let invitation = query
+            .set(invitation_dsl::revoked_at.eq(diesel::dsl::now))
+            .returning(Invitation::as_returning())
+            .get_result(&mut db_conn)
+            .optional()?;
+
+        Ok(invitation)
+    }
+
+    // Creates the user and uses up the invitation in one transaction | Returns None if the
+    // invitation has been accepted, revoked or has expired in the meantime
+    pub fn create_user_with_invitation(
+        &self,
+        new_user: &NewUser,
+        token_hash: &str,
+    ) -> Result<Option<(User, Invitation)>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let created = db_conn.transaction::<_, DatabaseError, _>(|conn| {
+            // Claimed first, so concurrent registrations cannot both use it | Rolled back with
+            // the transaction if the user cannot be created
+            let invitation = diesel::update(invitation_dsl::invitations)
+                .filter(invitation_dsl::token_hash.eq(token_hash))
+                .filter(invitation_dsl::accepted_at.is_null())
+                .filter(invitation_dsl::revoked_at.is_null())
+                .filter(invitation_dsl::expires_at.gt(diesel::dsl::now))
+                .set(invitation_dsl::accepted_at.eq(diesel::dsl::now))
+                .returning(Invitation::as_returning())
+                .get_result(conn)
+                .optional()?;
+            let Some(invitation) = invitation else {
+                return Ok(None);
+            };
+
+            let user: User = diesel::insert_into(user_dsl::users)
+                .values(new_user)
+                .get_result(conn)
+                .map_err(|err| match err {
+                    diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                        DatabaseError::UserAlreadyExists(
+                            "An account already exists with that mail".to_string(),
+                        )
+                    }
+                    err => err.into(),
+                })?;
+
+            if let (Some(organization_id), Some(role)) =
+                (invitation.organization_id, invitation.organization_role)
+            {
+                diesel::insert_into(membership_dsl::memberships)
+                    .values(&NewMembership {
+                        organization_id,
+                        user_id: user.id,
+                        role,
+                    })
+                    .execute(conn)?;
+            }
+
+            Ok(Some((user, invitation)))
+        })?;
+
+        Ok(created)
+    }
+
+    // Organizations
+    // Creates the organization with the user as its owner
+    pub fn create_organization(
+        &self,
+        new_organization: &NewOrganization,
+        owner_id: i32,
+    ) -> Result<Organization, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let organization = db_conn.transaction::<_, DatabaseError, _>(|conn| {
+            let organization: Organization = diesel::insert_into(organization_dsl::organizations)
+                .values(new_organization)
+                .returning(Organization::as_returning())
+                .get_result(conn)
+                .map_err(|err| match err {
+                    diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                        DatabaseError::SlugTaken(
+                            "Another organization already uses this address".to_string(),
+                        )
+                    }
+                    err => err.into(),
+                })?;
+
+            diesel::insert_into(membership_dsl::memberships)
+                .values(&NewMembership {
+                    organization_id: organization.id,
+                    user_id: owner_id,
+                    role: OrgRole::Owner,
+                })
+                .execute(conn)?;
+
+            Ok(organization)
+        })?;
+
+        Ok(organization)
+    }
+
+    // Organizations of the user with their role in each, ordered by name
+    pub fn get_memberships(
+        &self,
+        user_id: i32,
+    ) -> Result<Vec<(Organization, OrgRole)>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let memberships = membership_dsl::memberships
+            .inner_join(organization_dsl::organizations)
+            .filter(membership_dsl::user_id.eq(user_id))
+            .order((organization_dsl::name, organization_dsl::id))
+            .select((Organization::as_select(), membership_dsl::role))
+            .load(&mut db_conn)?;
+
+        Ok(memberships)
+    }
+
+    // The organization and the role of the user in it | None if the user is no member
+    pub fn get_membership(
+        &self,
+        organization_id: i32,
+        user_id: i32,
+    ) -> Result<Option<(Organization, OrgRole)>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let membership = membership_dsl::memberships
+            .inner_join(organization_dsl::organizations)
+            .filter(membership_dsl::organization_id.eq(organization_id))
+            .filter(membership_dsl::user_id.eq(user_id))
+            .select((Organization::as_select(), membership_dsl::role))
+            .first(&mut db_conn)
+            .optional()?;
+
+        Ok(membership)
+    }
+
+    // Members with their email, ordered by email
+    pub fn get_members(&self, scope: OrgScope) -> Result<Vec<(Membership, String)>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let members = membership_dsl::memberships
+            .scoped(scope)
+            .inner_join(user_dsl::users)
+            .order(user_dsl::email)
+            .select((Membership::as_select(), user_dsl::email))
+            .load(&mut db_conn)?;
+
+        Ok(members)
+    }
+
+    // Returns None if the user is no member | Fails with LastOwner instead of demoting the
+    // only owner
+    pub fn change_member_role(
+        &self,
+        scope: OrgScope,
+        user_id: i32,
+        role: OrgRole,
+    ) -> Result<Option<Membership>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let membership = db_conn.transaction::<_, DatabaseError, _>(|conn| {
+            if role != OrgRole::Owner {
+                ensure_other_owner(conn, scope, user_id)?;
+            }
+
+            let membership = diesel::update(
+                membership_dsl::memberships
+                    .scoped(scope)
+                    .filter(membership_dsl::user_id.eq(user_id)),
+            )
+            .set(membership_dsl::role.eq(role))
+            .returning(Membership::as_returning())
+            .get_result(conn)
+            .optional()?;
+
+            Ok(membership)
+        })?;
+
+        Ok(membership)
+    }
+
+    // Returns None if the user is no member | Fails with LastOwner instead of removing the
+    // only owner
+    pub fn remove_member(
+        &self,
+        scope: OrgScope,
+        user_id: i32,
+    ) -> Result<Option<Membership>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let membership = db_conn.transaction::<_, DatabaseError, _>(|conn| {
+            ensure_other_owner(conn, scope, user_id)?;
+
+            let membership = diesel::delete(
+                membership_dsl::memberships
+                    .scoped(scope)
+                    .filter(membership_dsl::user_id.eq(user_id)),
+            )
+            .returning(Membership::as_returning())
+            .get_result(conn)
+            .optional()?;
+
+            Ok(membership)
+        })?;
+
+        Ok(membership)
+    }
+
+    // Invitations to the organization, newest first
+    pub fn get_organization_invitations(
+        &self,
+        scope: OrgScope,
+        limit: i64,
+    ) -> Result<Vec<Invitation>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let invitations = invitation_dsl::invitations
+            .scoped(scope)
+            .order(invitation_dsl::created_at.desc())
+            .limit(limit)
+            .select(Invitation::as_select())
+            .load(&mut db_conn)?;
+
+        Ok(invitations)
+    }
+
+    // Returns None if the invitation cannot be accepted anymore
+    pub fn revoke_organization_invitation(
+        &self,
+        scope: OrgScope,
+        invitation_id: i32,
+    ) -> Result<Option<Invitation>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let invitation = diesel::update(
+            invitation_dsl::invitations
+                .scoped(scope)
+                .filter(invitation_dsl::id.eq(invitation_id))
+                .filter(invitation_dsl::accepted_at.is_null())
+                .filter(invitation_dsl::revoked_at.is_null()),
+        )
+        .set(invitation_dsl::revoked_at.eq(diesel::dsl::now))
+        .returning(Invitation::as_returning())
+        .get_result(&mut db_conn)
+        .optional()?;
+
+        Ok(invitation)
+    }
+
+    // Uses up the invitation and adds the user to its organization in one transaction
+    // Returns None unless the token belongs to an invitation to join an organization which was
+    // sent to the user's email and can still be accepted | Members already keep their role
+    pub fn accept_organization_invitation(
+        &self,
+        token_hash: &str,
+        email: &str,
+        user_id: i32,
+    ) -> Result<Option<Invitation>, DatabaseError> {
+        let mut db_conn = self.db_pool.get()?;
+        let invitation = db_conn.transaction::<_, DatabaseError, _>(|conn| {
+            let invitation = diesel::update(invitation_dsl::invitations)
+                .filter(invitation_dsl::token_hash.eq(token_hash))
+                .filter(invitation_dsl::email.eq(email))
+                .filter(invitation_dsl::organization_id.is_not_null())
+                .filter(invitation_dsl::accepted_at.is_null())
+                .filter(invitation_dsl::revoked_at.is_null())
+                .filter(invitation_dsl::expires_at.gt(diesel::dsl::now))
+                .set(invitation_dsl::accepted_at.eq(diesel::dsl::now))
+                .returning(Invitation::as_returning())
+                .get_result(conn)
+                .optional()?;
+            let Some(invitation) = invitation else {
+                return Ok(None);
+            };
+
+            if let (Some(organization_id), Some(role)) =
+                (invitation.organization_id, invitation.organization_role)
+            {
+                diesel::insert_into(membership_dsl::memberships)
+                    .values(&NewMembership {
+                        organization_id,
+                        user_id,
+                        role,
+                    })
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+            }
+
+            Ok(Some(invitation))
+        })?;
+
+        Ok(invitation)
+    }
+}
+
+// Invitations which can still be accepted
+fn pending_invitations() -> tables::invitations::BoxedQuery<'static, diesel::pg::Pg> {
+    invitation_dsl::invitations
+        .filter(invitation_dsl::accepted_at.is_null())
+        .filter(invitation_dsl::revoked_at.is_null())
+        .filter(invitation_dsl::expires_at.gt(diesel::dsl::now))
+        .into_boxed()
+}
+
+// Fails with LastOwner if the user is the only owner of the organization | Locks the
+// organization, so two owners cannot step down at the same time
+fn ensure_other_owner(
+    conn: &mut PgConnection,
+    scope: OrgScope,
+    user_id: i32,
+) -> Result<(), DatabaseError> {
+    organization_dsl::organizations
+        .filter(organization_dsl::id.eq(scope.organization_id()))
+        .select(organization_dsl::id)
+        .for_update()
+        .first::<i32>(conn)?;
+
+    let owners: Vec<i32> = membership_dsl::memberships
+        .scoped(scope)
+        .filter(membership_dsl::role.eq(OrgRole::Owner))
+        .select(membership_dsl::user_id)
+        .load(conn)?;
+    if owners == [user_id] {
+        return Err(DatabaseError::LastOwner(
+            "Every organization needs an owner, make someone else owner first".to_string(),
+        ));
+    }
+
+    Ok(())
+}
@@ -1,17 +1,33 @@
-use actix_files;
-use actix_session::{storage::RedisSessionStore, SessionMiddleware};
-use actix_web::{cookie::Key, middleware::Logger, web, App, HttpServer};
+use actix_session::storage::RedisSessionStore;
+use actix_web::{
+    middleware::{from_fn, Logger},
+    web, App, HttpServer,
+};
 use env_logger::Env;
+use log::{error, info};
+use std::path::Path;
 use std::sync::Arc;
-use tera::Tera;
 
 mod app;
 mod database;
 mod models;
 mod schema;
+mod services;
 mod utils;
 
 use crate::database::db::Database;
+use crate::utils::assets::Assets;
+use crate::utils::compression::{compress, CompressionConfig};
+use crate::utils::conditional::conditional_get;
+use crate::utils::i18n::{negotiate_locale, Catalogs};
+use crate::utils::jwt::JwtConfig;
+use crate::utils::mailer::Mailer;
+use crate::utils::oidc::OidcConfig;
+use crate::utils::password_policy::PasswordPolicy;
+use crate::utils::registration::RegistrationConfig;
+use crate::utils::sessions::{persist_session_cookie, track_session, SessionConfig};
+use crate::utils::templates::Templates;
+use crate::utils::tenants::{resolve_tenant, TenantConfig};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -32,48 +48,179 @@ async fn main() -> std::io::Result<()> {
         Err(_) => String::from("8000"), // Default port
     };
 
+    // Embedded files are read from the source tree in debug builds
+    #[cfg(feature = "embed-static")]
+    let static_file_path = utils::assets::embedded_dir().to_string();
+    #[cfg(not(feature = "embed-static"))]
     let static_file_path = match std::env::var("STATIC_PATH") {
         Ok(path) => path,
         Err(_) => String::from("./static"),
     };
 
+    // Comma separated list of encodings offered to clients, "off" disables compression
+    let compression = match std::env::var("COMPRESSION") {
+        Ok(encodings) => CompressionConfig::parse(&encodings).expect("Invalid COMPRESSION"),
+        Err(_) => CompressionConfig::default(), // brotli, zstd and gzip
+    };
+
     let garnet_path = match std::env::var("GARNET_URL") {
         Ok(path) => path,
         Err(_) => String::from("redis://127.0.0.1:6379"),
     };
 
+    #[cfg(not(feature = "embed-static"))]
     let css_path = format!("{}/css", static_file_path);
+    #[cfg(not(feature = "embed-static"))]
     let js_path = format!("{}/js", static_file_path);
 
-    let template_path = format!("{}/templates/**/*", static_file_path);
+    let template_dir = format!("{}/templates", static_file_path);
 
     let bind_address = format!("{}:{}", host, port);
 
-    // TODO: Undo this to use permanent solution
-    // let secret_key = Key::generate();
-    let secret_key = Key::derive_from(&[0; 32]);
+    // Cookie attributes, timeouts and the key sessions are encrypted with
+    let session_config = web::Data::new(SessionConfig::from_env().expect("Invalid session config"));
 
     let store = RedisSessionStore::new(garnet_path).await.unwrap();
 
     // Create new database pool | expect is ok since server cant run without db
     let database = Arc::new(Database::new().unwrap());
 
+    // Hash css and js once for cache busting urls
+    #[cfg(feature = "embed-static")]
+    let assets = Assets::embedded();
+    #[cfg(not(feature = "embed-static"))]
+    let assets = Assets::new(&static_file_path);
+    let assets = web::Data::new(assets.expect("Failed to load static assets"));
+
+    // Password requirements for new passwords
+    let password_policy =
+        web::Data::new(PasswordPolicy::from_env().expect("Invalid password policy"));
+
+    let registration =
+        web::Data::new(RegistrationConfig::from_env().expect("Invalid registration config"));
+
+    // Routing of requests to organizations by subdomain or path
+    let tenants = web::Data::new(TenantConfig::from_env().expect("Invalid tenant config"));
+
+    let mailer = web::Data::new(Mailer::from_env().expect("Invalid mail config"));
+
+    // Keys and lifetimes of the tokens issued to API clients
+    let jwt = web::Data::new(JwtConfig::from_env().expect("Invalid JWT config"));
+
+    // Providers users can log in with instead of a password
+    let oidc = web::Data::new(OidcConfig::from_env().expect("Invalid OIDC config"));
+
+    // Days deleted accounts are kept before they are removed for good
+    let deletion_grace_days = match std::env::var("ACCOUNT_DELETION_GRACE_DAYS") {
+        Ok(days) => days.parse().expect("Invalid ACCOUNT_DELETION_GRACE_DAYS"),
+        Err(_) => 30,
+    };
+    utils::jobs::spawn_account_purge(
+        database.clone(),
+        chrono::Duration::days(deletion_grace_days),
+    );
+
+    // Computed up front so the first login with an unknown email is not slower than the others
+    utils::argon2::dummy_hash();
+
+    // Parse templates once and share them between all workers
+    #[cfg(feature = "embed-static")]
+    let templates = Templates::embedded(assets.clone());
+    #[cfg(not(feature = "embed-static"))]
+    let templates = Templates::new(&format!("{}/**/*", template_dir), assets.clone());
+    let templates = web::Data::new(templates.expect("Failed to initialize Tera"));
+
+    // Translations of the interface, read once like the templates
+    #[cfg(feature = "embed-static")]
+    let catalogs = Catalogs::embedded();
+    #[cfg(not(feature = "embed-static"))]
+    let catalogs = Catalogs::new(&format!("{}/locales", static_file_path));
+    let catalogs = web::Data::new(catalogs.expect("Failed to load translations"));
+
+    // Refuse to start if a template used by the views cannot be found
+    let missing_templates = templates.missing_templates();
+    if !missing_templates.is_empty() {
+        error!(
+            "Missing templates in {}: {:?}",
+            template_dir, missing_templates
+        );
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Referenced templates are missing",
+        ));
+    }
+
+    // Reload templates on change in debug builds | Watcher has to stay alive until shutdown
+    let _template_watcher = if cfg!(debug_assertions) {
+        info!("Watching {} for template changes", template_dir);
+        Some(
+            Templates::watch(templates.clone(), Path::new(&template_dir))
+                .expect("Failed to watch templates"),
+        )
+    } else {
+        None
+    };
+
+    let _asset_watcher = if cfg!(debug_assertions) {
+        Some(
+            Assets::watch(assets.clone(), Path::new(&static_file_path))
+                .expect("Failed to watch assets"),
+        )
+    } else {
+        None
+    };
+
     // Create and start web server
     HttpServer::new(move || {
-        let tera = Tera::new(&template_path.to_owned()).expect("Failed to initialize Tera");
-
-        App::new()
-            // Include css and javascript for dashboard
-            .service(actix_files::Files::new("/css", css_path.clone()).show_files_listing())
-            .service(actix_files::Files::new("/js", js_path.clone()).show_files_listing())
+        // Include fingerprinted css and javascript
+        let app = App::new()
+            .app_data(assets.clone())
+            .configure(utils::assets::register_urls);
+
+        // Unversioned css and javascript | Directory listings only while developing
+        #[cfg(not(feature = "embed-static"))]
+        let app = {
+            let css_files = actix_files::Files::new("/css", css_path.clone());
+            let js_files = actix_files::Files::new("/js", js_path.clone());
+            #[cfg(debug_assertions)]
+            let (css_files, js_files) = (
+                css_files.show_files_listing(),
+                js_files.show_files_listing(),
+            );
+            app.service(css_files).service(js_files)
+        };
+
+        app
+            // ETags and 304 responses | Innermost since it needs the plain response body
+            .wrap(from_fn(conditional_get))
+            // Language of the request | Needs the session for the preference of the user
+            .wrap(from_fn(negotiate_locale))
+            // Signs out revoked sessions | Needs to run inside the session middleware
+            .wrap(from_fn(track_session))
             // Include logger
             .wrap(Logger::default())
             // Session middleware
-            .wrap(SessionMiddleware::new(store.clone(), secret_key.clone()))
+            .wrap(session_config.middleware(store.clone()))
+            // Persistent cookies for "remember me" | Needs to run outside the session middleware
+            .wrap(from_fn(persist_session_cookie))
+            // Organization of the request | Outside the session middleware since it renames
+            // the session cookie per organization
+            .wrap(from_fn(resolve_tenant))
+            // Compression
+            .wrap(from_fn(compress))
+            .app_data(web::Data::new(compression.clone()))
             // Database clone
             .app_data(web::Data::new(database.clone()))
             // Templating
-            .app_data(web::Data::new(tera))
+            .app_data(templates.clone())
+            .app_data(password_policy.clone())
+            .app_data(session_config.clone())
+            .app_data(registration.clone())
+            .app_data(tenants.clone())
+            .app_data(catalogs.clone())
+            .app_data(mailer.clone())
+            .app_data(jwt.clone())
+            .app_data(oidc.clone())
             // Routing
             .configure(app::register_urls)
     })
@@ -0,0 +1,136 @@
+use chrono::{NaiveDateTime, SubsecRound};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::schema::audit_log;
+
+// prev_hash of the first entry
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Queryable, Serialize, Debug)]
+#[diesel(table_name = audit_log)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub created_at: NaiveDateTime,
+    pub actor_id: Option<i32>,
+    pub actor_email: Option<String>,
+    pub ip: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub metadata: serde_json::Value,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    // False if the entry has been changed since it was written
+    pub fn hash_matches(&self) -> bool {
+        let expected = chain_hash(
+            &self.prev_hash,
+            self.created_at,
+            self.actor_id,
+            &self.actor_email,
+            &self.ip,
+            &self.action,
+            &self.target,
+            &self.metadata,
+        );
+        expected == self.hash
+    }
+}
+
+// Entry before it has been linked to the chain
+pub struct AuditRecord {
+    pub actor_id: Option<i32>,
+    pub actor_email: Option<String>,
+    pub ip: Option<String>,
+    pub action: &'static str,
+    pub target: Option<String>,
+    pub metadata: serde_json::Value,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = audit_log)]
+pub struct NewAuditEntry<'a> {
+    pub created_at: NaiveDateTime,
+    pub actor_id: Option<i32>,
+    pub actor_email: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub action: &'a str,
+    pub target: Option<&'a str>,
+    pub metadata: &'a serde_json::Value,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl<'a> NewAuditEntry<'a> {
+    // Links the record to the entry before it
+    pub fn chain(record: &'a AuditRecord, prev_hash: String, now: NaiveDateTime) -> Self {
+        // Postgres keeps microseconds, the hash has to match what is read back
+        let created_at = now.trunc_subsecs(6);
+        let hash = chain_hash(
+            &prev_hash,
+            created_at,
+            record.actor_id,
+            &record.actor_email,
+            &record.ip,
+            record.action,
+            &record.target,
+            &record.metadata,
+        );
+
+        NewAuditEntry {
+            created_at,
+            actor_id: record.actor_id,
+            actor_email: record.actor_email.as_deref(),
+            ip: record.ip.as_deref(),
+            action: record.action,
+            target: record.target.as_deref(),
+            metadata: &record.metadata,
+            prev_hash,
+            hash,
+        }
+    }
+}
+
+// blake3 of the previous hash and the fields as a JSON array | Object keys of metadata are
+// sorted by serde_json, so the same entry always serializes to the same bytes
+fn chain_hash(
+    prev_hash: &str,
+    created_at: NaiveDateTime,
+    actor_id: Option<i32>,
+    actor_email: &Option<String>,
+    ip: &Option<String>,
+    action: &str,
+    target: &Option<String>,
+    metadata: &serde_json::Value,
+) -> String {
+    let fields = serde_json::json!([
+        created_at.format("%Y-%m-%dT%H:%M:%S%.6f").to_string(),
+        actor_id,
+        actor_email,
+        ip,
+        action,
+        target,
+        metadata,
+    ]);
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(prev_hash.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(fields.to_string().as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+// Filters of the audit log view | None matches everything
+#[derive(Default)]
+pub struct AuditFilter {
+    pub action: Option<String>,
+    // Part of the email of the actor
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    // Only entries older than this id, for paging
+    pub before: Option<i64>,
+}
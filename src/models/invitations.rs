@@ -0,0 +1,2 @@
+// Declared next to their table, which only the queries in database::tenant can use
+pub use crate::database::tenant::{Invitation, NewInvitation};
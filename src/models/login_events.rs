@@ -0,0 +1,148 @@
+use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::prelude::*;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
+use serde::Serialize;
+
+use crate::schema::login_events;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginOutcome {
+    Success,
+    WrongPassword,
+    // Correct password, but the status of the account does not allow logging in
+    Blocked,
+}
+
+impl LoginOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginOutcome::Success => "success",
+            LoginOutcome::WrongPassword => "wrong_password",
+            LoginOutcome::Blocked => "blocked",
+        }
+    }
+
+    // Shown in the login history on the settings page
+    pub fn description(&self) -> &'static str {
+        match self {
+            LoginOutcome::Success => "Signed in",
+            LoginOutcome::WrongPassword => "Wrong password",
+            LoginOutcome::Blocked => "Refused, account not active",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for LoginOutcome {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), &mut out.reborrow())
+    }
+}
+
+impl FromSql<Text, Pg> for LoginOutcome {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <String as FromSql<Text, Pg>>::from_sql(bytes)?.as_str() {
+            "success" => Ok(LoginOutcome::Success),
+            "wrong_password" => Ok(LoginOutcome::WrongPassword),
+            "blocked" => Ok(LoginOutcome::Blocked),
+            outcome => Err(format!("Unknown login outcome {}", outcome).into()),
+        }
+    }
+}
+
+// How the user proved who they are
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginMethod {
+    Password,
+    // Account at an OpenID Connect provider
+    Oidc,
+    // Link sent by mail
+    MagicLink,
+}
+
+impl LoginMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginMethod::Password => "password",
+            LoginMethod::Oidc => "oidc",
+            LoginMethod::MagicLink => "magic_link",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for LoginMethod {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), &mut out.reborrow())
+    }
+}
+
+impl FromSql<Text, Pg> for LoginMethod {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <String as FromSql<Text, Pg>>::from_sql(bytes)?.as_str() {
+            "password" => Ok(LoginMethod::Password),
+            "oidc" => Ok(LoginMethod::Oidc),
+            "magic_link" => Ok(LoginMethod::MagicLink),
+            method => Err(format!("Unknown login method {}", method).into()),
+        }
+    }
+}
+
+#[derive(Queryable, Serialize, Debug)]
+#[diesel(table_name = login_events)]
+pub struct LoginEvent {
+    pub id: i32,
+    pub user_id: i32,
+    pub created_at: NaiveDateTime,
+    pub ip: String,
+    pub user_agent: String,
+    pub outcome: LoginOutcome,
+    pub method: LoginMethod,
+    pub fingerprint: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = login_events)]
+pub struct NewLoginEvent {
+    pub user_id: i32,
+    pub ip: String,
+    pub user_agent: String,
+    pub outcome: LoginOutcome,
+    pub method: LoginMethod,
+    pub fingerprint: String,
+}
+
+impl NewLoginEvent {
+    pub fn new(
+        user_id: i32,
+        ip: String,
+        user_agent: String,
+        outcome: LoginOutcome,
+        method: LoginMethod,
+    ) -> Self {
+        let fingerprint = device_fingerprint(&ip, &user_agent);
+        NewLoginEvent {
+            user_id,
+            ip,
+            user_agent,
+            outcome,
+            method,
+            fingerprint,
+        }
+    }
+}
+
+// Same browser on the same network | A new ip or browser counts as a new device
+pub fn device_fingerprint(ip: &str, user_agent: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(ip.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(user_agent.as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
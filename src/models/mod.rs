@@ -1 +1,10 @@
+pub mod api_tokens;
+pub mod audit;
+pub mod identities;
+pub mod invitations;
+pub mod login_events;
+pub mod oauth;
+pub mod organizations;
+pub mod refresh_tokens;
+pub mod sessions;
 pub mod users;
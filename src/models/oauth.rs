@@ -0,0 +1,88 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::schema::{oauth_authorization_codes, oauth_clients, oauth_consents};
+
+// Scopes applications can ask for, with the description shown on the consent page
+pub const OAUTH_SCOPES: &[(&str, &str)] = &[
+    (SCOPE_OPENID, "Confirm that it is you"),
+    (
+        SCOPE_PROFILE,
+        "See your name, picture, timezone and language",
+    ),
+    (SCOPE_EMAIL, "See your email address"),
+];
+
+pub const SCOPE_OPENID: &str = "openid";
+pub const SCOPE_PROFILE: &str = "profile";
+pub const SCOPE_EMAIL: &str = "email";
+
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name = oauth_clients)]
+pub struct OAuthClient {
+    pub id: i32,
+    pub client_id: String,
+    #[serde(skip)]
+    pub secret_hash: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl OAuthClient {
+    // Public clients cannot keep a secret, PKCE is their only proof
+    pub fn is_public(&self) -> bool {
+        self.secret_hash.is_none()
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = oauth_clients)]
+pub struct NewOAuthClient {
+    pub client_id: String,
+    pub secret_hash: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub created_by: Option<i32>,
+}
+
+// The hash is only used to find the code, so it is not selected
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = oauth_authorization_codes)]
+pub struct AuthorizationCode {
+    pub id: i32,
+    // Id of the row in oauth_clients, not the public client id
+    pub client_id: i32,
+    pub user_id: i32,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub auth_time: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    // Set once the code has been exchanged for tokens
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = oauth_authorization_codes)]
+pub struct NewAuthorizationCode {
+    pub code_hash: String,
+    pub client_id: i32,
+    pub user_id: i32,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub auth_time: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = oauth_consents)]
+pub struct OAuthConsent {
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+}
@@ -0,0 +1,101 @@
+use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::prelude::*;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
+use serde::{Deserialize, Serialize};
+
+pub use crate::database::tenant::Membership;
+use crate::schema::organizations;
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(table_name = organizations)]
+pub struct Organization {
+    pub id: i32,
+    pub name: String,
+    pub slug: String,
+    pub created_at: NaiveDateTime,
+    // Replace the name and colors of the app on pages of the organization | #rrggbb
+    pub logo_url: Option<String>,
+    pub primary_color: Option<String>,
+    pub accent_color: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = organizations)]
+pub struct NewOrganization {
+    pub name: String,
+    pub slug: String,
+}
+
+// Fields left empty fall back to the defaults of the app
+#[derive(AsChangeset)]
+#[diesel(table_name = organizations, treat_none_as_null = true)]
+pub struct OrganizationBranding {
+    pub logo_url: Option<String>,
+    pub primary_color: Option<String>,
+    pub accent_color: Option<String>,
+}
+
+// Role of a user in one organization | Unrelated to the role of the account
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    // Can do everything admins can and manage other owners | Every organization keeps one
+    Owner,
+    // Can invite and remove members
+    Admin,
+    Member,
+}
+
+pub const ORG_ROLES: [OrgRole; 3] = [OrgRole::Owner, OrgRole::Admin, OrgRole::Member];
+
+impl OrgRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrgRole::Owner => "owner",
+            OrgRole::Admin => "admin",
+            OrgRole::Member => "member",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        ORG_ROLES
+            .into_iter()
+            .find(|candidate| candidate.as_str() == role)
+    }
+
+    pub fn can_manage_members(&self) -> bool {
+        matches!(self, OrgRole::Owner | OrgRole::Admin)
+    }
+
+    // Logo and colors shown on the pages of the organization
+    pub fn can_change_branding(&self) -> bool {
+        matches!(self, OrgRole::Owner | OrgRole::Admin)
+    }
+
+    // Admins cannot hand out or take away ownership
+    pub fn can_assign(&self, role: OrgRole) -> bool {
+        match self {
+            OrgRole::Owner => true,
+            OrgRole::Admin => role != OrgRole::Owner,
+            OrgRole::Member => false,
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for OrgRole {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), &mut out.reborrow())
+    }
+}
+
+impl FromSql<Text, Pg> for OrgRole {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let role = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        OrgRole::parse(&role).ok_or_else(|| format!("Unknown organization role {}", role).into())
+    }
+}
@@ -0,0 +1,37 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::schema::refresh_tokens;
+
+// The hash is only used to find the token, so it is not selected
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = refresh_tokens)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub family_id: Uuid,
+    pub expires_at: NaiveDateTime,
+    // Set once the token has been exchanged for a new one
+    pub used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = refresh_tokens)]
+pub struct NewRefreshToken {
+    pub user_id: i32,
+    pub family_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+}
+
+// What happened when a refresh token was exchanged
+pub enum Rotation {
+    // The token was valid | Holds the stored replacement
+    Rotated(RefreshToken),
+    // The token had been exchanged before, so it was stolen or replayed | Its family is revoked
+    Reused(RefreshToken),
+    // Unknown, expired or revoked
+    Invalid,
+}
@@ -0,0 +1,16 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+// Logged in session of a user, stored in the cache next to the session itself
+// The session only carries the id, so removing this entry signs it out
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub ip: String,
+    pub user_agent: String,
+    // Longer timeouts and a cookie which survives closing the browser
+    #[serde(default)]
+    pub remember_me: bool,
+}
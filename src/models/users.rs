@@ -1,8 +1,15 @@
+use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
 use diesel::prelude::*;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
 use serde::{Deserialize, Serialize};
 
-use crate::schema::users;
+use crate::schema::{user_status_changes, users};
 use crate::utils::argon2::hash_password;
+pub use crate::utils::email::normalize_email;
 
 // This corresponds to a row in your `users_table`.
 #[derive(Queryable, Serialize, Deserialize, Debug, Identifiable)]
@@ -11,6 +18,21 @@ pub struct User {
     pub id: i32,
     pub email: String,
     pub hashed_password: String,
+    pub display_name: Option<String>,
+    // IANA name, e.g. "Europe/Berlin"
+    pub timezone: String,
+    // None follows the language of the browser
+    pub locale: Option<String>,
+    pub avatar_url: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub status: AccountStatus,
+    pub status_changed_at: NaiveDateTime,
+    pub role: Role,
+    // False for accounts created through a login provider until a password is set
+    pub has_password: bool,
+    // Only set once the owner of the address proved it, the status does not say so
+    pub email_verified: bool,
 }
 
 // Since id is autogenerated by db we do not need to insert it
@@ -19,16 +41,186 @@ pub struct User {
 pub struct NewUser {
     pub email: String,
     pub hashed_password: String,
+    pub status: AccountStatus,
+    pub has_password: bool,
+    pub email_verified: bool,
 }
 
 impl NewUser {
-    pub fn new(email: &String, password: &String) -> Result<NewUser, argon2::password_hash::Error> {
+    pub fn new(email: &str, password: &str) -> Result<NewUser, argon2::password_hash::Error> {
         // Create hash of password
         let password_hash = hash_password(password)?;
 
         Ok(NewUser {
-            email: email.clone(),
+            email: normalize_email(email),
             hashed_password: password_hash.to_string(),
+            status: AccountStatus::Active,
+            has_password: true,
+            email_verified: false,
         })
     }
 }
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    Active,
+    // Registered but the email address has not been confirmed yet
+    Unverified,
+    // Temporarily blocked, e.g. while a compromise is investigated
+    Locked,
+    Disabled,
+    // Soft deleted | Purged for good after the grace period
+    Deleted,
+}
+
+pub const ACCOUNT_STATUSES: [AccountStatus; 5] = [
+    AccountStatus::Active,
+    AccountStatus::Unverified,
+    AccountStatus::Locked,
+    AccountStatus::Disabled,
+    AccountStatus::Deleted,
+];
+
+impl AccountStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Unverified => "unverified",
+            AccountStatus::Locked => "locked",
+            AccountStatus::Disabled => "disabled",
+            AccountStatus::Deleted => "deleted",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        ACCOUNT_STATUSES
+            .into_iter()
+            .find(|candidate| candidate.as_str() == status)
+    }
+
+    // Accounts only become unverified on registration | Deleted ones can be restored
+    pub fn can_change_to(&self, next: AccountStatus) -> bool {
+        match (self, next) {
+            (current, next) if *current == next => false,
+            (_, AccountStatus::Unverified) => false,
+            (AccountStatus::Unverified, AccountStatus::Locked) => false,
+            _ => true,
+        }
+    }
+
+    // Message shown when an account with this status tries to log in | None if it may
+    pub fn login_error(&self) -> Option<&'static str> {
+        match self {
+            AccountStatus::Active => None,
+            AccountStatus::Unverified => {
+                Some("Please confirm your email address first, we sent you a link")
+            }
+            AccountStatus::Locked => Some("Your account is locked, please contact support"),
+            AccountStatus::Disabled => Some("Your account has been disabled"),
+            AccountStatus::Deleted => Some("Your account has been deleted"),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for AccountStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), &mut out.reborrow())
+    }
+}
+
+impl FromSql<Text, Pg> for AccountStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let status = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        AccountStatus::parse(&status)
+            .ok_or_else(|| format!("Unknown account status {}", status).into())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    // Can manage other accounts on /admin
+    Admin,
+}
+
+pub const ROLES: [Role; 2] = [Role::User, Role::Admin];
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        ROLES
+            .into_iter()
+            .find(|candidate| candidate.as_str() == role)
+    }
+}
+
+impl ToSql<Text, Pg> for Role {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), &mut out.reborrow())
+    }
+}
+
+impl FromSql<Text, Pg> for Role {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let role = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Role::parse(&role).ok_or_else(|| format!("Unknown role {}", role).into())
+    }
+}
+
+// Entry of the status history of an account
+#[derive(Queryable, Serialize, Debug)]
+#[diesel(table_name = user_status_changes)]
+pub struct StatusChange {
+    pub id: i32,
+    pub user_id: i32,
+    pub old_status: AccountStatus,
+    pub new_status: AccountStatus,
+    pub changed_by: Option<i32>,
+    pub reason: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_status_changes)]
+pub struct NewStatusChange<'a> {
+    pub user_id: i32,
+    pub old_status: AccountStatus,
+    pub new_status: AccountStatus,
+    pub changed_by: Option<i32>,
+    pub reason: &'a str,
+}
+
+// Fields a user can change on the settings page | None clears the column
+#[derive(AsChangeset)]
+#[diesel(table_name = users, treat_none_as_null = true)]
+pub struct UserProfile {
+    pub display_name: Option<String>,
+    pub timezone: String,
+    pub locale: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+// Email change waiting for the new address to be confirmed, stored in the cache
+#[derive(Serialize, Deserialize)]
+pub struct EmailChange {
+    pub user_id: i32,
+    pub email: String,
+}
+
+// Login link sent by mail, stored in the cache | Only works in the browser whose cookie holds
+// the nonce
+#[derive(Serialize, Deserialize)]
+pub struct MagicLink {
+    pub user_id: i32,
+    pub nonce_hash: String,
+}
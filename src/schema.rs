@@ -1,9 +1,178 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        prefix -> Varchar,
+        token_hash -> Varchar,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        last_used_ip -> Nullable<Varchar>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    audit_log (id) {
+        id -> Int8,
+        created_at -> Timestamp,
+        actor_id -> Nullable<Int4>,
+        actor_email -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        action -> Varchar,
+        target -> Nullable<Varchar>,
+        metadata -> Jsonb,
+        prev_hash -> Varchar,
+        hash -> Varchar,
+    }
+}
+
+diesel::table! {
+    login_events (id) {
+        id -> Int4,
+        user_id -> Int4,
+        created_at -> Timestamp,
+        ip -> Varchar,
+        user_agent -> Varchar,
+        outcome -> Varchar,
+        method -> Varchar,
+        fingerprint -> Varchar,
+    }
+}
+
+diesel::table! {
+    oauth_authorization_codes (id) {
+        id -> Int4,
+        code_hash -> Varchar,
+        client_id -> Int4,
+        user_id -> Int4,
+        redirect_uri -> Varchar,
+        scopes -> Array<Text>,
+        nonce -> Nullable<Varchar>,
+        code_challenge -> Varchar,
+        auth_time -> Timestamp,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    oauth_clients (id) {
+        id -> Int4,
+        client_id -> Varchar,
+        secret_hash -> Nullable<Varchar>,
+        name -> Varchar,
+        redirect_uris -> Array<Text>,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    oauth_consents (user_id, client_id) {
+        user_id -> Int4,
+        client_id -> Int4,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    organizations (id) {
+        id -> Int4,
+        name -> Varchar,
+        slug -> Varchar,
+        created_at -> Timestamp,
+        logo_url -> Nullable<Varchar>,
+        primary_color -> Nullable<Varchar>,
+        accent_color -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        family_id -> Uuid,
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    user_identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_login_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    user_status_changes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        old_status -> Varchar,
+        new_status -> Varchar,
+        changed_by -> Nullable<Int4>,
+        reason -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int4,
         email -> Varchar,
         hashed_password -> Varchar,
+        display_name -> Nullable<Varchar>,
+        timezone -> Varchar,
+        locale -> Nullable<Varchar>,
+        avatar_url -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        status -> Varchar,
+        status_changed_at -> Timestamp,
+        role -> Varchar,
+        has_password -> Bool,
+        email_verified -> Bool,
     }
 }
+
+diesel::joinable!(api_tokens -> users (user_id));
+diesel::joinable!(login_events -> users (user_id));
+diesel::joinable!(oauth_authorization_codes -> oauth_clients (client_id));
+diesel::joinable!(oauth_authorization_codes -> users (user_id));
+diesel::joinable!(oauth_clients -> users (created_by));
+diesel::joinable!(oauth_consents -> oauth_clients (client_id));
+diesel::joinable!(oauth_consents -> users (user_id));
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(user_identities -> users (user_id));
+diesel::joinable!(user_status_changes -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    audit_log,
+    login_events,
+    oauth_authorization_codes,
+    oauth_clients,
+    oauth_consents,
+    organizations,
+    refresh_tokens,
+    user_identities,
+    user_status_changes,
+    users,
+);
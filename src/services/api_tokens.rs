@@ -0,0 +1,126 @@
+use actix_web::web;
+use chrono::{Duration, NaiveDateTime, Utc};
+use log::error;
+use std::sync::Arc;
+
+use crate::database::db::Database;
+use crate::database::errors::DatabaseError;
+use crate::models::api_tokens::{ApiToken, NewApiToken};
+use crate::models::users::User;
+use crate::utils::jwt::JwtConfig;
+use crate::utils::tokens::{api_token_prefix, generate_api_token, hash_token, token_matches};
+
+// How often the last use of a token is written at most, so busy clients do not write on every request
+const LAST_USED_INTERVAL_SECONDS: i64 = 60;
+
+// Why a bearer token was not accepted
+pub enum TokenError {
+    // Malformed, unknown, expired or revoked | Deliberately not told apart
+    Invalid,
+    // The account of the token may not log in anymore | Message for the client
+    Blocked(&'static str),
+    Internal,
+}
+
+impl From<DatabaseError> for TokenError {
+    fn from(err: DatabaseError) -> Self {
+        error!("{}", err);
+        TokenError::Internal
+    }
+}
+
+impl From<actix_web::error::BlockingError> for TokenError {
+    fn from(err: actix_web::error::BlockingError) -> Self {
+        error!("Blocking error occurred: {:?}", err);
+        TokenError::Internal
+    }
+}
+
+// New token for the user | Returns the token itself as well, which is only shown once
+pub fn new_api_token(
+    user: &User,
+    name: String,
+    scopes: Vec<String>,
+    expires_at: Option<NaiveDateTime>,
+) -> (String, NewApiToken) {
+    let (token, prefix) = generate_api_token();
+    let new_token = NewApiToken {
+        user_id: user.id,
+        name,
+        prefix,
+        token_hash: hash_token(&token),
+        scopes,
+        expires_at,
+    };
+    (token, new_token)
+}
+
+// Looks up a personal API token and its user | Records its last use
+pub async fn authenticate(
+    db: &web::Data<Arc<Database>>,
+    token: &str,
+    ip: String,
+) -> Result<(User, ApiToken), TokenError> {
+    let prefix = api_token_prefix(token)
+        .ok_or(TokenError::Invalid)?
+        .to_string();
+
+    let lookup_db = db.clone();
+    let api_token = web::block(move || lookup_db.get_api_token_by_prefix(&prefix))
+        .await??
+        .ok_or(TokenError::Invalid)?;
+
+    let now = Utc::now().naive_utc();
+    if !token_matches(token, &api_token.token_hash) || !api_token.is_usable(now) {
+        return Err(TokenError::Invalid);
+    }
+
+    let user_db = db.clone();
+    let user_id = api_token.user_id;
+    let user = web::block(move || user_db.get_user_by_id(user_id)).await??;
+    if let Some(message) = user.status.login_error() {
+        return Err(TokenError::Blocked(message));
+    }
+
+    let recently_used = api_token.last_used_at.is_some_and(|last_used_at| {
+        now - last_used_at < Duration::seconds(LAST_USED_INTERVAL_SECONDS)
+    });
+    if !recently_used {
+        let touch_db = db.clone();
+        let token_id = api_token.id;
+        // A failed update does not make the token less valid
+        match web::block(move || touch_db.touch_api_token(token_id, &ip)).await {
+            Ok(Err(err)) => error!("Failed to record use of API token: {}", err),
+            Err(err) => error!("Blocking error occurred: {:?}", err),
+            Ok(Ok(())) => {}
+        }
+    }
+
+    Ok((user, api_token))
+}
+
+// Looks up the user of a JWT access token | Only the signature is checked, the status of
+// the account is loaded on every request like for sessions
+pub async fn access_token_user(
+    db: &web::Data<Arc<Database>>,
+    jwt: &JwtConfig,
+    token: &str,
+) -> Result<User, TokenError> {
+    let claims = jwt.verify_access_token(token).ok_or(TokenError::Invalid)?;
+    let user_id: i32 = claims.sub.parse().map_err(|_| TokenError::Invalid)?;
+
+    let db = db.clone();
+    let user = match web::block(move || db.get_user_by_id(user_id)).await? {
+        Ok(user) => user,
+        // The account has been purged since the token was issued
+        Err(DatabaseError::DieselError(diesel::result::Error::NotFound)) => {
+            return Err(TokenError::Invalid)
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if let Some(message) = user.status.login_error() {
+        return Err(TokenError::Blocked(message));
+    }
+
+    Ok(user)
+}
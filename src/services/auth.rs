@@ -0,0 +1,1218 @@
+use actix_session::Session;
+use actix_web::dev::Payload;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, FromRequest, HttpRequest};
+use chrono::Utc;
+use futures_util::future::{ready, Ready};
+use log::{error, info, warn};
+use std::fmt;
+use std::sync::Arc;
+use tera::Context;
+use uuid::Uuid;
+
+use crate::database::db::Database;
+use crate::database::errors::DatabaseError;
+use crate::models::identities::NewUserIdentity;
+use crate::models::invitations::{Invitation, NewInvitation};
+use crate::models::login_events::{LoginMethod, LoginOutcome};
+use crate::models::refresh_tokens::{NewRefreshToken, Rotation};
+use crate::models::users::{normalize_email, AccountStatus, MagicLink, NewUser, Role, User};
+use crate::services::verification::{create_verification_token, send_verification_mail};
+use crate::utils::argon2::{verify_dummy_password, verify_password};
+use crate::utils::audit::{self, user_target, Actor, AuditAction};
+use crate::utils::i18n::translate_with;
+use crate::utils::jwt::JwtConfig;
+use crate::utils::login_history::record_login;
+use crate::utils::mailer::Mailer;
+use crate::utils::oidc::{IdTokenClaims, OidcProvider};
+use crate::utils::password_policy::PasswordPolicy;
+use crate::utils::registration::{RegistrationConfig, RegistrationMode};
+use crate::utils::sessions::{sign_in, SessionConfig};
+use crate::utils::templates::Templates;
+use crate::utils::tenants::spawn_with_page;
+use crate::utils::tokens::{generate_token, hash_token, token_matches, MAGIC_LINK};
+use crate::utils::validation::FieldErrors;
+
+// Login links are valid for 15 minutes
+pub const MAGIC_LINK_TTL_SECONDS: usize = 15 * 60;
+
+// Only one login link per account in this time, so the form cannot flood an inbox
+const MAGIC_LINK_COOLDOWN_SECONDS: usize = 60;
+
+const MAGIC_LINK_EXPIRED: &str =
+    "This login link is invalid or has expired, please request a new one";
+const MAGIC_LINK_OTHER_BROWSER: &str =
+    "Please open the login link in the browser where you requested it";
+
+// Only this many invitations of a user can wait to be accepted, admins are not limited
+pub const MAX_PENDING_INVITATIONS: i64 = 20;
+
+pub const INVITATION_INVALID: &str = "This invitation is invalid or has expired";
+
+// Why registering or logging in failed | Views turn these into pages, the API into JSON
+#[derive(Debug)]
+pub enum AuthError {
+    // Errors per field, e.g. a password which breaks the password policy
+    Invalid(FieldErrors),
+    // Only returned while existing accounts are not hidden
+    EmailTaken(String),
+    // Unknown email or wrong password, deliberately not told apart
+    InvalidCredentials,
+    // Correct password, but the status of the account does not allow logging in
+    AccountBlocked(&'static str),
+    // Unknown, expired, revoked or reused refresh token
+    InvalidRefreshToken,
+    // The account at an OpenID Connect provider cannot be used | Message for the user
+    IdentityRejected(String),
+    // Unknown, expired or used login link, or opened in another browser | Message for the user
+    InvalidLink(&'static str),
+    // The registration mode does not allow the registration or invitation | Message for the user
+    RegistrationClosed(String),
+    Internal(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Invalid(errors) => write!(f, "Invalid input: {:?}", errors),
+            AuthError::EmailTaken(msg) => write!(f, "{}", msg),
+            AuthError::InvalidCredentials => write!(f, "Invalid mail or password"),
+            AuthError::AccountBlocked(msg) => write!(f, "{}", msg),
+            AuthError::InvalidRefreshToken => write!(f, "Invalid refresh token"),
+            AuthError::IdentityRejected(msg) => write!(f, "{}", msg),
+            AuthError::InvalidLink(msg) => write!(f, "{}", msg),
+            AuthError::RegistrationClosed(msg) => write!(f, "{}", msg),
+            AuthError::Internal(msg) => write!(f, "Internal error: {}", msg),
+        }
+    }
+}
+
+impl From<DatabaseError> for AuthError {
+    fn from(err: DatabaseError) -> Self {
+        AuthError::Internal(err.to_string())
+    }
+}
+
+impl From<actix_web::error::BlockingError> for AuthError {
+    fn from(err: actix_web::error::BlockingError) -> Self {
+        AuthError::Internal(err.to_string())
+    }
+}
+
+pub enum Registration {
+    // The account can be used right away
+    Created(User),
+    // The visitor has to check their inbox, either to verify the address or because hidden
+    // existing accounts look like new ones
+    CheckInbox,
+}
+
+// Tokens of stateless API clients
+pub struct IssuedTokens {
+    // Signed JWT which is valid for expires_in seconds
+    pub access_token: String,
+    pub expires_in: i64,
+    // Opaque token which is exchanged for new tokens once the access token expired
+    pub refresh_token: String,
+}
+
+// Registration, login, logout and API tokens | Extracted from the app data of the request
+#[derive(Clone)]
+pub struct AuthService {
+    db: web::Data<Arc<Database>>,
+    mailer: web::Data<Mailer>,
+    templates: web::Data<Templates>,
+    password_policy: web::Data<PasswordPolicy>,
+    registration: web::Data<RegistrationConfig>,
+    session_config: web::Data<SessionConfig>,
+    jwt: web::Data<JwtConfig>,
+}
+
+impl FromRequest for AuthService {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let service = (|| {
+            Some(AuthService {
+                db: req.app_data::<web::Data<Arc<Database>>>()?.clone(),
+                mailer: req.app_data::<web::Data<Mailer>>()?.clone(),
+                templates: req.app_data::<web::Data<Templates>>()?.clone(),
+                password_policy: req.app_data::<web::Data<PasswordPolicy>>()?.clone(),
+                registration: req.app_data::<web::Data<RegistrationConfig>>()?.clone(),
+                session_config: req.app_data::<web::Data<SessionConfig>>()?.clone(),
+                jwt: req.app_data::<web::Data<JwtConfig>>()?.clone(),
+            })
+        })();
+
+        ready(service.ok_or_else(|| ErrorInternalServerError("Auth service not configured")))
+    }
+}
+
+impl AuthService {
+    // Email format and other rules of the request have been checked by the caller
+    // The token of an invitation lets the address register whatever the registration mode
+    // allows, except while registration is closed
+    pub async fn register(
+        &self,
+        req: &HttpRequest,
+        email: &str,
+        password: &str,
+        invitation_token: Option<&str>,
+    ) -> Result<Registration, AuthError> {
+        let invitation =
+            match invitation_token {
+                Some(token) => Some(self.invitation(token).await?.ok_or_else(|| {
+                    AuthError::RegistrationClosed(String::from(INVITATION_INVALID))
+                })?),
+                None => None,
+            };
+
+        let mut errors = FieldErrors::new();
+        match &invitation {
+            Some(invitation) if normalize_email(email) != invitation.email => errors
+                .entry(String::from("email"))
+                .or_default()
+                .push(translate_with(
+                    "Please register with {email}, the address the invitation was sent to",
+                    &[("email", &invitation.email)],
+                )),
+            Some(_) => {}
+            None if self.registration.mode.allows(email) => {}
+            None => {
+                let restriction = self.registration.mode.restriction().unwrap_or_default();
+                match self.registration.mode {
+                    RegistrationMode::AllowedDomains(_) => errors
+                        .entry(String::from("email"))
+                        .or_default()
+                        .push(restriction),
+                    _ => return Err(AuthError::RegistrationClosed(restriction)),
+                }
+            }
+        }
+        for violation in self.password_policy.check(password, email) {
+            errors
+                .entry(String::from("password"))
+                .or_default()
+                .push(violation);
+        }
+        if !errors.is_empty() {
+            return Err(AuthError::Invalid(errors));
+        }
+
+        // Existing accounts are detected by the unique email index on insert
+        let verify_email = self.registration.verify_email;
+        let db = self.db.clone();
+        let mail = email.to_string();
+        let password = password.to_string();
+        let invitation_hash = invitation_token.map(hash_token);
+        let result = web::block(move || {
+            let mut new_user = NewUser::new(&mail, &password)?;
+            // The invitation link was mailed to the address, so it needs no verification
+            if let Some(token_hash) = invitation_hash {
+                new_user.email_verified = true;
+                return Ok(db
+                    .create_user_with_invitation(&new_user, &token_hash)?
+                    .map(|(user, _)| (user, None)));
+            }
+            if verify_email {
+                new_user.status = AccountStatus::Unverified;
+            }
+            let user = db.create_user(&new_user)?;
+
+            let token = match verify_email {
+                true => Some(create_verification_token(&db, user.id)?),
+                false => None,
+            };
+            Ok::<_, DatabaseError>(Some((user, token)))
+        })
+        .await?;
+
+        let mut mail_context = Context::new();
+        mail_context.insert("email", email);
+
+        match result {
+            Ok(Some((user, token))) => {
+                audit::record(
+                    &self.db,
+                    Actor::user(&user, req),
+                    AuditAction::Register,
+                    user_target(user.id),
+                    serde_json::json!({
+                        "status": user.status,
+                        "invitation_id": invitation.as_ref().map(|invitation| invitation.id),
+                        "organization_id": invitation.and_then(|invitation| invitation.organization_id),
+                    }),
+                )
+                .await;
+
+                if let Some(token) = token {
+                    info!("Created new unverified user with email {}", user.email);
+                    send_verification_mail(
+                        &self.mailer,
+                        &self.templates,
+                        &user.email,
+                        user.locale.as_deref(),
+                        &token,
+                    );
+                    return Ok(Registration::CheckInbox);
+                }
+
+                info!("Created new user with email {}", user.email);
+                if !self.registration.hide_existing_accounts {
+                    return Ok(Registration::Created(user));
+                }
+
+                // Both outcomes look the same to the visitor, only the mail tells them apart
+                Mailer::send_template(
+                    &self.mailer,
+                    &self.templates,
+                    &user.email,
+                    user.locale.as_deref(),
+                    "Welcome",
+                    "emails/welcome.txt",
+                    &mail_context,
+                );
+                Ok(Registration::CheckInbox)
+            }
+            Err(DatabaseError::UserAlreadyExists(_))
+                if self.registration.hide_existing_accounts =>
+            {
+                info!("Registration attempt for existing email {}", email);
+                self.send_account_exists_mail(email, mail_context);
+                Ok(Registration::CheckInbox)
+            }
+            // Accepted, revoked or expired since it was looked up
+            Ok(None) => Err(AuthError::RegistrationClosed(String::from(
+                INVITATION_INVALID,
+            ))),
+            Err(DatabaseError::UserAlreadyExists(msg)) => Err(AuthError::EmailTaken(msg)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // In the language of the account, which is looked up after the response, so the time of the
+    // registration does not reveal existing accounts
+    fn send_account_exists_mail(&self, email: &str, context: Context) {
+        let auth = self.clone();
+        let email = email.to_string();
+        spawn_with_page(async move {
+            let db = auth.db.clone();
+            let mail = email.clone();
+            let locale = match web::block(move || db.get_user_by_email(&mail)).await {
+                Ok(Ok(user)) => user.locale,
+                Ok(Err(err)) => {
+                    error!("Failed to load user: {}", err);
+                    None
+                }
+                Err(err) => {
+                    error!("Blocking error occurred: {:?}", err);
+                    None
+                }
+            };
+            Mailer::send_template(
+                &auth.mailer,
+                &auth.templates,
+                &email,
+                locale.as_deref(),
+                "You already have an account",
+                "emails/account_exists.txt",
+                &context,
+            );
+        });
+    }
+
+    // Invitation which can still be accepted, for the registration page
+    pub async fn invitation(&self, token: &str) -> Result<Option<Invitation>, AuthError> {
+        if !self.registration.mode.allows_invitations() {
+            return Err(AuthError::RegistrationClosed(
+                self.registration.mode.restriction().unwrap_or_default(),
+            ));
+        }
+
+        let db = self.db.clone();
+        let token_hash = hash_token(token);
+        Ok(web::block(move || db.get_invitation(&token_hash)).await??)
+    }
+
+    // Mails an invitation to register to the address
+    pub async fn invite(
+        &self,
+        req: &HttpRequest,
+        inviter: &User,
+        email: &str,
+    ) -> Result<Invitation, AuthError> {
+        if !self.registration.mode.allows_invitations() {
+            return Err(AuthError::RegistrationClosed(String::from(
+                "Invitations are disabled",
+            )));
+        }
+
+        let db = self.db.clone();
+        let mail = normalize_email(email);
+        let inviter_id = inviter.id;
+        let (existing, locale, pending) = web::block(move || {
+            let (existing, locale) = match db.get_user_by_email(&mail) {
+                Ok(user) => (true, user.locale),
+                Err(DatabaseError::DieselError(diesel::result::Error::NotFound)) => (false, None),
+                Err(err) => return Err(err),
+            };
+            Ok((existing, locale, db.count_pending_invitations(inviter_id)?))
+        })
+        .await??;
+
+        // Checked first, so the limit also holds for probing which addresses have accounts
+        let mut errors = FieldErrors::new();
+        if pending >= MAX_PENDING_INVITATIONS && inviter.role != Role::Admin {
+            errors
+                .entry(String::from("email"))
+                .or_default()
+                .push(String::from(
+                    "You have too many open invitations, please revoke some first",
+                ));
+        } else if existing && !self.registration.hide_existing_accounts {
+            errors
+                .entry(String::from("email"))
+                .or_default()
+                .push(String::from(
+                    "There already is an account with this address",
+                ));
+        }
+        if !errors.is_empty() {
+            return Err(AuthError::Invalid(errors));
+        }
+
+        let token = generate_token();
+        let new_invitation = NewInvitation {
+            token_hash: hash_token(&token),
+            email: normalize_email(email),
+            invited_by: Some(inviter.id),
+            expires_at: Utc::now().naive_utc() + self.registration.invitation_ttl,
+            organization_role: None,
+        };
+        let db = self.db.clone();
+        let invitation = web::block(move || db.create_invitation(None, &new_invitation)).await??;
+
+        info!("{} invited {}", inviter.email, invitation.email);
+        audit::record(
+            &self.db,
+            Actor::user(inviter, req),
+            AuditAction::InvitationCreate,
+            user_target(inviter.id),
+            serde_json::json!({ "invitation_id": invitation.id, "email": invitation.email }),
+        )
+        .await;
+
+        let mut context = Context::new();
+        context.insert("email", &invitation.email);
+        context.insert("inviter", &inviter.email);
+        context.insert("token", &token);
+        context.insert("days", &self.registration.invitation_ttl.num_days());
+        // The invitation is created either way, so the inviter cannot tell the outcomes apart |
+        // It cannot be accepted, registering with the address fails
+        let template = if existing {
+            info!("Invitation for existing email {}", invitation.email);
+            "emails/invitation_account_exists.txt"
+        } else {
+            "emails/invitation.txt"
+        };
+        Mailer::send_template(
+            &self.mailer,
+            &self.templates,
+            &invitation.email,
+            locale.as_deref(),
+            "You have been invited",
+            template,
+            &context,
+        );
+        Ok(invitation)
+    }
+
+    // Newest first | Invitations of every user if invited_by is None, with the inviter's email
+    pub async fn invitations(
+        &self,
+        invited_by: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<(Invitation, Option<String>)>, AuthError> {
+        let db = self.db.clone();
+        Ok(web::block(move || db.get_invitations(invited_by, limit)).await??)
+    }
+
+    pub fn registration(&self) -> &RegistrationConfig {
+        &self.registration
+    }
+
+    // Returns None if the invitation cannot be accepted anymore | Admins revoke any invitation,
+    // everybody else only their own
+    pub async fn revoke_invitation(
+        &self,
+        req: &HttpRequest,
+        user: &User,
+        invitation_id: i32,
+    ) -> Result<Option<Invitation>, AuthError> {
+        let db = self.db.clone();
+        let invited_by = match user.role {
+            Role::Admin => None,
+            Role::User => Some(user.id),
+        };
+        let invitation =
+            web::block(move || db.revoke_invitation(invitation_id, invited_by)).await??;
+
+        if let Some(invitation) = &invitation {
+            audit::record(
+                &self.db,
+                Actor::user(user, req),
+                AuditAction::InvitationRevoke,
+                user_target(user.id),
+                serde_json::json!({ "invitation_id": invitation.id, "email": invitation.email }),
+            )
+            .await;
+        }
+        Ok(invitation)
+    }
+
+    // Checks the credentials and signs the session in
+    pub async fn login(
+        &self,
+        req: &HttpRequest,
+        session: &Session,
+        email: &str,
+        password: &str,
+        remember_me: bool,
+    ) -> Result<User, AuthError> {
+        let user = self.authenticate(req, email, password).await?;
+
+        sign_in(
+            &self.db,
+            &self.session_config,
+            session,
+            req,
+            user.id,
+            remember_me,
+        )
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+
+        self.record(req, &user, LoginOutcome::Success);
+        Ok(user)
+    }
+
+    // Checks the credentials without signing anything in | Failed attempts are recorded
+    async fn authenticate(
+        &self,
+        req: &HttpRequest,
+        email: &str,
+        password: &str,
+    ) -> Result<User, AuthError> {
+        let db = self.db.clone();
+        let mail = email.to_string();
+        let user = match web::block(move || db.get_user_by_email(&mail)).await? {
+            Ok(user) => user,
+            // Verify against a dummy hash so this takes as long as a wrong password
+            Err(DatabaseError::DieselError(diesel::result::Error::NotFound)) => {
+                verify_dummy_password(password);
+                warn!("Login attempt for unknown email {}", email);
+                // Spawned like the login history of existing accounts, see record_login
+                let db = self.db.clone();
+                let actor = Actor::anonymous(req);
+                let metadata = serde_json::json!({ "email": email, "reason": "unknown_email" });
+                actix_web::rt::spawn(async move {
+                    audit::record(&db, actor, AuditAction::LoginFailed, None, metadata).await;
+                });
+                return Err(AuthError::InvalidCredentials);
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if !verify_password(password, &user.hashed_password) {
+            warn!("Wrong password for {}", email);
+            self.record(req, &user, LoginOutcome::WrongPassword);
+            return Err(AuthError::InvalidCredentials);
+        }
+
+        // Only reported after the password has been checked, so it does not reveal anything
+        if let Some(message) = user.status.login_error() {
+            warn!("Login of {} account {}", user.status.as_str(), user.email);
+            self.record(req, &user, LoginOutcome::Blocked);
+
+            // The first link might have expired or got lost
+            if user.status == AccountStatus::Unverified {
+                let user_id = user.id;
+                let db = self.db.clone();
+                match web::block(move || create_verification_token(&db, user_id)).await {
+                    Ok(Ok(token)) => send_verification_mail(
+                        &self.mailer,
+                        &self.templates,
+                        &user.email,
+                        user.locale.as_deref(),
+                        &token,
+                    ),
+                    Ok(Err(err)) => error!("{}", err),
+                    Err(err) => error!("Blocking error occurred: {:?}", err),
+                }
+            }
+
+            return Err(AuthError::AccountBlocked(message));
+        }
+
+        Ok(user)
+    }
+
+    // Mails a login link to the account, which only works in the browser holding the nonce
+    // Unknown emails are not reported, so the form does not reveal which accounts exist | The
+    // account is looked up after the response, so its time does not reveal them either
+    pub fn request_magic_link(&self, req: &HttpRequest, email: &str, nonce: &str) {
+        let auth = self.clone();
+        let actor = Actor::anonymous(req);
+        let email = email.to_string();
+        let nonce = nonce.to_string();
+        spawn_with_page(async move {
+            if let Err(err) = auth.send_magic_link(actor, &email, &nonce).await {
+                error!("Failed to send login link to {}: {}", email, err);
+            }
+        });
+    }
+
+    async fn send_magic_link(
+        &self,
+        actor: Actor,
+        email: &str,
+        nonce: &str,
+    ) -> Result<(), AuthError> {
+        let db = self.db.clone();
+        let mail = email.to_string();
+        let user = match web::block(move || db.get_user_by_email(&mail)).await? {
+            Ok(user) => user,
+            Err(DatabaseError::DieselError(diesel::result::Error::NotFound)) => {
+                info!("Login link requested for unknown email {}", email);
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let db = self.db.clone();
+        let cooldown_key = format!("{}:{}", MAGIC_LINK, user.id);
+        let claimed =
+            web::block(move || db.claim_cooldown(&cooldown_key, MAGIC_LINK_COOLDOWN_SECONDS))
+                .await??;
+        if !claimed {
+            info!("Login link for {} requested again too soon", user.email);
+            return Ok(());
+        }
+
+        // The link would not log in anyway | Unverified accounts get their verification link
+        if user.status == AccountStatus::Unverified {
+            let user_id = user.id;
+            let db = self.db.clone();
+            let token = web::block(move || create_verification_token(&db, user_id)).await??;
+            send_verification_mail(
+                &self.mailer,
+                &self.templates,
+                &user.email,
+                user.locale.as_deref(),
+                &token,
+            );
+            return Ok(());
+        }
+
+        let token = generate_token();
+        let link = MagicLink {
+            user_id: user.id,
+            nonce_hash: hash_token(nonce),
+        };
+        let db = self.db.clone();
+        let token_hash = hash_token(&token);
+        web::block(move || db.store_token(MAGIC_LINK, &token_hash, &link, MAGIC_LINK_TTL_SECONDS))
+            .await??;
+
+        audit::record(
+            &self.db,
+            actor,
+            AuditAction::MagicLinkRequest,
+            user_target(user.id),
+            serde_json::json!({}),
+        )
+        .await;
+
+        let mut context = Context::new();
+        context.insert("email", &user.email);
+        context.insert("token", &token);
+        Mailer::send_template(
+            &self.mailer,
+            &self.templates,
+            &user.email,
+            user.locale.as_deref(),
+            "Your login link",
+            "emails/magic_link.txt",
+            &context,
+        );
+        Ok(())
+    }
+
+    // Logs in with a mailed link | The link is only used up in the browser which requested it,
+    // so mail scanners opening it do not break it
+    pub async fn login_with_magic_link(
+        &self,
+        req: &HttpRequest,
+        session: &Session,
+        token: &str,
+        nonce: Option<&str>,
+    ) -> Result<User, AuthError> {
+        let db = self.db.clone();
+        let token_hash = hash_token(token);
+        let nonce = nonce.map(str::to_string);
+        let user = web::block(move || {
+            let link = match db.peek_token::<MagicLink>(MAGIC_LINK, &token_hash)? {
+                Some(link) => link,
+                None => return Ok(Err(MAGIC_LINK_EXPIRED)),
+            };
+            match nonce {
+                Some(nonce) if token_matches(&nonce, &link.nonce_hash) => {}
+                _ => return Ok(Err(MAGIC_LINK_OTHER_BROWSER)),
+            }
+            // Another request might have used it in the meantime
+            if db
+                .take_token::<MagicLink>(MAGIC_LINK, &token_hash)?
+                .is_none()
+            {
+                return Ok(Err(MAGIC_LINK_EXPIRED));
+            }
+            db.get_user_by_id(link.user_id).map(Ok)
+        })
+        .await??
+        .map_err(AuthError::InvalidLink)?;
+
+        if let Some(message) = user.status.login_error() {
+            warn!("Login of {} account {}", user.status.as_str(), user.email);
+            self.record_method(req, &user, LoginOutcome::Blocked, LoginMethod::MagicLink);
+            return Err(AuthError::AccountBlocked(message));
+        }
+
+        sign_in(&self.db, &self.session_config, session, req, user.id, false)
+            .await
+            .map_err(|err| AuthError::Internal(err.to_string()))?;
+
+        self.record_method(req, &user, LoginOutcome::Success, LoginMethod::MagicLink);
+        Ok(user)
+    }
+
+    // Password grant of the token endpoint | Starts a new family of refresh tokens
+    pub async fn issue_tokens(
+        &self,
+        req: &HttpRequest,
+        email: &str,
+        password: &str,
+    ) -> Result<(User, IssuedTokens), AuthError> {
+        let user = self.authenticate(req, email, password).await?;
+
+        let refresh_token = generate_token();
+        let new_token = NewRefreshToken {
+            user_id: user.id,
+            family_id: Uuid::new_v4(),
+            token_hash: hash_token(&refresh_token),
+            expires_at: Utc::now().naive_utc() + self.jwt.refresh_token_ttl,
+        };
+        let db = self.db.clone();
+        web::block(move || db.create_refresh_token(&new_token)).await??;
+
+        let tokens = self.tokens_for(&user, refresh_token)?;
+        self.record(req, &user, LoginOutcome::Success);
+        Ok((user, tokens))
+    }
+
+    // Refresh grant of the token endpoint | The refresh token can only be used once,
+    // presenting it again revokes every token of its family
+    pub async fn refresh_tokens(
+        &self,
+        req: &HttpRequest,
+        refresh_token: &str,
+    ) -> Result<(User, IssuedTokens), AuthError> {
+        let new_refresh_token = generate_token();
+        let db = self.db.clone();
+        let token_hash = hash_token(refresh_token);
+        let new_token_hash = hash_token(&new_refresh_token);
+        let expires_at = Utc::now().naive_utc() + self.jwt.refresh_token_ttl;
+        let rotation =
+            web::block(move || db.rotate_refresh_token(&token_hash, new_token_hash, expires_at))
+                .await??;
+
+        let user_id = match rotation {
+            Rotation::Rotated(token) => token.user_id,
+            Rotation::Reused(token) => {
+                warn!("Reuse of refresh token family {}", token.family_id);
+                audit::record(
+                    &self.db,
+                    Actor::anonymous(req),
+                    AuditAction::RefreshTokenReuse,
+                    user_target(token.user_id),
+                    serde_json::json!({ "family_id": token.family_id }),
+                )
+                .await;
+                return Err(AuthError::InvalidRefreshToken);
+            }
+            Rotation::Invalid => return Err(AuthError::InvalidRefreshToken),
+        };
+
+        let db = self.db.clone();
+        let user = web::block(move || db.get_user_by_id(user_id)).await??;
+        if let Some(message) = user.status.login_error() {
+            return Err(AuthError::AccountBlocked(message));
+        }
+
+        let tokens = self.tokens_for(&user, new_refresh_token)?;
+        Ok((user, tokens))
+    }
+
+    // Signs API clients out | Unknown tokens are ignored, so this does not reveal which exist
+    pub async fn revoke_refresh_token(&self, req: &HttpRequest, refresh_token: &str) {
+        let db = self.db.clone();
+        let token_hash = hash_token(refresh_token);
+        match web::block(move || db.revoke_refresh_token(&token_hash)).await {
+            Ok(Ok(Some(token))) => {
+                audit::record(
+                    &self.db,
+                    Actor::anonymous(req),
+                    AuditAction::RefreshTokenRevoke,
+                    user_target(token.user_id),
+                    serde_json::json!({ "family_id": token.family_id }),
+                )
+                .await
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(err)) => error!("{}", err),
+            Err(err) => error!("Blocking error occurred: {:?}", err),
+        }
+    }
+
+    fn tokens_for(&self, user: &User, refresh_token: String) -> Result<IssuedTokens, AuthError> {
+        let access_token = self
+            .jwt
+            .issue_access_token(user)
+            .map_err(|err| AuthError::Internal(err.to_string()))?;
+
+        Ok(IssuedTokens {
+            access_token,
+            expires_in: self.jwt.access_token_ttl.num_seconds(),
+            refresh_token,
+        })
+    }
+
+    // Logs in with an account at an OpenID Connect provider whose ID token has been verified
+    // Identities which are not linked yet are linked to the account with the same email if the
+    // provider verified it, otherwise a new account is created
+    pub async fn login_with_identity(
+        &self,
+        req: &HttpRequest,
+        session: &Session,
+        provider: &OidcProvider,
+        claims: IdTokenClaims,
+    ) -> Result<User, AuthError> {
+        let db = self.db.clone();
+        let provider_name = provider.name.clone();
+        let subject = claims.sub.clone();
+        let identity = web::block(move || db.get_identity(&provider_name, &subject)).await??;
+
+        let (user, identity_id) = match identity {
+            Some(identity) => {
+                let db = self.db.clone();
+                let user_id = identity.user_id;
+                (
+                    web::block(move || db.get_user_by_id(user_id)).await??,
+                    identity.id,
+                )
+            }
+            None => self.user_for_identity(req, provider, &claims).await?,
+        };
+
+        if let Some(message) = user.status.login_error() {
+            warn!("Login of {} account {}", user.status.as_str(), user.email);
+            self.record_method(req, &user, LoginOutcome::Blocked, LoginMethod::Oidc);
+            return Err(AuthError::AccountBlocked(message));
+        }
+
+        let db = self.db.clone();
+        match web::block(move || db.touch_identity(identity_id)).await {
+            Ok(Err(err)) => error!("Failed to record use of identity: {}", err),
+            Err(err) => error!("Blocking error occurred: {:?}", err),
+            Ok(Ok(())) => {}
+        }
+
+        sign_in(&self.db, &self.session_config, session, req, user.id, false)
+            .await
+            .map_err(|err| AuthError::Internal(err.to_string()))?;
+
+        self.record_method(req, &user, LoginOutcome::Success, LoginMethod::Oidc);
+        Ok(user)
+    }
+
+    // Connects an account at a provider to the logged in user
+    pub async fn link_identity(
+        &self,
+        req: &HttpRequest,
+        user: &User,
+        provider: &OidcProvider,
+        claims: IdTokenClaims,
+    ) -> Result<(), AuthError> {
+        let db = self.db.clone();
+        let new_identity_user = user.id;
+        let provider_name = provider.name.clone();
+        let subject = claims.sub.clone();
+        let email = claims.email.clone();
+        let result = web::block(move || {
+            if let Some(identity) = db.get_identity(&provider_name, &subject)? {
+                if identity.user_id == new_identity_user {
+                    return Ok(None);
+                }
+            }
+            db.link_identity(&NewUserIdentity {
+                user_id: new_identity_user,
+                provider: &provider_name,
+                subject: &subject,
+                email: email.as_deref(),
+            })
+            .map(Some)
+        })
+        .await?;
+
+        match result {
+            Ok(Some(identity)) => {
+                self.record_identity_link(req, user, provider, &identity.subject, "settings")
+                    .await;
+                Ok(())
+            }
+            // Linked before
+            Ok(None) => Ok(()),
+            Err(DatabaseError::IdentityAlreadyLinked(_)) => {
+                Err(AuthError::IdentityRejected(translate_with(
+                    "This {provider} account is already connected to another user, or you \
+                     connected a different one before",
+                    &[("provider", &provider.display_name)],
+                )))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Account for an identity which is not linked yet
+    async fn user_for_identity(
+        &self,
+        req: &HttpRequest,
+        provider: &OidcProvider,
+        claims: &IdTokenClaims,
+    ) -> Result<(User, i32), AuthError> {
+        let email = match &claims.email {
+            Some(email) if claims.email_verified => email.clone(),
+            // Taking over an account needs proof of owning its email, and so does a new account,
+            // like registering at the allowed domains
+            _ => {
+                return Err(AuthError::IdentityRejected(translate_with(
+                    "Your {provider} account has no verified email address. Log in with your \
+                     password and connect it in your settings instead.",
+                    &[("provider", &provider.display_name)],
+                )))
+            }
+        };
+
+        let db = self.db.clone();
+        let mail = email.clone();
+        let existing = match web::block(move || db.get_user_by_email(&mail)).await? {
+            Ok(user) => Some(user),
+            Err(DatabaseError::DieselError(diesel::result::Error::NotFound)) => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(user) = existing {
+            let db = self.db.clone();
+            let new_identity_user = user.id;
+            let provider_name = provider.name.clone();
+            let subject = claims.sub.clone();
+            let mail = email.clone();
+            let identity = web::block(move || {
+                db.link_identity(&NewUserIdentity {
+                    user_id: new_identity_user,
+                    provider: &provider_name,
+                    subject: &subject,
+                    email: Some(&mail),
+                })
+            })
+            .await?;
+
+            return match identity {
+                // The provider verified the address
+                Ok(identity) if !user.email_verified => {
+                    let db = self.db.clone();
+                    let user = web::block(move || db.mark_email_verified(user.id)).await??;
+                    info!("Linked {} identity to {}", provider.name, user.email);
+                    self.record_identity_link(req, &user, provider, &identity.subject, "email")
+                        .await;
+                    Ok((user, identity.id))
+                }
+                Ok(identity) => {
+                    info!("Linked {} identity to {}", provider.name, user.email);
+                    self.record_identity_link(req, &user, provider, &identity.subject, "email")
+                        .await;
+                    Ok((user, identity.id))
+                }
+                // The user already has a different account at the provider
+                Err(DatabaseError::IdentityAlreadyLinked(_)) => {
+                    Err(AuthError::IdentityRejected(translate_with(
+                        "Another {provider} account is connected to the account with this email",
+                        &[("provider", &provider.display_name)],
+                    )))
+                }
+                Err(err) => Err(err.into()),
+            };
+        }
+
+        if !self.registration.mode.allows(&email) {
+            return Err(AuthError::IdentityRejected(translate_with(
+                "There is no account for {email}. {restriction}.",
+                &[
+                    ("email", &email),
+                    (
+                        "restriction",
+                        &self.registration.mode.restriction().unwrap_or_default(),
+                    ),
+                ],
+            )));
+        }
+
+        // Nobody knows this password | The account sets one without the current password later
+        let db = self.db.clone();
+        let provider_name = provider.name.clone();
+        let subject = claims.sub.clone();
+        let (user, identity) = web::block(move || {
+            let mut new_user = NewUser::new(&email, &generate_token())?;
+            new_user.has_password = false;
+            // Checked above, the provider verified the address
+            new_user.email_verified = true;
+            db.create_user_with_identity(&new_user, &provider_name, &subject)
+        })
+        .await??;
+
+        info!(
+            "Created new user with email {} via {}",
+            user.email, provider.name
+        );
+        audit::record(
+            &self.db,
+            Actor::user(&user, req),
+            AuditAction::Register,
+            user_target(user.id),
+            serde_json::json!({ "status": user.status, "provider": provider.name }),
+        )
+        .await;
+        Ok((user, identity.id))
+    }
+
+    async fn record_identity_link(
+        &self,
+        req: &HttpRequest,
+        user: &User,
+        provider: &OidcProvider,
+        subject: &str,
+        reason: &str,
+    ) {
+        audit::record(
+            &self.db,
+            Actor::user(user, req),
+            AuditAction::IdentityLink,
+            user_target(user.id),
+            serde_json::json!({ "provider": provider.name, "subject": subject, "reason": reason }),
+        )
+        .await;
+    }
+
+    // Signs the session out | user is None if the session had no usable user
+    pub async fn logout(&self, req: &HttpRequest, session: &Session, user: Option<&User>) {
+        if let Some(user) = user {
+            audit::record(
+                &self.db,
+                Actor::user(user, req),
+                AuditAction::Logout,
+                user_target(user.id),
+                serde_json::json!({}),
+            )
+            .await;
+        }
+
+        session.purge();
+    }
+
+    fn record(&self, req: &HttpRequest, user: &User, outcome: LoginOutcome) {
+        self.record_method(req, user, outcome, LoginMethod::Password);
+    }
+
+    fn record_method(
+        &self,
+        req: &HttpRequest,
+        user: &User,
+        outcome: LoginOutcome,
+        method: LoginMethod,
+    ) {
+        record_login(
+            &self.db,
+            &self.mailer,
+            &self.templates,
+            req,
+            user,
+            outcome,
+            method,
+        );
+    }
+}
+
+// Needs the database and Garnet of DATABASE_URL and GARNET_URL with the migrations applied
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::assets::Assets;
+    use crate::utils::oidc::tests::provider;
+    use actix_web::test::TestRequest;
+    use chrono::Duration;
+
+    fn service() -> AuthService {
+        dotenv::dotenv().ok();
+        #[cfg(feature = "embed-static")]
+        let assets = web::Data::new(Assets::embedded().unwrap());
+        #[cfg(not(feature = "embed-static"))]
+        let assets = web::Data::new(Assets::new("./static").unwrap());
+        #[cfg(feature = "embed-static")]
+        let templates = Templates::embedded(assets).unwrap();
+        #[cfg(not(feature = "embed-static"))]
+        let templates = Templates::new("./static/templates/**/*", assets).unwrap();
+
+        AuthService {
+            db: web::Data::new(Arc::new(Database::new().unwrap())),
+            mailer: web::Data::new(Mailer::from_env().unwrap()),
+            templates: web::Data::new(templates),
+            password_policy: web::Data::new(PasswordPolicy::from_env().unwrap()),
+            registration: web::Data::new(RegistrationConfig {
+                hide_existing_accounts: false,
+                verify_email: false,
+                mode: RegistrationMode::Open,
+                invitation_ttl: Duration::days(7),
+            }),
+            session_config: web::Data::new(SessionConfig::from_env().unwrap()),
+            jwt: web::Data::new(JwtConfig::from_env().unwrap()),
+        }
+    }
+
+    // Fresh email and subject, so the tests do not depend on each other or earlier runs
+    fn claims(verified: bool) -> IdTokenClaims {
+        let id = generate_token()[..12].to_lowercase();
+        IdTokenClaims {
+            sub: format!("subject-{}", id),
+            nonce: None,
+            email: Some(format!("oidc-{}@example.com", id)),
+            email_verified: verified,
+        }
+    }
+
+    fn create_user(auth: &AuthService, email: &str) -> User {
+        let new_user = NewUser::new(email, &generate_token()).unwrap();
+        auth.db.create_user(&new_user).unwrap()
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL and GARNET_URL"]
+    async fn verified_email_links_the_existing_account() {
+        let auth = service();
+        let claims = claims(true);
+        let existing = create_user(&auth, claims.email.as_deref().unwrap());
+        let req = TestRequest::default().to_http_request();
+
+        let (user, identity_id) = auth
+            .user_for_identity(&req, &provider("http://127.0.0.1"), &claims)
+            .await
+            .unwrap();
+
+        assert_eq!(user.id, existing.id);
+        assert!(!existing.email_verified && user.email_verified);
+        let identity = auth.db.get_identity("mock", &claims.sub).unwrap().unwrap();
+        assert_eq!((identity.id, identity.user_id), (identity_id, existing.id));
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL and GARNET_URL"]
+    async fn unknown_email_creates_an_account_without_password() {
+        let auth = service();
+        let claims = claims(true);
+        let req = TestRequest::default().to_http_request();
+
+        let (user, identity_id) = auth
+            .user_for_identity(&req, &provider("http://127.0.0.1"), &claims)
+            .await
+            .unwrap();
+
+        assert_eq!(Some(user.email.as_str()), claims.email.as_deref());
+        assert!(!user.has_password);
+        assert!(user.email_verified);
+        let identity = auth.db.get_identity("mock", &claims.sub).unwrap().unwrap();
+        assert_eq!((identity.id, identity.user_id), (identity_id, user.id));
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL and GARNET_URL"]
+    async fn unverified_email_is_refused() {
+        let auth = service();
+        let claims = claims(false);
+        create_user(&auth, claims.email.as_deref().unwrap());
+        let req = TestRequest::default().to_http_request();
+
+        let result = auth
+            .user_for_identity(&req, &provider("http://127.0.0.1"), &claims)
+            .await;
+
+        assert!(matches!(result, Err(AuthError::IdentityRejected(_))));
+        assert!(auth.db.get_identity("mock", &claims.sub).unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL and GARNET_URL"]
+    async fn unverified_email_at_an_allowed_domain_creates_no_account() {
+        let mut auth = service();
+        auth.registration = web::Data::new(RegistrationConfig {
+            hide_existing_accounts: false,
+            verify_email: true,
+            mode: RegistrationMode::AllowedDomains(vec![String::from("example.com")]),
+            invitation_ttl: Duration::days(7),
+        });
+        let claims = claims(false);
+        let req = TestRequest::default().to_http_request();
+
+        let result = auth
+            .user_for_identity(&req, &provider("http://127.0.0.1"), &claims)
+            .await;
+
+        assert!(matches!(result, Err(AuthError::IdentityRejected(_))));
+        assert!(auth
+            .db
+            .get_user_by_email(claims.email.as_deref().unwrap())
+            .is_err());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs DATABASE_URL and GARNET_URL"]
+    async fn account_with_another_identity_of_the_provider_is_refused() {
+        let auth = service();
+        let claims = claims(true);
+        let existing = create_user(&auth, claims.email.as_deref().unwrap());
+        auth.db
+            .link_identity(&NewUserIdentity {
+                user_id: existing.id,
+                provider: "mock",
+                subject: &format!("other-{}", claims.sub),
+                email: claims.email.as_deref(),
+            })
+            .unwrap();
+        let req = TestRequest::default().to_http_request();
+
+        let result = auth
+            .user_for_identity(&req, &provider("http://127.0.0.1"), &claims)
+            .await;
+
+        assert!(matches!(result, Err(AuthError::IdentityRejected(_))));
+        assert!(auth.db.get_identity("mock", &claims.sub).unwrap().is_none());
+    }
+}
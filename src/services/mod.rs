@@ -0,0 +1,6 @@
+// Logic shared by the HTML views and the JSON API
+pub mod api_tokens;
+pub mod auth;
+pub mod oauth;
+pub mod organizations;
+pub mod verification;
@@ -0,0 +1,468 @@
+use actix_session::Session;
+use actix_web::dev::Payload;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, FromRequest, HttpRequest};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::future::{ready, Ready};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use url::Url;
+
+use crate::database::db::Database;
+use crate::database::errors::DatabaseError;
+use crate::models::oauth::{
+    NewAuthorizationCode, OAuthClient, OAUTH_SCOPES, SCOPE_EMAIL, SCOPE_OPENID, SCOPE_PROFILE,
+};
+use crate::models::users::User;
+use crate::services::api_tokens::TokenError;
+use crate::utils::audit::{self, user_target, Actor, AuditAction};
+use crate::utils::jwt::JwtConfig;
+use crate::utils::oidc::pkce_challenge;
+use crate::utils::sessions::current_session_id;
+use crate::utils::tokens::{generate_token, hash_token, token_matches};
+
+// Codes have to be exchanged right after the redirect
+const CODE_TTL_SECONDS: i64 = 60;
+
+// Error of the authorization or token endpoint | Codes of RFC 6749 and OpenID Connect,
+// e.g. invalid_grant, sent to the application with a description
+#[derive(Debug)]
+pub struct OAuthError {
+    pub error: &'static str,
+    pub description: String,
+}
+
+impl OAuthError {
+    pub fn new(error: &'static str, description: &str) -> Self {
+        OAuthError {
+            error,
+            description: description.to_string(),
+        }
+    }
+
+    pub fn invalid_request(description: &str) -> Self {
+        OAuthError::new("invalid_request", description)
+    }
+
+    pub fn invalid_client() -> Self {
+        OAuthError::new("invalid_client", "Unknown client or wrong client secret")
+    }
+
+    pub fn invalid_grant(description: &str) -> Self {
+        OAuthError::new("invalid_grant", description)
+    }
+
+    // Details are only logged
+    pub fn server_error(err: impl fmt::Display) -> Self {
+        error!("{}", err);
+        OAuthError::new(
+            "server_error",
+            "We are experiencing problems, please try again later",
+        )
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.description)
+    }
+}
+
+impl From<DatabaseError> for OAuthError {
+    fn from(err: DatabaseError) -> Self {
+        OAuthError::server_error(err)
+    }
+}
+
+impl From<actix_web::error::BlockingError> for OAuthError {
+    fn from(err: actix_web::error::BlockingError) -> Self {
+        OAuthError::server_error(err)
+    }
+}
+
+// Checked authorization request of an application, kept in the session while the user is
+// asked for consent
+#[derive(Serialize, Deserialize)]
+pub struct AuthorizationRequest {
+    // Sent with the consent form, so the form of an older request cannot answer this one
+    pub id: String,
+    pub user_id: i32,
+    // Public id of the client
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+}
+
+// Tokens an application gets for a code
+pub struct ClientTokens {
+    // Signed JWT which is only accepted by the userinfo endpoint
+    pub access_token: String,
+    pub expires_in: i64,
+    // Only for the openid scope
+    pub id_token: Option<String>,
+    // Space separated scopes the user allowed
+    pub scope: String,
+}
+
+// Authorization server for other applications | Extracted from the app data of the request
+pub struct OAuthService {
+    db: web::Data<Arc<Database>>,
+    jwt: web::Data<JwtConfig>,
+}
+
+impl FromRequest for OAuthService {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let service = (|| {
+            Some(OAuthService {
+                db: req.app_data::<web::Data<Arc<Database>>>()?.clone(),
+                jwt: req.app_data::<web::Data<JwtConfig>>()?.clone(),
+            })
+        })();
+
+        ready(service.ok_or_else(|| ErrorInternalServerError("OAuth service not configured")))
+    }
+}
+
+impl OAuthService {
+    pub fn issuer(&self) -> &str {
+        &self.jwt.issuer
+    }
+
+    // Returns None for unknown and revoked clients
+    pub async fn client(&self, client_id: &str) -> Result<Option<OAuthClient>, OAuthError> {
+        let db = self.db.clone();
+        let client_id = client_id.to_string();
+        Ok(web::block(move || db.get_oauth_client(&client_id)).await??)
+    }
+
+    // Confidential clients have to send their secret | Public clients only prove themselves
+    // with PKCE
+    pub async fn authenticate_client(
+        &self,
+        client_id: &str,
+        client_secret: Option<&str>,
+    ) -> Result<OAuthClient, OAuthError> {
+        let client = self
+            .client(client_id)
+            .await?
+            .ok_or_else(OAuthError::invalid_client)?;
+
+        match (&client.secret_hash, client_secret) {
+            (None, _) => Ok(client),
+            (Some(secret_hash), Some(secret)) if token_matches(secret, secret_hash) => Ok(client),
+            _ => Err(OAuthError::invalid_client()),
+        }
+    }
+
+    // True if the user allowed the client every scope before
+    pub async fn has_consent(
+        &self,
+        user_id: i32,
+        client: &OAuthClient,
+        scopes: &[String],
+    ) -> Result<bool, OAuthError> {
+        let db = self.db.clone();
+        let client_id = client.id;
+        let consent = web::block(move || db.get_consent(user_id, client_id)).await??;
+
+        Ok(
+            consent
+                .is_some_and(|consent| scopes.iter().all(|scope| consent.scopes.contains(scope))),
+        )
+    }
+
+    // Adds the scopes to those the user allowed the client before
+    pub async fn grant_consent(
+        &self,
+        req: &HttpRequest,
+        user: &User,
+        client: &OAuthClient,
+        scopes: &[String],
+    ) -> Result<(), OAuthError> {
+        let db = self.db.clone();
+        let user_id = user.id;
+        let client_id = client.id;
+        let requested = scopes.to_vec();
+        let granted = web::block(move || {
+            let previous = db
+                .get_consent(user_id, client_id)?
+                .map(|consent| consent.scopes)
+                .unwrap_or_default();
+            let granted: Vec<String> = OAUTH_SCOPES
+                .iter()
+                .map(|(scope, _)| scope.to_string())
+                .filter(|scope| previous.contains(scope) || requested.contains(scope))
+                .collect();
+            db.save_consent(user_id, client_id, &granted)?;
+            Ok::<_, DatabaseError>(granted)
+        })
+        .await??;
+
+        audit::record(
+            &self.db,
+            Actor::user(user, req),
+            AuditAction::OAuthConsent,
+            user_target(user.id),
+            serde_json::json!({
+                "client_id": client.client_id,
+                "name": client.name,
+                "scopes": granted,
+            }),
+        )
+        .await;
+        Ok(())
+    }
+
+    // Code for the redirect to the application | Only its hash is stored
+    pub async fn issue_code(
+        &self,
+        session: &Session,
+        client: &OAuthClient,
+        request: &AuthorizationRequest,
+    ) -> Result<String, OAuthError> {
+        let code = generate_token();
+        let auth_time = self.auth_time(session, request.user_id).await;
+        let new_code = NewAuthorizationCode {
+            code_hash: hash_token(&code),
+            client_id: client.id,
+            user_id: request.user_id,
+            redirect_uri: request.redirect_uri.clone(),
+            scopes: request.scopes.clone(),
+            nonce: request.nonce.clone(),
+            code_challenge: request.code_challenge.clone(),
+            auth_time,
+            expires_at: Utc::now().naive_utc() + Duration::seconds(CODE_TTL_SECONDS),
+        };
+
+        let db = self.db.clone();
+        web::block(move || db.create_authorization_code(&new_code)).await??;
+        Ok(code)
+    }
+
+    // Checks the code against the request it was issued for | A code presented a second time
+    // has been stolen or replayed and is recorded in the audit log
+    pub async fn exchange_code(
+        &self,
+        req: &HttpRequest,
+        client: &OAuthClient,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<ClientTokens, OAuthError> {
+        let db = self.db.clone();
+        let code_hash = hash_token(code);
+        let authorization = web::block(move || db.redeem_authorization_code(&code_hash))
+            .await??
+            .ok_or_else(|| OAuthError::invalid_grant("The code is invalid or expired"))?;
+
+        if authorization.used_at.is_some() {
+            warn!(
+                "Authorization code of user {} was used again by client {}",
+                authorization.user_id, client.client_id
+            );
+            audit::record(
+                &self.db,
+                Actor::anonymous(req),
+                AuditAction::OAuthCodeReuse,
+                user_target(authorization.user_id),
+                serde_json::json!({ "client_id": client.client_id }),
+            )
+            .await;
+            return Err(OAuthError::invalid_grant("The code is invalid or expired"));
+        }
+        if authorization.client_id != client.id
+            || authorization.expires_at <= Utc::now().naive_utc()
+        {
+            return Err(OAuthError::invalid_grant("The code is invalid or expired"));
+        }
+        if authorization.redirect_uri != redirect_uri {
+            return Err(OAuthError::invalid_grant(
+                "redirect_uri does not match the authorization request",
+            ));
+        }
+        if pkce_challenge(code_verifier) != authorization.code_challenge {
+            return Err(OAuthError::invalid_grant(
+                "code_verifier does not match the code_challenge",
+            ));
+        }
+
+        let db = self.db.clone();
+        let user_id = authorization.user_id;
+        let user = match web::block(move || db.get_user_by_id(user_id)).await? {
+            Ok(user) => user,
+            Err(DatabaseError::DieselError(diesel::result::Error::NotFound)) => {
+                return Err(OAuthError::invalid_grant("The code is invalid or expired"))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(message) = user.status.login_error() {
+            return Err(OAuthError::invalid_grant(message));
+        }
+
+        let scope = authorization.scopes.join(" ");
+        let access_token = self
+            .jwt
+            .issue_client_access_token(user.id, &client.client_id, &scope)
+            .map_err(OAuthError::server_error)?;
+
+        let id_token = match authorization
+            .scopes
+            .iter()
+            .any(|scope| scope == SCOPE_OPENID)
+        {
+            true => Some(
+                self.jwt
+                    .issue_id_token(
+                        user.id,
+                        &client.client_id,
+                        authorization.auth_time.and_utc().timestamp(),
+                        authorization.nonce,
+                        user_claims(&user, &authorization.scopes),
+                    )
+                    .map_err(OAuthError::server_error)?,
+            ),
+            false => None,
+        };
+
+        Ok(ClientTokens {
+            access_token,
+            expires_in: self.jwt.access_token_ttl.num_seconds(),
+            id_token,
+            scope,
+        })
+    }
+
+    // Claims of the user of a client access token | Tokens stop working once the client or
+    // the consent of the user has been revoked
+    pub async fn userinfo(
+        &self,
+        token: &str,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, TokenError> {
+        let claims = self
+            .jwt
+            .verify_client_access_token(token)
+            .ok_or(TokenError::Invalid)?;
+        let user_id: i32 = claims.sub.parse().map_err(|_| TokenError::Invalid)?;
+
+        let db = self.db.clone();
+        let client_id = claims.client_id.clone();
+        let consent = web::block(move || match db.get_oauth_client(&client_id)? {
+            Some(client) => db.get_consent(user_id, client.id),
+            None => Ok(None),
+        })
+        .await??;
+        if consent.is_none() {
+            return Err(TokenError::Invalid);
+        }
+
+        let db = self.db.clone();
+        let user = match web::block(move || db.get_user_by_id(user_id)).await? {
+            Ok(user) => user,
+            Err(DatabaseError::DieselError(diesel::result::Error::NotFound)) => {
+                return Err(TokenError::Invalid)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(message) = user.status.login_error() {
+            return Err(TokenError::Blocked(message));
+        }
+
+        let scopes: Vec<String> = claims.scope.split(' ').map(String::from).collect();
+        let mut userinfo = serde_json::Map::new();
+        userinfo.insert(String::from("sub"), user.id.to_string().into());
+        userinfo.extend(user_claims(&user, &scopes));
+        Ok(userinfo)
+    }
+
+    // Where the browser is sent back to | The redirect uri may have a query of its own
+    pub fn redirect_location(&self, redirect_uri: &str, params: &[(&str, &str)]) -> String {
+        let mut url = match Url::parse(redirect_uri) {
+            Ok(url) => url,
+            // Redirect uris are checked when a client is registered
+            Err(_) => return redirect_uri.to_string(),
+        };
+        url.query_pairs_mut()
+            .extend_pairs(params)
+            .append_pair("iss", &self.jwt.issuer);
+        url.to_string()
+    }
+
+    // When the user logged in, the time of the request for sessions which are not tracked
+    async fn auth_time(&self, session: &Session, user_id: i32) -> NaiveDateTime {
+        let now = Utc::now().naive_utc();
+        let Some(session_id) = current_session_id(session) else {
+            return now;
+        };
+
+        let db = self.db.clone();
+        match web::block(move || db.get_session(user_id, &session_id)).await {
+            Ok(Ok(Some(info))) => info.created_at,
+            Ok(Ok(None)) => now,
+            Ok(Err(err)) => {
+                error!("Failed to load session: {}", err);
+                now
+            }
+            Err(err) => {
+                error!("Blocking error occurred: {:?}", err);
+                now
+            }
+        }
+    }
+}
+
+// Requested scopes in the order of OAUTH_SCOPES | Unknown scopes are refused instead of dropped
+pub fn parse_scopes(scope: &str) -> Result<Vec<String>, OAuthError> {
+    let requested: Vec<&str> = scope.split_whitespace().collect();
+    if requested.is_empty() {
+        return Err(OAuthError::new("invalid_scope", "No scope requested"));
+    }
+    if let Some(unknown) = requested
+        .iter()
+        .find(|scope| !OAUTH_SCOPES.iter().any(|(known, _)| known == *scope))
+    {
+        return Err(OAuthError::new(
+            "invalid_scope",
+            &format!("Unknown scope {}", unknown),
+        ));
+    }
+
+    Ok(OAUTH_SCOPES
+        .iter()
+        .filter(|(scope, _)| requested.contains(scope))
+        .map(|(scope, _)| scope.to_string())
+        .collect())
+}
+
+// Claims about the user which the scopes allow, for ID tokens and the userinfo endpoint
+fn user_claims(user: &User, scopes: &[String]) -> serde_json::Map<String, serde_json::Value> {
+    let mut claims = serde_json::Map::new();
+    if scopes.iter().any(|scope| scope == SCOPE_PROFILE) {
+        if let Some(display_name) = &user.display_name {
+            claims.insert(String::from("name"), display_name.as_str().into());
+        }
+        if let Some(avatar_url) = &user.avatar_url {
+            claims.insert(String::from("picture"), avatar_url.as_str().into());
+        }
+        claims.insert(String::from("zoneinfo"), user.timezone.as_str().into());
+        if let Some(locale) = &user.locale {
+            claims.insert(String::from("locale"), locale.as_str().into());
+        }
+        claims.insert(
+            String::from("updated_at"),
+            user.updated_at.and_utc().timestamp().into(),
+        );
+    }
+    if scopes.iter().any(|scope| scope == SCOPE_EMAIL) {
+        claims.insert(String::from("email"), user.email.as_str().into());
+        claims.insert(String::from("email_verified"), user.email_verified.into());
+    }
+    claims
+}
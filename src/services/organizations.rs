@@ -0,0 +1,514 @@
+use actix_web::dev::Payload;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, FromRequest, HttpRequest};
+use chrono::Utc;
+use futures_util::future::{ready, Ready};
+use log::info;
+use std::fmt;
+use std::sync::Arc;
+use tera::Context;
+
+use crate::database::db::Database;
+use crate::database::errors::DatabaseError;
+use crate::database::tenant::OrgScope;
+use crate::models::invitations::{Invitation, NewInvitation};
+use crate::models::organizations::{
+    Membership, NewOrganization, OrgRole, Organization, OrganizationBranding,
+};
+use crate::models::users::{normalize_email, Role, User};
+use crate::services::auth::{INVITATION_INVALID, MAX_PENDING_INVITATIONS};
+use crate::utils::audit::{self, organization_target, Actor, AuditAction};
+use crate::utils::auth::ActiveOrganization;
+use crate::utils::i18n::{translate, translate_with};
+use crate::utils::mailer::Mailer;
+use crate::utils::registration::RegistrationConfig;
+use crate::utils::templates::Templates;
+use crate::utils::tokens::{generate_token, hash_token};
+use crate::utils::validation::FieldErrors;
+
+// Most invitations shown on the organization page
+const INVITATION_LIST_LENGTH: i64 = 50;
+
+const NOT_ALLOWED: &str = "Your role in this organization does not allow this";
+
+// Why a change to an organization failed | Views turn these into pages
+#[derive(Debug)]
+pub enum OrganizationError {
+    // Errors per field, e.g. a slug which is taken
+    Invalid(FieldErrors),
+    // The role of the user does not allow the change, or it would leave the organization
+    // without owner | Message for the user
+    Denied(String),
+    Internal(String),
+}
+
+impl fmt::Display for OrganizationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrganizationError::Invalid(errors) => write!(f, "Invalid input: {:?}", errors),
+            OrganizationError::Denied(msg) => write!(f, "{}", msg),
+            OrganizationError::Internal(msg) => write!(f, "Internal error: {}", msg),
+        }
+    }
+}
+
+impl From<DatabaseError> for OrganizationError {
+    fn from(err: DatabaseError) -> Self {
+        match err {
+            DatabaseError::LastOwner(msg) => OrganizationError::Denied(msg),
+            err => OrganizationError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<actix_web::error::BlockingError> for OrganizationError {
+    fn from(err: actix_web::error::BlockingError) -> Self {
+        OrganizationError::Internal(err.to_string())
+    }
+}
+
+// Organizations, their members and invitations | Extracted from the app data of the request
+pub struct OrganizationService {
+    db: web::Data<Arc<Database>>,
+    mailer: web::Data<Mailer>,
+    templates: web::Data<Templates>,
+    registration: web::Data<RegistrationConfig>,
+}
+
+impl FromRequest for OrganizationService {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let service = (|| {
+            Some(OrganizationService {
+                db: req.app_data::<web::Data<Arc<Database>>>()?.clone(),
+                mailer: req.app_data::<web::Data<Mailer>>()?.clone(),
+                templates: req.app_data::<web::Data<Templates>>()?.clone(),
+                registration: req.app_data::<web::Data<RegistrationConfig>>()?.clone(),
+            })
+        })();
+
+        ready(
+            service.ok_or_else(|| ErrorInternalServerError("Organization service not configured")),
+        )
+    }
+}
+
+impl OrganizationService {
+    // Organizations of the user with their role, ordered by name
+    pub async fn memberships(
+        &self,
+        user: &User,
+    ) -> Result<Vec<(Organization, OrgRole)>, OrganizationError> {
+        let db = self.db.clone();
+        let user_id = user.id;
+        Ok(web::block(move || db.get_memberships(user_id)).await??)
+    }
+
+    // Returns None if the user is no member of the organization
+    pub async fn membership(
+        &self,
+        user: &User,
+        organization_id: i32,
+    ) -> Result<Option<(Organization, OrgRole)>, OrganizationError> {
+        let db = self.db.clone();
+        let user_id = user.id;
+        Ok(web::block(move || db.get_membership(organization_id, user_id)).await??)
+    }
+
+    // Name and slug have been checked by the caller | The user becomes the owner
+    pub async fn create(
+        &self,
+        req: &HttpRequest,
+        user: &User,
+        name: &str,
+        slug: &str,
+    ) -> Result<Organization, OrganizationError> {
+        let new_organization = NewOrganization {
+            name: name.trim().to_string(),
+            slug: slug.to_string(),
+        };
+        let db = self.db.clone();
+        let owner_id = user.id;
+        let result =
+            web::block(move || db.create_organization(&new_organization, owner_id)).await?;
+
+        let organization = match result {
+            Ok(organization) => organization,
+            Err(DatabaseError::SlugTaken(msg)) => {
+                let mut errors = FieldErrors::new();
+                errors.entry(String::from("slug")).or_default().push(msg);
+                return Err(OrganizationError::Invalid(errors));
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        info!("{} created organization {}", user.email, organization.slug);
+        audit::record(
+            &self.db,
+            Actor::user(user, req),
+            AuditAction::OrganizationCreate,
+            organization_target(organization.id),
+            serde_json::json!({ "name": organization.name, "slug": organization.slug }),
+        )
+        .await;
+        Ok(organization)
+    }
+
+    // Members with their email, ordered by email
+    pub async fn members(
+        &self,
+        organization: &ActiveOrganization,
+    ) -> Result<Vec<(Membership, String)>, OrganizationError> {
+        let db = self.db.clone();
+        let scope = organization.scope;
+        Ok(web::block(move || db.get_members(scope)).await??)
+    }
+
+    // Newest first
+    pub async fn invitations(
+        &self,
+        organization: &ActiveOrganization,
+    ) -> Result<Vec<Invitation>, OrganizationError> {
+        let db = self.db.clone();
+        let scope = organization.scope;
+        Ok(
+            web::block(move || db.get_organization_invitations(scope, INVITATION_LIST_LENGTH))
+                .await??,
+        )
+    }
+
+    // Addresses with an account get a link to join, others a link to register which adds them
+    // to the organization | Only the latter depend on the registration mode
+    pub async fn invite(
+        &self,
+        req: &HttpRequest,
+        organization: &ActiveOrganization,
+        email: &str,
+        role: OrgRole,
+    ) -> Result<Invitation, OrganizationError> {
+        let inviter = &organization.user;
+        if !organization.role.can_manage_members() || !organization.role.can_assign(role) {
+            return Err(OrganizationError::Denied(String::from(NOT_ALLOWED)));
+        }
+
+        let db = self.db.clone();
+        let mail = normalize_email(email);
+        let organization_id = organization.organization.id;
+        let inviter_id = inviter.id;
+        let (member, registered, locale, pending) = web::block(move || {
+            let (member, registered, locale) = match db.get_user_by_email(&mail) {
+                Ok(user) => (
+                    db.get_membership(organization_id, user.id)?.is_some(),
+                    true,
+                    user.locale,
+                ),
+                Err(DatabaseError::DieselError(diesel::result::Error::NotFound)) => {
+                    (false, false, None)
+                }
+                Err(err) => return Err(err),
+            };
+            Ok((
+                member,
+                registered,
+                locale,
+                db.count_pending_invitations(inviter_id)?,
+            ))
+        })
+        .await??;
+
+        // Checked first, so the limit also holds for probing which addresses have accounts
+        let mut errors = FieldErrors::new();
+        if pending >= MAX_PENDING_INVITATIONS && inviter.role != Role::Admin {
+            errors
+                .entry(String::from("email"))
+                .or_default()
+                .push(translate(
+                    "You have too many open invitations, please revoke some first",
+                ));
+        } else if member {
+            errors
+                .entry(String::from("email"))
+                .or_default()
+                .push(translate("This address is a member already"));
+        }
+        if !errors.is_empty() {
+            return Err(OrganizationError::Invalid(errors));
+        }
+
+        let token = generate_token();
+        let new_invitation = NewInvitation {
+            token_hash: hash_token(&token),
+            email: normalize_email(email),
+            invited_by: Some(inviter.id),
+            expires_at: Utc::now().naive_utc() + self.registration.invitation_ttl,
+            organization_role: Some(role),
+        };
+        let db = self.db.clone();
+        let scope = organization.scope;
+        let invitation =
+            web::block(move || db.create_invitation(Some(scope), &new_invitation)).await??;
+
+        info!(
+            "{} invited {} to organization {}",
+            inviter.email, invitation.email, organization.organization.slug
+        );
+        audit::record(
+            &self.db,
+            Actor::user(inviter, req),
+            AuditAction::InvitationCreate,
+            organization_target(organization_id),
+            serde_json::json!({
+                "invitation_id": invitation.id,
+                "email": invitation.email,
+                "role": role.as_str(),
+            }),
+        )
+        .await;
+
+        // Created and listed like any other invitation, so the inviter cannot tell whether the
+        // address has an account | Not mailed, nobody can register with it
+        if !registered && !self.registration.mode.allows_invitations() {
+            info!(
+                "Not mailing invitation for {} while registration is closed",
+                invitation.email
+            );
+            return Ok(invitation);
+        }
+
+        let path = if registered {
+            format!("/organizations/join?token={}", token)
+        } else {
+            format!("/register?invitation={}", token)
+        };
+        let mut context = Context::new();
+        context.insert("email", &invitation.email);
+        context.insert("inviter", &inviter.email);
+        context.insert("organization", &organization.organization.name);
+        context.insert("role", role.as_str());
+        context.insert("registered", &registered);
+        context.insert("path", &path);
+        context.insert("days", &self.registration.invitation_ttl.num_days());
+        Mailer::send_template(
+            &self.mailer,
+            &self.templates,
+            &invitation.email,
+            locale.as_deref(),
+            "Join {organization}",
+            "emails/organization_invitation.txt",
+            &context,
+        );
+        Ok(invitation)
+    }
+
+    // Values have been checked by the caller | Empty values fall back to the defaults
+    pub async fn update_branding(
+        &self,
+        req: &HttpRequest,
+        organization: &ActiveOrganization,
+        logo_url: &str,
+        primary_color: &str,
+        accent_color: &str,
+    ) -> Result<Organization, OrganizationError> {
+        if !organization.role.can_change_branding() {
+            return Err(OrganizationError::Denied(String::from(NOT_ALLOWED)));
+        }
+
+        let optional = |value: &str| match value.trim() {
+            "" => None,
+            value => Some(value.to_string()),
+        };
+        let branding = OrganizationBranding {
+            logo_url: optional(logo_url),
+            primary_color: optional(primary_color).map(|color| color.to_lowercase()),
+            accent_color: optional(accent_color).map(|color| color.to_lowercase()),
+        };
+        let db = self.db.clone();
+        let scope = organization.scope;
+        let updated =
+            web::block(move || db.update_organization_branding(scope, &branding)).await??;
+
+        audit::record(
+            &self.db,
+            Actor::user(&organization.user, req),
+            AuditAction::OrganizationBrandingChange,
+            organization_target(updated.id),
+            serde_json::json!({
+                "logo_url": updated.logo_url,
+                "primary_color": updated.primary_color,
+                "accent_color": updated.accent_color,
+            }),
+        )
+        .await;
+        Ok(updated)
+    }
+
+    // Returns None if the invitation cannot be revoked anymore
+    pub async fn revoke_invitation(
+        &self,
+        req: &HttpRequest,
+        organization: &ActiveOrganization,
+        invitation_id: i32,
+    ) -> Result<Option<Invitation>, OrganizationError> {
+        if !organization.role.can_manage_members() {
+            return Err(OrganizationError::Denied(String::from(NOT_ALLOWED)));
+        }
+
+        let db = self.db.clone();
+        let scope = organization.scope;
+        let invitation =
+            web::block(move || db.revoke_organization_invitation(scope, invitation_id)).await??;
+
+        if let Some(invitation) = &invitation {
+            audit::record(
+                &self.db,
+                Actor::user(&organization.user, req),
+                AuditAction::InvitationRevoke,
+                organization_target(organization.organization.id),
+                serde_json::json!({ "invitation_id": invitation.id, "email": invitation.email }),
+            )
+            .await;
+        }
+        Ok(invitation)
+    }
+
+    // Adds the user to the organization of the invitation | Only for the address it was sent to
+    pub async fn join(
+        &self,
+        req: &HttpRequest,
+        user: &User,
+        token: &str,
+    ) -> Result<Organization, OrganizationError> {
+        let db = self.db.clone();
+        let token_hash = hash_token(token);
+        let invitation = web::block(move || db.get_invitation(&token_hash))
+            .await??
+            .filter(|invitation| invitation.organization_id.is_some())
+            .ok_or_else(|| OrganizationError::Denied(String::from(INVITATION_INVALID)))?;
+
+        if invitation.email != user.email {
+            return Err(OrganizationError::Denied(translate_with(
+                "This invitation was sent to {email}, please log in with that address",
+                &[("email", &invitation.email)],
+            )));
+        }
+
+        let db = self.db.clone();
+        let token_hash = hash_token(token);
+        let email = user.email.clone();
+        let user_id = user.id;
+        let verified = user.email_verified;
+        let membership = web::block(move || {
+            match db.accept_organization_invitation(&token_hash, &email, user_id)? {
+                Some(invitation) => {
+                    // The invitation was mailed to the address of the account
+                    if !verified {
+                        db.mark_email_verified(user_id)?;
+                    }
+                    let organization_id = invitation.organization_id.unwrap_or_default();
+                    Ok(db.get_membership(organization_id, user_id)?)
+                }
+                None => Ok::<_, DatabaseError>(None),
+            }
+        })
+        .await??;
+        let (organization, role) = membership
+            .ok_or_else(|| OrganizationError::Denied(String::from(INVITATION_INVALID)))?;
+
+        info!("{} joined organization {}", user.email, organization.slug);
+        audit::record(
+            &self.db,
+            Actor::user(user, req),
+            AuditAction::OrganizationJoin,
+            organization_target(organization.id),
+            serde_json::json!({ "invitation_id": invitation.id, "role": role.as_str() }),
+        )
+        .await;
+        Ok(organization)
+    }
+
+    // Returns None if the user is no member | Admins cannot change owners or make someone owner
+    pub async fn change_role(
+        &self,
+        req: &HttpRequest,
+        organization: &ActiveOrganization,
+        user_id: i32,
+        role: OrgRole,
+    ) -> Result<Option<Membership>, OrganizationError> {
+        let Some(current) = self.member_role(organization.scope, user_id).await? else {
+            return Ok(None);
+        };
+        if !organization.role.can_manage_members()
+            || !organization.role.can_assign(current)
+            || !organization.role.can_assign(role)
+        {
+            return Err(OrganizationError::Denied(String::from(NOT_ALLOWED)));
+        }
+
+        let db = self.db.clone();
+        let scope = organization.scope;
+        let membership = web::block(move || db.change_member_role(scope, user_id, role)).await??;
+
+        if let Some(membership) = &membership {
+            audit::record(
+                &self.db,
+                Actor::user(&organization.user, req),
+                AuditAction::MemberRoleChange,
+                organization_target(organization.organization.id),
+                serde_json::json!({
+                    "user_id": membership.user_id,
+                    "old_role": current.as_str(),
+                    "new_role": role.as_str(),
+                }),
+            )
+            .await;
+        }
+        Ok(membership)
+    }
+
+    // Every member can leave | Removing others needs a role which could assign theirs
+    pub async fn remove_member(
+        &self,
+        req: &HttpRequest,
+        organization: &ActiveOrganization,
+        user_id: i32,
+    ) -> Result<Option<Membership>, OrganizationError> {
+        let Some(current) = self.member_role(organization.scope, user_id).await? else {
+            return Ok(None);
+        };
+        let leaving = user_id == organization.user.id;
+        if !leaving
+            && (!organization.role.can_manage_members() || !organization.role.can_assign(current))
+        {
+            return Err(OrganizationError::Denied(String::from(NOT_ALLOWED)));
+        }
+
+        let db = self.db.clone();
+        let scope = organization.scope;
+        let membership = web::block(move || db.remove_member(scope, user_id)).await??;
+
+        if let Some(membership) = &membership {
+            audit::record(
+                &self.db,
+                Actor::user(&organization.user, req),
+                AuditAction::MemberRemove,
+                organization_target(organization.organization.id),
+                serde_json::json!({ "user_id": membership.user_id, "role": current.as_str() }),
+            )
+            .await;
+        }
+        Ok(membership)
+    }
+
+    async fn member_role(
+        &self,
+        scope: OrgScope,
+        user_id: i32,
+    ) -> Result<Option<OrgRole>, OrganizationError> {
+        let db = self.db.clone();
+        let membership =
+            web::block(move || db.get_membership(scope.organization_id(), user_id)).await??;
+        Ok(membership.map(|(_, role)| role))
+    }
+}
@@ -0,0 +1,46 @@
+use actix_web::web;
+use tera::Context;
+
+use crate::database::db::Database;
+use crate::database::errors::DatabaseError;
+use crate::utils::mailer::Mailer;
+use crate::utils::templates::Templates;
+use crate::utils::tokens::{generate_token, hash_token, EMAIL_VERIFICATION};
+
+// Verification links are valid for three days
+const VERIFICATION_TTL_SECONDS: usize = 3 * 86400;
+
+// Stores a new verification token for the user and returns it | Blocking
+pub fn create_verification_token(db: &Database, user_id: i32) -> Result<String, DatabaseError> {
+    let token = generate_token();
+    db.store_token(
+        EMAIL_VERIFICATION,
+        &hash_token(&token),
+        &user_id,
+        VERIFICATION_TTL_SECONDS,
+    )?;
+
+    Ok(token)
+}
+
+pub fn send_verification_mail(
+    mailer: &web::Data<Mailer>,
+    tera: &web::Data<Templates>,
+    email: &str,
+    locale: Option<&str>,
+    token: &str,
+) {
+    let mut context = Context::new();
+    context.insert("email", email);
+    context.insert("token", token);
+
+    Mailer::send_template(
+        mailer,
+        tera,
+        email,
+        locale,
+        "Confirm your email address",
+        "emails/verify_email.txt",
+        &context,
+    );
+}
@@ -3,8 +3,13 @@ use argon2::{
     Argon2,
 };
 use log::error;
+use rand::distributions::{Alphanumeric, DistString};
+use std::sync::OnceLock;
 
-pub fn hash_password(password: &String) -> Result<String, argon2::password_hash::Error> {
+// Hash of a random password with the same parameters as real ones
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
 
@@ -12,17 +17,16 @@ pub fn hash_password(password: &String) -> Result<String, argon2::password_hash:
     Ok(password_hash.to_string())
 }
 
-pub fn verify_password(password: &String, hash: &String) -> bool {
+pub fn verify_password(password: &str, hash: &str) -> bool {
     // Convert hash into PasswordHash type
-    let parsed_hash = PasswordHash::new(&hash);
+    let parsed_hash = PasswordHash::new(hash);
 
     match parsed_hash {
         Ok(password_hash) => {
             // Verify hash
-            match Argon2::default().verify_password(password.as_bytes(), &password_hash) {
-                Ok(_) => true,
-                Err(_) => false,
-            }
+            Argon2::default()
+                .verify_password(password.as_bytes(), &password_hash)
+                .is_ok()
         }
         Err(err) => {
             error!("Error parsing hash: {}", err);
@@ -30,3 +34,17 @@ pub fn verify_password(password: &String, hash: &String) -> bool {
         }
     }
 }
+
+// Returns the dummy hash, computing it on first use
+pub fn dummy_hash() -> &'static str {
+    DUMMY_HASH.get_or_init(|| {
+        let password = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        hash_password(&password).expect("Failed to hash dummy password")
+    })
+}
+
+// Costs as much as verify_password | Used when there is no user so response times do not
+// reveal whether an email is registered
+pub fn verify_dummy_password(password: &str) {
+    verify_password(password, dummy_hash());
+}
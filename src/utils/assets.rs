@@ -0,0 +1,318 @@
+use actix_web::http::header::{
+    CacheControl, CacheDirective, ContentType, ETag, EntityTag, LastModified,
+};
+use actix_web::mime::Mime;
+use actix_web::{web, HttpResponse};
+use log::{error, info};
+use notify::RecommendedWatcher;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use super::watcher;
+
+// Files compiled into the binary | Debug builds read them from the filesystem instead
+#[cfg(feature = "embed-static")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "static/"]
+#[include = "css/**/*"]
+#[include = "js/**/*"]
+struct StaticAssets;
+
+#[cfg(feature = "embed-static")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "static/templates/"]
+struct TemplateAssets;
+
+#[cfg(feature = "embed-static")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "static/locales/"]
+struct LocaleAssets;
+
+// Directories below the static path which are served as assets
+const ASSET_DIRS: [&str; 2] = ["css", "js"];
+
+// One year, fingerprinted assets never change
+const MAX_AGE: u32 = 31_536_000;
+
+// Returns the name and content of every embedded template
+#[cfg(feature = "embed-static")]
+pub fn templates() -> Vec<(String, String)> {
+    TemplateAssets::iter()
+        .filter_map(|name| {
+            let file = TemplateAssets::get(&name)?;
+            let content = String::from_utf8(file.data.into_owned()).ok()?;
+            Some((name.to_string(), content))
+        })
+        .collect()
+}
+
+// Content of an embedded gettext catalog, e.g. de.po
+#[cfg(feature = "embed-static")]
+pub fn locale(name: &str) -> Option<String> {
+    let file = LocaleAssets::get(name)?;
+    String::from_utf8(file.data.into_owned()).ok()
+}
+
+// Directory the embedded files are read from in debug builds
+#[cfg(feature = "embed-static")]
+pub fn embedded_dir() -> &'static str {
+    concat!(env!("CARGO_MANIFEST_DIR"), "/static")
+}
+
+// Where assets are loaded from
+enum AssetSource {
+    // Static path containing the css and js directories
+    #[cfg(not(feature = "embed-static"))]
+    Directory(String),
+    #[cfg(feature = "embed-static")]
+    Embedded,
+}
+
+impl AssetSource {
+    // Returns all asset files with their path relative to the static path, e.g. css/base.css
+    fn files(&self) -> std::io::Result<Vec<AssetFile>> {
+        match self {
+            #[cfg(not(feature = "embed-static"))]
+            AssetSource::Directory(static_path) => {
+                let mut files = Vec::new();
+                for dir in ASSET_DIRS {
+                    let path = Path::new(static_path).join(dir);
+                    if path.is_dir() {
+                        read_dir_recursive(&path, dir, &mut files)?;
+                    }
+                }
+                Ok(files)
+            }
+            #[cfg(feature = "embed-static")]
+            AssetSource::Embedded => Ok(StaticAssets::iter()
+                .filter_map(|name| {
+                    let file = StaticAssets::get(&name)?;
+                    let modified = file
+                        .metadata
+                        .last_modified()
+                        .map(|secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+                    Some((name.to_string(), file.data.into_owned(), modified))
+                })
+                .collect()),
+        }
+    }
+}
+
+#[cfg(not(feature = "embed-static"))]
+fn read_dir_recursive(dir: &Path, prefix: &str, files: &mut Vec<AssetFile>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = format!("{}/{}", prefix, path.file_name().unwrap().to_string_lossy());
+        if path.is_dir() {
+            read_dir_recursive(&path, &name, files)?;
+        } else {
+            let modified = path.metadata()?.modified().ok();
+            files.push((name, std::fs::read(&path)?, modified));
+        }
+    }
+    Ok(())
+}
+
+// Path relative to the static path, content and modification time
+type AssetFile = (String, Vec<u8>, Option<SystemTime>);
+
+pub struct Asset {
+    data: Vec<u8>,
+    etag: EntityTag,
+    mime: Mime,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Default)]
+struct Manifest {
+    // Path relative to the static path, e.g. css/base.css, to its url, e.g. /assets/css/base.<hash>.css
+    urls: HashMap<String, String>,
+    // Assets by path relative to the static path
+    #[cfg(feature = "embed-static")]
+    by_path: HashMap<String, Arc<Asset>>,
+    // Assets by fingerprinted name, e.g. css/base.<hash>.css
+    by_fingerprint: HashMap<String, Arc<Asset>>,
+}
+
+impl Manifest {
+    fn build(files: Vec<AssetFile>) -> Self {
+        let mut manifest = Manifest::default();
+
+        for (path, data, modified) in files {
+            let hash = blake3::hash(&data).to_hex().to_string();
+            let name = fingerprint(&path, &hash[..16]);
+
+            let asset = Arc::new(Asset {
+                mime: mime_guess::from_path(&path).first_or_octet_stream(),
+                etag: EntityTag::new_strong(hash),
+                modified,
+                data,
+            });
+
+            manifest
+                .urls
+                .insert(path.clone(), format!("/assets/{}", name));
+            #[cfg(feature = "embed-static")]
+            manifest.by_path.insert(path, asset.clone());
+            manifest.by_fingerprint.insert(name, asset);
+        }
+
+        manifest
+    }
+}
+
+// Inserts the hash before the extension | The directory is kept, so names never collide
+// e.g. css/base.css -> css/base.<hash>.css
+fn fingerprint(path: &str, hash: &str) -> String {
+    let (directory, name) = match path.rsplit_once('/') {
+        Some((directory, name)) => (format!("{}/", directory), name),
+        None => (String::new(), path),
+    };
+
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}{}.{}.{}", directory, stem, hash, extension)
+        }
+        _ => format!("{}{}.{}", directory, name, hash),
+    }
+}
+
+// Content hashed copy of css and js shared by all workers
+pub struct Assets {
+    source: AssetSource,
+    manifest: RwLock<Manifest>,
+}
+
+impl Assets {
+    #[cfg(not(feature = "embed-static"))]
+    pub fn new(static_path: &str) -> std::io::Result<Self> {
+        Self::from_source(AssetSource::Directory(static_path.to_string()))
+    }
+
+    // Uses the assets compiled into the binary
+    #[cfg(feature = "embed-static")]
+    pub fn embedded() -> std::io::Result<Self> {
+        Self::from_source(AssetSource::Embedded)
+    }
+
+    fn from_source(source: AssetSource) -> std::io::Result<Self> {
+        let manifest = Manifest::build(source.files()?);
+
+        Ok(Assets {
+            source,
+            manifest: RwLock::new(manifest),
+        })
+    }
+
+    // Reads and hashes all assets again
+    pub fn reload(&self) -> std::io::Result<()> {
+        let manifest = Manifest::build(self.source.files()?);
+        *self.manifest.write().expect("Asset lock poisoned") = manifest;
+
+        Ok(())
+    }
+
+    // Returns the fingerprinted url of an asset, e.g. css/base.css -> /assets/css/base.<hash>.css
+    pub fn url(&self, path: &str) -> Option<String> {
+        let manifest = self.manifest.read().expect("Asset lock poisoned");
+        manifest.urls.get(path.trim_start_matches('/')).cloned()
+    }
+
+    fn by_fingerprint(&self, name: &str) -> Option<Arc<Asset>> {
+        let manifest = self.manifest.read().expect("Asset lock poisoned");
+        manifest.by_fingerprint.get(name).cloned()
+    }
+
+    #[cfg(feature = "embed-static")]
+    fn by_path(&self, path: &str) -> Option<Arc<Asset>> {
+        let manifest = self.manifest.read().expect("Asset lock poisoned");
+        manifest.by_path.get(path).cloned()
+    }
+
+    // Tera function returning the fingerprinted url, used as {{ asset(path="css/base.css") | safe }}
+    pub fn tera_function(assets: web::Data<Assets>) -> impl tera::Function {
+        move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+            let path = args
+                .get("path")
+                .and_then(|path| path.as_str())
+                .ok_or_else(|| tera::Error::msg("asset() requires a `path` argument"))?;
+
+            match assets.url(path) {
+                Some(url) => Ok(tera::Value::String(url)),
+                None => Err(tera::Error::msg(format!("Unknown asset '{}'", path))),
+            }
+        }
+    }
+
+    // Rehashes the assets whenever a file below the static path changes
+    // The returned watcher stops watching once it is dropped
+    pub fn watch(
+        assets: web::Data<Assets>,
+        static_path: &Path,
+    ) -> notify::Result<RecommendedWatcher> {
+        let dirs: Vec<_> = ASSET_DIRS.iter().map(|dir| static_path.join(dir)).collect();
+        let dirs: Vec<&Path> = dirs.iter().map(|dir| dir.as_path()).collect();
+
+        watcher::watch(&dirs, move || match assets.reload() {
+            Ok(_) => info!("Reloaded assets"),
+            Err(err) => error!("Failed to reload assets: {}", err),
+        })
+    }
+}
+
+pub fn register_urls(cfg: &mut web::ServiceConfig) {
+    cfg.route("/assets/{name:.*}", web::get().to(fingerprinted));
+
+    // Unversioned urls are only served from here when embedded, otherwise actix_files handles them
+    #[cfg(feature = "embed-static")]
+    cfg.route("/css/{path:.*}", web::get().to(css))
+        .route("/js/{path:.*}", web::get().to(js));
+}
+
+async fn fingerprinted(assets: web::Data<Assets>, name: web::Path<String>) -> HttpResponse {
+    let cache_control = CacheControl(vec![
+        CacheDirective::Public,
+        CacheDirective::MaxAge(MAX_AGE),
+        CacheDirective::Extension("immutable".to_string(), None),
+    ]);
+
+    match assets.by_fingerprint(&name) {
+        Some(asset) => serve(&asset, cache_control),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(feature = "embed-static")]
+async fn css(assets: web::Data<Assets>, path: web::Path<String>) -> HttpResponse {
+    unversioned(&assets, &format!("css/{}", path.into_inner()))
+}
+
+#[cfg(feature = "embed-static")]
+async fn js(assets: web::Data<Assets>, path: web::Path<String>) -> HttpResponse {
+    unversioned(&assets, &format!("js/{}", path.into_inner()))
+}
+
+// Content behind unversioned urls can change, so clients have to revalidate using the ETag
+#[cfg(feature = "embed-static")]
+fn unversioned(assets: &Assets, path: &str) -> HttpResponse {
+    match assets.by_path(path) {
+        Some(asset) => serve(&asset, CacheControl(vec![CacheDirective::NoCache])),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+fn serve(asset: &Asset, cache_control: CacheControl) -> HttpResponse {
+    // Conditional requests are answered by the conditional_get middleware
+    let mut res = HttpResponse::Ok();
+    res.insert_header(ContentType(asset.mime.clone()))
+        .insert_header(ETag(asset.etag.clone()))
+        .insert_header(cache_control);
+
+    if let Some(modified) = asset.modified {
+        res.insert_header(LastModified(modified.into()));
+    }
+
+    res.body(asset.data.clone())
+}
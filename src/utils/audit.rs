@@ -0,0 +1,206 @@
+use actix_web::{web, HttpRequest};
+use log::error;
+use std::sync::Arc;
+
+use crate::database::db::Database;
+use crate::models::audit::AuditRecord;
+use crate::models::users::User;
+use crate::utils::sessions::client_ip;
+
+// Security relevant actions which end up in the audit log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditAction {
+    Register,
+    VerifyEmail,
+    Login,
+    LoginFailed,
+    Logout,
+    MagicLinkRequest,
+    ProfileUpdate,
+    PasswordChange,
+    EmailChangeRequest,
+    EmailChange,
+    AccountDelete,
+    AccountPurge,
+    SessionRevoke,
+    SessionRevokeOthers,
+    IdentityLink,
+    IdentityUnlink,
+    InvitationCreate,
+    InvitationRevoke,
+    OrganizationCreate,
+    OrganizationJoin,
+    MemberRoleChange,
+    MemberRemove,
+    OrganizationBrandingChange,
+    TokenCreate,
+    TokenRevoke,
+    RefreshTokenRevoke,
+    RefreshTokenReuse,
+    OAuthConsent,
+    OAuthConsentRevoke,
+    OAuthCodeReuse,
+    StatusChange,
+    RoleChange,
+    OAuthClientCreate,
+    OAuthClientRevoke,
+    AuditExport,
+}
+
+pub const AUDIT_ACTIONS: [AuditAction; 35] = [
+    AuditAction::Register,
+    AuditAction::VerifyEmail,
+    AuditAction::Login,
+    AuditAction::LoginFailed,
+    AuditAction::Logout,
+    AuditAction::MagicLinkRequest,
+    AuditAction::ProfileUpdate,
+    AuditAction::PasswordChange,
+    AuditAction::EmailChangeRequest,
+    AuditAction::EmailChange,
+    AuditAction::AccountDelete,
+    AuditAction::AccountPurge,
+    AuditAction::SessionRevoke,
+    AuditAction::SessionRevokeOthers,
+    AuditAction::IdentityLink,
+    AuditAction::IdentityUnlink,
+    AuditAction::InvitationCreate,
+    AuditAction::InvitationRevoke,
+    AuditAction::OrganizationCreate,
+    AuditAction::OrganizationJoin,
+    AuditAction::MemberRoleChange,
+    AuditAction::MemberRemove,
+    AuditAction::OrganizationBrandingChange,
+    AuditAction::TokenCreate,
+    AuditAction::TokenRevoke,
+    AuditAction::RefreshTokenRevoke,
+    AuditAction::RefreshTokenReuse,
+    AuditAction::OAuthConsent,
+    AuditAction::OAuthConsentRevoke,
+    AuditAction::OAuthCodeReuse,
+    AuditAction::StatusChange,
+    AuditAction::RoleChange,
+    AuditAction::OAuthClientCreate,
+    AuditAction::OAuthClientRevoke,
+    AuditAction::AuditExport,
+];
+
+impl AuditAction {
+    // Stored in the log | Never rename these, old entries keep the old name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Register => "user.register",
+            AuditAction::VerifyEmail => "user.verify_email",
+            AuditAction::Login => "user.login",
+            AuditAction::LoginFailed => "user.login_failed",
+            AuditAction::Logout => "user.logout",
+            AuditAction::MagicLinkRequest => "user.magic_link_request",
+            AuditAction::ProfileUpdate => "user.profile_update",
+            AuditAction::PasswordChange => "user.password_change",
+            AuditAction::EmailChangeRequest => "user.email_change_request",
+            AuditAction::EmailChange => "user.email_change",
+            AuditAction::AccountDelete => "user.delete",
+            AuditAction::AccountPurge => "user.purge",
+            AuditAction::SessionRevoke => "session.revoke",
+            AuditAction::SessionRevokeOthers => "session.revoke_others",
+            AuditAction::IdentityLink => "identity.link",
+            AuditAction::IdentityUnlink => "identity.unlink",
+            AuditAction::InvitationCreate => "invitation.create",
+            AuditAction::InvitationRevoke => "invitation.revoke",
+            AuditAction::OrganizationCreate => "organization.create",
+            AuditAction::OrganizationJoin => "organization.join",
+            AuditAction::MemberRoleChange => "organization.member_role_change",
+            AuditAction::MemberRemove => "organization.member_remove",
+            AuditAction::OrganizationBrandingChange => "organization.branding_change",
+            AuditAction::TokenCreate => "api_token.create",
+            AuditAction::TokenRevoke => "api_token.revoke",
+            AuditAction::RefreshTokenRevoke => "refresh_token.revoke",
+            AuditAction::RefreshTokenReuse => "refresh_token.reuse",
+            AuditAction::OAuthConsent => "oauth.consent",
+            AuditAction::OAuthConsentRevoke => "oauth.consent_revoke",
+            AuditAction::OAuthCodeReuse => "oauth.code_reuse",
+            AuditAction::StatusChange => "admin.status_change",
+            AuditAction::RoleChange => "admin.role_change",
+            AuditAction::OAuthClientCreate => "admin.oauth_client_create",
+            AuditAction::OAuthClientRevoke => "admin.oauth_client_revoke",
+            AuditAction::AuditExport => "admin.audit_export",
+        }
+    }
+}
+
+// Who did something | Email and ip are copied into the entry, it has to stay readable
+// after the account is gone
+pub struct Actor {
+    pub user_id: Option<i32>,
+    pub email: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl Actor {
+    pub fn user(user: &User, req: &HttpRequest) -> Self {
+        Actor {
+            user_id: Some(user.id),
+            email: Some(user.email.clone()),
+            ip: Some(client_ip(req)),
+        }
+    }
+
+    // Visitor who is not logged in
+    pub fn anonymous(req: &HttpRequest) -> Self {
+        Actor {
+            user_id: None,
+            email: None,
+            ip: Some(client_ip(req)),
+        }
+    }
+
+    // Background jobs
+    pub fn system() -> Self {
+        Actor {
+            user_id: None,
+            email: None,
+            ip: None,
+        }
+    }
+}
+
+// Target of actions on accounts
+pub fn user_target(user_id: i32) -> Option<String> {
+    Some(format!("user:{}", user_id))
+}
+
+// Target of actions on organizations and their members
+pub fn organization_target(organization_id: i32) -> Option<String> {
+    Some(format!("organization:{}", organization_id))
+}
+
+// Target of actions on OAuth clients
+pub fn client_target(client_id: &str) -> Option<String> {
+    Some(format!("oauth_client:{}", client_id))
+}
+
+// Appends an entry to the audit log | The action has already happened at this point,
+// so failures are logged instead of failing the request
+pub async fn record(
+    db: &Arc<Database>,
+    actor: Actor,
+    action: AuditAction,
+    target: Option<String>,
+    metadata: serde_json::Value,
+) {
+    let record = AuditRecord {
+        actor_id: actor.user_id,
+        actor_email: actor.email,
+        ip: actor.ip,
+        action: action.as_str(),
+        target,
+        metadata,
+    };
+
+    let db = db.clone();
+    match web::block(move || db.append_audit_entry(&record)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(err)) => error!("Failed to write audit log entry: {}", err),
+        Err(err) => error!("Blocking error occurred: {:?}", err),
+    }
+}
@@ -0,0 +1,250 @@
+use actix_session::{Session, SessionExt};
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, InternalError};
+use actix_web::http::header::LOCATION;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use log::error;
+use std::sync::Arc;
+
+use crate::database::db::Database;
+use crate::database::errors::DatabaseError;
+use crate::database::tenant::OrgScope;
+use crate::get_user_id_from_session;
+use crate::models::organizations::{OrgRole, Organization};
+use crate::models::users::{Role, User};
+use crate::utils::flash::{FlashMessages, Level};
+use crate::utils::tenants::Tenant;
+
+// Session key of the page to go back to after logging in, e.g. the authorization request of
+// an OAuth application
+const RETURN_TO_KEY: &str = "return_to";
+
+// Session key of the organization the user works in
+const ORGANIZATION_KEY: &str = "organization_id";
+
+// Logged in user, loaded for every request which uses it as extractor
+// Redirects to /login if there is no session, its user no longer exists or is not active
+pub struct CurrentUser(pub User);
+
+impl std::ops::Deref for CurrentUser {
+    type Target = User;
+
+    fn deref(&self) -> &User {
+        &self.0
+    }
+}
+
+impl FromRequest for CurrentUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let session = req.get_session();
+        let db = req.app_data::<web::Data<Arc<Database>>>().cloned();
+
+        Box::pin(async move {
+            match session_user(&session, db).await {
+                Ok(user) => Ok(CurrentUser(user)),
+                Err(SessionUserError::NotLoggedIn) => Err(redirect_to_login()),
+                // The fresh session only carries the reason to the login page
+                Err(SessionUserError::Blocked(message)) => {
+                    FlashMessages::new(&session).push(Level::Error, message);
+                    Err(redirect_to_login())
+                }
+                Err(SessionUserError::Internal) => {
+                    Err(ErrorInternalServerError("Failed to load user"))
+                }
+            }
+        })
+    }
+}
+
+// Why a request has no usable user
+pub enum SessionUserError {
+    // No session or its user no longer exists
+    NotLoggedIn,
+    // The account may not log in anymore | Message for the user
+    Blocked(&'static str),
+    Internal,
+}
+
+// Loads the user of the session | Sessions whose user is gone or not active are signed out
+pub async fn session_user(
+    session: &Session,
+    db: Option<web::Data<Arc<Database>>>,
+) -> Result<User, SessionUserError> {
+    let user_id = get_user_id_from_session!(session).ok_or(SessionUserError::NotLoggedIn)?;
+    let db = db.ok_or_else(|| {
+        error!("Database not configured");
+        SessionUserError::Internal
+    })?;
+
+    match web::block(move || db.get_user_by_id(user_id)).await {
+        Ok(Ok(user)) => match user.status.login_error() {
+            None => Ok(user),
+            Some(message) => {
+                session.clear();
+                session.renew();
+                Err(SessionUserError::Blocked(message))
+            }
+        },
+        Ok(Err(DatabaseError::DieselError(diesel::result::Error::NotFound))) => {
+            session.purge();
+            Err(SessionUserError::NotLoggedIn)
+        }
+        Ok(Err(err)) => {
+            error!("{}", err);
+            Err(SessionUserError::Internal)
+        }
+        Err(err) => {
+            error!("Blocking error occurred: {:?}", err);
+            Err(SessionUserError::Internal)
+        }
+    }
+}
+
+// Logged in user with the admin role | Everyone else gets 403 Forbidden
+pub struct AdminUser(pub User);
+
+impl std::ops::Deref for AdminUser {
+    type Target = User;
+
+    fn deref(&self) -> &User {
+        &self.0
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = CurrentUser::from_request(req, payload);
+
+        Box::pin(async move {
+            let CurrentUser(user) = user.await?;
+            match user.role {
+                Role::Admin => Ok(AdminUser(user)),
+                Role::User => Err(ErrorForbidden("Only administrators can access this page")),
+            }
+        })
+    }
+}
+
+// Organization the logged in user works in, with their role in it | Tenant-owned data is only
+// queried through its scope
+// Falls back to the first organization of the user if the session has none or the user is no
+// member anymore, and redirects to /organizations if they have none at all
+// Requests routed to an organization by subdomain or path always use it, or get a 403, without
+// remembering it in the session
+pub struct ActiveOrganization {
+    pub user: User,
+    pub organization: Organization,
+    pub role: OrgRole,
+    pub scope: OrgScope,
+}
+
+impl FromRequest for ActiveOrganization {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = CurrentUser::from_request(req, payload);
+        let session = req.get_session();
+        let db = req.app_data::<web::Data<Arc<Database>>>().cloned();
+        let tenant = req
+            .extensions()
+            .get::<Tenant>()
+            .map(|tenant| tenant.organization.id);
+
+        Box::pin(async move {
+            let CurrentUser(user) = user.await?;
+            let db = db.ok_or_else(|| ErrorInternalServerError("Database not configured"))?;
+            let selected = session.get::<i32>(ORGANIZATION_KEY).ok().flatten();
+
+            let user_id = user.id;
+            let result = web::block(move || {
+                if let Some(organization_id) = tenant {
+                    return db.get_membership(organization_id, user_id);
+                }
+                if let Some(organization_id) = selected {
+                    if let Some(membership) = db.get_membership(organization_id, user_id)? {
+                        return Ok(Some(membership));
+                    }
+                }
+                Ok::<_, DatabaseError>(db.get_memberships(user_id)?.into_iter().next())
+            })
+            .await;
+
+            match result {
+                Ok(Ok(Some((organization, role)))) => {
+                    // Pages of an organization do not change the one picked for the other pages
+                    if tenant.is_none() && selected != Some(organization.id) {
+                        set_active_organization(&session, organization.id)?;
+                    }
+                    Ok(ActiveOrganization {
+                        scope: OrgScope::new(organization.id),
+                        user,
+                        organization,
+                        role,
+                    })
+                }
+                Ok(Ok(None)) if tenant.is_some() => {
+                    Err(ErrorForbidden("You are not a member of this organization"))
+                }
+                Ok(Ok(None)) => {
+                    FlashMessages::new(&session)
+                        .push(Level::Info, "Please create or join an organization first");
+                    Err(redirect("/organizations", "No organization"))
+                }
+                Ok(Err(err)) => {
+                    error!("{}", err);
+                    Err(ErrorInternalServerError("Failed to load organization"))
+                }
+                Err(err) => {
+                    error!("Blocking error occurred: {:?}", err);
+                    Err(ErrorInternalServerError("Failed to load organization"))
+                }
+            }
+        })
+    }
+}
+
+// Switches the organization | Callers check the membership, the extractor checks it again
+// on every request
+pub fn set_active_organization(
+    session: &Session,
+    organization_id: i32,
+) -> Result<(), actix_web::Error> {
+    session.insert(ORGANIZATION_KEY, organization_id)?;
+    Ok(())
+}
+
+// Remembers where to go after logging in | Only local paths, so the login page cannot be used
+// to send users to other sites
+pub fn set_return_to(session: &Session, location: &str) -> Result<(), actix_web::Error> {
+    if location.starts_with('/') && !location.starts_with("//") && !location.contains('\\') {
+        session.insert(RETURN_TO_KEY, location)?;
+    }
+    Ok(())
+}
+
+// Where to go after logging in | The dashboard unless set_return_to was called before
+pub fn take_return_to(session: &Session) -> String {
+    session
+        .remove_as::<String>(RETURN_TO_KEY)
+        .and_then(Result::ok)
+        .unwrap_or_else(|| String::from("/dashboard"))
+}
+
+fn redirect_to_login() -> actix_web::Error {
+    redirect("/login", "Not logged in")
+}
+
+fn redirect(location: &'static str, reason: &'static str) -> actix_web::Error {
+    let response = HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish();
+    InternalError::from_response(reason, response).into()
+}
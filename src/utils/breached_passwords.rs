@@ -0,0 +1,46 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// File layout: MAGIC followed by sorted, deduplicated u64 hashes in big endian
+// Kept free of other crate modules so the breach_list binary can include it as well
+pub const MAGIC: &[u8; 4] = b"BPW1";
+
+// Set of known breached passwords, stored as truncated blake3 hashes
+// False positives are possible but rare (64 bit hashes), false negatives are not
+pub struct BreachedPasswords {
+    hashes: Vec<u64>,
+}
+
+impl BreachedPasswords {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+
+        let body = bytes
+            .strip_prefix(MAGIC.as_slice())
+            .filter(|body| body.len() % 8 == 0)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Not a breached password list")
+            })?;
+
+        let hashes = body
+            .chunks_exact(8)
+            .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        Ok(BreachedPasswords { hashes })
+    }
+
+    pub fn contains(&self, password: &str) -> bool {
+        self.hashes.binary_search(&hash(password)).is_ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+}
+
+pub fn hash(password: &str) -> u64 {
+    let digest = blake3::hash(password.as_bytes());
+    u64::from_be_bytes(digest.as_bytes()[..8].try_into().unwrap())
+}
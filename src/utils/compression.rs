@@ -0,0 +1,142 @@
+use actix_http::encoding::Encoder;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{
+    self, AcceptEncoding, ContentEncoding, Encoding, EntityTag, Header, HeaderValue,
+};
+use actix_web::middleware::Next;
+use actix_web::mime::{self, Mime};
+use actix_web::{web, Error};
+
+// Content types which are compressed already, compressing them again only wastes cpu time
+const COMPRESSED_TYPES: [&str; 10] = [
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-7z-compressed",
+    "application/x-bzip2",
+    "application/x-rar-compressed",
+    "application/x-xz",
+    "font/woff",
+    "font/woff2",
+];
+
+// Encodings offered to clients, taken from the COMPRESSION env variable
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    encodings: Vec<Encoding>,
+}
+
+impl CompressionConfig {
+    // Parses a comma separated list of encodings like "br,zstd,gzip" | "off" disables compression
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut encodings = Vec::new();
+
+        for name in value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let encoding = match name {
+                "off" | "none" => continue,
+                "br" | "brotli" => ContentEncoding::Brotli,
+                "gzip" => ContentEncoding::Gzip,
+                "zstd" => ContentEncoding::Zstd,
+                _ => return Err(format!("Unsupported compression '{}'", name)),
+            };
+            encodings.push(Encoding::Known(encoding));
+        }
+
+        Ok(CompressionConfig { encodings })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.encodings.is_empty()
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            encodings: vec![
+                Encoding::Known(ContentEncoding::Brotli),
+                Encoding::Known(ContentEncoding::Zstd),
+                Encoding::Known(ContentEncoding::Gzip),
+            ],
+        }
+    }
+}
+
+fn is_compressible(content_type: Option<&HeaderValue>) -> bool {
+    let mime = match content_type
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Mime>().ok())
+    {
+        Some(mime) => mime,
+        None => return true,
+    };
+
+    match mime.type_() {
+        mime::IMAGE => mime.subtype() == mime::SVG,
+        mime::VIDEO | mime::AUDIO => false,
+        _ => !COMPRESSED_TYPES.contains(&mime.essence_str()),
+    }
+}
+
+// Compresses response bodies with the best encoding both client and config accept
+pub async fn compress(
+    config: web::Data<CompressionConfig>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    // Requests without Accept-Encoding get an uncompressed response
+    let encoding = match AcceptEncoding::parse(req.request()) {
+        Ok(accept) if config.enabled() && req.headers().contains_key(header::ACCEPT_ENCODING) => {
+            match accept.negotiate(config.encodings.iter()) {
+                Some(Encoding::Known(encoding)) => encoding,
+                _ => ContentEncoding::Identity,
+            }
+        }
+        _ => ContentEncoding::Identity,
+    };
+
+    let res = next.call(req).await?;
+
+    Ok(res.map_body(move |head, body| {
+        let compressible = is_compressible(head.headers().get(header::CONTENT_TYPE));
+        let encoding = if compressible {
+            encoding
+        } else {
+            ContentEncoding::Identity
+        };
+
+        let encoder = Encoder::response(encoding, head, body);
+
+        if head.headers().contains_key(header::CONTENT_ENCODING) {
+            // Encoded bodies differ byte wise, so the ETag of the plain body becomes weak
+            let weak_etag = head
+                .headers()
+                .get(header::ETAG)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<EntityTag>().ok())
+                .filter(|etag| !etag.weak)
+                .and_then(|etag| {
+                    EntityTag::new_weak(etag.tag().to_string())
+                        .to_string()
+                        .parse()
+                        .ok()
+                });
+
+            if let Some(weak_etag) = weak_etag {
+                head.headers_mut().insert(header::ETAG, weak_etag);
+            }
+        } else if compressible && config.enabled() {
+            // Caches must not hand this response to clients with a different Accept-Encoding
+            head.headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+
+        encoder
+    }))
+}
@@ -0,0 +1,85 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, EntityTag, Header, HttpDate, IfModifiedSince, IfNoneMatch};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpRequest, HttpResponse};
+
+// Adds a strong ETag to buffered responses and answers fresh conditional GET requests with 304
+pub async fn conditional_get(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let is_get = matches!(*req.method(), Method::GET | Method::HEAD);
+    let res = next.call(req).await?;
+
+    if !is_get || res.status() != StatusCode::OK {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let (req, res) = res.into_parts();
+    let (mut res, body) = res.into_parts();
+
+    // Only bodies which are available as a whole can be hashed, streamed files bring their own
+    let body = match body.try_into_bytes() {
+        Ok(bytes) => {
+            if !res.headers().contains_key(header::ETAG) {
+                let hash = blake3::hash(&bytes).to_hex().to_string();
+                let etag = EntityTag::new_strong(hash).to_string();
+                res.headers_mut().insert(
+                    header::ETAG,
+                    etag.parse().expect("ETag is a valid header value"),
+                );
+            }
+            BoxBody::new(bytes)
+        }
+        Err(body) => body.boxed(),
+    };
+
+    if is_fresh(&req, &res) {
+        // Keep validators, cache headers and cookies | Only the body is dropped
+        let mut not_modified = HttpResponse::NotModified().finish();
+        for (name, value) in res.headers() {
+            if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+                not_modified
+                    .headers_mut()
+                    .append(name.clone(), value.clone());
+            }
+        }
+        return Ok(ServiceResponse::new(req, not_modified));
+    }
+
+    Ok(ServiceResponse::new(req, res.set_body(body)))
+}
+
+// Checks whether the client already has the current representation
+// If-None-Match takes precedence over If-Modified-Since
+fn is_fresh<B>(req: &HttpRequest, res: &HttpResponse<B>) -> bool {
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        let etag = res
+            .headers()
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<EntityTag>().ok());
+
+        return match (IfNoneMatch::parse(req), etag) {
+            (Ok(IfNoneMatch::Any), Some(_)) => true,
+            (Ok(IfNoneMatch::Items(tags)), Some(etag)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+            _ => false,
+        };
+    }
+
+    let last_modified = res
+        .headers()
+        .get(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<HttpDate>().ok());
+
+    match (IfModifiedSince::parse(req), last_modified) {
+        (Ok(IfModifiedSince(since)), Some(modified)) => {
+            req.headers().contains_key(header::IF_MODIFIED_SINCE)
+                && std::time::SystemTime::from(modified) <= std::time::SystemTime::from(since)
+        }
+        _ => false,
+    }
+}
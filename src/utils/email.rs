@@ -0,0 +1,14 @@
+// Canonical form emails are stored and looked up in
+// Trims, lowercases and converts internationalized domains to punycode, e.g. Foo@Bücher.de -> foo@xn--bcher-kva.de
+pub fn normalize_email(email: &str) -> String {
+    let email = email.trim().to_lowercase();
+
+    match email.rsplit_once('@') {
+        Some((local_part, domain)) => match idna::domain_to_ascii(domain) {
+            Ok(domain) => format!("{}@{}", local_part, domain),
+            // Invalid domains are rejected by form validation, keep them as entered
+            Err(_) => email,
+        },
+        None => email,
+    }
+}
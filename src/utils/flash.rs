@@ -0,0 +1,101 @@
+use actix_session::{Session, SessionExt};
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::future::{ready, Ready};
+
+use super::i18n::translate;
+
+// Session key the pending messages are stored under
+const FLASH_KEY: &str = "flash_messages";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Success,
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FlashMessage {
+    pub level: Level,
+    pub text: String,
+}
+
+// Messages which are shown once on the next rendered page, e.g. after a redirect
+// Can be used as extractor in views or created from an existing session
+pub struct FlashMessages {
+    session: Session,
+}
+
+impl FlashMessages {
+    pub fn new(session: &Session) -> Self {
+        FlashMessages {
+            session: session.clone(),
+        }
+    }
+
+    // Queues a message for the next render
+    pub fn push(&self, level: Level, text: &str) {
+        let mut messages = self.pending();
+        messages.push(FlashMessage {
+            level,
+            text: text.to_string(),
+        });
+
+        if let Err(err) = self.session.insert(FLASH_KEY, messages) {
+            error!("Failed to store flash message: {}", err);
+        }
+    }
+
+    // Returns all queued messages without removing them | Translated to the language of the page
+    // they are shown on, messages with values are translated by the caller with
+    // i18n::translate_with
+    pub fn peek(&self) -> Vec<FlashMessage> {
+        match self.session.get::<Vec<FlashMessage>>(FLASH_KEY) {
+            Ok(messages) => messages
+                .unwrap_or_default()
+                .into_iter()
+                .map(|message| FlashMessage {
+                    text: translate(&message.text),
+                    ..message
+                })
+                .collect(),
+            Err(_) => {
+                error!("Discarding malformed flash messages");
+                self.session.remove(FLASH_KEY);
+                Vec::new()
+            }
+        }
+    }
+
+    // Removes the queued messages once they have been shown
+    pub fn clear(&self) {
+        // Removing marks the session as changed, so only do it when there is something to remove
+        if self.session.entries().contains_key(FLASH_KEY) {
+            self.session.remove(FLASH_KEY);
+        }
+    }
+
+    fn pending(&self) -> Vec<FlashMessage> {
+        self.session
+            .get::<Vec<FlashMessage>>(FLASH_KEY)
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+}
+
+impl FromRequest for FlashMessages {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(FlashMessages {
+            session: req.get_session(),
+        }))
+    }
+}
@@ -0,0 +1,357 @@
+use actix_session::SessionExt;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{HeaderValue, ACCEPT_LANGUAGE, CONTENT_TYPE, VARY};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use log::error;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tera::{Context, Value};
+
+use crate::database::db::Database;
+use crate::get_user_id_from_session;
+
+// Languages the interface can be shown in | English is the language of the source code and
+// templates, the others have a gettext catalog in static/locales/<locale>.po
+pub const LOCALES: &[&str] = &["en", "de"];
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+// Cookie set by the language switcher | Overrides the browser and the account
+pub const LOCALE_COOKIE: &str = "locale";
+
+// Translations of one language, keyed by the English text
+type Catalog = HashMap<String, String>;
+
+// Catalogs of every language in LOCALES but English
+pub struct Catalogs {
+    catalogs: HashMap<&'static str, Arc<Catalog>>,
+}
+
+impl Catalogs {
+    // Reads <dir>/<locale>.po for every language
+    #[cfg(not(feature = "embed-static"))]
+    pub fn new(dir: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load(|file| Ok(std::fs::read_to_string(format!("{}/{}", dir, file))?))
+    }
+
+    #[cfg(feature = "embed-static")]
+    pub fn embedded() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load(|file| {
+            super::assets::locale(file).ok_or_else(|| format!("Missing catalog {}", file).into())
+        })
+    }
+
+    fn load(
+        read: impl Fn(&str) -> Result<String, Box<dyn std::error::Error>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut catalogs = HashMap::new();
+        for locale in LOCALES.iter().filter(|locale| **locale != DEFAULT_LOCALE) {
+            let file = format!("{}.po", locale);
+            let catalog = parse_po(&read(&file)?).map_err(|err| format!("{}: {}", file, err))?;
+            catalogs.insert(*locale, Arc::new(catalog));
+        }
+        Ok(Catalogs { catalogs })
+    }
+}
+
+// Language picked for the request | Templates and messages are built without the request,
+// so it is kept for the task handling the request
+#[derive(Clone)]
+struct ActiveLocale {
+    locale: &'static str,
+    catalogs: web::Data<Catalogs>,
+}
+
+tokio::task_local! {
+    static LOCALE: ActiveLocale;
+}
+
+// Language of the current request | The default outside of requests, e.g. in jobs
+pub fn current_locale() -> &'static str {
+    LOCALE
+        .try_with(|active| active.locale)
+        .unwrap_or(DEFAULT_LOCALE)
+}
+
+// Returns the English text if the catalog has no translation
+pub fn translate(message: &str) -> String {
+    LOCALE
+        .try_with(|active| {
+            active
+                .catalogs
+                .catalogs
+                .get(active.locale)
+                .and_then(|catalog| catalog.get(message))
+                .cloned()
+        })
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| message.to_string())
+}
+
+// Runs f in another language, e.g. to render a mail in the language of its recipient
+// Keeps the language of the request if the locale is not supported
+pub fn with_locale<R>(locale: Option<&str>, f: impl FnOnce() -> R) -> R {
+    let active = locale.and_then(supported).and_then(|locale| {
+        LOCALE
+            .try_with(|active| ActiveLocale {
+                locale,
+                catalogs: active.catalogs.clone(),
+            })
+            .ok()
+    });
+    match active {
+        Some(active) => LOCALE.sync_scope(active, f),
+        None => f(),
+    }
+}
+
+// Keeps the language of the request for work which outlives it
+pub fn keep_locale<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let active = LOCALE.try_with(ActiveLocale::clone).ok();
+    async move {
+        match active {
+            Some(active) => LOCALE.scope(active, future).await,
+            None => future.await,
+        }
+    }
+}
+
+// Translates the message and fills in its {name} placeholders
+pub fn translate_with(message: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(translate(message), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+#[derive(Serialize)]
+struct LocaleOption {
+    code: &'static str,
+    name: &'static str,
+}
+
+// Adds locale for <html lang> and the languages of the switcher to the context of a page
+pub fn insert_locale_context(context: &mut Context) {
+    let options: Vec<LocaleOption> = LOCALES
+        .iter()
+        .map(|code| LocaleOption {
+            code,
+            name: locale_name(code),
+        })
+        .collect();
+    context.insert("locale", current_locale());
+    context.insert("locales", &options);
+}
+
+// Name of the language in the language itself
+fn locale_name(locale: &str) -> &'static str {
+    match locale {
+        "de" => "Deutsch",
+        _ => "English",
+    }
+}
+
+// {{ t(msg="Hello {name}", name=user.email) }} | Other arguments fill in placeholders
+pub fn tera_function() -> impl tera::Function {
+    |args: &HashMap<String, Value>| -> tera::Result<Value> {
+        let message = args
+            .get("msg")
+            .and_then(Value::as_str)
+            .ok_or_else(|| tera::Error::msg("t() needs a msg argument"))?;
+        let values: Vec<(&str, String)> = args
+            .iter()
+            .filter(|(name, _)| name.as_str() != "msg")
+            .map(|(name, value)| match value {
+                Value::String(text) => (name.as_str(), text.clone()),
+                value => (name.as_str(), value.to_string()),
+            })
+            .collect();
+        let values: Vec<(&str, &str)> = values
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        Ok(Value::String(translate_with(message, &values)))
+    }
+}
+
+// Picks the language of the request: the cookie of the language switcher, then the preference
+// of the logged in user, then Accept-Language | Needs to run inside the session middleware
+// HTML responses vary on the headers the language is picked from, so caches do not serve them
+// in the wrong language
+pub async fn negotiate_locale(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let catalogs = req
+        .app_data::<web::Data<Catalogs>>()
+        .cloned()
+        .ok_or_else(|| ErrorInternalServerError("Catalogs not configured"))?;
+
+    let mut locale = req
+        .cookie(LOCALE_COOKIE)
+        .and_then(|cookie| supported(cookie.value()));
+
+    if locale.is_none() {
+        locale = user_locale(&req).await;
+    }
+
+    let locale = locale.unwrap_or_else(|| {
+        let header = req
+            .headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        accepted_locale(header)
+    });
+
+    let active = ActiveLocale { locale, catalogs };
+    let mut res = LOCALE.scope(active, next.call(req)).await?;
+
+    let html = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    if html {
+        res.headers_mut()
+            .append(VARY, HeaderValue::from_static("Accept-Language, Cookie"));
+    }
+    Ok(res)
+}
+
+// Language chosen on the settings page | The user is cached, so this rarely hits the database
+async fn user_locale(req: &ServiceRequest) -> Option<&'static str> {
+    let session = req.get_session();
+    let user_id = get_user_id_from_session!(session)?;
+    let db = req.app_data::<web::Data<Arc<Database>>>()?.clone();
+
+    match web::block(move || db.get_user_by_id(user_id)).await {
+        Ok(Ok(user)) => user.locale.as_deref().and_then(supported),
+        Ok(Err(err)) => {
+            error!("Failed to load user: {}", err);
+            None
+        }
+        Err(err) => {
+            error!("Blocking error occurred: {:?}", err);
+            None
+        }
+    }
+}
+
+// Best language of an Accept-Language header like "de-CH,de;q=0.9,en;q=0.8"
+fn accepted_locale(header: &str) -> &'static str {
+    let mut ranges: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty())?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((tag, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // Stable, so ranges with the same quality keep their order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ranges
+        .into_iter()
+        .find_map(|(tag, _)| {
+            let tag = tag.to_lowercase();
+            supported(&tag).or_else(|| supported(tag.split('-').next()?))
+        })
+        .unwrap_or(DEFAULT_LOCALE)
+}
+
+fn supported(locale: &str) -> Option<&'static str> {
+    LOCALES
+        .iter()
+        .copied()
+        .find(|supported| *supported == locale)
+}
+
+// Entries of a gettext catalog | Untranslated and fuzzy entries and the header are left out
+// Contexts and plural forms are not supported
+fn parse_po(content: &str) -> Result<Catalog, String> {
+    enum Field {
+        None,
+        Id,
+        Str,
+    }
+
+    let mut catalog = Catalog::new();
+    let mut msgid = String::new();
+    let mut msgstr = String::new();
+    let mut fuzzy = false;
+    let mut next_fuzzy = false;
+    let mut field = Field::None;
+
+    let mut finish = |msgid: &mut String, msgstr: &mut String, fuzzy: bool| {
+        if !msgid.is_empty() && !msgstr.is_empty() && !fuzzy {
+            catalog.insert(std::mem::take(msgid), std::mem::take(msgstr));
+        }
+        msgid.clear();
+        msgstr.clear();
+    };
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        let invalid = |reason: &str| format!("line {}: {}", number + 1, reason);
+
+        if line.is_empty() {
+            continue;
+        } else if let Some(flags) = line.strip_prefix("#,") {
+            next_fuzzy = flags.split(',').any(|flag| flag.trim() == "fuzzy");
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some(rest) = line.strip_prefix("msgid ") {
+            finish(&mut msgid, &mut msgstr, fuzzy);
+            fuzzy = std::mem::take(&mut next_fuzzy);
+            msgid = unquote(rest).ok_or_else(|| invalid("malformed msgid"))?;
+            field = Field::Id;
+        } else if let Some(rest) = line.strip_prefix("msgstr ") {
+            msgstr = unquote(rest).ok_or_else(|| invalid("malformed msgstr"))?;
+            field = Field::Str;
+        } else if line.starts_with('"') {
+            let text = unquote(line).ok_or_else(|| invalid("malformed string"))?;
+            match field {
+                Field::Id => msgid.push_str(&text),
+                Field::Str => msgstr.push_str(&text),
+                Field::None => return Err(invalid("string outside of an entry")),
+            }
+        } else {
+            return Err(invalid("unsupported keyword"));
+        }
+    }
+    finish(&mut msgid, &mut msgstr, fuzzy);
+
+    Ok(catalog)
+}
+
+// "Say \"hi\"\n" -> Say "hi" and a line break
+fn unquote(quoted: &str) -> Option<String> {
+    let inner = quoted.strip_prefix('"')?.strip_suffix('"')?;
+    let mut text = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => text.push('\n'),
+                't' => text.push('\t'),
+                c @ ('"' | '\\') => text.push(c),
+                _ => return None,
+            },
+            '"' => return None,
+            c => text.push(c),
+        }
+    }
+    Some(text)
+}
@@ -0,0 +1,52 @@
+use actix_web::web;
+use chrono::{Duration, Utc};
+use log::{error, info};
+use std::sync::Arc;
+
+use crate::database::db::Database;
+use crate::utils::audit::{self, user_target, Actor, AuditAction};
+
+// How often background jobs run
+const JOB_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+// Removes deleted accounts for good once their grace period is over | Until then an admin
+// can still restore them
+pub fn spawn_account_purge(db: Arc<Database>, grace_period: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(JOB_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let purge_db = db.clone();
+            let deleted_before = Utc::now().naive_utc() - grace_period;
+            let purged =
+                match web::block(move || purge_db.purge_deleted_users(deleted_before)).await {
+                    Ok(Ok(purged)) => purged,
+                    Ok(Err(err)) => {
+                        error!("Failed to purge deleted accounts: {}", err);
+                        continue;
+                    }
+                    Err(err) => {
+                        error!("Blocking error occurred: {:?}", err);
+                        continue;
+                    }
+                };
+
+            if purged.is_empty() {
+                continue;
+            }
+            info!("Purged {} deleted accounts", purged.len());
+
+            for user in &purged {
+                audit::record(
+                    &db,
+                    Actor::system(),
+                    AuditAction::AccountPurge,
+                    user_target(user.id),
+                    serde_json::json!({ "email": user.email }),
+                )
+                .await;
+            }
+        }
+    });
+}
@@ -0,0 +1,281 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+    OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use log::{info, warn};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::models::users::User;
+
+// Header type of access tokens, so other tokens signed with the same keys are not accepted as one
+const ACCESS_TOKEN_TYPE: &str = "at+jwt";
+const ID_TOKEN_TYPE: &str = "JWT";
+
+// Claims of access tokens
+#[derive(Serialize, Deserialize)]
+pub struct AccessClaims {
+    pub iss: String,
+    // Id of the user
+    pub sub: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+}
+
+// Claims of access tokens issued to OAuth clients | Only accepted by the userinfo endpoint
+#[derive(Serialize, Deserialize)]
+pub struct ClientAccessClaims {
+    pub iss: String,
+    // Id of the user
+    pub sub: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+    pub client_id: String,
+    // Space separated scopes the user allowed
+    pub scope: String,
+}
+
+// Claims of ID tokens | The claims of the user depend on the scopes, e.g. email
+#[derive(Serialize)]
+struct IdClaims {
+    iss: String,
+    sub: String,
+    // Client id of the application
+    aud: String,
+    iat: i64,
+    exp: i64,
+    auth_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(flatten)]
+    user_claims: serde_json::Map<String, serde_json::Value>,
+}
+
+// Ed25519 key | The private part signs, the public part verifies and is published as JWK
+struct SigningKey {
+    kid: String,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Jwk,
+}
+
+// Keys and lifetimes of the tokens issued to API clients and OAuth applications
+pub struct JwtConfig {
+    pub issuer: String,
+    pub audience: String,
+    // Audience of access tokens issued to OAuth clients
+    pub userinfo_audience: String,
+    pub access_token_ttl: Duration,
+    pub refresh_token_ttl: Duration,
+    // The first key signs new tokens, all of them are accepted and published
+    keys: Vec<SigningKey>,
+}
+
+impl JwtConfig {
+    // Reads JWT_SIGNING_KEYS (comma separated paths of PKCS#8 Ed25519 keys in PEM format),
+    // JWT_ACCESS_TOKEN_TTL and JWT_REFRESH_TOKEN_TTL in seconds and APP_URL as issuer
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let keys = match std::env::var("JWT_SIGNING_KEYS") {
+            Ok(paths) => paths
+                .split(',')
+                .map(|path| {
+                    let pem = std::fs::read(path.trim())
+                        .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+                    load_key(&pem)
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            // Required in release builds, tokens signed with a random key do not survive restarts
+            // and are not accepted by other instances
+            Err(_) if cfg!(debug_assertions) => {
+                warn!("JWT_SIGNING_KEYS not set, using a random key");
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .map_err(|_| "Failed to generate signing key")?;
+                vec![key_from_der(pkcs8.as_ref())?]
+            }
+            Err(_) => return Err("JWT_SIGNING_KEYS must be set".into()),
+        };
+        if keys.is_empty() {
+            return Err("JWT_SIGNING_KEYS does not contain a key".into());
+        }
+        info!("Signing tokens with key {}", keys[0].kid);
+
+        let access_token_ttl = match std::env::var("JWT_ACCESS_TOKEN_TTL") {
+            Ok(value) => Duration::seconds(value.parse()?),
+            Err(_) => Duration::minutes(15),
+        };
+
+        let refresh_token_ttl = match std::env::var("JWT_REFRESH_TOKEN_TTL") {
+            Ok(value) => Duration::seconds(value.parse()?),
+            Err(_) => Duration::days(30),
+        };
+
+        let issuer = match std::env::var("APP_URL") {
+            Ok(url) => url.trim_end_matches('/').to_string(),
+            Err(_) => String::from("http://localhost:8000"),
+        };
+
+        Ok(JwtConfig {
+            audience: format!("{}/api", issuer),
+            userinfo_audience: format!("{}/oauth/userinfo", issuer),
+            issuer,
+            access_token_ttl,
+            refresh_token_ttl,
+            keys,
+        })
+    }
+
+    pub fn issue_access_token(&self, user: &User) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = Utc::now();
+        let claims = AccessClaims {
+            iss: self.issuer.clone(),
+            sub: user.id.to_string(),
+            aud: self.audience.clone(),
+            iat: now.timestamp(),
+            exp: (now + self.access_token_ttl).timestamp(),
+            jti: Uuid::new_v4().to_string(),
+        };
+        self.sign(ACCESS_TOKEN_TYPE, &claims)
+    }
+
+    // None for tokens which are malformed, expired, not meant for the API or signed by
+    // an unknown key
+    pub fn verify_access_token(&self, token: &str) -> Option<AccessClaims> {
+        self.verify(token, ACCESS_TOKEN_TYPE, &self.audience)
+    }
+
+    pub fn issue_client_access_token(
+        &self,
+        user_id: i32,
+        client_id: &str,
+        scope: &str,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = Utc::now();
+        let claims = ClientAccessClaims {
+            iss: self.issuer.clone(),
+            sub: user_id.to_string(),
+            aud: self.userinfo_audience.clone(),
+            iat: now.timestamp(),
+            exp: (now + self.access_token_ttl).timestamp(),
+            jti: Uuid::new_v4().to_string(),
+            client_id: client_id.to_string(),
+            scope: scope.to_string(),
+        };
+        self.sign(ACCESS_TOKEN_TYPE, &claims)
+    }
+
+    pub fn verify_client_access_token(&self, token: &str) -> Option<ClientAccessClaims> {
+        self.verify(token, ACCESS_TOKEN_TYPE, &self.userinfo_audience)
+    }
+
+    // Tells the application who logged in | Applications verify it with the published keys
+    pub fn issue_id_token(
+        &self,
+        user_id: i32,
+        client_id: &str,
+        auth_time: i64,
+        nonce: Option<String>,
+        user_claims: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = Utc::now();
+        let claims = IdClaims {
+            iss: self.issuer.clone(),
+            sub: user_id.to_string(),
+            aud: client_id.to_string(),
+            iat: now.timestamp(),
+            exp: (now + self.access_token_ttl).timestamp(),
+            auth_time,
+            nonce,
+            user_claims,
+        };
+        self.sign(ID_TOKEN_TYPE, &claims)
+    }
+
+    fn sign<T: Serialize>(
+        &self,
+        token_type: &str,
+        claims: &T,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let key = &self.keys[0];
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.typ = Some(token_type.to_string());
+        header.kid = Some(key.kid.clone());
+        jsonwebtoken::encode(&header, claims, &key.encoding)
+    }
+
+    fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        token_type: &str,
+        audience: &str,
+    ) -> Option<T> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        if header.typ.as_deref() != Some(token_type) {
+            return None;
+        }
+        let kid = header.kid?;
+        let key = self.keys.iter().find(|key| key.kid == kid)?;
+
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        jsonwebtoken::decode(token, &key.decoding, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+
+    // Public keys for clients which verify tokens themselves
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.keys.iter().map(|key| key.jwk.clone()).collect(),
+        }
+    }
+}
+
+fn load_key(pem: &[u8]) -> Result<SigningKey, Box<dyn std::error::Error>> {
+    let pem = pem::parse(pem)?;
+    if pem.tag() != "PRIVATE KEY" {
+        return Err(format!("Expected a PRIVATE KEY, found {}", pem.tag()).into());
+    }
+    key_from_der(pem.contents())
+}
+
+// Key ids are derived from the public key, so they stay the same across restarts
+fn key_from_der(pkcs8: &[u8]) -> Result<SigningKey, Box<dyn std::error::Error>> {
+    let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+        .map_err(|err| format!("Invalid Ed25519 key: {}", err))?;
+    let public_key = key_pair.public_key().as_ref();
+    let kid = blake3::hash(public_key).to_hex()[..16].to_string();
+
+    let jwk = Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(KeyAlgorithm::EdDSA),
+            key_id: Some(kid.clone()),
+            ..Default::default()
+        },
+        algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: URL_SAFE_NO_PAD.encode(public_key),
+        }),
+    };
+
+    Ok(SigningKey {
+        kid,
+        encoding: EncodingKey::from_ed_der(pkcs8),
+        decoding: DecodingKey::from_jwk(&jwk)?,
+        jwk,
+    })
+}
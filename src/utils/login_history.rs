@@ -0,0 +1,94 @@
+use actix_web::{web, HttpRequest};
+use chrono::Utc;
+use log::{error, info};
+use std::sync::Arc;
+use tera::Context;
+
+use crate::database::db::Database;
+use crate::models::login_events::{LoginMethod, LoginOutcome, NewLoginEvent};
+use crate::models::users::User;
+use crate::utils::audit::{self, user_target, Actor, AuditAction};
+use crate::utils::mailer::Mailer;
+use crate::utils::sessions::{client_ip, user_agent};
+use crate::utils::templates::Templates;
+use crate::utils::tenants::spawn_with_page;
+
+// Login events shown on the settings page
+pub const LOGIN_HISTORY_LENGTH: i64 = 10;
+
+// Adds the login to the history of the user and the audit log and mails the user if it comes
+// from a new device
+// Runs in a spawned task, so a wrong password answers as fast as an unknown email and does not
+// reveal the account | Failures are only logged, they must not keep anyone from logging in
+pub fn record_login(
+    db: &web::Data<Arc<Database>>,
+    mailer: &web::Data<Mailer>,
+    templates: &web::Data<Templates>,
+    req: &HttpRequest,
+    user: &User,
+    outcome: LoginOutcome,
+    method: LoginMethod,
+) {
+    let event = NewLoginEvent::new(
+        user.id,
+        client_ip(req),
+        user_agent(req.headers()),
+        outcome,
+        method,
+    );
+    let actor = Actor::user(user, req);
+    let user_id = user.id;
+    let email = user.email.clone();
+    let locale = user.locale.clone();
+    let db = db.clone();
+    let mailer = mailer.clone();
+    let templates = templates.clone();
+
+    spawn_with_page(async move {
+        let ip = event.ip.clone();
+        let device = event.user_agent.clone();
+
+        let (action, metadata) = match outcome {
+            LoginOutcome::Success => (
+                AuditAction::Login,
+                serde_json::json!({ "method": method.as_str() }),
+            ),
+            _ => (
+                AuditAction::LoginFailed,
+                serde_json::json!({ "method": method.as_str(), "reason": outcome.as_str() }),
+            ),
+        };
+        audit::record(&db, actor, action, user_target(user_id), metadata).await;
+
+        let new_device = match web::block(move || db.record_login_event(&event)).await {
+            Ok(Ok(new_device)) => new_device,
+            Ok(Err(err)) => {
+                error!("Failed to record login: {}", err);
+                return;
+            }
+            Err(err) => {
+                error!("Blocking error occurred: {:?}", err);
+                return;
+            }
+        };
+
+        if new_device {
+            info!("Login of {} from new device {}", email, ip);
+
+            let mut context = Context::new();
+            context.insert("email", &email);
+            context.insert("ip", &ip);
+            context.insert("device", &device);
+            context.insert("time", &Utc::now().format("%Y-%m-%d %H:%M UTC").to_string());
+            Mailer::send_template(
+                &mailer,
+                &templates,
+                &email,
+                locale.as_deref(),
+                "New login to your account",
+                "emails/new_device_login.txt",
+                &context,
+            );
+        }
+    });
+}
@@ -1,3 +1,25 @@
 pub mod argon2;
+pub mod assets;
+pub mod audit;
+pub mod auth;
+pub mod breached_passwords;
+pub mod compression;
+pub mod conditional;
+pub mod email;
+pub mod flash;
+pub mod i18n;
+pub mod jobs;
+pub mod jwt;
+pub mod login_history;
 pub mod macros;
+pub mod mailer;
+pub mod oidc;
+pub mod password_policy;
+pub mod registration;
 pub mod render;
+pub mod sessions;
+pub mod templates;
+pub mod tenants;
+pub mod tokens;
+pub mod validation;
+pub mod watcher;
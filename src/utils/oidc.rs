@@ -0,0 +1,749 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::info;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::utils::tokens::generate_token;
+
+// Discovery documents and keys are fetched again after this long, so key rotations at the
+// provider are picked up
+const METADATA_TTL: Duration = Duration::from_secs(3600);
+
+// Signature algorithms accepted for ID tokens | HS256 would make the client secret a signing key
+const ID_TOKEN_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Debug)]
+pub enum OidcError {
+    // The provider could not be reached or answered with an error
+    Http(String),
+    // The provider answered with something which is not valid OpenID Connect
+    InvalidResponse(String),
+    InvalidIdToken(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OidcError::Http(msg) => write!(f, "OIDC request failed: {}", msg),
+            OidcError::InvalidResponse(msg) => write!(f, "Invalid OIDC response: {}", msg),
+            OidcError::InvalidIdToken(msg) => write!(f, "Invalid ID token: {}", msg),
+        }
+    }
+}
+
+impl From<ureq::Error> for OidcError {
+    fn from(err: ureq::Error) -> Self {
+        OidcError::Http(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for OidcError {
+    fn from(err: std::io::Error) -> Self {
+        OidcError::InvalidResponse(err.to_string())
+    }
+}
+
+// Parts of the discovery document which are used
+#[derive(Deserialize, Clone)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Metadata {
+    discovery: Discovery,
+    jwks: JwkSet,
+    fetched_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// Claims of ID tokens which are used
+#[derive(Deserialize)]
+pub struct IdTokenClaims {
+    // Stable id of the account at the provider
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    // Some providers send "true" as string
+    #[serde(default, deserialize_with = "bool_or_string")]
+    pub email_verified: bool,
+}
+
+// State of a login at a provider, kept in the session between redirect and callback
+#[derive(Serialize, Deserialize)]
+pub struct OidcFlow {
+    pub provider: String,
+    pub state: String,
+    pub nonce: String,
+    // PKCE code verifier | Only its hash is sent to the provider
+    pub verifier: String,
+    // Set if a logged in user connects the account to theirs instead of logging in
+    pub link_user_id: Option<i32>,
+}
+
+impl OidcFlow {
+    pub fn new(provider: &str, link_user_id: Option<i32>) -> Self {
+        OidcFlow {
+            provider: provider.to_string(),
+            state: generate_token(),
+            nonce: generate_token(),
+            verifier: generate_token(),
+            link_user_id,
+        }
+    }
+
+    // Whether a callback belongs to this flow | Its state has to come back unchanged
+    pub fn matches(&self, provider: &str, state: Option<&str>) -> bool {
+        self.provider == provider && state == Some(self.state.as_str())
+    }
+
+    fn code_challenge(&self) -> String {
+        pkce_challenge(&self.verifier)
+    }
+}
+
+// S256 PKCE challenge of a code verifier
+pub fn pkce_challenge(verifier: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, verifier.as_bytes());
+    URL_SAFE_NO_PAD.encode(digest.as_ref())
+}
+
+// OpenID Connect provider users can log in with, e.g. Google or a company Keycloak
+pub struct OidcProvider {
+    // Used in urls and stored with linked identities | Never rename a provider in use
+    pub name: String,
+    // Shown on the login page
+    pub display_name: String,
+    pub issuer: String,
+    pub client_id: String,
+    client_secret: String,
+    pub scopes: String,
+    pub redirect_uri: String,
+    // Fetched on first use, so the server starts while a provider is down
+    metadata: RwLock<Option<Metadata>>,
+}
+
+impl OidcProvider {
+    // Where the browser is sent to log in | Blocks while discovering the provider
+    pub fn authorization_url(
+        &self,
+        agent: &ureq::Agent,
+        flow: &OidcFlow,
+    ) -> Result<String, OidcError> {
+        let discovery = self.discovery(agent)?;
+        let mut url = Url::parse(&discovery.authorization_endpoint)
+            .map_err(|err| OidcError::InvalidResponse(err.to_string()))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", &flow.state)
+            .append_pair("nonce", &flow.nonce)
+            .append_pair("code_challenge", &flow.code_challenge())
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.to_string())
+    }
+
+    // Exchanges the code of the callback for an ID token and verifies it | Blocks
+    pub fn exchange_code(
+        &self,
+        agent: &ureq::Agent,
+        flow: &OidcFlow,
+        code: &str,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let discovery = self.discovery(agent)?;
+        let response: TokenResponse = agent
+            .post(&discovery.token_endpoint)
+            .set("Accept", "application/json")
+            .send_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_uri),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("code_verifier", &flow.verifier),
+            ])?
+            .into_json()?;
+
+        let claims = self.verify_id_token(agent, &response.id_token)?;
+        if claims.nonce.as_deref() != Some(flow.nonce.as_str()) {
+            return Err(OidcError::InvalidIdToken(String::from(
+                "nonce does not match",
+            )));
+        }
+        Ok(claims)
+    }
+
+    fn verify_id_token(
+        &self,
+        agent: &ureq::Agent,
+        id_token: &str,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let header = jsonwebtoken::decode_header(id_token)
+            .map_err(|err| OidcError::InvalidIdToken(err.to_string()))?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            return Err(OidcError::InvalidIdToken(format!(
+                "algorithm {:?} is not accepted",
+                header.alg
+            )));
+        }
+
+        // Unknown keys might have been added since the keys were fetched
+        let key = match self.decoding_key(agent, header.kid.as_deref(), false)? {
+            Some(key) => key,
+            None => self
+                .decoding_key(agent, header.kid.as_deref(), true)?
+                .ok_or_else(|| OidcError::InvalidIdToken(String::from("unknown signing key")))?,
+        };
+
+        // The issuer of the discovery document matches the configured one but for a trailing
+        // slash, which providers like Auth0 also put into iss
+        let issuer = self.discovery(agent)?.issuer;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[issuer]);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|err| OidcError::InvalidIdToken(err.to_string()))
+    }
+
+    // Key with the id, or the only key if the token does not name one
+    fn decoding_key(
+        &self,
+        agent: &ureq::Agent,
+        kid: Option<&str>,
+        refresh: bool,
+    ) -> Result<Option<DecodingKey>, OidcError> {
+        self.load_metadata(agent, refresh)?;
+        let metadata = self.metadata.read().unwrap();
+        let jwks = &metadata.as_ref().expect("metadata loaded").jwks;
+
+        let jwk = match kid {
+            Some(kid) => jwks.find(kid),
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        };
+        jwk.map(|jwk| {
+            DecodingKey::from_jwk(jwk).map_err(|err| OidcError::InvalidResponse(err.to_string()))
+        })
+        .transpose()
+    }
+
+    fn discovery(&self, agent: &ureq::Agent) -> Result<Discovery, OidcError> {
+        self.load_metadata(agent, false)?;
+        let metadata = self.metadata.read().unwrap();
+        Ok(metadata
+            .as_ref()
+            .expect("metadata loaded")
+            .discovery
+            .clone())
+    }
+
+    // Fetches the discovery document and keys unless they are cached and fresh
+    fn load_metadata(&self, agent: &ureq::Agent, refresh: bool) -> Result<(), OidcError> {
+        let fresh = self
+            .metadata
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|metadata| metadata.fetched_at.elapsed() < METADATA_TTL);
+        if fresh && !refresh {
+            return Ok(());
+        }
+
+        let discovery_url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let discovery: Discovery = agent.get(&discovery_url).call()?.into_json()?;
+        // Prevents a compromised or misconfigured document from vouching for another issuer
+        if discovery.issuer.trim_end_matches('/') != self.issuer {
+            return Err(OidcError::InvalidResponse(format!(
+                "issuer {} does not match {}",
+                discovery.issuer, self.issuer
+            )));
+        }
+        let jwks: JwkSet = agent.get(&discovery.jwks_uri).call()?.into_json()?;
+        info!(
+            "Loaded {} keys of OIDC provider {}",
+            jwks.keys.len(),
+            self.name
+        );
+
+        *self.metadata.write().unwrap() = Some(Metadata {
+            discovery,
+            jwks,
+            fetched_at: Instant::now(),
+        });
+        Ok(())
+    }
+}
+
+// Configured OpenID Connect providers
+pub struct OidcConfig {
+    pub providers: Vec<OidcProvider>,
+    // Requests to providers are made from blocking threads
+    pub agent: ureq::Agent,
+}
+
+impl OidcConfig {
+    // Reads OIDC_PROVIDERS (comma separated names) and for each name OIDC_<NAME>_ISSUER,
+    // OIDC_<NAME>_CLIENT_ID, OIDC_<NAME>_CLIENT_SECRET and optionally OIDC_<NAME>_DISPLAY_NAME
+    // and OIDC_<NAME>_SCOPES | Callbacks go to APP_URL/login/oidc/<name>/callback
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let app_url = match std::env::var("APP_URL") {
+            Ok(url) => url.trim_end_matches('/').to_string(),
+            Err(_) => String::from("http://localhost:8000"),
+        };
+
+        let names = std::env::var("OIDC_PROVIDERS").unwrap_or_default();
+
+        let mut providers = Vec::new();
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let name = name.to_lowercase();
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err(format!("Invalid OIDC provider name: {}", name).into());
+            }
+            let prefix = format!("OIDC_{}_", name.to_uppercase().replace('-', "_"));
+            let required = |key: &str| {
+                std::env::var(format!("{}{}", prefix, key))
+                    .map_err(|_| format!("{}{} not set", prefix, key))
+            };
+
+            let display_name = match std::env::var(format!("{}DISPLAY_NAME", prefix)) {
+                Ok(display_name) => display_name,
+                Err(_) => name.clone(),
+            };
+
+            let scopes = match std::env::var(format!("{}SCOPES", prefix)) {
+                Ok(scopes) => scopes,
+                Err(_) => String::from("openid email profile"),
+            };
+
+            providers.push(OidcProvider {
+                redirect_uri: format!("{}/login/oidc/{}/callback", app_url, name),
+                issuer: required("ISSUER")?.trim_end_matches('/').to_string(),
+                client_id: required("CLIENT_ID")?,
+                client_secret: required("CLIENT_SECRET")?,
+                name,
+                display_name,
+                scopes,
+                metadata: RwLock::new(None),
+            });
+        }
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+
+        Ok(OidcConfig { providers, agent })
+    }
+
+    pub fn provider(&self, name: &str) -> Option<&OidcProvider> {
+        self.providers.iter().find(|provider| provider.name == name)
+    }
+}
+
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => value,
+        BoolOrString::String(value) => value == "true",
+    })
+}
+
+// Runs the flow against a local issuer which publishes keys and signs ID tokens like a real one
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    const CLIENT_ID: &str = "app";
+    const CODE: &str = "code-1";
+
+    // Provider as configured by OidcConfig::from_env
+    pub(crate) fn provider(issuer: &str) -> OidcProvider {
+        OidcProvider {
+            name: String::from("mock"),
+            display_name: String::from("Mock ID"),
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id: String::from(CLIENT_ID),
+            client_secret: String::from("secret"),
+            scopes: String::from("openid email"),
+            redirect_uri: String::from("http://localhost:8000/login/oidc/mock/callback"),
+            metadata: RwLock::new(None),
+        }
+    }
+
+    struct SigningKey {
+        kid: String,
+        pkcs8: Vec<u8>,
+        jwk: Value,
+    }
+
+    impl SigningKey {
+        fn generate(kid: &str) -> Self {
+            let rng = ring::rand::SystemRandom::new();
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+            let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            let jwk = json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "use": "sig",
+                "kid": kid,
+                "x": URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+            });
+            SigningKey {
+                kid: kid.to_string(),
+                pkcs8: pkcs8.as_ref().to_vec(),
+                jwk,
+            }
+        }
+
+        fn sign(&self, claims: &Value) -> String {
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some(self.kid.clone());
+            jsonwebtoken::encode(&header, claims, &EncodingKey::from_ed_der(&self.pkcs8)).unwrap()
+        }
+    }
+
+    struct IssuerState {
+        url: String,
+        // iss of the discovery document
+        issuer: String,
+        keys: Vec<Value>,
+        // Returned by the token endpoint
+        id_token: String,
+        // Challenge sent with the authorization request the code was issued for
+        code_challenge: String,
+        jwks_requests: usize,
+    }
+
+    impl IssuerState {
+        fn handle(&mut self, path: &str, body: &[u8]) -> (u16, Value) {
+            match path {
+                "/.well-known/openid-configuration" => (
+                    200,
+                    json!({
+                        "issuer": self.issuer,
+                        "authorization_endpoint": format!("{}/authorize", self.url),
+                        "token_endpoint": format!("{}/token", self.url),
+                        "jwks_uri": format!("{}/jwks", self.url),
+                    }),
+                ),
+                "/jwks" => {
+                    self.jwks_requests += 1;
+                    (200, json!({ "keys": self.keys }))
+                }
+                "/token" => {
+                    let form: HashMap<String, String> =
+                        url::form_urlencoded::parse(body).into_owned().collect();
+                    let challenge = form.get("code_verifier").map(|v| pkce_challenge(v));
+                    if form.get("code").map(String::as_str) != Some(CODE)
+                        || challenge.as_ref() != Some(&self.code_challenge)
+                    {
+                        return (400, json!({ "error": "invalid_grant" }));
+                    }
+                    (
+                        200,
+                        json!({ "token_type": "Bearer", "id_token": self.id_token }),
+                    )
+                }
+                _ => (404, json!({})),
+            }
+        }
+    }
+
+    struct MockIssuer {
+        url: String,
+        state: Arc<Mutex<IssuerState>>,
+    }
+
+    impl MockIssuer {
+        fn start(keys: &[&SigningKey]) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let state = Arc::new(Mutex::new(IssuerState {
+                url: url.clone(),
+                issuer: url.clone(),
+                keys: keys.iter().map(|key| key.jwk.clone()).collect(),
+                id_token: String::new(),
+                code_challenge: String::new(),
+                jwks_requests: 0,
+            }));
+
+            let served = state.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    respond(stream, &served);
+                }
+            });
+            MockIssuer { url, state }
+        }
+
+        fn publish(&self, keys: &[&SigningKey]) {
+            self.state.lock().unwrap().keys = keys.iter().map(|key| key.jwk.clone()).collect();
+        }
+
+        fn jwks_requests(&self) -> usize {
+            self.state.lock().unwrap().jwks_requests
+        }
+
+        // The browser was sent to the provider for the flow and came back with CODE
+        fn authorize(&self, flow: &OidcFlow, id_token: String) {
+            let mut state = self.state.lock().unwrap();
+            state.code_challenge = flow.code_challenge();
+            state.id_token = id_token;
+        }
+
+        fn claims(&self, flow: &OidcFlow, extra: Value) -> Value {
+            let mut claims = json!({
+                "iss": self.url,
+                "aud": CLIENT_ID,
+                "sub": "subject-1",
+                "exp": chrono::Utc::now().timestamp() + 300,
+                "iat": chrono::Utc::now().timestamp(),
+                "nonce": flow.nonce,
+            });
+            for (name, value) in extra.as_object().unwrap() {
+                claims[name] = value.clone();
+            }
+            claims
+        }
+    }
+
+    fn respond(mut stream: TcpStream, state: &Mutex<IssuerState>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+        let (status, response) = state.lock().unwrap().handle(path, &body);
+        let response = response.to_string();
+        write!(
+            stream,
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            response.len(),
+            response
+        )
+        .unwrap();
+    }
+
+    fn exchange(
+        issuer: &MockIssuer,
+        key: &SigningKey,
+        extra: Value,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let provider = provider(&issuer.url);
+        let flow = OidcFlow::new("mock", None);
+        issuer.authorize(&flow, key.sign(&issuer.claims(&flow, extra)));
+        provider.exchange_code(&ureq::Agent::new(), &flow, CODE)
+    }
+
+    #[test]
+    fn discovery_provides_the_authorization_endpoint() {
+        let issuer = MockIssuer::start(&[&SigningKey::generate("k1")]);
+        let flow = OidcFlow::new("mock", None);
+
+        let url = provider(&issuer.url)
+            .authorization_url(&ureq::Agent::new(), &flow)
+            .unwrap();
+
+        let url = Url::parse(&url).unwrap();
+        assert_eq!(url.path(), "/authorize");
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], CLIENT_ID);
+        assert_eq!(query["state"], flow.state);
+        assert_eq!(query["nonce"], flow.nonce);
+        assert_eq!(query["code_challenge"], pkce_challenge(&flow.verifier));
+        assert_eq!(query["code_challenge_method"], "S256");
+    }
+
+    #[test]
+    fn discovery_of_another_issuer_is_refused() {
+        let issuer = MockIssuer::start(&[&SigningKey::generate("k1")]);
+        issuer.state.lock().unwrap().issuer = String::from("https://evil.example.com");
+
+        let result = provider(&issuer.url)
+            .authorization_url(&ureq::Agent::new(), &OidcFlow::new("mock", None));
+
+        assert!(matches!(result, Err(OidcError::InvalidResponse(_))));
+    }
+
+    #[test]
+    fn issuer_with_trailing_slash_is_accepted() {
+        let key = SigningKey::generate("k1");
+        let issuer = MockIssuer::start(&[&key]);
+        let iss = format!("{}/", issuer.url);
+        issuer.state.lock().unwrap().issuer = iss.clone();
+
+        let claims = exchange(&issuer, &key, json!({ "iss": iss })).unwrap();
+
+        assert_eq!(claims.sub, "subject-1");
+    }
+
+    #[test]
+    fn token_of_another_issuer_is_rejected() {
+        let key = SigningKey::generate("k1");
+        let issuer = MockIssuer::start(&[&key]);
+
+        let result = exchange(&issuer, &key, json!({ "iss": "https://evil.example.com" }));
+
+        assert!(matches!(result, Err(OidcError::InvalidIdToken(_))));
+    }
+
+    #[test]
+    fn unknown_kid_is_rejected_after_fetching_the_keys_again() {
+        let issuer = MockIssuer::start(&[&SigningKey::generate("k1")]);
+        let unknown = SigningKey::generate("k2");
+
+        let result = exchange(&issuer, &unknown, json!({}));
+
+        assert!(
+            matches!(result, Err(OidcError::InvalidIdToken(ref msg)) if msg == "unknown signing key")
+        );
+        assert_eq!(issuer.jwks_requests(), 2);
+    }
+
+    #[test]
+    fn rotated_keys_are_picked_up() {
+        let old = SigningKey::generate("k1");
+        let new = SigningKey::generate("k2");
+        let issuer = MockIssuer::start(&[&old]);
+        let provider = provider(&issuer.url);
+        let agent = ureq::Agent::new();
+
+        let flow = OidcFlow::new("mock", None);
+        issuer.authorize(&flow, old.sign(&issuer.claims(&flow, json!({}))));
+        provider.exchange_code(&agent, &flow, CODE).unwrap();
+
+        // The cached keys do not know k2 yet
+        issuer.publish(&[&new]);
+        let flow = OidcFlow::new("mock", None);
+        issuer.authorize(&flow, new.sign(&issuer.claims(&flow, json!({}))));
+        let claims = provider.exchange_code(&agent, &flow, CODE).unwrap();
+
+        assert_eq!(claims.sub, "subject-1");
+        assert_eq!(issuer.jwks_requests(), 2);
+    }
+
+    #[test]
+    fn nonce_mismatch_is_rejected() {
+        let key = SigningKey::generate("k1");
+        let issuer = MockIssuer::start(&[&key]);
+
+        let result = exchange(&issuer, &key, json!({ "nonce": "replayed" }));
+
+        assert!(
+            matches!(result, Err(OidcError::InvalidIdToken(ref msg)) if msg == "nonce does not match")
+        );
+    }
+
+    #[test]
+    fn pkce_mismatch_is_rejected() {
+        let key = SigningKey::generate("k1");
+        let issuer = MockIssuer::start(&[&key]);
+        let flow = OidcFlow::new("mock", None);
+        issuer.authorize(&flow, key.sign(&issuer.claims(&flow, json!({}))));
+
+        // Same flow but a verifier which does not belong to the challenge the code was issued for
+        let other = OidcFlow {
+            provider: flow.provider.clone(),
+            state: flow.state.clone(),
+            nonce: flow.nonce.clone(),
+            verifier: generate_token(),
+            link_user_id: None,
+        };
+        let result = provider(&issuer.url).exchange_code(&ureq::Agent::new(), &other, CODE);
+
+        assert!(matches!(result, Err(OidcError::Http(_))));
+    }
+
+    #[test]
+    fn state_mismatch_is_rejected() {
+        let flow = OidcFlow::new("mock", None);
+
+        assert!(flow.matches("mock", Some(&flow.state)));
+        assert!(!flow.matches("mock", Some("forged")));
+        assert!(!flow.matches("mock", None));
+        assert!(!flow.matches("other", Some(&flow.state)));
+    }
+
+    #[test]
+    fn email_verified_as_bool_or_string() {
+        let key = SigningKey::generate("k1");
+        let issuer = MockIssuer::start(&[&key]);
+
+        for (email_verified, expected) in [
+            (json!(true), true),
+            (json!(false), false),
+            (json!("true"), true),
+            (json!("false"), false),
+        ] {
+            let claims = exchange(
+                &issuer,
+                &key,
+                json!({ "email": "mock@example.com", "email_verified": email_verified }),
+            )
+            .unwrap();
+            assert_eq!(claims.email_verified, expected, "{}", email_verified);
+        }
+
+        let claims = exchange(&issuer, &key, json!({ "email": "mock@example.com" })).unwrap();
+        assert!(!claims.email_verified);
+    }
+}
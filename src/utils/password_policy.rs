@@ -0,0 +1,184 @@
+use log::{info, warn};
+use std::path::Path;
+
+use super::breached_passwords::BreachedPasswords;
+use super::i18n::{translate, translate_with};
+
+// Keyboard rows, typing along them is as predictable as counting
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+// Requirements new passwords have to meet on registration, password change and reset
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    // Minimum estimated entropy in bits
+    pub min_entropy: f64,
+    pub forbid_email: bool,
+    breached: Option<BreachedPasswords>,
+}
+
+impl PasswordPolicy {
+    // Reads the policy from PASSWORD_MIN_LENGTH, PASSWORD_MIN_ENTROPY, PASSWORD_ALLOW_EMAIL
+    // and PASSWORD_BREACH_LIST | "off" disables the breach check, a missing breach list only
+    // disables it in debug builds
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let min_length = match std::env::var("PASSWORD_MIN_LENGTH") {
+            Ok(value) => value.parse()?,
+            Err(_) => 10,
+        };
+
+        let min_entropy = match std::env::var("PASSWORD_MIN_ENTROPY") {
+            Ok(value) => value.parse()?,
+            Err(_) => 40.0,
+        };
+
+        let forbid_email = match std::env::var("PASSWORD_ALLOW_EMAIL") {
+            Ok(value) => !value.parse::<bool>()?,
+            Err(_) => true,
+        };
+
+        let breach_list_path = match std::env::var("PASSWORD_BREACH_LIST") {
+            Ok(path) => path,
+            Err(_) => String::from("./data/breached_passwords.bin"),
+        };
+
+        let breached = match BreachedPasswords::load(Path::new(&breach_list_path)) {
+            _ if breach_list_path == "off" => {
+                warn!("Breached password check disabled by PASSWORD_BREACH_LIST");
+                None
+            }
+            Ok(breached) => {
+                info!(
+                    "Loaded {} breached passwords from {}",
+                    breached.len(),
+                    breach_list_path
+                );
+                Some(breached)
+            }
+            Err(err) if cfg!(debug_assertions) => {
+                warn!(
+                    "Breached password check disabled, failed to load {}: {}",
+                    breach_list_path, err
+                );
+                None
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Failed to load {}: {}, set PASSWORD_BREACH_LIST=\"off\" to run without \
+                     the breach check",
+                    breach_list_path, err
+                )
+                .into())
+            }
+        };
+
+        Ok(PasswordPolicy {
+            min_length,
+            min_entropy,
+            forbid_email,
+            breached,
+        })
+    }
+
+    // Returns one message per violated rule | Empty if the password is acceptable
+    pub fn check(&self, password: &str, email: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        if password.chars().count() < self.min_length {
+            violations.push(translate_with(
+                "Password must be at least {length} characters long",
+                &[("length", &self.min_length.to_string())],
+            ));
+        }
+
+        if self.forbid_email && contains_email(password, email) {
+            violations.push(translate("Password must not contain your email address"));
+        }
+
+        let breached = self.breached.as_ref().is_some_and(|breached| {
+            breached.contains(password) || breached.contains(&password.to_lowercase())
+        });
+
+        if breached {
+            violations.push(translate(
+                "This password appeared in a data breach, please choose a different one",
+            ));
+        } else if estimate_entropy(password) < self.min_entropy {
+            violations.push(translate(
+                "Password is too easy to guess, try a longer passphrase of unrelated words",
+            ));
+        }
+
+        violations
+    }
+}
+
+// Checks for the full address and its local part, e.g. "jane" for jane@example.com
+fn contains_email(password: &str, email: &str) -> bool {
+    let password = password.to_lowercase();
+    let email = email.trim().to_lowercase();
+    let local_part = email.split('@').next().unwrap_or_default();
+
+    (!email.is_empty() && password.contains(&email))
+        || (local_part.chars().count() >= 3 && password.contains(local_part))
+}
+
+// Rough guess count in bits, similar in spirit to zxcvbn
+// Every character adds the entropy of its character pool unless it repeats the previous one
+// or continues a sequence like "abc", "321" or a keyboard row like "qwerty"
+pub fn estimate_entropy(password: &str) -> f64 {
+    let chars: Vec<char> = password.chars().collect();
+    if chars.is_empty() {
+        return 0.0;
+    }
+
+    let mut pool = 0;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        pool += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        pool += 100;
+    }
+
+    let bits_per_char = (pool as f64).log2();
+
+    chars.windows(2).fold(bits_per_char, |entropy, pair| {
+        if is_predictable(pair[0], pair[1]) {
+            entropy + 1.0
+        } else {
+            entropy + bits_per_char
+        }
+    })
+}
+
+fn is_predictable(previous: char, current: char) -> bool {
+    let previous = previous.to_ascii_lowercase();
+    let current = current.to_ascii_lowercase();
+
+    if previous == current {
+        return true;
+    }
+
+    // Alphabetical or numerical sequence in either direction
+    if previous.is_ascii_alphanumeric()
+        && current.is_ascii_alphanumeric()
+        && (previous as i32 - current as i32).abs() == 1
+    {
+        return true;
+    }
+
+    KEYBOARD_ROWS
+        .iter()
+        .any(|row| match (row.find(previous), row.find(current)) {
+            (Some(a), Some(b)) => (a as i32 - b as i32).abs() == 1,
+            _ => false,
+        })
+}
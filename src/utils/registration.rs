@@ -0,0 +1,124 @@
+use chrono::Duration;
+
+use super::i18n::{translate, translate_with};
+use crate::models::users::normalize_email;
+
+// Who may create an account
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegistrationMode {
+    Open,
+    // Only with an invitation sent by an existing user
+    InviteOnly,
+    // Nobody, not even with an invitation
+    Closed,
+    // Addresses at these domains, and everyone with an invitation
+    AllowedDomains(Vec<String>),
+}
+
+impl RegistrationMode {
+    // Whether the address may register without an invitation
+    pub fn allows(&self, email: &str) -> bool {
+        match self {
+            RegistrationMode::Open => true,
+            RegistrationMode::InviteOnly | RegistrationMode::Closed => false,
+            RegistrationMode::AllowedDomains(domains) => {
+                let email = normalize_email(email);
+                email
+                    .rsplit_once('@')
+                    .is_some_and(|(_, domain)| domains.iter().any(|allowed| allowed == domain))
+            }
+        }
+    }
+
+    pub fn allows_invitations(&self) -> bool {
+        *self != RegistrationMode::Closed
+    }
+
+    // Shown instead of the registration form, or next to the email field for domains
+    pub fn restriction(&self) -> Option<String> {
+        match self {
+            RegistrationMode::Open => None,
+            RegistrationMode::InviteOnly => Some(translate("Registration is by invitation only")),
+            RegistrationMode::Closed => Some(translate("Registration is closed")),
+            RegistrationMode::AllowedDomains(domains) => Some(translate_with(
+                "Registration is only open for addresses at {domains}",
+                &[("domains", &domains.join(", "))],
+            )),
+        }
+    }
+}
+
+// How the registration page behaves
+pub struct RegistrationConfig {
+    // Always answer "check your inbox" and notify the owner by mail when an email is taken,
+    // instead of telling the visitor that an account exists
+    pub hide_existing_accounts: bool,
+    // New accounts stay unverified until the link sent to their email has been opened | Always
+    // for allowed domains, which would not restrict anything without proof of the address
+    pub verify_email: bool,
+    pub mode: RegistrationMode,
+    // How long invitation links can be used
+    pub invitation_ttl: Duration,
+}
+
+impl RegistrationConfig {
+    // Reads REGISTRATION_HIDE_EXISTING_ACCOUNTS, REGISTRATION_VERIFY_EMAIL (always on for
+    // domains), REGISTRATION_MODE (open, invite, closed or domains),
+    // REGISTRATION_ALLOWED_DOMAINS (comma separated, for domains) and REGISTRATION_INVITATION_DAYS
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let hide_existing_accounts = match std::env::var("REGISTRATION_HIDE_EXISTING_ACCOUNTS") {
+            Ok(value) => value.parse()?,
+            Err(_) => false,
+        };
+
+        let mode = match std::env::var("REGISTRATION_MODE") {
+            Ok(value) => match value.to_lowercase().as_str() {
+                "open" => RegistrationMode::Open,
+                "invite" => RegistrationMode::InviteOnly,
+                "closed" => RegistrationMode::Closed,
+                "domains" => {
+                    // Normalized like emails, so internationalized domains match
+                    let domains: Vec<String> = std::env::var("REGISTRATION_ALLOWED_DOMAINS")
+                        .unwrap_or_default()
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|domain| !domain.is_empty())
+                        .map(|domain| {
+                            let domain = domain.to_lowercase();
+                            idna::domain_to_ascii(&domain).unwrap_or(domain)
+                        })
+                        .collect();
+                    if domains.is_empty() {
+                        return Err("REGISTRATION_ALLOWED_DOMAINS is empty".into());
+                    }
+                    RegistrationMode::AllowedDomains(domains)
+                }
+                mode => return Err(format!("Unknown registration mode {}", mode).into()),
+            },
+            Err(_) => RegistrationMode::Open,
+        };
+
+        let domains = matches!(mode, RegistrationMode::AllowedDomains(_));
+        let verify_email = match std::env::var("REGISTRATION_VERIFY_EMAIL") {
+            Ok(value) => value.parse()?,
+            Err(_) => domains,
+        };
+        if domains && !verify_email {
+            return Err(
+                "REGISTRATION_VERIFY_EMAIL cannot be false for REGISTRATION_MODE domains".into(),
+            );
+        }
+
+        let invitation_ttl = match std::env::var("REGISTRATION_INVITATION_DAYS") {
+            Ok(value) => Duration::days(value.parse()?),
+            Err(_) => Duration::days(7),
+        };
+
+        Ok(RegistrationConfig {
+            hide_existing_accounts,
+            verify_email,
+            mode,
+            invitation_ttl,
+        })
+    }
+}
@@ -1,41 +1,53 @@
+use actix_session::Session;
 use actix_web::{error, http::StatusCode, web, Error, HttpResponse, Result};
 use log::error;
-use tera::{Context, Tera};
+use serde::Serialize;
+use tera::Context;
+
+use super::flash::FlashMessages;
+use super::i18n::{insert_locale_context, translate};
+use super::templates::Templates;
+use super::tenants::insert_page_context;
+use super::validation::ValidatedForm;
 
 // Function to call when displaying error on the same page where it occurs, e.g. login or register
 pub fn render_error(
-    tera: &web::Data<Tera>,
+    tera: &web::Data<Templates>,
+    session: &Session,
     message: &str,
     template_path: &str,
     status_code: StatusCode,
 ) -> Result<HttpResponse, Error> {
     let mut context = Context::new();
-    context.insert("error_message", message);
-    let rendered = tera
-        .render(template_path, &context)
-        .map_err(|_| error::ErrorInternalServerError("Failed to render template"))?;
-    Ok(HttpResponse::build(status_code)
-        .content_type("text/html")
-        .body(rendered))
+    context.insert("error_message", &translate(message));
+    render_page(tera, session, template_path, context, status_code)
+        .map_err(|_| error::ErrorInternalServerError("Failed to render template"))
 }
 
 pub fn render_template(
-    tera: &web::Data<Tera>,
+    tera: &web::Data<Templates>,
+    session: &Session,
     template_path: &str,
     context: &Context,
     status_code: StatusCode,
 ) -> Result<HttpResponse, Error> {
-    match tera.render(template_path, context) {
-        Ok(rendered) => Ok(HttpResponse::build(status_code)
-            .content_type("text/html")
-            .body(rendered)),
+    // Error messages of the views are English and translated here, see utils::i18n
+    let mut context = context.clone();
+    if let Some(message) = context.get("error_message").and_then(|m| m.as_str()) {
+        let message = translate(message);
+        context.insert("error_message", &message);
+    }
+
+    match render_page(tera, session, template_path, context, status_code) {
+        Ok(response) => Ok(response),
         Err(_) => {
             // Log the error when rendering fails
             error!("Failed to render template '{}'", template_path);
 
             // Call to render an error template
             render_error(
-                &tera,
+                tera,
+                session,
                 "We are experiencing problems, please try again later.",
                 "errors/error_page.html",
                 actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
@@ -43,3 +55,45 @@ pub fn render_template(
         }
     }
 }
+
+// Pending flash messages and the branding of the organization are shown on every page
+// The flash messages are only removed once the page rendered, so a failed render keeps them
+fn render_page(
+    tera: &web::Data<Templates>,
+    session: &Session,
+    template_path: &str,
+    mut context: Context,
+    status_code: StatusCode,
+) -> Result<HttpResponse, tera::Error> {
+    let flash_messages = FlashMessages::new(session);
+    context.insert("flash_messages", &flash_messages.peek());
+    insert_page_context(&mut context);
+    insert_locale_context(&mut context);
+
+    let rendered = tera.render(template_path, &context)?;
+    flash_messages.clear();
+    Ok(HttpResponse::build(status_code)
+        .content_type("text/html")
+        .body(rendered))
+}
+
+// Re-renders a form after a failed submit with per-field errors and the values the user entered
+// Secret fields like passwords are left out by marking them #[serde(skip_serializing)]
+// context holds the other values of the page
+pub fn render_form<T: Serialize>(
+    tera: &web::Data<Templates>,
+    session: &Session,
+    template_path: &str,
+    mut context: Context,
+    form: &ValidatedForm<T>,
+    message: Option<&str>,
+    status_code: StatusCode,
+) -> Result<HttpResponse, Error> {
+    context.insert("form", &form.data);
+    context.insert("field_errors", &form.errors);
+    if let Some(message) = message {
+        context.insert("error_message", message);
+    }
+
+    render_template(tera, session, template_path, &context, status_code)
+}
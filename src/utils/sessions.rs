@@ -0,0 +1,558 @@
+use actix_session::config::BrowserSession;
+use actix_session::storage::SessionStore;
+use actix_session::{Session, SessionExt, SessionMiddleware};
+use actix_web::body::MessageBody;
+use actix_web::cookie::{self, Cookie, Key, SameSite};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{
+    HeaderMap, HeaderName, HeaderValue, FORWARDED, HOST, SET_COOKIE, USER_AGENT, X_FORWARDED_FOR,
+    X_FORWARDED_HOST,
+};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest};
+use chrono::{Duration, Utc};
+use log::{error, info, warn};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::database::db::Database;
+use crate::database::errors::DatabaseError;
+use crate::get_user_id_from_session;
+use crate::models::sessions::SessionInfo;
+
+// Session key of the id which links a session to its SessionInfo
+const SESSION_ID_KEY: &str = "session_id";
+
+// Session key set for sessions which should survive closing the browser
+const REMEMBER_ME_KEY: &str = "remember_me";
+
+// last_seen is only written once it is older than this, so not every request causes a write
+const TOUCH_INTERVAL_SECONDS: i64 = 60;
+
+// Header the trusted proxies add the address of their client to
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProxyHeader {
+    XForwardedFor,
+    Forwarded,
+}
+
+// Cookie attributes and lifetimes of sessions
+#[derive(Clone)]
+pub struct SessionConfig {
+    // Signs out sessions which have not been used for this long
+    pub idle_timeout: Duration,
+    // Signs out sessions this long after login, no matter how active they are
+    pub absolute_timeout: Duration,
+    // Idle and absolute timeout of "remember me" sessions, which also get a persistent cookie
+    pub remember_me_timeout: Duration,
+    pub cookie_secure: bool,
+    pub cookie_same_site: SameSite,
+    // Proxies whose Forwarded or X-Forwarded-For header is believed, see client_ip
+    pub trusted_proxies: Vec<IpAddr>,
+    pub proxy_header: ProxyHeader,
+    key: Key,
+}
+
+impl SessionConfig {
+    // Reads SESSION_IDLE_TIMEOUT, SESSION_ABSOLUTE_TIMEOUT and SESSION_REMEMBER_ME_TIMEOUT in seconds,
+    // SESSION_COOKIE_SECURE, SESSION_COOKIE_SAME_SITE (strict or lax), SESSION_SECRET,
+    // TRUSTED_PROXIES (comma separated ips) and TRUSTED_PROXY_HEADER (x-forwarded-for or forwarded)
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let idle_timeout = match std::env::var("SESSION_IDLE_TIMEOUT") {
+            Ok(value) => Duration::seconds(value.parse()?),
+            Err(_) => Duration::hours(2),
+        };
+
+        let absolute_timeout = match std::env::var("SESSION_ABSOLUTE_TIMEOUT") {
+            Ok(value) => Duration::seconds(value.parse()?),
+            Err(_) => Duration::hours(12),
+        };
+
+        let remember_me_timeout = match std::env::var("SESSION_REMEMBER_ME_TIMEOUT") {
+            Ok(value) => Duration::seconds(value.parse()?),
+            Err(_) => Duration::days(30),
+        };
+
+        // Browsers only send secure cookies over https | Off by default in debug builds
+        let cookie_secure = match std::env::var("SESSION_COOKIE_SECURE") {
+            Ok(value) => value.parse()?,
+            Err(_) => !cfg!(debug_assertions),
+        };
+
+        // The forms have no CSRF token, so they rely on browsers not sending the session cookie
+        // with requests from other sites | SameSite=None is refused for that reason
+        let cookie_same_site = match std::env::var("SESSION_COOKIE_SAME_SITE") {
+            Ok(value) => match value.to_lowercase().as_str() {
+                "strict" => SameSite::Strict,
+                "lax" => SameSite::Lax,
+                _ => return Err(format!("Invalid SESSION_COOKIE_SAME_SITE: {}", value).into()),
+            },
+            Err(_) => SameSite::Lax,
+        };
+
+        // At least 64 bytes | Required in release builds, with a random key sessions do not
+        // survive restarts and are not shared between instances
+        let key = match std::env::var("SESSION_SECRET") {
+            Ok(secret) => Key::try_from(secret.as_bytes())?,
+            Err(_) if cfg!(debug_assertions) => {
+                warn!("SESSION_SECRET not set, using a random key");
+                Key::generate()
+            }
+            Err(_) => return Err("SESSION_SECRET must be set".into()),
+        };
+
+        let trusted_proxies = match std::env::var("TRUSTED_PROXIES") {
+            Ok(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty())
+                .map(|ip| {
+                    ip.parse()
+                        .map_err(|_| format!("Invalid TRUSTED_PROXIES entry: {}", ip))
+                })
+                .collect::<Result<Vec<IpAddr>, _>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        let proxy_header = match std::env::var("TRUSTED_PROXY_HEADER") {
+            Ok(value) => match value.to_lowercase().as_str() {
+                "x-forwarded-for" => ProxyHeader::XForwardedFor,
+                "forwarded" => ProxyHeader::Forwarded,
+                header => return Err(format!("Unknown proxy header {}", header).into()),
+            },
+            Err(_) => ProxyHeader::XForwardedFor,
+        };
+
+        Ok(SessionConfig {
+            idle_timeout,
+            absolute_timeout,
+            remember_me_timeout,
+            cookie_secure,
+            cookie_same_site,
+            trusted_proxies,
+            proxy_header,
+            key,
+        })
+    }
+
+    // The __Host- prefix makes browsers reject the cookie unless it is secure, has path /
+    // and no domain, so it cannot be set by subdomains
+    pub fn cookie_name(&self) -> &'static str {
+        match self.cookie_secure {
+            true => "__Host-id",
+            false => "id",
+        }
+    }
+
+    // Longest time any session can live
+    fn max_lifetime(&self) -> Duration {
+        self.absolute_timeout.max(self.remember_me_timeout)
+    }
+
+    fn timeouts(&self, remember_me: bool) -> (Duration, Duration) {
+        match remember_me {
+            true => (self.remember_me_timeout, self.remember_me_timeout),
+            false => (self.idle_timeout, self.absolute_timeout),
+        }
+    }
+
+    // Session cookies end with the browser | Timeouts are enforced by track_session, the store
+    // only has to keep the state as long as the longest session may live
+    pub fn middleware<S: SessionStore>(&self, store: S) -> SessionMiddleware<S> {
+        let lifecycle = BrowserSession::default().state_ttl(cookie::time::Duration::seconds(
+            self.max_lifetime().num_seconds(),
+        ));
+
+        SessionMiddleware::builder(store, self.key.clone())
+            .cookie_name(self.cookie_name().to_string())
+            .cookie_secure(self.cookie_secure)
+            .cookie_same_site(self.cookie_same_site)
+            .cookie_http_only(true)
+            .cookie_path("/".to_string())
+            .cookie_domain(None)
+            .session_lifecycle(lifecycle)
+            .build()
+    }
+}
+
+// Logs the user in and adds the session to their list of sessions
+// The session gets a new id, so an id planted before login is worthless afterwards
+pub async fn sign_in(
+    db: &web::Data<Arc<Database>>,
+    config: &SessionConfig,
+    session: &Session,
+    req: &HttpRequest,
+    user_id: i32,
+    remember_me: bool,
+) -> Result<(), Error> {
+    let now = Utc::now().naive_utc();
+    let info = SessionInfo {
+        id: uuid::Uuid::new_v4().to_string(),
+        created_at: now,
+        last_seen: now,
+        ip: client_ip(req),
+        user_agent: user_agent(req.headers()),
+        remember_me,
+    };
+
+    session.renew();
+    session.insert("user_id", user_id)?;
+    session.insert(SESSION_ID_KEY, &info.id)?;
+    session.insert(REMEMBER_ME_KEY, remember_me)?;
+
+    let db = db.clone();
+    let ttl_seconds = config.max_lifetime().num_seconds();
+    web::block(move || db.save_session(user_id, &info, ttl_seconds))
+        .await?
+        .map_err(|err| ErrorInternalServerError(err.to_string()))
+}
+
+pub fn current_session_id(session: &Session) -> Option<String> {
+    session.get::<String>(SESSION_ID_KEY).ok().flatten()
+}
+
+// Sessions of the user which have not expired, most recently used first
+pub fn active_sessions(
+    db: &Database,
+    config: &SessionConfig,
+    user_id: i32,
+) -> Result<Vec<SessionInfo>, DatabaseError> {
+    let now = Utc::now().naive_utc();
+    let (mut active, expired): (Vec<SessionInfo>, Vec<SessionInfo>) = db
+        .get_sessions(user_id)?
+        .into_iter()
+        .partition(|info| !is_expired(config, info, now));
+
+    for info in expired {
+        db.remove_session(user_id, &info.id)?;
+    }
+
+    active.sort_by_key(|info| std::cmp::Reverse(info.last_seen));
+    Ok(active)
+}
+
+// Marks responses whose session cookie has to outlive the browser
+struct PersistentCookie;
+
+// Signs out sessions which have been revoked or timed out and keeps last_seen of the others
+// up to date | Sessions from before sessions were tracked have no id and are signed out as well
+pub async fn track_session(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let session = req.get_session();
+
+    if let Some(user_id) = get_user_id_from_session!(session) {
+        let db = req
+            .app_data::<web::Data<Arc<Database>>>()
+            .cloned()
+            .ok_or_else(|| ErrorInternalServerError("Database not configured"))?;
+        let config = req
+            .app_data::<web::Data<SessionConfig>>()
+            .cloned()
+            .ok_or_else(|| ErrorInternalServerError("Sessions not configured"))?;
+        let session_id = current_session_id(&session);
+        let ip = client_ip(req.request());
+
+        match web::block(move || touch_session(&db, &config, user_id, session_id, ip)).await {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => {
+                info!("Signing out revoked or expired session of user {}", user_id);
+                session.purge();
+            }
+            Ok(Err(err)) => error!("Failed to check session: {}", err),
+            Err(err) => error!("Blocking error occurred: {:?}", err),
+        }
+    }
+
+    let mut res = next.call(req).await?;
+
+    // Checked afterwards since the view might just have logged in
+    let remember_me = session.get::<bool>(REMEMBER_ME_KEY).ok().flatten();
+    if remember_me == Some(true) {
+        res.response_mut().extensions_mut().insert(PersistentCookie);
+    }
+
+    Ok(res)
+}
+
+// Adds Max-Age to session cookies of "remember me" sessions | The session middleware only
+// supports one lifecycle for all sessions, so this has to run outside of it
+pub async fn persist_session_cookie(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let config = req
+        .app_data::<web::Data<SessionConfig>>()
+        .cloned()
+        .ok_or_else(|| ErrorInternalServerError("Sessions not configured"))?;
+    let mut res = next.call(req).await?;
+
+    if res
+        .response()
+        .extensions()
+        .get::<PersistentCookie>()
+        .is_none()
+    {
+        return Ok(res);
+    }
+
+    let max_age = cookie::time::Duration::seconds(config.remember_me_timeout.num_seconds());
+    let cookies: Vec<HeaderValue> = res
+        .headers()
+        .get_all(SET_COOKIE)
+        .map(|value| {
+            let parsed = value
+                .to_str()
+                .ok()
+                .and_then(|v| Cookie::parse_encoded(v).ok());
+            match parsed {
+                Some(mut cookie)
+                    if cookie.name() == config.cookie_name() && cookie.max_age().is_none() =>
+                {
+                    cookie.set_max_age(max_age);
+                    HeaderValue::from_str(&cookie.encoded().to_string()).unwrap_or(value.clone())
+                }
+                _ => value.clone(),
+            }
+        })
+        .collect();
+
+    let headers = res.headers_mut();
+    headers.remove(SET_COOKIE);
+    for value in cookies {
+        headers.append(SET_COOKIE, value);
+    }
+
+    Ok(res)
+}
+
+// Returns false if the session has been revoked or timed out
+fn touch_session(
+    db: &Database,
+    config: &SessionConfig,
+    user_id: i32,
+    session_id: Option<String>,
+    ip: String,
+) -> Result<bool, DatabaseError> {
+    let session_id = match session_id {
+        Some(session_id) => session_id,
+        None => return Ok(false),
+    };
+
+    let mut info = match db.get_session(user_id, &session_id)? {
+        Some(info) => info,
+        None => return Ok(false),
+    };
+
+    let now = Utc::now().naive_utc();
+    if is_expired(config, &info, now) {
+        db.remove_session(user_id, &session_id)?;
+        return Ok(false);
+    }
+
+    if now - info.last_seen > Duration::seconds(TOUCH_INTERVAL_SECONDS) {
+        info.last_seen = now;
+        info.ip = ip;
+        db.save_session(user_id, &info, config.max_lifetime().num_seconds())?;
+    }
+
+    Ok(true)
+}
+
+fn is_expired(config: &SessionConfig, info: &SessionInfo, now: chrono::NaiveDateTime) -> bool {
+    let (idle_timeout, absolute_timeout) = config.timeouts(info.remember_me);
+    now - info.last_seen > idle_timeout || now - info.created_at > absolute_timeout
+}
+
+// Whether the connection comes from one of the TRUSTED_PROXIES, whose forwarding headers can be
+// believed
+pub fn from_trusted_proxy(req: &HttpRequest) -> bool {
+    req.app_data::<web::Data<SessionConfig>>()
+        .zip(req.peer_addr())
+        .is_some_and(|(config, peer)| config.trusted_proxies.contains(&peer.ip()))
+}
+
+// Address of the client | Forwarded and X-Forwarded-For can be set by anyone, so they are only
+// used for connections from one of the TRUSTED_PROXIES
+pub fn client_ip(req: &HttpRequest) -> String {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+
+    if from_trusted_proxy(req) {
+        if let Some(config) = req.app_data::<web::Data<SessionConfig>>() {
+            let hops = forwarded_hops(req.headers(), config.proxy_header);
+            if let Some(ip) = first_untrusted_hop(&hops, &config.trusted_proxies) {
+                return ip;
+            }
+        }
+    }
+    peer.map_or_else(|| String::from("unknown"), |ip| ip.to_string())
+}
+
+// Host the client asked for | Forwarded hosts are only believed from TRUSTED_PROXIES, like the
+// client ip, and only from the configured header
+pub fn request_host(req: &HttpRequest) -> String {
+    let forwarded = req
+        .app_data::<web::Data<SessionConfig>>()
+        .filter(|_| from_trusted_proxy(req))
+        .and_then(|config| forwarded_host(req.headers(), config.proxy_header));
+
+    forwarded
+        .or_else(|| {
+            req.headers()
+                .get(HOST)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        })
+        .or_else(|| req.uri().host().map(str::to_string))
+        .unwrap_or_default()
+}
+
+// Addresses in the forwarding header, the client first and the last proxy last
+fn forwarded_hops(headers: &HeaderMap, header: ProxyHeader) -> Vec<String> {
+    match header {
+        ProxyHeader::XForwardedFor => header_elements(headers, &X_FORWARDED_FOR)
+            .map(str::to_string)
+            .collect(),
+        ProxyHeader::Forwarded => header_elements(headers, &FORWARDED)
+            .filter_map(|element| forwarded_param(element, "for"))
+            .collect(),
+    }
+}
+
+// Set by the last proxy
+fn forwarded_host(headers: &HeaderMap, header: ProxyHeader) -> Option<String> {
+    match header {
+        ProxyHeader::XForwardedFor => header_elements(headers, &X_FORWARDED_HOST)
+            .last()
+            .map(str::to_string),
+        ProxyHeader::Forwarded => header_elements(headers, &FORWARDED)
+            .filter_map(|element| forwarded_param(element, "host"))
+            .last(),
+    }
+}
+
+// Comma separated values of every line of the header
+fn header_elements<'a>(
+    headers: &'a HeaderMap,
+    name: &HeaderName,
+) -> impl Iterator<Item = &'a str> + 'a {
+    headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|element| !element.is_empty())
+}
+
+// for="[2001:db8::17]:4711";proto=https
+fn forwarded_param(element: &str, name: &str) -> Option<String> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+// Proxies append the address they got the request from, anything left of that was sent by the
+// client | So the header is walked from the right and trusted proxies are skipped
+fn first_untrusted_hop(hops: &[String], trusted_proxies: &[IpAddr]) -> Option<String> {
+    for hop in hops.iter().rev() {
+        match hop_ip(hop) {
+            Some(ip) if trusted_proxies.contains(&ip) => continue,
+            Some(ip) => return Some(ip.to_string()),
+            // Obfuscated or "unknown" | Added by a trusted proxy, but nothing to look further for
+            None => return Some(hop.clone()),
+        }
+    }
+    // Every hop is a trusted proxy, the leftmost one is where the request came from
+    hops.first().cloned()
+}
+
+// 192.0.2.1, 192.0.2.1:4711, 2001:db8::1 or [2001:db8::1]:4711
+fn hop_ip(hop: &str) -> Option<IpAddr> {
+    if let Ok(ip) = hop.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    hop.rsplit_once(':')?.0.parse().ok()
+}
+
+// Truncated, it is stored with sessions and login events
+pub fn user_agent(headers: &HeaderMap) -> String {
+    headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("unknown")
+        .chars()
+        .take(256)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: actix_web::http::header::HeaderName, values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn client(headers: &HeaderMap, header: ProxyHeader) -> Option<String> {
+        let trusted: Vec<IpAddr> = vec!["10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap()];
+        first_untrusted_hop(&forwarded_hops(headers, header), &trusted)
+    }
+
+    #[test]
+    fn spoofed_hops_left_of_the_proxy_are_ignored() {
+        // nginx appends the address it got the request from to what the client sent
+        let spoofed = headers(X_FORWARDED_FOR, &["1.1.1.1, 203.0.113.7"]);
+        assert_eq!(
+            client(&spoofed, ProxyHeader::XForwardedFor).as_deref(),
+            Some("203.0.113.7")
+        );
+
+        let chained = headers(X_FORWARDED_FOR, &["1.1.1.1", "203.0.113.7, 10.0.0.3"]);
+        assert_eq!(
+            client(&chained, ProxyHeader::XForwardedFor).as_deref(),
+            Some("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn forwarded_host_is_set_by_the_last_proxy() {
+        let host = headers(X_FORWARDED_HOST, &["evil.example.com, acme.example.com"]);
+        assert_eq!(
+            forwarded_host(&host, ProxyHeader::XForwardedFor).as_deref(),
+            Some("acme.example.com")
+        );
+
+        let forwarded = headers(FORWARDED, &["host=acme.example.com;for=1.1.1.1"]);
+        assert_eq!(
+            forwarded_host(&forwarded, ProxyHeader::Forwarded).as_deref(),
+            Some("acme.example.com")
+        );
+        assert_eq!(forwarded_host(&forwarded, ProxyHeader::XForwardedFor), None);
+    }
+
+    #[test]
+    fn forwarded_header_is_parsed() {
+        let forwarded = headers(
+            FORWARDED,
+            &[r#"for=1.1.1.1, for="[2001:db8::17]:4711";proto=https, for=10.0.0.2"#],
+        );
+        assert_eq!(
+            client(&forwarded, ProxyHeader::Forwarded).as_deref(),
+            Some("2001:db8::17")
+        );
+        // Only the configured header is believed
+        assert_eq!(client(&forwarded, ProxyHeader::XForwardedFor), None);
+    }
+}
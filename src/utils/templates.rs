@@ -0,0 +1,111 @@
+use actix_web::web;
+use log::{error, info};
+use notify::RecommendedWatcher;
+use std::path::Path;
+use std::sync::RwLock;
+use tera::{Context, Tera};
+
+use super::assets::Assets;
+use super::i18n;
+use super::watcher;
+
+// Provides REFERENCED_TEMPLATES, generated by build.rs from the template paths used in the views
+include!(concat!(env!("OUT_DIR"), "/template_refs.rs"));
+
+// Where templates are loaded from
+enum TemplateSource {
+    // Glob matching the template files, e.g. ./static/templates/**/*
+    #[cfg(not(feature = "embed-static"))]
+    Directory(String),
+    #[cfg(feature = "embed-static")]
+    Embedded,
+}
+
+impl TemplateSource {
+    fn load(&self, assets: &web::Data<Assets>) -> Result<Tera, tera::Error> {
+        let mut tera = match self {
+            #[cfg(not(feature = "embed-static"))]
+            TemplateSource::Directory(glob) => Tera::new(glob)?,
+            #[cfg(feature = "embed-static")]
+            TemplateSource::Embedded => {
+                let mut tera = Tera::default();
+                tera.add_raw_templates(super::assets::templates())?;
+                tera
+            }
+        };
+
+        tera.register_function("asset", Assets::tera_function(assets.clone()));
+        tera.register_function("t", i18n::tera_function());
+
+        Ok(tera)
+    }
+}
+
+// Tera instance shared by all workers, swapped out as a whole when templates are reloaded
+pub struct Templates {
+    source: TemplateSource,
+    assets: web::Data<Assets>,
+    tera: RwLock<Tera>,
+}
+
+impl Templates {
+    #[cfg(not(feature = "embed-static"))]
+    pub fn new(glob: &str, assets: web::Data<Assets>) -> Result<Self, tera::Error> {
+        Self::from_source(TemplateSource::Directory(glob.to_string()), assets)
+    }
+
+    // Uses the templates compiled into the binary
+    #[cfg(feature = "embed-static")]
+    pub fn embedded(assets: web::Data<Assets>) -> Result<Self, tera::Error> {
+        Self::from_source(TemplateSource::Embedded, assets)
+    }
+
+    fn from_source(source: TemplateSource, assets: web::Data<Assets>) -> Result<Self, tera::Error> {
+        let tera = source.load(&assets)?;
+
+        Ok(Templates {
+            source,
+            assets,
+            tera: RwLock::new(tera),
+        })
+    }
+
+    pub fn render(&self, template_path: &str, context: &Context) -> Result<String, tera::Error> {
+        self.tera
+            .read()
+            .expect("Template lock poisoned")
+            .render(template_path, context)
+    }
+
+    // Parses all templates again | The old templates stay active if parsing fails
+    pub fn reload(&self) -> Result<(), tera::Error> {
+        let tera = self.source.load(&self.assets)?;
+        *self.tera.write().expect("Template lock poisoned") = tera;
+
+        Ok(())
+    }
+
+    // Returns every template referenced by the views which is not loaded
+    pub fn missing_templates(&self) -> Vec<&'static str> {
+        let tera = self.tera.read().expect("Template lock poisoned");
+        let loaded: Vec<&str> = tera.get_template_names().collect();
+
+        REFERENCED_TEMPLATES
+            .iter()
+            .filter(|template| !loaded.contains(template))
+            .copied()
+            .collect()
+    }
+
+    // Reloads the templates whenever a file below the given directory changes
+    // The returned watcher stops watching once it is dropped
+    pub fn watch(
+        templates: web::Data<Templates>,
+        template_dir: &Path,
+    ) -> notify::Result<RecommendedWatcher> {
+        watcher::watch(&[template_dir], move || match templates.reload() {
+            Ok(_) => info!("Reloaded templates"),
+            Err(err) => error!("Failed to reload templates: {}", err),
+        })
+    }
+}
@@ -0,0 +1,376 @@
+use actix_session::SessionExt;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{HeaderValue, COOKIE, LOCATION, SET_COOKIE};
+use actix_web::http::{StatusCode, Uri};
+use actix_web::middleware::Next;
+use actix_web::{cookie::Cookie, web, Error, HttpMessage};
+use log::error;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use tera::Context;
+
+use super::i18n::keep_locale;
+use super::render::render_error;
+use super::sessions::{request_host, SessionConfig};
+use super::templates::Templates;
+use crate::database::db::Database;
+use crate::models::organizations::Organization;
+
+// Prefix of the paths of an organization in path mode, e.g. /t/acme/dashboard
+const PATH_PREFIX: &str = "/t/";
+
+// Subdomains of the base domain which belong to no organization
+const RESERVED_SUBDOMAINS: [&str; 1] = ["www"];
+
+// How requests are routed to organizations
+#[derive(Clone, Debug, PartialEq)]
+pub enum TenantRouting {
+    Off,
+    // acme.example.com | Holds the base domain, e.g. example.com
+    Subdomain(String),
+    // /t/acme/...
+    Path,
+}
+
+#[derive(Clone)]
+pub struct TenantConfig {
+    pub routing: TenantRouting,
+    // Shown on pages which belong to no organization
+    pub app_name: String,
+}
+
+impl TenantConfig {
+    // Reads TENANT_ROUTING (off, subdomain or path), TENANT_BASE_DOMAIN and APP_NAME
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let routing = match std::env::var("TENANT_ROUTING") {
+            Ok(value) => match value.to_lowercase().as_str() {
+                "off" => TenantRouting::Off,
+                "path" => TenantRouting::Path,
+                "subdomain" => match std::env::var("TENANT_BASE_DOMAIN") {
+                    Ok(domain) if !domain.trim().is_empty() => TenantRouting::Subdomain(
+                        domain.trim().trim_start_matches('.').to_lowercase(),
+                    ),
+                    _ => return Err("TENANT_BASE_DOMAIN is required for subdomain routing".into()),
+                },
+                _ => return Err(format!("Invalid TENANT_ROUTING: {}", value).into()),
+            },
+            Err(_) => TenantRouting::Off,
+        };
+
+        let app_name = match std::env::var("APP_NAME") {
+            Ok(name) => name,
+            Err(_) => String::from("Company Name"),
+        };
+
+        Ok(TenantConfig { routing, app_name })
+    }
+}
+
+// Organization the request has been routed to | Kept in the request extensions
+#[derive(Clone)]
+pub struct Tenant {
+    pub organization: Organization,
+}
+
+// Name, logo and colors shown by base/base.html | Colors are #rrggbb, None keeps the stylesheet
+#[derive(Clone, Default, Serialize)]
+pub struct Branding {
+    pub name: String,
+    pub logo_url: Option<String>,
+    pub primary_color: Option<String>,
+    pub accent_color: Option<String>,
+}
+
+// What pages need to know about the tenant | Templates are rendered without the request,
+// so it is kept for the task handling the request
+#[derive(Clone, Default)]
+struct PageContext {
+    branding: Branding,
+    // Prepended to links in templates, e.g. /t/acme in path mode
+    base_path: String,
+    // Replaces the host of APP_URL in mails, e.g. acme.example.com in subdomain mode
+    host: Option<String>,
+}
+
+tokio::task_local! {
+    static PAGE: PageContext;
+}
+
+// Adds branding and base_path to the context of a page
+pub fn insert_page_context(context: &mut Context) {
+    let page = PAGE.try_with(PageContext::clone).unwrap_or_default();
+    context.insert("branding", &page.branding);
+    context.insert("base_path", &page.base_path);
+}
+
+// APP_URL of the organization the request is routed to, for links in mails
+pub fn tenant_url(app_url: &str) -> String {
+    let Ok(page) = PAGE.try_with(PageContext::clone) else {
+        return app_url.to_string();
+    };
+
+    let mut base = app_url.trim_end_matches('/').to_string();
+    if let Some(host) = &page.host {
+        if let Ok(mut url) = url::Url::parse(app_url) {
+            if url.set_host(Some(host)).is_ok() {
+                base = url.as_str().trim_end_matches('/').to_string();
+            }
+        }
+    }
+    format!("{}{}", base, page.base_path)
+}
+
+// Spawns work which outlives the response, e.g. recording a login | Keeps the organization and
+// the language of the request, so mails sent from it link to the organization
+pub fn spawn_with_page(future: impl Future<Output = ()> + 'static) {
+    let future = keep_locale(future);
+    match PAGE.try_with(PageContext::clone) {
+        Ok(page) => actix_web::rt::spawn(PAGE.scope(page, future)),
+        Err(_) => actix_web::rt::spawn(future),
+    };
+}
+
+// Finds the organization of the request before routing | Unknown organizations get a 404
+// In path mode the prefix is stripped, so the app routes stay the same, and added again to
+// redirects and cookies | Every organization gets its own session cookie, in subdomain mode
+// since session cookies are host-only
+pub async fn resolve_tenant(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let config = req
+        .app_data::<web::Data<TenantConfig>>()
+        .cloned()
+        .ok_or_else(|| ErrorInternalServerError("Tenants not configured"))?;
+    let page = PageContext {
+        branding: Branding {
+            name: config.app_name.clone(),
+            ..Branding::default()
+        },
+        ..PageContext::default()
+    };
+
+    PAGE.scope(page, route(config, req, next)).await
+}
+
+async fn route(
+    config: web::Data<TenantConfig>,
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let slug = match &config.routing {
+        TenantRouting::Off => None,
+        // The Host the client connected to, unless a trusted proxy forwarded another one
+        TenantRouting::Subdomain(domain) => subdomain_slug(&request_host(req.request()), domain),
+        TenantRouting::Path => path_slug(req.path()).map(|(slug, _)| slug.to_string()),
+    };
+    let Some(slug) = slug else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    let db = req
+        .app_data::<web::Data<Arc<Database>>>()
+        .cloned()
+        .ok_or_else(|| ErrorInternalServerError("Database not configured"))?;
+    let lookup = slug.clone();
+    let organization = match web::block(move || db.get_organization_by_slug(&lookup)).await {
+        Ok(Ok(Some(organization))) => organization,
+        Ok(Ok(None)) => return not_found(req).map(ServiceResponse::map_into_right_body),
+        Ok(Err(err)) => {
+            error!("Failed to load organization: {}", err);
+            return Err(ErrorInternalServerError("Failed to load organization"));
+        }
+        Err(err) => {
+            error!("Blocking error occurred: {:?}", err);
+            return Err(ErrorInternalServerError("Failed to load organization"));
+        }
+    };
+
+    let session_cookie = req
+        .app_data::<web::Data<SessionConfig>>()
+        .map(|sessions| sessions.cookie_name())
+        .ok_or_else(|| ErrorInternalServerError("Sessions not configured"))?;
+    let base_path = match config.routing {
+        TenantRouting::Path => {
+            strip_prefix(&mut req)?;
+            scope_cookie_header(&mut req, session_cookie, &slug);
+            format!("{}{}", PATH_PREFIX, slug)
+        }
+        _ => String::new(),
+    };
+
+    let page = PageContext {
+        branding: Branding {
+            name: organization.name.clone(),
+            logo_url: organization.logo_url.clone(),
+            primary_color: organization.primary_color.clone(),
+            accent_color: organization.accent_color.clone(),
+        },
+        base_path: base_path.clone(),
+        host: match &config.routing {
+            TenantRouting::Subdomain(domain) => Some(format!("{}.{}", slug, domain)),
+            _ => None,
+        },
+    };
+    req.extensions_mut().insert(Tenant { organization });
+
+    let mut res = PAGE.scope(page, next.call(req)).await?;
+    if !base_path.is_empty() {
+        prefix_response(&mut res, &base_path, session_cookie, &slug);
+    }
+    Ok(res.map_into_left_body())
+}
+
+fn not_found(req: ServiceRequest) -> Result<ServiceResponse, Error> {
+    let templates = req
+        .app_data::<web::Data<Templates>>()
+        .cloned()
+        .ok_or_else(|| ErrorInternalServerError("Templates not configured"))?;
+    let response = render_error(
+        &templates,
+        &req.get_session(),
+        "This organization does not exist.",
+        "errors/error_page.html",
+        StatusCode::NOT_FOUND,
+    )?;
+    Ok(req.into_response(response))
+}
+
+// acme for acme.example.com | None for the base domain itself, reserved subdomains and other hosts
+fn subdomain_slug(host: &str, domain: &str) -> Option<String> {
+    let host = host.split(':').next()?.to_lowercase();
+    let slug = host.strip_suffix(domain)?.strip_suffix('.')?;
+    if slug.is_empty() || RESERVED_SUBDOMAINS.contains(&slug) {
+        return None;
+    }
+    Some(slug.to_string())
+}
+
+// acme and /dashboard for /t/acme/dashboard
+fn path_slug(path: &str) -> Option<(&str, &str)> {
+    let rest = path.strip_prefix(PATH_PREFIX)?;
+    let (slug, rest) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if slug.is_empty() {
+        return None;
+    }
+    Some((slug, rest))
+}
+
+// Routes /t/acme/dashboard?tab=1 like /dashboard?tab=1
+fn strip_prefix(req: &mut ServiceRequest) -> Result<(), Error> {
+    let Some((_, rest)) = path_slug(req.path()) else {
+        return Ok(());
+    };
+    let path_and_query = match req.query_string() {
+        "" => rest.to_string(),
+        query => format!("{}?{}", rest, query),
+    };
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(
+        path_and_query
+            .parse()
+            .map_err(|_| ErrorInternalServerError("Invalid path"))?,
+    );
+    let uri = Uri::from_parts(parts).map_err(|_| ErrorInternalServerError("Invalid path"))?;
+
+    req.match_info_mut().get_mut().update(&uri);
+    req.head_mut().uri = uri;
+    Ok(())
+}
+
+// Session cookie of the organization, e.g. id-acme | All organizations share the host
+fn tenant_cookie_name(session_cookie: &str, slug: &str) -> String {
+    format!("{}-{}", session_cookie, slug)
+}
+
+// Hands the session cookie of the organization to the session middleware under the usual name
+// and drops the one of the app, so sessions of different organizations stay apart
+fn scope_cookie_header(req: &mut ServiceRequest, session_cookie: &str, slug: &str) {
+    let tenant_cookie = tenant_cookie_name(session_cookie, slug);
+    let cookies: Vec<String> = req
+        .headers()
+        .get_all(COOKIE)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter_map(|pair| {
+            let name = pair.split('=').next().unwrap_or_default();
+            if name == session_cookie {
+                None
+            } else if name == tenant_cookie {
+                Some(format!(
+                    "{}{}",
+                    session_cookie,
+                    &pair[tenant_cookie.len()..]
+                ))
+            } else {
+                Some(pair.to_string())
+            }
+        })
+        .filter(|pair| !pair.is_empty())
+        .collect();
+
+    let headers = req.headers_mut();
+    headers.remove(COOKIE);
+    if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+        if !cookies.is_empty() {
+            headers.insert(COOKIE, value);
+        }
+    }
+}
+
+// Keeps redirects and cookies below /t/acme | The session cookie keeps path /, since
+// __Host- cookies require it, and is renamed instead
+fn prefix_response<B>(
+    res: &mut ServiceResponse<B>,
+    base_path: &str,
+    session_cookie: &str,
+    slug: &str,
+) {
+    let location = res
+        .headers()
+        .get(LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|location| location.starts_with('/') && !location.starts_with("//"))
+        .and_then(|location| HeaderValue::from_str(&format!("{}{}", base_path, location)).ok());
+    if let Some(location) = location {
+        res.headers_mut().insert(LOCATION, location);
+    }
+
+    let cookies: Vec<HeaderValue> = res
+        .headers()
+        .get_all(SET_COOKIE)
+        .map(|value| {
+            let parsed = value
+                .to_str()
+                .ok()
+                .and_then(|v| Cookie::parse_encoded(v).ok());
+            let cookie = match parsed {
+                Some(mut cookie) if cookie.name() == session_cookie => {
+                    cookie.set_name(tenant_cookie_name(session_cookie, slug));
+                    cookie
+                }
+                Some(mut cookie) => {
+                    let path = cookie.path().unwrap_or("/");
+                    cookie.set_path(format!("{}{}", base_path, path));
+                    cookie
+                }
+                None => return value.clone(),
+            };
+            HeaderValue::from_str(&cookie.encoded().to_string()).unwrap_or(value.clone())
+        })
+        .collect();
+
+    let headers = res.headers_mut();
+    headers.remove(SET_COOKIE);
+    for value in cookies {
+        headers.append(SET_COOKIE, value);
+    }
+}
@@ -0,0 +1,54 @@
+use rand::distributions::{Alphanumeric, DistString};
+
+// Purposes of tokens | Prefix of their cache keys, so a token only works for what it was sent for
+pub const EMAIL_CHANGE: &str = "email_change";
+pub const EMAIL_VERIFICATION: &str = "email_verification";
+pub const MAGIC_LINK: &str = "magic_link";
+
+// Random secret for links sent by mail, e.g. email confirmation | About 256 bits
+pub fn generate_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 43)
+}
+
+// Public id of an OAuth client | Not a secret, only has to be unique
+pub fn generate_client_id() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 24)
+}
+
+// Only hashes of tokens are stored, so a leaked cache or database does not contain usable links
+pub fn hash_token(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+// Personal API tokens look like pat_<prefix>_<secret> | The prefix finds the token in the
+// database and tells users which token is which, the secret is never stored
+const API_TOKEN_PREFIX: &str = "pat";
+const API_TOKEN_PREFIX_LENGTH: usize = 8;
+
+// Returns the token and its prefix
+pub fn generate_api_token() -> (String, String) {
+    let prefix = Alphanumeric.sample_string(&mut rand::thread_rng(), API_TOKEN_PREFIX_LENGTH);
+    let token = format!("{}_{}_{}", API_TOKEN_PREFIX, prefix, generate_token());
+    (token, prefix)
+}
+
+// Prefix of a token in the pat_<prefix>_<secret> format
+pub fn api_token_prefix(token: &str) -> Option<&str> {
+    let mut parts = token.splitn(3, '_');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(API_TOKEN_PREFIX), Some(prefix), Some(secret))
+            if prefix.len() == API_TOKEN_PREFIX_LENGTH && !secret.is_empty() =>
+        {
+            Some(prefix)
+        }
+        _ => None,
+    }
+}
+
+// Compares in constant time, so response times do not reveal how much of a token matched
+pub fn token_matches(token: &str, token_hash: &str) -> bool {
+    match blake3::Hash::from_hex(token_hash) {
+        Ok(expected) => blake3::hash(token.as_bytes()) == expected,
+        Err(_) => false,
+    }
+}
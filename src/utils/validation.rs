@@ -0,0 +1,122 @@
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use validator::{Validate, ValidationErrors};
+
+use super::i18n::{translate, translate_with};
+
+// Error messages per form field, e.g. "email" -> ["Please enter a valid email address"]
+pub type FieldErrors = HashMap<String, Vec<String>>;
+
+// Form which has been deserialized and checked against its #[validate(...)] rules
+// Extraction only fails for malformed bodies | Rule violations end up in errors
+pub struct ValidatedForm<T> {
+    pub data: T,
+    pub errors: FieldErrors,
+}
+
+impl<T> ValidatedForm<T> {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    // Adds an error which can only be detected later on, e.g. an email which is already taken
+    // Translated like the messages of the rules
+    pub fn add_error(&mut self, field: &str, message: &str) {
+        self.errors
+            .entry(field.to_string())
+            .or_default()
+            .push(translate(message));
+    }
+}
+
+impl<T> std::ops::Deref for ValidatedForm<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+pub fn field_errors(errors: &ValidationErrors) -> FieldErrors {
+    errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| {
+            let messages = errors
+                .iter()
+                .map(|error| match &error.message {
+                    Some(message) => translate(message),
+                    None => translate_with("Invalid value ({code})", &[("code", &error.code)]),
+                })
+                .collect();
+            (field.to_string(), messages)
+        })
+        .collect()
+}
+
+impl<T> FromRequest for ValidatedForm<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let form = web::Form::<T>::from_request(req, payload);
+
+        Box::pin(async move {
+            let data = form.await?.into_inner();
+            let errors = match data.validate() {
+                Ok(_) => FieldErrors::new(),
+                Err(errors) => field_errors(&errors),
+            };
+
+            Ok(ValidatedForm { data, errors })
+        })
+    }
+}
+
+// JSON counterpart of ValidatedForm for the API
+pub struct ValidatedJson<T> {
+    pub data: T,
+    pub errors: FieldErrors,
+}
+
+impl<T> ValidatedJson<T> {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl<T> std::ops::Deref for ValidatedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T> FromRequest for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+
+        Box::pin(async move {
+            let data = json.await?.into_inner();
+            let errors = match data.validate() {
+                Ok(_) => FieldErrors::new(),
+                Err(errors) => field_errors(&errors),
+            };
+
+            Ok(ValidatedJson { data, errors })
+        })
+    }
+}
@@ -0,0 +1,32 @@
+use log::error;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::Path;
+
+// Calls on_change whenever a file below one of the directories is created, modified or removed
+// The returned watcher stops watching once it is dropped
+pub fn watch<F>(dirs: &[&Path], on_change: F) -> notify::Result<RecommendedWatcher>
+where
+    F: Fn() + Send + 'static,
+{
+    let mut watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) {
+                    on_change();
+                }
+            }
+            Err(err) => error!("File watcher error: {}", err),
+        })?;
+
+    for dir in dirs {
+        // Directories like static/js are optional
+        if dir.is_dir() {
+            watcher.watch(dir, RecursiveMode::Recursive)?;
+        }
+    }
+
+    Ok(watcher)
+}